@@ -0,0 +1,29 @@
+//! Sets `GIT_HASH` to the commit the binary is built from.
+//!
+//! Builds without a checkout (e.g. in a container) can set `GIT_HASH`
+//! themselves, it is left out if neither is available.
+
+use std::process::Command;
+
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    let stdout = String::from_utf8(output.stdout).ok()?;
+    output.status.success().then(|| stdout.trim().to_owned())
+}
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=GIT_HASH");
+    if std::env::var_os("GIT_HASH").is_some() {
+        return;
+    }
+    let Some(hash) = git(&["rev-parse", "--short=12", "HEAD"]) else {
+        return;
+    };
+    println!("cargo:rustc-env=GIT_HASH={hash}");
+    // Rebuild when HEAD moves, either by checking out or by committing
+    for path in ["HEAD", "refs/heads"] {
+        if let Some(path) = git(&["rev-parse", "--git-path", path]) {
+            println!("cargo:rerun-if-changed={path}");
+        }
+    }
+}
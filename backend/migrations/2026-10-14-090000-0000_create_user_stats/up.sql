@@ -0,0 +1,10 @@
+CREATE TABLE user_stats (
+	user_id INTEGER NOT NULL PRIMARY KEY,
+	games_played INTEGER NOT NULL DEFAULT 0,
+	total_kills INTEGER NOT NULL DEFAULT 0,
+	total_time_played INTEGER NOT NULL DEFAULT 0,
+	last_game_kills INTEGER NOT NULL DEFAULT 0,
+	last_game_time_played INTEGER NOT NULL DEFAULT 0,
+	last_game_at DATETIME NOT NULL,
+	FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
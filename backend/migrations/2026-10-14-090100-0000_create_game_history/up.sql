@@ -0,0 +1,9 @@
+CREATE TABLE game_history (
+	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	user_id INTEGER NOT NULL,
+	kills INTEGER NOT NULL,
+	time_played INTEGER NOT NULL,
+	played_at DATETIME NOT NULL,
+	FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+CREATE INDEX idx_game_history_user_id_played_at ON game_history(user_id, played_at);
@@ -0,0 +1,12 @@
+CREATE TABLE user_bans (
+	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	user_id INTEGER NOT NULL,
+	banned_by INTEGER,
+	reason TEXT NOT NULL,
+	until DATETIME,
+	created_at DATETIME NOT NULL,
+	lifted_at DATETIME,
+	FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+	FOREIGN KEY (banned_by) REFERENCES users(id) ON DELETE SET NULL
+);
+CREATE INDEX idx_user_bans_user_id ON user_bans(user_id);
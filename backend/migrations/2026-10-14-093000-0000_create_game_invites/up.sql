@@ -0,0 +1,13 @@
+CREATE TABLE game_invites (
+	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	from_user_id INTEGER NOT NULL,
+	to_user_id INTEGER NOT NULL,
+	-- pending, accepted, declined or expired
+	status TEXT NOT NULL DEFAULT 'pending',
+	created_at DATETIME NOT NULL,
+	expires_at DATETIME NOT NULL,
+	FOREIGN KEY (from_user_id) REFERENCES users(id) ON DELETE CASCADE,
+	FOREIGN KEY (to_user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+CREATE INDEX idx_game_invites_from_user_id ON game_invites(from_user_id, status);
+CREATE INDEX idx_game_invites_to_user_id ON game_invites(to_user_id, status);
@@ -0,0 +1,12 @@
+CREATE TABLE trusted_devices (
+	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	user_id INTEGER NOT NULL,
+	device_id TEXT NOT NULL,
+	-- blake3 hash of the secret in the trusted_device cookie
+	token_hash BLOB NOT NULL,
+	created_at DATETIME NOT NULL,
+	last_used_at DATETIME NOT NULL,
+	expires_at DATETIME NOT NULL,
+	FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+	UNIQUE (user_id, device_id)
+);
@@ -0,0 +1,13 @@
+CREATE TABLE security_events (
+	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	user_id INTEGER NOT NULL,
+	-- variant name of SecurityEvent, e.g. PasswordChanged
+	event_type TEXT NOT NULL,
+	-- JSON object with details of the event
+	metadata TEXT,
+	ip_address TEXT,
+	device_name TEXT,
+	created_at DATETIME NOT NULL,
+	FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+CREATE INDEX idx_security_events_user_id ON security_events(user_id, created_at);
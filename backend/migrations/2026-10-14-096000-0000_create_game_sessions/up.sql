@@ -0,0 +1,14 @@
+CREATE TABLE game_sessions (
+	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	-- NULL once the player deleted their account, the game stays in the
+	-- history of the other
+	player_a INTEGER,
+	player_b INTEGER,
+	-- created, running or finished
+	state TEXT NOT NULL DEFAULT 'created',
+	created_at DATETIME NOT NULL,
+	FOREIGN KEY (player_a) REFERENCES users(id) ON DELETE SET NULL,
+	FOREIGN KEY (player_b) REFERENCES users(id) ON DELETE SET NULL
+);
+CREATE INDEX idx_game_sessions_player_a ON game_sessions(player_a);
+CREATE INDEX idx_game_sessions_player_b ON game_sessions(player_b);
@@ -0,0 +1,15 @@
+CREATE TABLE idempotency_keys (
+	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	user_id INTEGER NOT NULL,
+	-- Idempotency-Key header sent by the client
+	key TEXT NOT NULL,
+	-- blake3 hash of the method, path and body of the first request
+	request_hash BLOB NOT NULL,
+	-- NULL while the first request is still being handled
+	status INTEGER,
+	response_body BLOB,
+	created_at DATETIME NOT NULL,
+	FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+	UNIQUE (user_id, key)
+);
+CREATE INDEX idx_idempotency_keys_created_at ON idempotency_keys(created_at);
@@ -0,0 +1,27 @@
+-- Store email domains in lowercase, like crate::validate::normalize_email.
+-- Addresses can't contain another '@' (see the email validation at
+-- registration). The email column is unique with COLLATE NOCASE and lower()
+-- only folds ASCII, so accounts shouldn't collide. If they do, abort before
+-- changing anything. The SQLite we link only takes a literal RAISE message,
+-- list the conflicts with the SELECT of the INSERT below.
+CREATE TEMP TABLE email_domain_conflicts (emails TEXT NOT NULL);
+CREATE TEMP TRIGGER abort_on_email_domain_conflicts
+AFTER INSERT ON email_domain_conflicts
+BEGIN
+	SELECT RAISE(ABORT, 'Emails collide once their domains are lowercased, list them with the SELECT in normalize_email_domains/up.sql');
+END;
+INSERT INTO email_domain_conflicts
+SELECT group_concat(email, ', ')
+FROM users
+WHERE instr(email, '@') > 0
+GROUP BY substr(email, 1, instr(email, '@'))
+	|| lower(substr(email, instr(email, '@') + 1)) COLLATE NOCASE
+HAVING count(*) > 1;
+DROP TABLE email_domain_conflicts;
+
+UPDATE users
+SET email = substr(email, 1, instr(email, '@'))
+	|| lower(substr(email, instr(email, '@') + 1))
+WHERE instr(email, '@') > 0
+	AND substr(email, instr(email, '@') + 1)
+		!= lower(substr(email, instr(email, '@') + 1));
@@ -0,0 +1,4 @@
+-- The name derived from the User-Agent, device_name is the one shown and
+-- differs from it once the user renamed the session
+ALTER TABLE sessions ADD COLUMN device_name_auto TEXT;
+UPDATE sessions SET device_name_auto = device_name;
@@ -0,0 +1,7 @@
+CREATE TABLE feature_flags (
+	key TEXT NOT NULL PRIMARY KEY,
+	enabled BOOLEAN NOT NULL DEFAULT FALSE,
+	-- Optional JSON settings of the feature
+	payload TEXT,
+	updated_at DATETIME NOT NULL
+);
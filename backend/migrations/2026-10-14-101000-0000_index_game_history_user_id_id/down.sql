@@ -0,0 +1 @@
+DROP INDEX idx_game_history_user_id_id;
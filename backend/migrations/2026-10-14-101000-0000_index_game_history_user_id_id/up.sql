@@ -0,0 +1,2 @@
+-- Scans of a user's history in id order, see GET /api/game/history/export
+CREATE INDEX idx_game_history_user_id_id ON game_history(user_id, id);
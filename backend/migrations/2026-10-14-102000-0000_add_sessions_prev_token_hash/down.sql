@@ -0,0 +1,2 @@
+DROP INDEX idx_sessions_prev_token_hash;
+ALTER TABLE sessions DROP COLUMN prev_token_hash;
@@ -0,0 +1,4 @@
+-- The token hash replaced at the last rotation, presenting that token again
+-- revokes the session
+ALTER TABLE sessions ADD COLUMN prev_token_hash BLOB;
+CREATE INDEX idx_sessions_prev_token_hash ON sessions(prev_token_hash);
@@ -0,0 +1,4 @@
+-- Set while an admin locks the account, e.g. pending a review. Unlike a ban
+-- it is independent of user_bans and leaves the user's data untouched
+ALTER TABLE users ADD COLUMN locked_at DATETIME;
+ALTER TABLE users ADD COLUMN lock_reason TEXT;
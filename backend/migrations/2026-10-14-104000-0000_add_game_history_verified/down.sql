@@ -0,0 +1,5 @@
+DROP INDEX idx_game_history_game_session_id;
+ALTER TABLE game_history DROP COLUMN reported_time_played;
+ALTER TABLE game_history DROP COLUMN reported_kills;
+ALTER TABLE game_history DROP COLUMN verified;
+ALTER TABLE game_history DROP COLUMN game_session_id;
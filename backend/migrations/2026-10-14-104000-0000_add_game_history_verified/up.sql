@@ -0,0 +1,9 @@
+-- The server-side game a row was recorded for, NULL for practice games
+ALTER TABLE game_history ADD COLUMN game_session_id INTEGER REFERENCES game_sessions(id) ON DELETE SET NULL;
+-- Whether the numbers come from the server. Only verified games count for
+-- user_stats and the leaderboard, rows recorded before stay counted
+ALTER TABLE game_history ADD COLUMN verified BOOLEAN NOT NULL DEFAULT 1;
+-- What the client submitted for a verified game, telemetry only
+ALTER TABLE game_history ADD COLUMN reported_kills INTEGER;
+ALTER TABLE game_history ADD COLUMN reported_time_played INTEGER;
+CREATE INDEX idx_game_history_game_session_id ON game_history(game_session_id);
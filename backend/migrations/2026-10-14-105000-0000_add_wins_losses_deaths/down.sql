@@ -0,0 +1,5 @@
+ALTER TABLE user_stats DROP COLUMN total_deaths;
+ALTER TABLE user_stats DROP COLUMN losses;
+ALTER TABLE user_stats DROP COLUMN wins;
+ALTER TABLE game_history DROP COLUMN won;
+ALTER TABLE game_history DROP COLUMN deaths;
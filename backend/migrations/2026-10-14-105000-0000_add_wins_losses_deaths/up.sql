@@ -0,0 +1,9 @@
+-- Points conceded and whether the player won, existing rows are games
+-- without deaths that weren't won
+ALTER TABLE game_history ADD COLUMN deaths INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE game_history ADD COLUMN won BOOLEAN NOT NULL DEFAULT 0;
+-- Totals of the verified games, like the other user_stats columns, zero for
+-- the games recorded before
+ALTER TABLE user_stats ADD COLUMN wins INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE user_stats ADD COLUMN losses INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE user_stats ADD COLUMN total_deaths INTEGER NOT NULL DEFAULT 0;
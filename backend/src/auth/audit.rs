@@ -0,0 +1,413 @@
+//! Audit log of security-relevant account events.
+//!
+//! Events are stored in the `security_events` table, so users can review
+//! their own history and support can follow what happened to an account.
+//! Record an event in the transaction of the action it describes, where the
+//! action has one.
+
+use crate::auth::AuthError;
+use crate::models::{NewSecurityEventEntry, SecurityEventEntry};
+use crate::prelude::*;
+
+use super::util;
+
+const DEFAULT_EVENTS_LIMIT: i64 = 20;
+const MAX_EVENTS_LIMIT: i64 = 100;
+
+/// The variant name is stored as `event_type`, the fields as JSON metadata.
+///
+/// Never put secrets (passwords, codes, tokens) into the fields.
+#[derive(Debug, Serialize, strum::IntoStaticStr)]
+#[serde(untagged)]
+pub enum SecurityEvent {
+    LoginSucceeded {
+        trusted_device: bool,
+    },
+    LoginFailed {
+        reason: LoginFailure,
+    },
+    Reauth,
+    PasswordChanged {
+        other_sessions_logged_out: bool,
+    },
+    TwoFaStarted,
+    TwoFaConfirmed,
+    TwoFaDisabled,
+    RecoveryCodeUsed {
+        remaining: i64,
+    },
+    RecoveryCodesRegenerated,
+    SessionsLoggedOut {
+        session_ids: Vec<i32>,
+    },
+    OtherSessionsLoggedOut {
+        count: usize,
+    },
+    SessionsDeleted {
+        session_ids: Vec<i32>,
+    },
+    TrustedDeviceRemoved {
+        trusted_device_id: i32,
+    },
+    DataExported,
+    /// A login had the `device_id` of a Session, but neither its network
+    /// nor its device name, so a new Session was created instead
+    DeviceIdMismatch {
+        session_id: i32,
+    },
+    /// The previous refresh token of a Session was presented again
+    SessionTokenReused {
+        session_id: i32,
+    },
+    /// An admin locked the account, see [`super::ban`]
+    AccountLocked,
+    AccountUnlocked,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoginFailure {
+    Password,
+    TwoFactor,
+}
+
+impl LoginFailure {
+    /// The failure of a login error, `None` if it isn't about the credentials.
+    pub fn from_error(err: &ApiError) -> Option<Self> {
+        match err {
+            ApiError::PasswordHash(argon2::password_hash::Error::Password)
+            | ApiError::Auth(AuthError::InvalidCredentials) => {
+                Some(Self::Password)
+            }
+            ApiError::Auth(AuthError::TwoFactorInvalid) => {
+                Some(Self::TwoFactor)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Where an event came from, empty if it happened without a request.
+#[derive(Debug, Default)]
+pub struct AuditContext {
+    ip_address: Option<String>,
+    device_name: Option<String>,
+}
+
+impl AuditContext {
+    pub fn from_request(req: &Request) -> Self {
+        let (device_name, ip_address) = util::get_device_and_ip(req);
+        Self {
+            ip_address,
+            device_name,
+        }
+    }
+}
+
+/// Store a security event of a user.
+pub fn record(
+    conn: &mut DbConn,
+    user_id: i32,
+    event: SecurityEvent,
+    ctx: &AuditContext,
+) -> AppResult<()> {
+    use crate::schema::security_events::dsl::security_events;
+
+    let metadata = match serde_json::to_value(&event) {
+        Ok(serde_json::Value::Null) => None,
+        Ok(value) => Some(value.to_string()),
+        Err(err) => {
+            tracing::error!(%err, "Failed to serialize security event");
+            None
+        }
+    };
+    diesel::insert_into(security_events)
+        .values(&NewSecurityEventEntry {
+            user_id,
+            event_type: <&'static str>::from(&event).to_owned(),
+            metadata,
+            ip_address: ctx.ip_address.clone(),
+            device_name: ctx.device_name.clone(),
+            created_at: chrono::Utc::now().naive_utc(),
+        })
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Log the deletion of the account of `user_id`.
+///
+/// Its security events are deleted with it, so the log is all that is left
+/// for support and incident response.
+pub fn log_account_deleted(user_id: i32, ctx: &AuditContext) {
+    tracing::info!(
+        user_id,
+        event_type = "AccountDeleted",
+        ip_address = ctx.ip_address.as_deref(),
+        device_name = ctx.device_name.as_deref(),
+        "Account deleted"
+    );
+}
+
+/// Record a failed login if `err` is about the credentials of an existing
+/// user.
+///
+/// Errors are only logged, the login already failed.
+pub fn record_failed_login(
+    conn: &mut DbConn,
+    login_email: &str,
+    err: &ApiError,
+    ctx: &AuditContext,
+) {
+    use crate::schema::users::dsl::*;
+
+    let Some(reason) = LoginFailure::from_error(err) else {
+        return;
+    };
+    let result = users
+        .filter(email.eq(login_email))
+        .select(id)
+        .first::<i32>(conn)
+        .optional()
+        .map_err(ApiError::from)
+        .and_then(|user_id| match user_id {
+            Some(user_id) => record(
+                conn,
+                user_id,
+                SecurityEvent::LoginFailed { reason },
+                ctx,
+            ),
+            None => Ok(()),
+        });
+    if let Err(err) = result {
+        tracing::error!(%err, "Failed to record failed login");
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SecurityEventInfo {
+    pub id: i32,
+    /// E.g. `PasswordChanged`
+    pub event_type: String,
+    /// Details of the event, depending on `event_type`. Stored as recorded,
+    /// so the keys are snake_case.
+    pub metadata: Option<serde_json::Value>,
+    pub ip_address: Option<String>,
+    pub device_name: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+impl From<SecurityEventEntry> for SecurityEventInfo {
+    fn from(entry: SecurityEventEntry) -> Self {
+        Self {
+            id: entry.id,
+            event_type: entry.event_type,
+            metadata: entry
+                .metadata
+                .and_then(|metadata| serde_json::from_str(&metadata).ok()),
+            ip_address: entry.ip_address,
+            device_name: entry.device_name,
+            created_at: entry.created_at,
+        }
+    }
+}
+
+/// Retrieve the security events of the current User, newest first
+#[endpoint]
+pub fn list_security_events(
+    limit: QueryParam<i64, false>,
+    offset: QueryParam<i64, false>,
+    depot: &mut Depot,
+) -> JsonResult<Vec<SecurityEventInfo>> {
+    use crate::schema::security_events::dsl::*;
+
+    let conn = &mut db::get()?;
+    let limit = limit
+        .into_inner()
+        .unwrap_or(DEFAULT_EVENTS_LIMIT)
+        .clamp(1, MAX_EVENTS_LIMIT);
+    let offset = offset.into_inner().unwrap_or(0).max(0);
+
+    let events = security_events
+        .filter(user_id.eq(depot.user_id()))
+        .order((created_at.desc(), id.desc()))
+        .limit(limit)
+        .offset(offset)
+        .load::<SecurityEventEntry>(conn)?;
+
+    json_ok(events.into_iter().map(Into::into).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use salvo::test::{ResponseExt, TestClient};
+    use serde_json::{Value, json};
+
+    use crate::prelude::*;
+    use crate::test_db::{self, Client, PASSWORD};
+
+    const BASE: &str = "http://localhost/api";
+    const EMAIL: &str = "mallory@example.com";
+
+    async fn event_types(
+        client: &mut Client,
+        service: &Service,
+    ) -> Vec<String> {
+        let mut res = client
+            .send(
+                TestClient::get(format!("{BASE}/user/security-events"))
+                    .query("limit", 100),
+                service,
+            )
+            .await;
+        assert_eq!(res.status_code, Some(StatusCode::OK));
+        let events: Vec<Value> = res.take_json().await.unwrap();
+        events
+            .iter()
+            .map(|event| event["eventType"].as_str().unwrap().to_owned())
+            .collect()
+    }
+
+    async fn login(service: &Service, password: &str) -> (Client, StatusCode) {
+        let mut client = Client::default();
+        let res = client
+            .send(
+                TestClient::post(format!("{BASE}/auth/login"))
+                    .json(&json!({ "email": EMAIL, "password": password })),
+                service,
+            )
+            .await;
+        (client, res.status_code.unwrap())
+    }
+
+    /// Run `action` and check that it recorded exactly `event_type`.
+    async fn expect_event(
+        client: &mut Client,
+        service: &Service,
+        event_type: &str,
+        action: impl AsyncFnOnce(),
+    ) {
+        let before = event_types(client, service).await;
+        action().await;
+        let after = event_types(client, service).await;
+        assert_eq!(after.len(), before.len() + 1, "{after:?}");
+        assert_eq!(after[0], event_type);
+    }
+
+    #[tokio::test]
+    async fn instrumented_endpoints_record_one_event() {
+        test_db::with_service(|service| async move {
+            let (mut client, _) =
+                Client::register(&service, EMAIL, "mallory").await;
+            let service = &service;
+            // Lists the events, none of the actions touch its Session
+            let (mut observer, _) = login(service, PASSWORD).await;
+            let observer = &mut observer;
+
+            expect_event(observer, service, "LoginFailed", async || {
+                let (_, status) = login(service, "wrong password 123").await;
+                assert_eq!(status, StatusCode::UNAUTHORIZED);
+            })
+            .await;
+            expect_event(observer, service, "LoginSucceeded", async || {
+                let (_, status) = login(service, PASSWORD).await;
+                assert_eq!(status, StatusCode::OK);
+            })
+            .await;
+            expect_event(observer, service, "Reauth", async || {
+                let res = client
+                    .send(
+                        TestClient::post(format!(
+                            "{BASE}/auth/session-management/reauth"
+                        ))
+                        .json(&json!({ "password": PASSWORD })),
+                        service,
+                    )
+                    .await;
+                assert_eq!(res.status_code, Some(StatusCode::OK));
+            })
+            .await;
+            expect_event(observer, service, "PasswordChanged", async || {
+                let res = client
+                    .send(
+                        TestClient::post(format!(
+                            "{BASE}/user/change-password"
+                        ))
+                        .json(&json!({
+                            "password": PASSWORD,
+                            "newPassword": "a different long passphrase 7",
+                            "keepOtherSessionsLoggedIn": true,
+                        })),
+                        service,
+                    )
+                    .await;
+                assert_eq!(res.status_code, Some(StatusCode::OK));
+            })
+            .await;
+            // Deleting the current Session logs it out
+            let mut res = client
+                .send(TestClient::get(format!("{BASE}/user/session")), service)
+                .await;
+            let session: Value = res.take_json().await.unwrap();
+            let session_id = session["sessionId"].as_i64().unwrap();
+            expect_event(observer, service, "SessionsLoggedOut", async || {
+                let res = client
+                    .send(
+                        TestClient::delete(format!(
+                            "{BASE}/user/sessions/{session_id}"
+                        ))
+                        .json(&json!({})),
+                        service,
+                    )
+                    .await;
+                assert_eq!(res.status_code, Some(StatusCode::OK));
+            })
+            .await;
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn listing_paginates_newest_first() {
+        test_db::with_service(|service| async move {
+            let (mut client, _) =
+                Client::register(&service, EMAIL, "mallory").await;
+            for _ in 0..3 {
+                login(&service, "wrong password 123").await;
+            }
+            login(&service, PASSWORD).await;
+            let all = event_types(&mut client, &service).await;
+            assert_eq!(
+                all,
+                [
+                    "LoginSucceeded",
+                    "LoginFailed",
+                    "LoginFailed",
+                    "LoginFailed"
+                ]
+            );
+
+            let page = |offset: i64| {
+                let request =
+                    TestClient::get(format!("{BASE}/user/security-events"))
+                        .query("limit", 2)
+                        .query("offset", offset);
+                client.with_cookies(request)
+            };
+            let mut res = page(0).send(&service).await;
+            let first: Vec<Value> = res.take_json().await.unwrap();
+            let mut res = page(2).send(&service).await;
+            let second: Vec<Value> = res.take_json().await.unwrap();
+            assert_eq!(first.len(), 2);
+            assert_eq!(second.len(), 2);
+            assert_eq!(first[0]["eventType"], "LoginSucceeded");
+            // Every test has a peer of its own in 10.0.0.0/24
+            assert_eq!(first[0]["ipAddress"], "10.0.0.0");
+            assert!(first[1]["id"].as_i64() > second[0]["id"].as_i64());
+            let mut res = page(4).send(&service).await;
+            let rest: Vec<Value> = res.take_json().await.unwrap();
+            assert!(rest.is_empty());
+        })
+        .await;
+    }
+}
@@ -0,0 +1,62 @@
+//! Bans and locks issued through the admin routes.
+//!
+//! A banned user can't log in, refresh or use an access token until the ban
+//! expires or is lifted. A locked account is rejected the same way until an
+//! admin unlocks it, e.g. while suspicious activity is reviewed. Bans and
+//! locks are independent, lifting one leaves the other in effect.
+
+use chrono::NaiveDateTime;
+
+use crate::prelude::*;
+use crate::schema::{user_bans, users};
+
+/// Whether a `user_bans` row is in effect at `now`.
+#[diesel::dsl::auto_type]
+pub(crate) fn ban_in_effect(now: NaiveDateTime) -> _ {
+    user_bans::lifted_at
+        .is_null()
+        .and(user_bans::until.is_null().or(user_bans::until.gt(now)))
+}
+
+pub(crate) fn is_banned(
+    conn: &mut DbConn,
+    target_user: i32,
+) -> AppResult<bool> {
+    let now = chrono::Utc::now().naive_utc();
+    let banned = diesel::select(diesel::dsl::exists(
+        user_bans::table
+            .filter(user_bans::user_id.eq(target_user))
+            .filter(ban_in_effect(now)),
+    ))
+    .get_result(conn)?;
+    Ok(banned)
+}
+
+/// Reject banned users with [`AuthError::Banned`](super::AuthError::Banned).
+pub(crate) fn ensure_not_banned(
+    conn: &mut DbConn,
+    target_user: i32,
+) -> AppResult<()> {
+    if is_banned(conn, target_user)? {
+        return Err(super::AuthError::Banned.into());
+    }
+    Ok(())
+}
+
+/// Reject locked accounts with
+/// [`AuthError::AccountLocked`](super::AuthError::AccountLocked).
+pub(crate) fn ensure_not_locked(
+    conn: &mut DbConn,
+    target_user: i32,
+) -> AppResult<()> {
+    let locked = diesel::select(diesel::dsl::exists(
+        users::table
+            .filter(users::id.eq(target_user))
+            .filter(users::locked_at.is_not_null()),
+    ))
+    .get_result(conn)?;
+    if locked {
+        return Err(super::AuthError::AccountLocked.into());
+    }
+    Ok(())
+}
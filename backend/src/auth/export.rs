@@ -0,0 +1,355 @@
+//! Export of all data belonging to a user, the counterpart of deleting the
+//! account.
+//!
+//! The export is a single JSON object, streamed to the client one table at a
+//! time instead of being built in memory first. Its layout is versioned by
+//! [`EXPORT_SCHEMA_VERSION`]. Secrets (password hash, TOTP secret, token
+//! hashes, recovery codes) are never included.
+
+use salvo::http::body::BodySender;
+use salvo::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE, HeaderValue};
+
+use super::audit::{self, AuditContext, SecurityEvent, SecurityEventInfo};
+use super::trusted_device::TrustedDeviceInfo;
+use super::user::SessionInfo;
+use super::util;
+use crate::auth::router::PasswordInput;
+use crate::models::{
+    GameHistory, GameInvite, GameSession, SecurityEventEntry, Session,
+    TrustedDevice, User, UserBan, UserStats,
+};
+use crate::prelude::*;
+
+/// Increased whenever fields are removed, renamed or change their meaning.
+const EXPORT_SCHEMA_VERSION: u32 = 2;
+
+/// Export all data of the current User as a JSON file
+///
+/// Requires current password for verification, and a 2FA code if 2FA is
+/// enabled. The export is downloaded as an attachment. Limited to one export
+/// per hour, failed attempts included.
+#[endpoint(responses(
+    (status_code = 200, description = "The JSON export of the User"),
+))]
+pub async fn export_data(
+    json: JsonBody<PasswordInput>,
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> AppResult<()> {
+    let session = depot.session().clone();
+    let me = session.user_id;
+    let PasswordInput { password, mfa_code } = json.into_inner();
+    let ctx = AuditContext::from_request(req);
+    let user = db::with_conn(move |conn| {
+        let user = util::check_password_and_mfa_if_enabled(
+            me,
+            &password,
+            mfa_code.as_deref(),
+            conn,
+        )?;
+        audit::record(conn, me, SecurityEvent::DataExported, &ctx)?;
+        Ok(user)
+    })
+    .await?;
+
+    let filename =
+        format!("attachment; filename=\"transcendence-export-{me}.json\"");
+    let headers = res.headers_mut();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("application/json; charset=utf-8"),
+    );
+    headers.insert(
+        CONTENT_DISPOSITION,
+        HeaderValue::from_str(&filename).expect("ASCII header value"),
+    );
+    let mut writer = ExportWriter::new(res.channel());
+    tokio::spawn(async move {
+        if let Err(err) = write_export(&mut writer, user, session).await {
+            tracing::error!(%err, user_id = me, "Failed to export user data");
+            // Abort the download instead of ending it like a complete export
+            writer
+                .sender
+                .send_error(std::io::Error::other(err.to_string()));
+        }
+    });
+    Ok(())
+}
+
+async fn write_export(
+    writer: &mut ExportWriter,
+    user: User,
+    session: Session,
+) -> anyhow::Result<()> {
+    let me = user.id;
+    writer
+        .field("schemaVersion", &EXPORT_SCHEMA_VERSION)
+        .await?;
+    writer
+        .field("exportedAt", &chrono::Utc::now().naive_utc())
+        .await?;
+    writer.field("user", &user).await?;
+    // TODO add friendships once they exist
+
+    writer
+        .section("userStats", move |conn| {
+            use crate::schema::user_stats;
+            Ok(user_stats::table
+                .find(me)
+                .first::<UserStats>(conn)
+                .optional()?)
+        })
+        .await?;
+    writer
+        .section("gameHistory", move |conn| {
+            use crate::schema::game_history;
+            Ok(game_history::table
+                .filter(game_history::user_id.eq(me))
+                .order(game_history::played_at.asc())
+                .load::<GameHistory>(conn)?)
+        })
+        .await?;
+    writer
+        .section("gameSessions", move |conn| {
+            use crate::schema::game_sessions;
+            Ok(game_sessions::table
+                .filter(
+                    game_sessions::player_a
+                        .eq(me)
+                        .or(game_sessions::player_b.eq(me)),
+                )
+                .order(game_sessions::created_at.asc())
+                .load::<GameSession>(conn)?)
+        })
+        .await?;
+    writer
+        .section("gameInvites", move |conn| {
+            use crate::schema::game_invites;
+            Ok(game_invites::table
+                .filter(
+                    game_invites::from_user_id
+                        .eq(me)
+                        .or(game_invites::to_user_id.eq(me)),
+                )
+                .order(game_invites::created_at.asc())
+                .load::<GameInvite>(conn)?)
+        })
+        .await?;
+    writer
+        .section("sessions", move |conn| {
+            use crate::schema::sessions;
+            let sessions: Vec<Session> = sessions::table
+                .filter(sessions::user_id.eq(me))
+                .order(sessions::created_at.asc())
+                .load(conn)?;
+            Ok(sessions
+                .iter()
+                .map(|other| SessionInfo::from(other).with_current(session.id))
+                .collect::<Vec<_>>())
+        })
+        .await?;
+    let device_id = session.device_id.clone();
+    writer
+        .section("trustedDevices", move |conn| {
+            use crate::schema::trusted_devices;
+            let devices: Vec<TrustedDevice> = trusted_devices::table
+                .filter(trusted_devices::user_id.eq(me))
+                .order(trusted_devices::created_at.asc())
+                .load(conn)?;
+            Ok(devices
+                .into_iter()
+                .map(|device| TrustedDeviceInfo {
+                    id: device.id,
+                    current: device.device_id == device_id,
+                    created_at: device.created_at,
+                    last_used_at: device.last_used_at,
+                    expires_at: device.expires_at,
+                })
+                .collect::<Vec<_>>())
+        })
+        .await?;
+    writer
+        .section("bans", move |conn| {
+            use crate::schema::user_bans;
+            Ok(user_bans::table
+                .filter(user_bans::user_id.eq(me))
+                .order(user_bans::created_at.asc())
+                .load::<UserBan>(conn)?)
+        })
+        .await?;
+    writer
+        .section("securityEvents", move |conn| {
+            use crate::schema::security_events;
+            let events: Vec<SecurityEventEntry> = security_events::table
+                .filter(security_events::user_id.eq(me))
+                .order(security_events::created_at.asc())
+                .load(conn)?;
+            Ok(events
+                .into_iter()
+                .map(SecurityEventInfo::from)
+                .collect::<Vec<_>>())
+        })
+        .await?;
+    writer.finish().await
+}
+
+/// Writes the fields of a top-level JSON object to a response body.
+struct ExportWriter {
+    sender: BodySender,
+    empty: bool,
+}
+
+impl ExportWriter {
+    fn new(sender: BodySender) -> Self {
+        Self {
+            sender,
+            empty: true,
+        }
+    }
+
+    async fn field<T: Serialize>(
+        &mut self,
+        name: &str,
+        value: &T,
+    ) -> anyhow::Result<()> {
+        let mut chunk = vec![if self.empty { b'{' } else { b',' }];
+        serde_json::to_writer(&mut chunk, name)?;
+        chunk.push(b':');
+        serde_json::to_writer(&mut chunk, value)?;
+        self.sender.send_data(chunk).await?;
+        self.empty = false;
+        Ok(())
+    }
+
+    /// Write the field `name` with the value loaded by `load`.
+    async fn section<T, F>(&mut self, name: &str, load: F) -> anyhow::Result<()>
+    where
+        F: FnOnce(&mut DbConn) -> AppResult<T> + Send + 'static,
+        T: Serialize + Send + 'static,
+    {
+        let value = db::with_conn(load).await?;
+        self.field(name, &value).await
+    }
+
+    async fn finish(&mut self) -> anyhow::Result<()> {
+        let end: &'static [u8] = if self.empty { b"{}" } else { b"}" };
+        self.sender.send_data(end).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use salvo::test::{ResponseExt, TestClient};
+    use serde_json::{Value, json};
+
+    use super::*;
+    use crate::test_db::{self, Client, PASSWORD};
+
+    const EXPORT: &str = "http://localhost/api/user/export";
+
+    /// Every key of the objects in `value`, nested ones included.
+    fn keys(value: &Value, found: &mut Vec<String>) {
+        match value {
+            Value::Object(map) => {
+                for (key, value) in map {
+                    found.push(key.clone());
+                    keys(value, found);
+                }
+            }
+            Value::Array(items) => {
+                for item in items {
+                    keys(item, found);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn export_contains_the_sections_without_secrets() {
+        test_db::with_service(|service| async move {
+            let (mut client, body) =
+                Client::register(&service, "exa@example.com", "exa").await;
+            let me = body["user"]["id"].as_i64().unwrap();
+            let export = || {
+                TestClient::post(EXPORT).json(&json!({ "password": PASSWORD }))
+            };
+
+            let mut res = client.send(export(), &service).await;
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+            assert_eq!(
+                res.headers()[CONTENT_DISPOSITION],
+                format!(
+                    "attachment; filename=\"transcendence-export-{me}.json\""
+                )
+            );
+            let body: Value =
+                serde_json::from_str(&res.take_string().await.unwrap())
+                    .unwrap();
+            let mut sections: Vec<_> = body
+                .as_object()
+                .unwrap()
+                .keys()
+                .map(String::as_str)
+                .collect();
+            sections.sort_unstable();
+            let mut expected = [
+                "schemaVersion",
+                "exportedAt",
+                "user",
+                "userStats",
+                "gameHistory",
+                "gameSessions",
+                "gameInvites",
+                "sessions",
+                "trustedDevices",
+                "bans",
+                "securityEvents",
+            ];
+            expected.sort_unstable();
+            assert_eq!(sections, expected);
+            assert_eq!(body["schemaVersion"], EXPORT_SCHEMA_VERSION);
+            assert_eq!(body["user"]["id"], me);
+            assert_eq!(body["sessions"].as_array().unwrap().len(), 1);
+
+            let mut found = Vec::new();
+            keys(&body, &mut found);
+            for secret in [
+                "passwordHash",
+                "password_hash",
+                "totpSecretEnc",
+                "totp_secret_enc",
+                "tokenHash",
+                "token_hash",
+                "prevTokenHash",
+                "codeHash",
+            ] {
+                assert!(!found.iter().any(|key| key == secret), "{secret}");
+            }
+
+            // One export per hour
+            let res = client.send(export(), &service).await;
+            assert_eq!(res.status_code, Some(StatusCode::TOO_MANY_REQUESTS));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn failed_exports_count_against_the_limit() {
+        test_db::with_service(|service| async move {
+            let (mut client, _) =
+                Client::register(&service, "exo@example.com", "exo").await;
+            let export = |password: &str| {
+                TestClient::post(EXPORT).json(&json!({ "password": password }))
+            };
+
+            let res = client.send(export("wrong password 123"), &service).await;
+            assert_eq!(res.status_code, Some(StatusCode::UNAUTHORIZED));
+            let res = client.send(export(PASSWORD), &service).await;
+            assert_eq!(res.status_code, Some(StatusCode::TOO_MANY_REQUESTS));
+        })
+        .await;
+    }
+}
@@ -1,17 +1,23 @@
+use diesel::OptionalExtension;
+use jsonwebtoken::errors::ErrorKind;
 use salvo::oapi::SecurityRequirement;
 use thiserror::Error;
 use ulid::Ulid;
 
-use crate::auth::{JwtClaims, jwt_decoding_key, jwt_validation};
+use crate::auth::{JwtClaims, jwt_decode};
 use crate::models::Session;
 use crate::prelude::*;
 
 #[derive(Debug, Error, Clone, Copy, strum::IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
 pub enum AuthError {
     #[error("Missing access token")]
     MissingJwtCookie,
     #[error("Access token is invalid")]
     InvalidJwt,
+    #[error("Access token has expired")]
+    #[strum(serialize = "token_expired")]
+    ExpiredJwt,
     #[error("Missing session token")]
     MissingSessionCookie,
     #[error("Session token is invalid")]
@@ -30,13 +36,36 @@ pub enum AuthError {
     TwoFactorRequired,
     #[error("Two-factor authentication code is invalid")]
     TwoFactorInvalid,
+    #[error("Missing or invalid admin token")]
+    InvalidAdminToken,
+    #[error("Account is banned")]
+    Banned,
+    #[error("Account is locked, contact an admin")]
+    AccountLocked,
+    #[error("Admin privileges required")]
+    NotAdmin,
+    #[error("Too many failed login attempts, try again later")]
+    LoginLocked { retry_after_secs: u64 },
+    #[error(
+        "Recent authentication required, reauthenticate at /api/auth/session-management/reauth"
+    )]
+    FreshAuthRequired,
+    #[error(
+        "Session token was already used, the Session was revoked, login again"
+    )]
+    SessionTokenReuse,
 }
 
 #[allow(unused)]
 pub trait DepotAuthExt {
     fn user_id(&self) -> i32;
+    /// `None` if no Session was loaded, see [optional_access_hoop]
+    fn try_user_id(&self) -> Option<i32>;
     fn session(&self) -> &crate::models::Session;
     fn device_id(&self) -> &str;
+    /// Whether the Session authenticated recently, see
+    /// [RouterAuthExt::requires_fresh_auth]
+    fn has_fresh_auth(&self) -> bool;
 }
 
 impl DepotAuthExt for Depot {
@@ -44,6 +73,12 @@ impl DepotAuthExt for Depot {
         self.session().user_id
     }
 
+    fn try_user_id(&self) -> Option<i32> {
+        self.get::<crate::models::Session>("session")
+            .ok()
+            .map(|session| session.user_id)
+    }
+
     fn session(&self) -> &crate::models::Session {
         self.get::<crate::models::Session>("session")
             .expect("Needs session or access hoop")
@@ -54,6 +89,10 @@ impl DepotAuthExt for Depot {
             .map(|s| s.as_str())
             .expect("Needs device_id inserter hoop")
     }
+
+    fn has_fresh_auth(&self) -> bool {
+        self.get::<bool>("fresh_auth").is_ok_and(|fresh| *fresh)
+    }
 }
 
 pub(super) fn set_session(depot: &mut Depot, session: crate::models::Session) {
@@ -76,10 +115,118 @@ pub async fn device_id_inserter_hoop(
         }
         None => {
             let device_id = Ulid::new().to_string();
-            set_device_id(depot, device_id.clone());
-            res.add_cookie(super::util::device_id_cookie(depot));
+            res.add_cookie(super::util::device_id_cookie(&device_id));
+            set_device_id(depot, device_id);
+        }
+    }
+}
+
+/// Load and verify the Session of the access_token jwt cookie.
+async fn load_access_session(req: &mut Request) -> Result<Session, ApiError> {
+    let jwt_token = req
+        .cookie(super::JWT_COOKIE_NAME)
+        .ok_or(AuthError::MissingJwtCookie)?
+        .value();
+    let claims: JwtClaims = jwt_decode(jwt_token)
+        .map_err(|err| match err.kind() {
+            // The client should refresh its access token.
+            ErrorKind::ExpiredSignature => AuthError::ExpiredJwt,
+            // The client should log in again.
+            _ => AuthError::InvalidJwt,
+        })?
+        .claims;
+
+    use crate::schema::{sessions, user_bans, users};
+    let now = chrono::Utc::now().naive_utc();
+    let (session, banned, locked) =
+        match super::session_cache::lookup(claims.sid, &claims.jti) {
+            Ok(cached) => (cached.session, cached.banned, cached.locked),
+            Err(miss) => {
+                let (session, banned, locked): (Session, bool, bool) =
+                    db::with_conn(move |conn| {
+                        Ok(sessions::table
+                            .filter(sessions::id.eq(claims.sid))
+                            .select((
+                                Session::as_select(),
+                                diesel::dsl::exists(
+                                    user_bans::table
+                                        .filter(
+                                            user_bans::user_id
+                                                .eq(sessions::user_id),
+                                        )
+                                        .filter(super::ban::ban_in_effect(now)),
+                                ),
+                                diesel::dsl::exists(
+                                    users::table
+                                        .filter(users::id.eq(sessions::user_id))
+                                        .filter(users::locked_at.is_not_null()),
+                                ),
+                            ))
+                            .first(conn)
+                            .optional()?
+                            .ok_or(AuthError::SessionNotFound)?)
+                    })
+                    .await?;
+                miss.insert(session.clone(), banned, locked);
+                (session, banned, locked)
+            }
+        };
+
+    if session.user_id != claims.sub {
+        return Err(AuthError::SessionMismatch.into());
+    }
+
+    if session.token_hash != claims.jti {
+        return Err(AuthError::SessionMismatch.into());
+    }
+
+    if banned {
+        return Err(AuthError::Banned.into());
+    }
+
+    if locked {
+        return Err(AuthError::AccountLocked.into());
+    }
+
+    if session_requires_reauth(&session, now) {
+        return Err(AuthError::NeedReauth.into());
+    }
+
+    let mut session = session;
+    if now - session.last_used_at > LAST_USED_INTERVAL {
+        let session_id = session.id;
+        match db::with_conn(move |conn| {
+            mark_session_used(conn, session_id, now)
+        })
+        .await
+        {
+            Ok(_) => session.last_used_at = now,
+            Err(err) => {
+                tracing::error!(%err, session_id, "Failed to update last_used_at");
+            }
         }
     }
+
+    Ok(session)
+}
+
+/// How outdated `last_used_at` of a Session may get before an authenticated
+/// request updates it, so not every request writes to the DB.
+const LAST_USED_INTERVAL: chrono::TimeDelta = chrono::TimeDelta::minutes(5);
+
+/// Set `last_used_at` of a Session to `now`.
+pub(crate) fn mark_session_used(
+    conn: &mut DbConn,
+    session_id: i32,
+    now: chrono::NaiveDateTime,
+) -> AppResult<usize> {
+    use crate::schema::sessions::dsl::*;
+
+    let updated = diesel::update(sessions.find(session_id))
+        .set(last_used_at.eq(now))
+        .execute(conn)?;
+    super::session_cache::touch(session_id, now);
+    Ok(updated)
 }
 
 /// Load a valid Session from the access_token jwt cookie.
@@ -89,58 +236,127 @@ pub async fn device_id_inserter_hoop(
 /// For convenience there is a Router extension method [RouterAuthExt::requires_user_login]
 /// that adds this hoop along with OpenAPI security metadata.
 #[handler]
-pub fn access_hoop(
+pub async fn access_hoop(
     req: &mut Request,
     depot: &mut Depot,
     res: &mut Response,
     ctrl: &mut FlowCtrl,
 ) {
-    // TODO triage whether we can introduce a cache (moka or quick-cache) for hot sessions to avoid DB hits on every request
-    // need to make sure to update the cache on session refresh, reauth, logout, etc.
-    fn inner(req: &mut Request, depot: &mut Depot) -> Result<(), ApiError> {
-        let jwt_token = req
-            .cookie(super::JWT_COOKIE_NAME)
-            .ok_or(AuthError::MissingJwtCookie)?
-            .value();
-        let claims: JwtClaims = jsonwebtoken::decode(
-            jwt_token,
-            jwt_decoding_key(),
-            jwt_validation(),
-        )
-        .map_err(|_| AuthError::InvalidJwt)?
-        .claims;
-
-        use crate::schema::sessions::dsl::*;
-        let session: Session = sessions
-            .filter(id.eq(claims.sid))
-            .first(&mut db::get()?)
-            .map_err(|_| AuthError::SessionNotFound)?;
-
-        if session.user_id != claims.sub {
-            return Err(AuthError::SessionMismatch.into());
+    match load_access_session(req).await {
+        Ok(session) => {
+            super::presence::touch(session.user_id);
+            set_session(depot, session);
         }
-
-        if session.token_hash != claims.jti {
-            return Err(AuthError::SessionMismatch.into());
-        }
-
-        if session_requires_reauth(&session, chrono::Utc::now().naive_utc()) {
-            return Err(AuthError::NeedReauth.into());
+        Err(err) => {
+            err.render(res);
+            ctrl.skip_rest();
         }
+    }
+}
 
+/// Like [access_hoop], but lets requests without a valid access token pass
+/// without a Session.
+///
+/// Use [DepotAuthExt::try_user_id] in the handler.
+#[handler]
+pub async fn optional_access_hoop(req: &mut Request, depot: &mut Depot) {
+    if let Ok(session) = load_access_session(req).await {
         set_session(depot, session);
-        Ok(())
     }
+}
+
+/// Only lets admins pass, needs to run after [access_hoop].
+#[handler]
+pub async fn require_admin_hoop(
+    depot: &mut Depot,
+    res: &mut Response,
+    ctrl: &mut FlowCtrl,
+) {
+    let user_id = depot.user_id();
+    let admin = db::with_conn(move |conn| {
+        use crate::schema::users::dsl::*;
+        Ok(users.find(user_id).select(is_admin).first::<bool>(conn)?)
+    })
+    .await;
+    let err = match admin {
+        Ok(true) => return,
+        Ok(false) => ApiError::from(AuthError::NotAdmin),
+        Err(err) => err,
+    };
+    err.render(res);
+    ctrl.skip_rest();
+}
+
+/// Only lets Sessions pass that authenticated with credentials within the
+/// given time, needs to run after [access_hoop].
+///
+/// Stale Sessions may still send their `password` (and `mfaCode`) in the body
+/// instead, which is verified here, so the handlers only see fresh Sessions.
+/// This fallback is deprecated.
+struct FreshAuthHoop(std::time::Duration);
+
+#[async_trait]
+impl Handler for FreshAuthHoop {
+    async fn handle(
+        &self,
+        req: &mut Request,
+        depot: &mut Depot,
+        res: &mut Response,
+        ctrl: &mut FlowCtrl,
+    ) {
+        let session = depot.session();
+        let user_id = session.user_id;
+        if super::util::is_freshly_authenticated(session, self.0) {
+            depot.insert("fresh_auth", true);
+            return;
+        }
 
-    if let Err(err) = inner(req, depot) {
-        err.render(res);
-        ctrl.skip_rest();
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct PasswordFallback {
+            #[serde(default)]
+            password: Option<String>,
+            #[serde(default)]
+            mfa_code: Option<String>,
+        }
+        let fallback = req.payload().await.ok().and_then(|body| {
+            serde_json::from_slice::<PasswordFallback>(body).ok()
+        });
+        let verified = match fallback {
+            Some(PasswordFallback {
+                password: Some(password),
+                mfa_code,
+            }) => db::with_conn(move |conn| {
+                super::util::check_password_and_mfa_if_enabled(
+                    user_id,
+                    &password,
+                    mfa_code.as_deref(),
+                    conn,
+                )
+            })
+            .await
+            .map(drop),
+            _ => Err(AuthError::FreshAuthRequired.into()),
+        };
+        match verified {
+            Ok(()) => {
+                depot.insert("fresh_auth", true);
+            }
+            Err(err) => {
+                err.render(res);
+                ctrl.skip_rest();
+            }
+        }
     }
 }
 
 pub trait RouterAuthExt {
     /// see [access_hoop]
     fn requires_user_login(self) -> Self;
+    /// see [require_admin_hoop], implies [RouterAuthExt::requires_user_login]
+    fn requires_admin(self) -> Self;
+    /// see [FreshAuthHoop], needs [RouterAuthExt::requires_user_login]
+    fn requires_fresh_auth(self, max_age: std::time::Duration) -> Self;
 }
 
 impl RouterAuthExt for Router {
@@ -151,6 +367,14 @@ impl RouterAuthExt for Router {
                 Vec::<String>::new(),
             ))
     }
+
+    fn requires_admin(self) -> Self {
+        self.requires_user_login().hoop(require_admin_hoop)
+    }
+
+    fn requires_fresh_auth(self, max_age: std::time::Duration) -> Self {
+        self.hoop(FreshAuthHoop(max_age))
+    }
 }
 
 fn duration_cutoff(
@@ -164,8 +388,314 @@ pub fn session_requires_reauth(
     session: &Session,
     now: chrono::NaiveDateTime,
 ) -> bool {
-    let rolling_cutoff = duration_cutoff(now, super::SESSION_EXPIRY);
-    let forced_cutoff = duration_cutoff(now, super::SESSION_FORCED_EXPIRY);
+    let config = super::config();
+    let rolling_cutoff = duration_cutoff(now, config.session_expiry());
+    let forced_cutoff = duration_cutoff(now, config.forced_expiry());
     session.refreshed_at <= rolling_cutoff
         || session.last_authenticated_at <= forced_cutoff
 }
+
+#[cfg(test)]
+mod tests {
+    use salvo::test::{ResponseExt, TestClient};
+    use serde_json::Value;
+
+    use super::*;
+    use crate::test_db::{self, Client, PASSWORD};
+
+    const ME: &str = "http://localhost/api/user/me";
+
+    /// The stored access token signed again with `exp` as its expiry.
+    fn reissue(client: &Client, exp: chrono::DateTime<chrono::Utc>) -> String {
+        let token = client.cookie(crate::auth::JWT_COOKIE_NAME).unwrap();
+        let mut claims = jwt_decode(token).unwrap().claims;
+        claims.iat = (exp - chrono::Duration::minutes(15)).timestamp() as usize;
+        claims.exp = exp.timestamp() as usize;
+        jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &claims,
+            crate::auth::jwt_encoding_key(),
+        )
+        .unwrap()
+    }
+
+    async fn me_code(client: &mut Client, service: &Service) -> Value {
+        let mut res = client.send(TestClient::get(ME), service).await;
+        assert_eq!(res.status_code, Some(StatusCode::UNAUTHORIZED));
+        let body: Value = res.take_json().await.unwrap();
+        body["code"].clone()
+    }
+
+    #[tokio::test]
+    async fn expired_jwt_yields_token_expired() {
+        test_db::with_service(|service| async move {
+            let (mut client, _) =
+                Client::register(&service, "frank@example.com", "frank").await;
+            // Further in the past than the leeway of the validation
+            let expired = reissue(
+                &client,
+                chrono::Utc::now() - chrono::Duration::minutes(5),
+            );
+            client.set_cookie(crate::auth::JWT_COOKIE_NAME, expired);
+            assert_eq!(me_code(&mut client, &service).await, "token_expired");
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn forged_jwt_yields_invalid_jwt() {
+        test_db::with_service(|service| async move {
+            let (mut client, _) =
+                Client::register(&service, "grace@example.com", "grace").await;
+            let token = client.cookie(crate::auth::JWT_COOKIE_NAME).unwrap();
+            let claims = jwt_decode(token).unwrap().claims;
+            let forged = jsonwebtoken::encode(
+                &jsonwebtoken::Header::default(),
+                &claims,
+                &jsonwebtoken::EncodingKey::from_secret(&[7; 32]),
+            )
+            .unwrap();
+            client.set_cookie(crate::auth::JWT_COOKIE_NAME, forged);
+            assert_eq!(me_code(&mut client, &service).await, "invalid_jwt");
+
+            client.set_cookie(crate::auth::JWT_COOKIE_NAME, "garbage");
+            assert_eq!(me_code(&mut client, &service).await, "invalid_jwt");
+        })
+        .await;
+    }
+
+    const SESSIONS: &str = "http://localhost/api/user/sessions";
+
+    /// Make the Sessions of `user_id` last authenticate an hour ago.
+    fn make_stale(user_id: i64) {
+        use crate::schema::sessions;
+        let conn = &mut db::get().unwrap();
+        let stale = chrono::Utc::now().naive_utc() - chrono::Duration::hours(1);
+        let user_sessions =
+            sessions::table.filter(sessions::user_id.eq(user_id as i32));
+        let ids: Vec<i32> = diesel::update(user_sessions)
+            .set(sessions::last_authenticated_at.eq(stale))
+            .returning(sessions::id)
+            .get_results(conn)
+            .unwrap();
+        for id in ids {
+            crate::auth::session_cache::invalidate(id);
+        }
+    }
+
+    async fn list_sessions(
+        client: &mut Client,
+        service: &Service,
+        body: Value,
+    ) -> (StatusCode, Value) {
+        let mut res = client
+            .send(TestClient::post(SESSIONS).json(&body), service)
+            .await;
+        let status = res.status_code.unwrap();
+        (status, res.take_json().await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn fresh_session_passes_fresh_auth() {
+        test_db::with_service(|service| async move {
+            let (mut client, _) =
+                Client::register(&service, "heidi@example.com", "heidi").await;
+            let (status, _) =
+                list_sessions(&mut client, &service, serde_json::json!({}))
+                    .await;
+            assert_eq!(status, StatusCode::OK);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn stale_session_needs_fresh_auth() {
+        test_db::with_service(|service| async move {
+            let (mut client, body) =
+                Client::register(&service, "ivan@example.com", "ivan").await;
+            make_stale(body["user"]["id"].as_i64().unwrap());
+
+            let (status, body) =
+                list_sessions(&mut client, &service, serde_json::json!({}))
+                    .await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+            assert_eq!(body["code"], "fresh_auth_required");
+
+            // The deprecated fallback checks the password in the hoop
+            let (status, body) = list_sessions(
+                &mut client,
+                &service,
+                serde_json::json!({ "password": "not the password 1" }),
+            )
+            .await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+            assert_eq!(body["code"], "invalid_credentials");
+            let (status, _) = list_sessions(
+                &mut client,
+                &service,
+                serde_json::json!({ "password": PASSWORD }),
+            )
+            .await;
+            assert_eq!(status, StatusCode::OK);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn reauth_unlocks_fresh_auth() {
+        test_db::with_service(|service| async move {
+            let (mut client, body) =
+                Client::register(&service, "judy@example.com", "judy").await;
+            make_stale(body["user"]["id"].as_i64().unwrap());
+
+            let res = client
+                .send(
+                    TestClient::post(
+                        "http://localhost/api/auth/session-management/reauth",
+                    )
+                    .json(&serde_json::json!({ "password": PASSWORD })),
+                    &service,
+                )
+                .await;
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+            let (status, _) =
+                list_sessions(&mut client, &service, serde_json::json!({}))
+                    .await;
+            assert_eq!(status, StatusCode::OK);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn admin_routes_need_an_admin() {
+        test_db::with_service(|service| async move {
+            let (mut client, body) =
+                Client::register(&service, "oscar@example.com", "oscar").await;
+            let users = "http://localhost/api/admin/users";
+            let mut res = client.send(TestClient::get(users), &service).await;
+            assert_eq!(res.status_code, Some(StatusCode::FORBIDDEN));
+            let error: Value = res.take_json().await.unwrap();
+            assert_eq!(error["code"], "not_admin");
+
+            let user_id = body["user"]["id"].as_i64().unwrap() as i32;
+            db::with_conn(move |conn| {
+                use crate::schema::users::dsl::*;
+                diesel::update(users.find(user_id))
+                    .set(is_admin.eq(true))
+                    .execute(conn)?;
+                Ok(())
+            })
+            .await
+            .unwrap();
+            let res = client.send(TestClient::get(users), &service).await;
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+        })
+        .await;
+    }
+
+    /// Set `last_used_at` of the Session `id`.
+    fn set_last_used(id: i32, at: chrono::NaiveDateTime) {
+        use crate::schema::sessions;
+        diesel::update(sessions::table.find(id))
+            .set(sessions::last_used_at.eq(at))
+            .execute(&mut db::get().unwrap())
+            .unwrap();
+        crate::auth::session_cache::invalidate(id);
+    }
+
+    fn last_used(id: i32) -> chrono::NaiveDateTime {
+        use crate::schema::sessions;
+        sessions::table
+            .find(id)
+            .select(sessions::last_used_at)
+            .first(&mut db::get().unwrap())
+            .unwrap()
+    }
+
+    async fn me_ok(client: &mut Client, service: &Service) {
+        let res = client.send(TestClient::get(ME), service).await;
+        assert_eq!(res.status_code, Some(StatusCode::OK));
+    }
+
+    #[tokio::test]
+    async fn requests_mark_the_session_used_every_few_minutes() {
+        test_db::with_service(|service| async move {
+            let (mut client, body) =
+                Client::register(&service, "kim@example.com", "kim").await;
+            let id = body["session"]["sessionId"].as_i64().unwrap() as i32;
+            let ago = |minutes| {
+                chrono::Utc::now().naive_utc()
+                    - chrono::Duration::minutes(minutes)
+            };
+
+            // The first request after a while updates it
+            set_last_used(id, ago(10));
+            me_ok(&mut client, &service).await;
+            let used = last_used(id);
+            assert!(used > ago(1), "{used}");
+            // Right after that, and within the interval, it doesn't
+            me_ok(&mut client, &service).await;
+            assert_eq!(last_used(id), used);
+            let recent = ago(4);
+            set_last_used(id, recent);
+            me_ok(&mut client, &service).await;
+            assert_eq!(last_used(id), recent);
+            // Past the interval it does again
+            set_last_used(id, ago(6));
+            me_ok(&mut client, &service).await;
+            assert!(last_used(id) > ago(1));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn pruning_keeps_the_recently_used_sessions() {
+        test_db::with_service(|service| async move {
+            let (mut client, body) =
+                Client::register(&service, "lou@example.com", "lou").await;
+            let first = body["session"]["sessionId"].as_i64().unwrap() as i32;
+            let login = || {
+                TestClient::post("http://localhost/api/auth/login").json(
+                    &serde_json::json!({
+                        "email": "lou@example.com",
+                        "password": PASSWORD,
+                    }),
+                )
+            };
+            let mut ids = vec![first];
+            for _ in 1..crate::auth::config().max_sessions_per_user {
+                let mut res = login().send(&service).await;
+                assert_eq!(res.status_code, Some(StatusCode::OK));
+                let body: Value = res.take_json().await.unwrap();
+                ids.push(body["session"]["sessionId"].as_i64().unwrap() as i32);
+            }
+            // Later sessions were used more recently, then the first
+            let hour_ago =
+                chrono::Utc::now().naive_utc() - chrono::Duration::hours(1);
+            for (n, id) in ids.iter().enumerate() {
+                set_last_used(
+                    *id,
+                    hour_ago + chrono::Duration::minutes(n as i64),
+                );
+            }
+            me_ok(&mut client, &service).await;
+
+            // One more prunes the least recently used, the second one
+            let res = login().send(&service).await;
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+            let remaining: Vec<i32> = {
+                use crate::schema::sessions;
+                sessions::table
+                    .filter(sessions::id.eq_any(&ids))
+                    .select(sessions::id)
+                    .order(sessions::id)
+                    .load(&mut db::get().unwrap())
+                    .unwrap()
+            };
+            let mut expected = ids.clone();
+            expected.remove(1);
+            assert_eq!(remaining, expected);
+        })
+        .await;
+    }
+}
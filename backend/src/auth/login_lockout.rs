@@ -0,0 +1,201 @@
+//! Per-account lockout after repeated failed logins.
+//!
+//! The IP rate limit of the login route doesn't slow down attacks spread over
+//! many addresses, so failed password and MFA attempts are also counted per
+//! email. After [`MAX_FAILURES`] failures the email is locked for
+//! [`BASE_LOCKOUT`], doubling with every further failure up to
+//! [`MAX_LOCKOUT`]. The count resets [`FAILURE_WINDOW`] after the last failure
+//! or on a successful login.
+//!
+//! Emails are counted whether an account exists or not, so a lockout doesn't
+//! reveal registered emails. They are kept as keyed hashes in a bounded cache
+//! that evicts old entries once it is full.
+
+use std::net::IpAddr;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use quick_cache::sync::Cache;
+
+use crate::auth::AuthError;
+use crate::prelude::*;
+
+const MAX_FAILURES: u32 = 5;
+const FAILURE_WINDOW: Duration = Duration::from_secs(15 * 60);
+const BASE_LOCKOUT: Duration = Duration::from_secs(60);
+const MAX_LOCKOUT: Duration = Duration::from_secs(60 * 60);
+/// Emails tracked at the same time.
+const CAPACITY: usize = 100_000;
+/// Distinct IPs remembered per email for the lockout warning.
+const MAX_TRACKED_IPS: usize = 8;
+
+type EmailKey = [u8; 16];
+
+#[derive(Default)]
+struct Failures {
+    count: u32,
+    last_at: Option<Instant>,
+    locked_until: Option<Instant>,
+    ips: Vec<IpAddr>,
+}
+
+static HASH_KEY: LazyLock<[u8; 32]> = LazyLock::new(rand::random);
+static FAILURES: LazyLock<Cache<EmailKey, Arc<Mutex<Failures>>>> =
+    LazyLock::new(|| Cache::new(CAPACITY));
+
+fn email_key(email: &str) -> EmailKey {
+    let hash =
+        blake3::keyed_hash(&HASH_KEY, email.trim().to_lowercase().as_bytes());
+    let mut key = EmailKey::default();
+    key.copy_from_slice(&hash.as_bytes()[..16]);
+    key
+}
+
+/// Reject a login for a locked email before checking its credentials.
+pub fn check(email: &str) -> Result<(), AuthError> {
+    let Some(failures) = FAILURES.get(&email_key(email)) else {
+        return Ok(());
+    };
+    let failures = failures.lock().expect("lockout mutex poisoned");
+    match failures.locked_until {
+        Some(until) if until > Instant::now() => Err(AuthError::LoginLocked {
+            retry_after_secs: (until - Instant::now()).as_secs() + 1,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Count a failed login if `err` means wrong credentials or MFA code.
+pub fn record_failure(email: &str, req: &Request, err: &ApiError) {
+    if super::audit::LoginFailure::from_error(err).is_none() {
+        return;
+    }
+
+    let now = Instant::now();
+    let Ok(failures) = FAILURES.get_or_insert_with(&email_key(email), || {
+        Ok::<_, std::convert::Infallible>(Arc::default())
+    });
+    let mut failures = failures.lock().expect("lockout mutex poisoned");
+    if failures
+        .last_at
+        .is_some_and(|last_at| now.duration_since(last_at) > FAILURE_WINDOW)
+    {
+        *failures = Failures::default();
+    }
+    failures.count += 1;
+    failures.last_at = Some(now);
+    if let Some(ip) = crate::utils::client_ip::client_ip(req)
+        && !failures.ips.contains(&ip)
+        && failures.ips.len() < MAX_TRACKED_IPS
+    {
+        failures.ips.push(ip);
+    }
+
+    if failures.count >= MAX_FAILURES {
+        let lockout = BASE_LOCKOUT
+            .saturating_mul(1 << (failures.count - MAX_FAILURES).min(16))
+            .min(MAX_LOCKOUT);
+        failures.locked_until = Some(now + lockout);
+        tracing::warn!(
+            failures = failures.count,
+            lockout_secs = lockout.as_secs(),
+            ips = ?failures.ips,
+            "Login locked after repeated failures"
+        );
+    }
+}
+
+/// Forget the failures of an email after a successful login.
+pub fn clear(email: &str) {
+    FAILURES.remove(&email_key(email));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fail(email: &str, times: u32) {
+        let req = Request::new();
+        for _ in 0..times {
+            record_failure(
+                email,
+                &req,
+                &ApiError::Auth(AuthError::InvalidCredentials),
+            );
+        }
+    }
+
+    /// Seconds until `email` can try again, `None` if it isn't locked.
+    fn retry_after(email: &str) -> Option<u64> {
+        match check(email) {
+            Ok(()) => None,
+            Err(AuthError::LoginLocked { retry_after_secs }) => {
+                Some(retry_after_secs)
+            }
+            Err(err) => panic!("unexpected {err:?}"),
+        }
+    }
+
+    /// Assert that `email` is locked for about `lockout`.
+    fn assert_locked_for(email: &str, lockout: Duration) {
+        let secs = retry_after(email).expect("the email should be locked");
+        assert!(
+            (lockout.as_secs()..=lockout.as_secs() + 1).contains(&secs),
+            "locked for {secs}s instead of {lockout:?}"
+        );
+    }
+
+    #[test]
+    fn locks_after_repeated_failures() {
+        let email = "lockout-locks@example.com";
+        fail(email, MAX_FAILURES - 1);
+        assert_eq!(retry_after(email), None);
+        fail(email, 1);
+        assert_locked_for(email, BASE_LOCKOUT);
+        // The email is normalized
+        assert!(retry_after(" Lockout-Locks@example.com").is_some());
+    }
+
+    #[test]
+    fn the_lockout_doubles_with_every_failure() {
+        let email = "lockout-doubles@example.com";
+        fail(email, MAX_FAILURES);
+        for factor in [2, 4, 8] {
+            fail(email, 1);
+            assert_locked_for(email, BASE_LOCKOUT * factor);
+        }
+        fail(email, 16);
+        assert_locked_for(email, MAX_LOCKOUT);
+    }
+
+    #[test]
+    fn a_successful_login_clears_the_failures() {
+        let email = "lockout-clears@example.com";
+        fail(email, MAX_FAILURES);
+        assert!(retry_after(email).is_some());
+        clear(email);
+        assert_eq!(retry_after(email), None);
+        fail(email, MAX_FAILURES - 1);
+        assert_eq!(retry_after(email), None);
+    }
+
+    #[test]
+    fn emails_are_counted_separately() {
+        let locked = "lockout-one@example.com";
+        let other = "lockout-other@example.com";
+        fail(locked, MAX_FAILURES);
+        fail(other, MAX_FAILURES - 1);
+        assert!(retry_after(locked).is_some());
+        assert_eq!(retry_after(other), None);
+    }
+
+    #[test]
+    fn other_errors_are_not_counted() {
+        let email = "lockout-other-errors@example.com";
+        let req = Request::new();
+        for _ in 0..MAX_FAILURES {
+            record_failure(email, &req, &ApiError::Auth(AuthError::Banned));
+        }
+        assert_eq!(retry_after(email), None);
+    }
+}
@@ -2,30 +2,34 @@ use std::{sync::LazyLock, time::Duration};
 
 use serde::{Deserialize, Serialize};
 
+pub mod audit;
+pub(crate) mod ban;
+mod export;
 mod hoops;
+mod login_lockout;
+pub(crate) mod notification_counts;
+pub mod presence;
 mod router;
+pub(crate) mod session_cache;
 pub mod session_token;
+mod trusted_device;
 mod two_factor;
 mod user;
 mod util;
 
+pub(crate) use hoops::mark_session_used;
 pub use hoops::{
     AuthError, DepotAuthExt, RouterAuthExt, device_id_inserter_hoop,
+    optional_access_hoop,
 };
 pub use router::router;
 pub use two_factor::TwoFactorError;
 pub use user::router as user_router;
+pub(crate) use user::expire_sessions;
 
 pub const JWT_COOKIE_NAME: &str = "access_token";
 pub const SESSION_COOKIE_NAME: &str = "session_token";
-const SESSION_EXPIRY: Duration = Duration::from_hours(7 * 24);
-const SESSION_FORCED_EXPIRY: Duration = Duration::from_hours(30 * 24);
-const ACCESS_EXPIRY: Duration = Duration::from_mins(15);
-
-/// Maximum number of sessions to keep per user.
-///
-/// When a new session is created, older sessions are pruned down to this limit.
-const MAX_SESSIONS_PER_USER: i64 = 10;
+pub const TRUSTED_DEVICE_COOKIE_NAME: &str = "trusted_device";
 
 /// How long the browser should keep the refresh-token cookie.
 ///
@@ -34,33 +38,84 @@ const MAX_SESSIONS_PER_USER: i64 = 10;
 const SESSION_COOKIE_MAX_AGE: Duration =
     Duration::from_secs(60 * 60 * 24 * 365 * 10);
 
-/// Dont care about old JWT tokens when server restarts,
-/// clients can just refresh their access tokens.
-static JWT_SECRET: LazyLock<[u8; 32]> = LazyLock::new(rand::random);
+/// Token lifetimes and limits, see [`crate::config::AuthConfig`].
+pub fn config() -> &'static crate::config::AuthConfig {
+    &crate::config::get().auth
+}
+
+struct JwtKeys {
+    encoding: jsonwebtoken::EncodingKey,
+    decoding: jsonwebtoken::DecodingKey,
+    /// Still accepted for validation while rotating secrets
+    decoding_previous: Option<jsonwebtoken::DecodingKey>,
+}
+
+impl JwtKeys {
+    /// Keys from a (validated) secret and the previous one still accepted.
+    ///
+    /// Without a secret a random one is used, so all access tokens become
+    /// invalid when the server restarts and clients have to refresh them.
+    fn new(secret: Option<&str>, previous: Option<&str>) -> Self {
+        let parse = |secret: &str| {
+            crate::utils::keys::parse_32_byte_key(secret)
+                .expect("jwt secrets are validated on startup")
+        };
+        let secret = secret.map(parse).unwrap_or_else(|| {
+            tracing::info!("No jwt_secret configured, using a random one");
+            rand::random()
+        });
+        JwtKeys {
+            encoding: jsonwebtoken::EncodingKey::from_secret(&secret),
+            decoding: jsonwebtoken::DecodingKey::from_secret(&secret),
+            decoding_previous: previous.map(parse).map(|previous| {
+                jsonwebtoken::DecodingKey::from_secret(&previous)
+            }),
+        }
+    }
 
-static JWT_ENCODING_KEY: LazyLock<jsonwebtoken::EncodingKey> =
-    LazyLock::new(|| {
-        jsonwebtoken::EncodingKey::from_secret(JWT_SECRET.as_slice())
-    });
+    /// Decode and validate an access token, trying the current secret first
+    /// and the previous one if the signature doesn't match.
+    fn decode(
+        &self,
+        token: &str,
+    ) -> jsonwebtoken::errors::Result<jsonwebtoken::TokenData<JwtClaims>> {
+        match jsonwebtoken::decode(token, &self.decoding, &JWT_VALIDATION) {
+            Err(err)
+                if *err.kind()
+                    == jsonwebtoken::errors::ErrorKind::InvalidSignature =>
+            {
+                match &self.decoding_previous {
+                    Some(previous) => {
+                        jsonwebtoken::decode(token, previous, &JWT_VALIDATION)
+                    }
+                    None => Err(err),
+                }
+            }
+            result => result,
+        }
+    }
+}
 
-static JWT_DECODING_KEY: LazyLock<jsonwebtoken::DecodingKey> =
-    LazyLock::new(|| {
-        jsonwebtoken::DecodingKey::from_secret(JWT_SECRET.as_slice())
-    });
+/// Keys from the configured `jwt_secret` and `jwt_secret_previous`.
+static JWT_KEYS: LazyLock<JwtKeys> = LazyLock::new(|| {
+    JwtKeys::new(
+        config().jwt_secret.as_deref(),
+        config().jwt_secret_previous.as_deref(),
+    )
+});
 
 static JWT_VALIDATION: LazyLock<jsonwebtoken::Validation> =
-    LazyLock::new(|| jsonwebtoken::Validation::default());
+    LazyLock::new(jsonwebtoken::Validation::default);
 
 fn jwt_encoding_key() -> &'static jsonwebtoken::EncodingKey {
-    &JWT_ENCODING_KEY
+    &JWT_KEYS.encoding
 }
 
-fn jwt_decoding_key() -> &'static jsonwebtoken::DecodingKey {
-    &JWT_DECODING_KEY
-}
-
-fn jwt_validation() -> &'static jsonwebtoken::Validation {
-    &JWT_VALIDATION
+/// Decode and validate an access token with the configured keys.
+fn jwt_decode(
+    token: &str,
+) -> jsonwebtoken::errors::Result<jsonwebtoken::TokenData<JwtClaims>> {
+    JWT_KEYS.decode(token)
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -71,3 +126,73 @@ struct JwtClaims {
     pub exp: usize,
     pub iat: usize,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CURRENT: &str =
+        "0101010101010101010101010101010101010101010101010101010101010101";
+    const PREVIOUS: &str =
+        "0202020202020202020202020202020202020202020202020202020202020202";
+    const UNKNOWN: &str =
+        "0303030303030303030303030303030303030303030303030303030303030303";
+
+    fn sign(keys: &JwtKeys) -> String {
+        let now = chrono::Utc::now().timestamp() as usize;
+        let claims = JwtClaims {
+            sub: 7,
+            sid: 3,
+            jti: session_token::SessionToken::generate()
+                .to_hash()
+                .to_truncated(),
+            exp: now + 60,
+            iat: now,
+        };
+        jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &claims,
+            &keys.encoding,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn tokens_from_the_previous_secret_still_validate() {
+        let keys = JwtKeys::new(Some(CURRENT), Some(PREVIOUS));
+
+        let claims = keys.decode(&sign(&keys)).unwrap().claims;
+        assert_eq!((claims.sub, claims.sid), (7, 3));
+        let old = sign(&JwtKeys::new(Some(PREVIOUS), None));
+        assert_eq!(keys.decode(&old).unwrap().claims.sub, 7);
+
+        // Once the rotation is over the old tokens are rejected
+        let rotated = JwtKeys::new(Some(CURRENT), None);
+        assert_eq!(
+            *rotated.decode(&old).unwrap_err().kind(),
+            jsonwebtoken::errors::ErrorKind::InvalidSignature
+        );
+    }
+
+    #[test]
+    fn tokens_from_unknown_secrets_are_rejected() {
+        let keys = JwtKeys::new(Some(CURRENT), Some(PREVIOUS));
+        let forged = sign(&JwtKeys::new(Some(UNKNOWN), None));
+        assert_eq!(
+            *keys.decode(&forged).unwrap_err().kind(),
+            jsonwebtoken::errors::ErrorKind::InvalidSignature
+        );
+    }
+
+    #[test]
+    fn without_a_secret_a_random_one_is_used() {
+        let first = JwtKeys::new(None, None);
+        let second = JwtKeys::new(None, None);
+        let token = sign(&first);
+
+        assert!(first.decode(&token).is_ok());
+        // Like after a restart, the token is no longer valid
+        assert!(second.decode(&token).is_err());
+        assert!(JwtKeys::new(Some(CURRENT), None).decode(&token).is_err());
+    }
+}
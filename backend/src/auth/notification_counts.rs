@@ -0,0 +1,174 @@
+//! Badge counts of the current user, e.g. for the header of the UI.
+//!
+//! Badges get polled, so the counts of a user are cached for [`TTL`]. Code
+//! that changes a count of a user calls [`invalidate`] after the write.
+//! A count loaded while another request wrote can still be cached, it is
+//! stale for [`TTL`] at most.
+//!
+//! Only game invites exist so far. Pending friend requests, unread chat and
+//! queued notifications are added here once their tables exist.
+
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+use quick_cache::sync::Cache;
+
+use crate::prelude::*;
+use crate::routers::game::invites;
+
+const TTL: Duration = Duration::from_secs(5);
+/// Users cached at the same time.
+const CAPACITY: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct NotificationCounts {
+    /// Game invites to the user that are pending and not expired
+    pub pending_game_invites: i64,
+}
+
+static COUNTS: LazyLock<Cache<i32, (Instant, NotificationCounts)>> =
+    LazyLock::new(|| Cache::new(CAPACITY));
+
+/// The counts of `user_id`, cached for [`TTL`].
+pub fn get(
+    conn: &mut db::DbConn,
+    user_id: i32,
+) -> AppResult<NotificationCounts> {
+    if let Some((cached_at, counts)) = COUNTS.get(&user_id)
+        && cached_at.elapsed() < TTL
+    {
+        return Ok(counts);
+    }
+    let counts = NotificationCounts {
+        pending_game_invites: invites::pending_count(conn, user_id)?,
+    };
+    COUNTS.insert(user_id, (Instant::now(), counts));
+    Ok(counts)
+}
+
+/// Drop the cached counts of `user_id`.
+pub fn invalidate(user_id: i32) {
+    COUNTS.remove(&user_id);
+}
+
+/// Retrieve the badge counts of the current User
+///
+/// The counts can be up to 5 seconds old.
+#[endpoint]
+pub fn get_notification_counts(
+    depot: &mut Depot,
+) -> JsonResult<NotificationCounts> {
+    let conn = &mut db::get()?;
+    json_ok(get(conn, depot.user_id())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use diesel::connection::{Connection as _, InstrumentationEvent};
+    use salvo::test::{ResponseExt, TestClient};
+    use serde_json::Value;
+
+    use super::*;
+    use crate::models::{InviteStatus, NewGameInvite};
+    use crate::test_db::{self, Client};
+
+    const BASE: &str = "http://localhost/api/user";
+
+    fn invite(
+        conn: &mut db::DbConn,
+        from: i32,
+        to: i32,
+        status: InviteStatus,
+        expires_in: chrono::Duration,
+    ) {
+        let now = chrono::Utc::now().naive_utc();
+        diesel::insert_into(crate::schema::game_invites::table)
+            .values(&NewGameInvite {
+                from_user_id: from,
+                to_user_id: to,
+                status,
+                created_at: now,
+                expires_at: now + expires_in,
+            })
+            .execute(conn)
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn counts_reflect_outstanding_invites() {
+        test_db::with_service(|service| async move {
+            let (mut client, body) =
+                Client::register(&service, "max@example.com", "max").await;
+            let me = body["user"]["id"].as_i64().unwrap() as i32;
+            let conn = &mut db::get().unwrap();
+            let [ann, bob, cid] = ["ann", "bob", "cid"]
+                .map(|name| test_db::create_user(conn, name).id);
+            let minutes = chrono::Duration::minutes;
+            invite(conn, ann, me, InviteStatus::Pending, minutes(5));
+            invite(conn, bob, me, InviteStatus::Pending, minutes(5));
+            invite(conn, cid, me, InviteStatus::Pending, minutes(-1));
+            invite(conn, cid, me, InviteStatus::Declined, minutes(5));
+            invite(conn, me, ann, InviteStatus::Pending, minutes(5));
+
+            let mut res = client
+                .send(
+                    TestClient::get(format!("{BASE}/notification-counts")),
+                    &service,
+                )
+                .await;
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+            let counts: Value = res.take_json().await.unwrap();
+            assert_eq!(counts["pendingGameInvites"], 2);
+
+            let mut res = client
+                .send(TestClient::get(format!("{BASE}/me")), &service)
+                .await;
+            let info: Value = res.take_json().await.unwrap();
+            assert_eq!(info["notificationCounts"], counts);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn counts_are_cached_until_invalidated() {
+        test_db::with_db(|| async {
+            let conn = &mut db::get().unwrap();
+            let me = test_db::create_user(conn, "kai").id;
+            let other = test_db::create_user(conn, "lou").id;
+            let queries = Arc::new(AtomicUsize::new(0));
+            conn.set_instrumentation({
+                let queries = Arc::clone(&queries);
+                move |event: InstrumentationEvent<'_>| {
+                    if matches!(event, InstrumentationEvent::StartQuery { .. })
+                    {
+                        queries.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            });
+
+            assert_eq!(get(conn, me).unwrap().pending_game_invites, 0);
+            assert_eq!(queries.load(Ordering::Relaxed), 1);
+            invite(
+                conn,
+                other,
+                me,
+                InviteStatus::Pending,
+                chrono::Duration::minutes(5),
+            );
+            let queries_before = queries.load(Ordering::Relaxed);
+
+            // Served from the cache, without a query and stale
+            assert_eq!(get(conn, me).unwrap().pending_game_invites, 0);
+            assert_eq!(queries.load(Ordering::Relaxed), queries_before);
+
+            invalidate(me);
+            assert_eq!(get(conn, me).unwrap().pending_game_invites, 1);
+            assert_eq!(queries.load(Ordering::Relaxed), queries_before + 1);
+        })
+        .await;
+    }
+}
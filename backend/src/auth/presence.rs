@@ -0,0 +1,256 @@
+//! Soft presence from REST activity.
+//!
+//! [access_hoop](super::hoops::access_hoop) records when a user last made an
+//! authenticated request, so users without a WebTransport connection can
+//! still show up as online. The timestamps are kept in memory and flushed to
+//! the `users.last_seen` column periodically.
+//!
+//! A user is online while connected via WebTransport or within
+//! [`ONLINE_WINDOW`] of a request. Connections that subscribed to a user get
+//! the changes, see [`StreamManager::publish_presence`]. Going offline by
+//! inactivity is published by the periodic flush, up to [`FLUSH_INTERVAL`]
+//! late.
+
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+use chrono::NaiveDateTime;
+use dashmap::DashMap;
+
+use crate::prelude::*;
+use crate::stream::StreamManager;
+
+/// Users with REST activity this recent count as online.
+pub const ONLINE_WINDOW: Duration = Duration::from_secs(2 * 60);
+/// Activity is recorded at most this often per user.
+const TOUCH_INTERVAL: Duration = Duration::from_secs(60);
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+/// Flushed entries older than this are evicted, `last_seen` then comes
+/// from the DB.
+const EVICT_AFTER: Duration = Duration::from_secs(10 * 60);
+
+struct Activity {
+    at: Instant,
+    at_utc: NaiveDateTime,
+    flushed: bool,
+    /// The activity keeps the user online, until [`publish_offline`] saw it
+    /// expire
+    online: bool,
+}
+
+static LAST_ACTIVITY: LazyLock<DashMap<i32, Activity, ahash::RandomState>> =
+    LazyLock::new(DashMap::default);
+
+/// Record activity of a user.
+pub(super) fn touch(user_id: i32) {
+    let was_online = is_online(user_id);
+    let now = Instant::now();
+    let mut entry = LAST_ACTIVITY.entry(user_id).or_insert(Activity {
+        at: now - TOUCH_INTERVAL,
+        at_utc: NaiveDateTime::default(),
+        flushed: true,
+        online: false,
+    });
+    if now.duration_since(entry.at) < TOUCH_INTERVAL {
+        return;
+    }
+    let at_utc = chrono::Utc::now().naive_utc();
+    *entry = Activity {
+        at: now,
+        at_utc,
+        flushed: false,
+        online: true,
+    };
+    drop(entry);
+    if !was_online {
+        StreamManager::global().publish_presence(user_id, true, Some(at_utc));
+    }
+}
+
+/// Whether the user is connected via WebTransport or was recently active.
+pub fn is_online(user_id: i32) -> bool {
+    StreamManager::global().is_connected(user_id)
+        || recently_active(user_id, ONLINE_WINDOW)
+}
+
+/// Whether the user made an authenticated request within `window`.
+///
+/// Accurate to [`TOUCH_INTERVAL`].
+pub fn recently_active(user_id: i32, window: Duration) -> bool {
+    LAST_ACTIVITY
+        .get(&user_id)
+        .is_some_and(|activity| activity.at.elapsed() <= window)
+}
+
+/// Last recorded activity of a user that may not be flushed to the DB yet.
+pub fn last_seen(user_id: i32) -> Option<NaiveDateTime> {
+    LAST_ACTIVITY
+        .get(&user_id)
+        .filter(|activity| activity.at_utc != NaiveDateTime::default())
+        .map(|activity| activity.at_utc)
+}
+
+/// Publish that the users whose activity expired went offline, unless they
+/// are still connected via WebTransport.
+fn publish_offline() {
+    let expired: Vec<(i32, NaiveDateTime)> = LAST_ACTIVITY
+        .iter_mut()
+        .filter(|entry| entry.online && entry.at.elapsed() > ONLINE_WINDOW)
+        .map(|mut entry| {
+            entry.online = false;
+            (*entry.key(), entry.at_utc)
+        })
+        .collect();
+    let manager = StreamManager::global();
+    for (user_id, at_utc) in expired {
+        if !manager.is_connected(user_id) {
+            manager.publish_presence(user_id, false, Some(at_utc));
+        }
+    }
+}
+
+fn flush() -> AppResult<usize> {
+    use crate::schema::users::dsl::*;
+
+    let pending: Vec<(i32, NaiveDateTime)> = LAST_ACTIVITY
+        .iter()
+        .filter(|entry| !entry.flushed)
+        .map(|entry| (*entry.key(), entry.at_utc))
+        .collect();
+    if pending.is_empty() {
+        return Ok(0);
+    }
+
+    let conn = &mut db::get()?;
+    conn.transaction::<_, ApiError, _>(|conn| {
+        for (user_id, at) in &pending {
+            diesel::update(users.find(user_id))
+                .set(last_seen.eq(at))
+                .execute(conn)?;
+        }
+        Ok(())
+    })?;
+
+    for (user_id, at) in &pending {
+        // Only mark as flushed if it wasn't touched again in the meantime
+        if let Some(mut activity) = LAST_ACTIVITY.get_mut(user_id)
+            && activity.at_utc == *at
+        {
+            activity.flushed = true;
+        }
+    }
+    LAST_ACTIVITY.retain(|_, activity| {
+        !activity.flushed || activity.at.elapsed() < EVICT_AFTER
+    });
+    Ok(pending.len())
+}
+
+pub fn periodic_flush() {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+        loop {
+            interval.tick().await;
+            publish_offline();
+            match tokio::task::spawn_blocking(flush).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(err)) => {
+                    tracing::error!(error = ?err, "Failed to flush last_seen");
+                }
+                Err(err) => {
+                    tracing::error!(error = ?err, "last_seen flush panicked");
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+
+    use super::*;
+    use crate::stream::testing::{ConnectionSession, FakeClient};
+
+    /// A connection to the global manager, which presence is published on,
+    /// subscribed to `user_id`.
+    async fn watch(watcher: i32, user_id: i32) -> FakeClient {
+        let mut client = FakeClient::connect(
+            StreamManager::global(),
+            Some(ConnectionSession::for_user(watcher)),
+        )
+        .await;
+        client
+            .send_control(json!({
+                "type": "SubscribePresence",
+                "data": { "userIds": [user_id] },
+            }))
+            .await;
+        assert_eq!(client.recv_control().await["type"], "Presence");
+        client
+    }
+
+    /// The next presence change the client gets.
+    async fn presence_changed(client: &mut FakeClient) -> Value {
+        let message = client.recv_control().await;
+        assert_eq!(message["type"], "Notification", "{message}");
+        assert_eq!(message["data"]["type"], "PresenceChanged", "{message}");
+        message["data"]["data"].clone()
+    }
+
+    /// Assert that nothing was sent to the client before its ping.
+    async fn assert_nothing_sent(client: &mut FakeClient) {
+        client.send_control(json!({ "type": "Ping" })).await;
+        assert_eq!(client.recv_control().await["type"], "Pong");
+    }
+
+    /// Move the activity of `user_id` out of the [`ONLINE_WINDOW`].
+    fn expire(user_id: i32) {
+        let mut activity = LAST_ACTIVITY.get_mut(&user_id).unwrap();
+        activity.at -= ONLINE_WINDOW + Duration::from_secs(1);
+    }
+
+    #[tokio::test]
+    async fn activity_goes_online_and_expires_offline() {
+        let (watcher, user_id) = (910_001, 910_002);
+        let mut client = watch(watcher, user_id).await;
+
+        touch(user_id);
+        assert!(is_online(user_id));
+        let online = presence_changed(&mut client).await;
+        assert_eq!(online["userId"], user_id);
+        assert_eq!(online["online"], true);
+        // Activity while online isn't published again
+        LAST_ACTIVITY.get_mut(&user_id).unwrap().at -= TOUCH_INTERVAL;
+        touch(user_id);
+        assert_nothing_sent(&mut client).await;
+
+        expire(user_id);
+        assert!(!is_online(user_id));
+        publish_offline();
+        let offline = presence_changed(&mut client).await;
+        assert_eq!(offline["userId"], user_id);
+        assert_eq!(offline["online"], false);
+        assert_eq!(offline["lastSeen"], json!(last_seen(user_id)));
+        // Only once
+        publish_offline();
+        assert_nothing_sent(&mut client).await;
+    }
+
+    #[tokio::test]
+    async fn connected_users_stay_online_when_the_activity_expires() {
+        let (watcher, user_id) = (910_003, 910_004);
+        let mut client = watch(watcher, user_id).await;
+        let _connected = FakeClient::connect(
+            StreamManager::global(),
+            Some(ConnectionSession::for_user(user_id)),
+        )
+        .await;
+        assert_eq!(presence_changed(&mut client).await["online"], true);
+
+        touch(user_id);
+        expire(user_id);
+        publish_offline();
+        assert!(is_online(user_id));
+        assert_nothing_sent(&mut client).await;
+    }
+}
@@ -1,11 +1,15 @@
 use diesel::OptionalExtension;
+use ulid::Ulid;
 
 use crate::auth::AuthError;
+use crate::auth::audit::{self, AuditContext, SecurityEvent};
 use crate::auth::hoops::set_session;
-use crate::auth::session_token::SessionToken;
+use crate::auth::session_token::{SessionToken, SessionTokenHash};
 use crate::auth::user::{SessionInfo, UserSessionInfo};
 use crate::models::{NewSession, NewUser, Session, User};
 use crate::prelude::*;
+use crate::stream::{Notification, StreamManager};
+use crate::utils::client_ip;
 
 use super::util;
 
@@ -36,10 +40,11 @@ pub fn router(path: &str) -> Router {
 }
 
 #[derive(Debug, Deserialize, Validate, ToSchema)]
+#[serde(rename_all = "camelCase")]
 struct RegisterInput {
     #[validate(email(message = "Must be a valid email address."))]
     pub email: String,
-    #[validate(custom(function = "crate::validate::password"))]
+    // Checked in register(), it must not match the other fields
     pub password: String,
     #[validate(custom(function = "crate::validate::nickname"))]
     pub nickname: String,
@@ -54,8 +59,24 @@ fn register(
     res: &mut Response,
 ) -> JsonResult<UserSessionInfo> {
     use crate::schema::users::dsl::*;
-    let input = json.into_inner();
-    input.validate()?;
+    let mut input = json.into_inner();
+    input.email = crate::validate::normalize_email(&input.email);
+    let result = crate::validate::with_field_check(
+        input.validate(),
+        "password",
+        crate::validate::password_with_context(
+            &input.password,
+            &[
+                crate::validate::email_local_part(&input.email),
+                &input.nickname,
+            ],
+        ),
+    );
+    crate::validate::with_field_check(
+        result,
+        "email",
+        crate::validate::email_domain(&input.email),
+    )?;
     let new_user = NewUser {
         email: input.email,
         nickname: input.nickname,
@@ -64,6 +85,10 @@ fn register(
         totp_confirmed_at: None,
         password_hash: util::hash_password(&input.password)?,
         created_at: chrono::Utc::now().naive_utc(),
+        is_admin: false,
+        last_seen: None,
+        locked_at: None,
+        lock_reason: None,
     };
     let conn = &mut db::get()?;
     // FIXME (not planned yet) account email enumeration vulnerability (need email confirmation flow)
@@ -71,62 +96,194 @@ fn register(
         .values(&new_user)
         .get_result(conn)?;
 
-    let session = create_session(conn, user.id, req, depot, res)?;
+    let session =
+        create_session(conn, user.id, &SessionOrigin::new(req, depot))?
+            .set_cookies(res);
     json_ok(UserSessionInfo::new(user, session))
 }
 
 #[derive(Debug, Deserialize, Validate, ToSchema)]
+#[serde(rename_all = "camelCase")]
 struct LoginInput {
     email: String,
     password: String,
     #[serde(default)]
     mfa_code: Option<String>,
+    /// Skip 2FA on this device for the next 30 days, only used together with
+    /// a `mfaCode`
+    #[serde(default)]
+    remember_device: bool,
 }
 
 /// Login a User and create a new Session
 ///
 /// We will try to find a session to reauth for the user with the matching device_id.
-/// Otherwise, a new session will be created.
+/// Otherwise, or if neither the network nor the device name of that session
+/// match, a new session with a new device_id will be created.
+/// No 2FA code is needed on a device remembered with `rememberDevice`.
 #[endpoint]
-fn login(
+async fn login(
     json: JsonBody<LoginInput>,
     req: &mut Request,
     depot: &mut Depot,
     res: &mut Response,
 ) -> JsonResult<UserSessionInfo> {
-    use crate::schema::sessions::dsl::*;
-
-    let conn = &mut db::get()?;
     let LoginInput {
         email,
         password,
         mfa_code,
+        remember_device,
     } = json.into_inner();
-    let user = util::get_user_by_credentials(&email, &password, conn)?;
+    let email = crate::validate::normalize_email(&email);
+    let ctx = AuditContext::from_request(req);
+    let origin = SessionOrigin::new(req, depot);
+    let trusted_token = super::trusted_device::cookie_token(req);
+    super::login_lockout::check(&email)?;
+
+    let login_email = email.clone();
+    let (user, session, trusted_cookie) = db::with_conn(move |conn| {
+        let (user, trusted, device) = verify_login(
+            conn,
+            &email,
+            &password,
+            mfa_code.as_deref(),
+            &origin,
+            trusted_token.as_ref(),
+        )
+        .inspect_err(|err| {
+            audit::record_failed_login(conn, &email, err, &ctx)
+        })?;
+        super::login_lockout::clear(&email);
+        super::ban::ensure_not_banned(conn, user.id)?;
+        super::ban::ensure_not_locked(conn, user.id)?;
+
+        let session = match device {
+            DeviceSession::Reuse(session) => {
+                rotate_session::<true>(conn, &session, &origin)?
+            }
+            DeviceSession::New => {
+                let issued = create_session(conn, user.id, &origin)?;
+                super::trusted_device::rekey(
+                    conn,
+                    user.id,
+                    &origin.device_id,
+                    &issued.session.device_id,
+                )?;
+                issued
+            }
+            DeviceSession::Mismatch(session) => {
+                audit::record(
+                    conn,
+                    user.id,
+                    SecurityEvent::DeviceIdMismatch {
+                        session_id: session.id,
+                    },
+                    &ctx,
+                )?;
+                create_session(conn, user.id, &origin)?
+            }
+        };
+        let trusted_cookie = if user.totp_enabled && !trusted && remember_device
+        {
+            Some(super::trusted_device::remember(
+                conn,
+                user.id,
+                &session.session.device_id,
+            )?)
+        } else {
+            None
+        };
+        audit::record(
+            conn,
+            user.id,
+            SecurityEvent::LoginSucceeded {
+                trusted_device: trusted,
+            },
+            &ctx,
+        )?;
+        Ok((user, session, trusted_cookie))
+    })
+    .await
+    .inspect_err(|err| {
+        super::login_lockout::record_failure(&login_email, req, err);
+    })?;
+
+    if let Some(cookie) = trusted_cookie {
+        res.add_cookie(cookie);
+    }
+    json_ok(UserSessionInfo::new(user, session.set_cookies(res)))
+}
 
-    super::two_factor::require_mfa_if_enabled(
-        conn,
-        &user,
-        mfa_code.as_deref(),
-    )?;
+/// Check the credentials and MFA code of a login.
+///
+/// Returns the user, whether MFA was skipped on a trusted device and the
+/// Session of the device. A device whose Session doesn't match the request
+/// is never trusted.
+fn verify_login(
+    conn: &mut db::DbConn,
+    email: &str,
+    password: &str,
+    mfa_code: Option<&str>,
+    origin: &SessionOrigin,
+    trusted_token: Option<&SessionToken>,
+) -> AppResult<(User, bool, DeviceSession)> {
+    let user = util::get_user_by_credentials(email, password, conn)?;
+    let device = DeviceSession::find(conn, user.id, origin)?;
+
+    let trusted = user.totp_enabled
+        && !matches!(device, DeviceSession::Mismatch(_))
+        && super::trusted_device::is_trusted(
+            conn,
+            user.id,
+            &origin.device_id,
+            trusted_token,
+        )?;
+    if !trusted {
+        super::two_factor::require_mfa_if_enabled(conn, &user, mfa_code)?;
+    }
+    Ok((user, trusted, device))
+}
 
-    let session = match sessions
-        .filter(user_id.eq(user.id))
-        .filter(device_id.eq(depot.device_id()))
-        .first(conn)
-        .optional()
-    {
-        Ok(Some(session)) => {
-            rotate_session::<true>(conn, &session, req, depot, res)?
-        }
-        Ok(None) => create_session(conn, user.id, req, depot, res)?,
-        Err(err) => return Err(err.into()),
-    };
+/// The Session a login reuses, by the `device_id` cookie.
+///
+/// The cookie isn't a secret, e.g. it can be copied to another browser.
+/// So a Session from neither the network nor the device name of the login
+/// is not reused, the login shouldn't inherit its history.
+enum DeviceSession {
+    /// No Session of the user has the `device_id`
+    New,
+    Reuse(Session),
+    /// Neither the network nor the device name match
+    Mismatch(Session),
+}
 
-    json_ok(UserSessionInfo::new(user, session))
+impl DeviceSession {
+    fn find(
+        conn: &mut db::DbConn,
+        target_user: i32,
+        origin: &SessionOrigin,
+    ) -> AppResult<Self> {
+        use crate::schema::sessions::dsl::*;
+
+        let Some(session) = sessions
+            .filter(user_id.eq(target_user))
+            .filter(device_id.eq(&origin.device_id))
+            .first::<Session>(conn)
+            .optional()?
+        else {
+            return Ok(Self::New);
+        };
+        if session.ip_address != origin.ip_address
+            && session.device_name_auto != origin.device_name
+        {
+            return Ok(Self::Mismatch(session));
+        }
+        Ok(Self::Reuse(session))
+    }
 }
 
 #[derive(Debug, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
 pub struct PasswordInput {
     pub password: String,
     #[serde(default)]
@@ -139,65 +296,108 @@ pub struct PasswordInput {
 #[endpoint(
     security(("reauth_session" = []))
 )]
-fn reauth(
+async fn reauth(
     json: JsonBody<PasswordInput>,
     req: &mut Request,
     depot: &mut Depot,
     res: &mut Response,
 ) -> JsonResult<UserSessionInfo> {
-    let conn = &mut db::get()?;
-    let session = depot.session();
+    let session = depot.session().clone();
+    let origin = SessionOrigin::new(req, depot);
+    let ctx = AuditContext::from_request(req);
     let PasswordInput { password, mfa_code } = json.into_inner();
-    util::check_password_and_mfa_if_enabled(
-        session.user_id,
-        &password,
-        mfa_code.as_deref(),
-        conn,
-    )?;
 
-    let session = rotate_session::<true>(conn, session, req, depot, res)?;
-    json_ok(UserSessionInfo::from_session(conn, session)?)
+    let (info, issued) = db::with_transaction(move |conn| {
+        util::check_password_and_mfa_if_enabled(
+            session.user_id,
+            &password,
+            mfa_code.as_deref(),
+            conn,
+        )?;
+
+        let issued = rotate_session::<true>(conn, &session, &origin)?;
+        audit::record(conn, session.user_id, SecurityEvent::Reauth, &ctx)?;
+        let info = UserSessionInfo::from_session(conn, issued.session.clone())?;
+        Ok((info, issued))
+    })
+    .await?;
+    issued.set_cookies(res);
+    json_ok(info)
 }
 
 /// Refresh JWT access token for the current Session
 #[endpoint(
     security(("session" = []))
 )]
-fn refresh_jwt(
+async fn refresh_jwt(
     req: &mut Request,
     depot: &mut Depot,
     res: &mut Response,
 ) -> JsonResult<SessionInfo> {
-    let conn = &mut db::get()?;
-    let session = depot.session();
+    let session = depot.session().clone();
+    let origin = SessionOrigin::new(req, depot);
+
+    let issued = db::with_conn(move |conn| {
+        rotate_session::<false>(conn, &session, &origin)
+    })
+    .await?;
+    json_ok(issued.set_cookies(res).into())
+}
 
-    json_ok(rotate_session::<false>(conn, session, req, depot, res)?.into())
+/// The device a Session is created or rotated for.
+///
+/// Taken from the request up front, so the DB work can run off the async
+/// runtime, see [db::with_conn].
+struct SessionOrigin {
+    device_id: String,
+    device_name: Option<String>,
+    /// Truncated, the full address is only used for rate limits
+    ip_address: Option<String>,
+}
+
+impl SessionOrigin {
+    fn new(req: &Request, depot: &Depot) -> Self {
+        Self {
+            device_id: depot.device_id().to_owned(),
+            device_name: util::get_device_name(req),
+            ip_address: client_ip::client_ip(req)
+                .map(|ip| client_ip::truncate(ip).to_string()),
+        }
+    }
 }
 
-fn set_auth_cookies(res: &mut Response, token: SessionToken, jwt: String) {
-    res.add_cookie(util::session_cookie(token));
-    res.add_cookie(util::jwt_cookie(jwt));
+/// A created or rotated Session whose cookies still have to be set.
+struct IssuedSession {
+    session: Session,
+    token: SessionToken,
+    jwt: String,
+}
+
+impl IssuedSession {
+    fn set_cookies(self, res: &mut Response) -> Session {
+        res.add_cookie(util::device_id_cookie(&self.session.device_id));
+        res.add_cookie(util::session_cookie(self.token));
+        res.add_cookie(util::jwt_cookie(self.jwt));
+        self.session
+    }
 }
 
 fn rotate_session<const DO_REAUTH: bool>(
     conn: &mut db::DbConn,
     session: &Session,
-    req: &Request,
-    depot: &Depot,
-    res: &mut Response,
-) -> AppResult<Session> {
+    origin: &SessionOrigin,
+) -> AppResult<IssuedSession> {
     use crate::schema::sessions::dsl as sessions_dsl;
 
     let now = chrono::Utc::now().naive_utc();
-    let (device_name, ip_address) = util::get_device_and_ip(req);
     let token = SessionToken::generate();
     let hashed_token = token.to_hash();
 
     let mut rotated = session.rotate(
         hashed_token,
-        depot.device_id().to_owned(),
-        device_name,
-        ip_address,
+        origin.device_id.clone(),
+        origin.device_name.clone(),
+        origin.ip_address.clone(),
     );
     if DO_REAUTH {
         rotated.last_authenticated_at = now;
@@ -216,30 +416,39 @@ fn rotate_session<const DO_REAUTH: bool>(
     if updated != 1 {
         return Err(AuthError::SessionMismatch.into());
     }
+    super::session_cache::invalidate(session.id);
 
     let jwt = util::jwt_create(&rotated, hashed_token.to_truncated())?;
-    set_auth_cookies(res, token, jwt);
-    Ok(rotated)
+    // Keep a WebTransport connection of this session alive
+    StreamManager::global().extend_deadline(
+        rotated.user_id,
+        rotated.id,
+        rotated.access_valid_until(),
+    );
+    Ok(IssuedSession {
+        session: rotated,
+        token,
+        jwt,
+    })
 }
 
 fn create_session(
     conn: &mut db::DbConn,
     user_id: i32,
-    req: &Request,
-    depot: &Depot,
-    res: &mut Response,
-) -> AppResult<Session> {
+    origin: &SessionOrigin,
+) -> AppResult<IssuedSession> {
     use crate::schema::sessions::dsl::sessions;
 
     let token = SessionToken::generate();
     let token_hash = token.to_hash();
-    let (device_name, ip_address) = util::get_device_and_ip(req);
+    // A new device_id for every new Session, so a copied cookie stops
+    // matching the sessions of the original browser
     let new_session = NewSession::new(
         user_id,
         token_hash,
-        depot.device_id().to_owned(),
-        device_name,
-        ip_address,
+        Ulid::new().to_string(),
+        origin.device_name.clone(),
+        origin.ip_address.clone(),
     );
 
     let session: Session = diesel::insert_into(sessions)
@@ -253,12 +462,14 @@ fn create_session(
     }
 
     let jwt = util::jwt_create(&session, token_hash.to_truncated())?;
-    set_auth_cookies(res, token, jwt);
-
-    Ok(session)
+    Ok(IssuedSession {
+        session,
+        token,
+        jwt,
+    })
 }
 
-pub(super) fn session_hoop_inner<const NO_PENDING_REAUTH: bool>(
+pub(super) async fn session_hoop_inner<const NO_PENDING_REAUTH: bool>(
     req: &mut Request,
     depot: &mut Depot,
     res: &mut Response,
@@ -269,12 +480,31 @@ pub(super) fn session_hoop_inner<const NO_PENDING_REAUTH: bool>(
             .value(),
     )
     .map_err(|_| AuthError::InvalidSessionToken)?;
-
-    use crate::schema::sessions::dsl::*;
-    let session: Session = sessions
-        .filter(token_hash.eq(session_token.to_hash()))
-        .first(&mut db::get()?)
-        .map_err(|_| AuthError::SessionNotFound)?;
+    let ctx = AuditContext::from_request(req);
+
+    let session = db::with_conn(move |conn| {
+        use crate::schema::sessions::dsl::*;
+        let presented = session_token.to_hash();
+        let Some(session) = sessions
+            .filter(token_hash.eq(presented))
+            .first::<Session>(conn)
+            .optional()?
+        else {
+            if revoke_on_token_reuse(conn, presented, &ctx)? {
+                return Err(AuthError::SessionTokenReuse.into());
+            }
+            return Err(AuthError::SessionNotFound.into());
+        };
+        super::ban::ensure_not_banned(conn, session.user_id)?;
+        super::ban::ensure_not_locked(conn, session.user_id)?;
+        Ok(session)
+    })
+    .await
+    .inspect_err(|err| {
+        if matches!(err, ApiError::Auth(AuthError::SessionTokenReuse)) {
+            util::delete_auth_cookies(res);
+        }
+    })?;
 
     if NO_PENDING_REAUTH
         && super::hoops::session_requires_reauth(
@@ -285,22 +515,85 @@ pub(super) fn session_hoop_inner<const NO_PENDING_REAUTH: bool>(
         return Err(AuthError::NeedReauth.into());
     }
     set_session(depot, session);
-    res.add_cookie(super::util::device_id_cookie(depot));
+    res.add_cookie(super::util::device_id_cookie(depot.device_id()));
     Ok(())
 }
 
+/// Revoke the Session whose previous refresh token is `presented`, returns
+/// whether there was one.
+///
+/// A rotated token is only presented again if it was copied, or if the client
+/// retried a refresh that went through. Both look the same, so the Session is
+/// revoked either way: it is deauthed and gets a token hash nobody has a
+/// token for, which also ends the access token of the current holder.
+fn revoke_on_token_reuse(
+    conn: &mut db::DbConn,
+    presented: SessionTokenHash,
+    ctx: &AuditContext,
+) -> AppResult<bool> {
+    use crate::schema::sessions::dsl::*;
+
+    let revoked = conn.transaction::<_, ApiError, _>(|conn| {
+        let Some(session) = sessions
+            .filter(prev_token_hash.eq(presented))
+            .first::<Session>(conn)
+            .optional()?
+        else {
+            return Ok(None);
+        };
+        diesel::update(sessions.find(session.id))
+            .set((
+                token_hash.eq(SessionToken::generate().to_hash()),
+                prev_token_hash.eq(None::<SessionTokenHash>),
+                last_authenticated_at
+                    .eq(chrono::DateTime::UNIX_EPOCH.naive_utc()),
+            ))
+            .execute(conn)?;
+        audit::record(
+            conn,
+            session.user_id,
+            SecurityEvent::SessionTokenReused {
+                session_id: session.id,
+            },
+            ctx,
+        )?;
+        Ok(Some(session))
+    })?;
+    let Some(session) = revoked else {
+        return Ok(false);
+    };
+
+    tracing::warn!(
+        user_id = session.user_id,
+        session_id = session.id,
+        "Session token reused, revoked the session"
+    );
+    super::session_cache::invalidate(session.id);
+    let streams = StreamManager::global();
+    streams.close_session_stream(session.user_id, session.id);
+    // Reaches the other Sessions of the user, an unconnected user sees the
+    // event in the security log
+    streams.notify(
+        session.user_id,
+        Notification::SessionRevoked {
+            session_id: session.id,
+        },
+    );
+    Ok(true)
+}
+
 /// Load a Session from the session cookie, enforcing reauth requirements.
 ///
 /// If the session requires reauth, an error is returned.
 /// Only to be used by the auth module
 #[handler]
-pub fn session_hoop(
+pub async fn session_hoop(
     req: &mut Request,
     depot: &mut Depot,
     res: &mut Response,
     ctrl: &mut FlowCtrl,
 ) {
-    if let Err(err) = session_hoop_inner::<true>(req, depot, res) {
+    if let Err(err) = session_hoop_inner::<true>(req, depot, res).await {
         err.render(res);
         ctrl.skip_rest();
     }
@@ -313,14 +606,372 @@ pub fn session_hoop(
 /// session is currently in a "needs reauth" state.
 /// Only to be used by the auth module
 #[handler]
-fn session_allow_reauth_hoop(
+async fn session_allow_reauth_hoop(
     req: &mut Request,
     depot: &mut Depot,
     res: &mut Response,
     ctrl: &mut FlowCtrl,
 ) {
-    if let Err(err) = session_hoop_inner::<false>(req, depot, res) {
+    if let Err(err) = session_hoop_inner::<false>(req, depot, res).await {
         err.render(res);
         ctrl.skip_rest();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use salvo::http::header::USER_AGENT;
+    use salvo::test::{ResponseExt, TestClient};
+    use serde_json::{Value, json};
+
+    use crate::auth::{SESSION_COOKIE_NAME, TRUSTED_DEVICE_COOKIE_NAME};
+    use crate::models::Session;
+    use crate::prelude::*;
+    use crate::stream::StreamManager;
+    use crate::stream::testing::FakeClient;
+    use crate::test_db::{self, Client, PASSWORD};
+
+    const BASE: &str = "http://localhost/api";
+
+    #[tokio::test]
+    async fn register_then_get_me() {
+        test_db::with_service(|service| async move {
+            let (mut client, body) =
+                Client::register(&service, "alice@example.com", "alice").await;
+            assert_eq!(body["user"]["email"], "alice@example.com");
+            assert_eq!(body["session"]["userId"], body["user"]["id"]);
+
+            let mut res = client
+                .send(TestClient::get(format!("{BASE}/user/me")), &service)
+                .await;
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+            let me: Value = res.take_json().await.unwrap();
+            assert_eq!(me["user"]["nickname"], "alice");
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn register_twice_conflicts() {
+        test_db::with_service(|service| async move {
+            Client::register(&service, "bob@example.com", "bob").await;
+            let res = TestClient::post(format!("{BASE}/auth/register"))
+                .json(&json!({
+                    "email": "bob@example.com",
+                    "password": PASSWORD,
+                    "nickname": "bobby",
+                }))
+                .send(&service)
+                .await;
+            assert_eq!(res.status_code, Some(StatusCode::CONFLICT));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn login_checks_password() {
+        test_db::with_service(|service| async move {
+            Client::register(&service, "carol@example.com", "carol").await;
+            let login = |password: &'static str| {
+                TestClient::post(format!("{BASE}/auth/login")).json(&json!({
+                    "email": "carol@example.com",
+                    "password": password,
+                }))
+            };
+            let res = login("wrong password 123").send(&service).await;
+            assert_eq!(res.status_code, Some(StatusCode::UNAUTHORIZED));
+            let res = login(PASSWORD).send(&service).await;
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn register_reports_every_invalid_field() {
+        test_db::with_service(|service| async move {
+            let mut res = TestClient::post(format!("{BASE}/auth/register"))
+                .json(&json!({
+                    "email": "dave@example.com",
+                    "password": "short",
+                    "nickname": "d",
+                }))
+                .send(&service)
+                .await;
+            assert_eq!(res.status_code, Some(StatusCode::BAD_REQUEST));
+            let body: Value = res.take_json().await.unwrap();
+            assert_eq!(body["code"], "validation");
+            assert!(body["fields"]["nickname"][0].is_string(), "{body}");
+            assert!(body["fields"]["password"][0].is_string(), "{body}");
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn malformed_json_is_invalid_json() {
+        test_db::with_service(|service| async move {
+            let mut res = TestClient::post(format!("{BASE}/auth/register"))
+                .raw_json("{\"email\": ")
+                .send(&service)
+                .await;
+            assert_eq!(res.status_code, Some(StatusCode::BAD_REQUEST));
+            let body: Value = res.take_json().await.unwrap();
+            assert_eq!(body["code"], "invalid_json");
+            assert!(body["requestId"].is_string());
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn requests_without_login_are_rejected() {
+        test_db::with_service(|service| async move {
+            let mut res = TestClient::get(format!("{BASE}/user/me"))
+                .send(&service)
+                .await;
+            assert_eq!(res.status_code, Some(StatusCode::UNAUTHORIZED));
+            let body: Value = res.take_json().await.unwrap();
+            assert_eq!(body["code"], "missing_jwt_cookie");
+        })
+        .await;
+    }
+
+    async fn refresh(client: &mut Client, service: &Service) -> Response {
+        client
+            .send(
+                TestClient::post(format!(
+                    "{BASE}/auth/session-management/refresh-jwt"
+                )),
+                service,
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn rotation_keeps_the_session() {
+        test_db::with_service(|service| async move {
+            let (mut client, body) =
+                Client::register(&service, "erin@example.com", "erin").await;
+            let first = client.cookie(SESSION_COOKIE_NAME).unwrap().to_owned();
+
+            for _ in 0..2 {
+                let mut res = refresh(&mut client, &service).await;
+                assert_eq!(res.status_code, Some(StatusCode::OK));
+                let session: Value = res.take_json().await.unwrap();
+                assert_eq!(session["sessionId"], body["session"]["sessionId"]);
+            }
+            assert_ne!(client.cookie(SESSION_COOKIE_NAME).unwrap(), first);
+            let res = client
+                .send(TestClient::get(format!("{BASE}/user/me")), &service)
+                .await;
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn reused_token_revokes_the_session() {
+        test_db::with_service(|service| async move {
+            let (mut thief, body) =
+                Client::register(&service, "frank@example.com", "frank").await;
+            let frank = body["user"]["id"].as_i64().unwrap() as i32;
+            let session_id =
+                body["session"]["sessionId"].as_i64().unwrap() as i32;
+            let stolen = thief.cookie(SESSION_COOKIE_NAME).unwrap().to_owned();
+
+            // Another Session of the user, connected to the stream
+            let mut other = Client::default();
+            let mut res = other
+                .send(
+                    TestClient::post(format!("{BASE}/auth/login")).json(
+                        &json!({
+                            "email": "frank@example.com",
+                            "password": PASSWORD,
+                        }),
+                    ),
+                    &service,
+                )
+                .await;
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+            let other_id =
+                res.take_json::<Value>().await.unwrap()["session"]["sessionId"]
+                    .as_i64()
+                    .unwrap() as i32;
+            let other_session = db::with_conn(move |conn| {
+                crate::schema::sessions::table
+                    .find(other_id)
+                    .first::<Session>(conn)
+                    .map_err(ApiError::from)
+            })
+            .await
+            .unwrap();
+            let mut stream = FakeClient::connect(
+                StreamManager::global(),
+                Some((&other_session).into()),
+            )
+            .await;
+
+            // The owner rotates, then the thief presents the old token
+            let mut owner = thief.clone();
+            let res = refresh(&mut owner, &service).await;
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+            thief.set_cookie(SESSION_COOKIE_NAME, stolen);
+            let mut res = refresh(&mut thief, &service).await;
+            assert_eq!(res.status_code, Some(StatusCode::UNAUTHORIZED));
+            let body: Value = res.take_json().await.unwrap();
+            assert_eq!(body["code"], "session_token_reuse");
+            assert_eq!(thief.cookie(SESSION_COOKIE_NAME), Some(""));
+
+            // The rotated token and its access token are revoked as well
+            let mut res = refresh(&mut owner, &service).await;
+            assert_eq!(res.status_code, Some(StatusCode::UNAUTHORIZED));
+            let body: Value = res.take_json().await.unwrap();
+            assert_eq!(body["code"], "session_not_found");
+            let res = owner
+                .send(TestClient::get(format!("{BASE}/user/me")), &service)
+                .await;
+            assert_eq!(res.status_code, Some(StatusCode::UNAUTHORIZED));
+
+            let events = db::with_conn(move |conn| {
+                use crate::schema::security_events::dsl::*;
+                security_events
+                    .filter(user_id.eq(frank))
+                    .filter(event_type.eq("SessionTokenReused"))
+                    .select(metadata)
+                    .load::<Option<String>>(conn)
+                    .map_err(ApiError::from)
+            })
+            .await
+            .unwrap();
+            assert_eq!(events.len(), 1);
+
+            let message = stream.recv_control().await;
+            assert_eq!(message["type"], "Notification");
+            assert_eq!(
+                message["data"],
+                json!({
+                    "type": "SessionRevoked",
+                    "data": { "sessionId": session_id },
+                })
+            );
+            assert!(StreamManager::global().is_connected(frank));
+        })
+        .await;
+    }
+
+    const FIREFOX: &str = "Mozilla/5.0 (X11; Linux x86_64; rv:128.0) \
+        Gecko/20100101 Firefox/128.0";
+
+    fn login(email: &str, body: Value) -> salvo::test::RequestBuilder {
+        let mut body = body;
+        body["email"] = email.into();
+        body["password"] = PASSWORD.into();
+        TestClient::post(format!("{BASE}/auth/login")).json(&body)
+    }
+
+    async fn session_id(res: &mut Response) -> i64 {
+        assert_eq!(res.status_code, Some(StatusCode::OK));
+        let body: Value = res.take_json().await.unwrap();
+        body["session"]["sessionId"].as_i64().unwrap()
+    }
+
+    #[tokio::test]
+    async fn copied_device_ids_get_a_new_session() {
+        test_db::with_service(|service| async move {
+            let email = "gwen@example.com";
+            let (mut client, body) =
+                Client::register(&service, email, "gwen").await;
+            let user_id = body["user"]["id"].as_i64().unwrap() as i32;
+            let first = body["session"]["sessionId"].as_i64().unwrap();
+
+            // The same device logs in again, also from another network
+            let mut res = client.send(login(email, json!({})), &service).await;
+            assert_eq!(session_id(&mut res).await, first);
+            let elsewhere =
+                test_db::service_from("198.51.100.7".parse().unwrap());
+            let mut res =
+                client.send(login(email, json!({})), &elsewhere).await;
+            assert_eq!(session_id(&mut res).await, first);
+
+            // A copied cookie, in another browser on yet another network
+            let attacker =
+                test_db::service_from("203.0.113.9".parse().unwrap());
+            let mut res = client
+                .with_cookies(login(email, json!({})))
+                .add_header(USER_AGENT, FIREFOX, true)
+                .send(&attacker)
+                .await;
+            let copied = client.cookie("device_id").unwrap().to_owned();
+            assert_ne!(res.cookie("device_id").unwrap().value(), copied);
+            let second = session_id(&mut res).await;
+            assert_ne!(second, first);
+
+            use crate::schema::{security_events, sessions};
+            let conn = &mut db::get().unwrap();
+            let ids: Vec<i32> = sessions::table
+                .filter(sessions::user_id.eq(user_id))
+                .order(sessions::id)
+                .select(sessions::id)
+                .load(conn)
+                .unwrap();
+            assert_eq!(ids, [first as i32, second as i32]);
+            let mismatches: i64 = security_events::table
+                .filter(security_events::user_id.eq(user_id))
+                .filter(security_events::event_type.eq("DeviceIdMismatch"))
+                .count()
+                .get_result(conn)
+                .unwrap();
+            assert_eq!(mismatches, 1);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn trusted_devices_skip_2fa_after_the_device_id_rotates() {
+        test_db::with_service(|service| async move {
+            use crate::schema::{sessions, trusted_devices, users};
+
+            let email = "hank@example.com";
+            let (_, body) = Client::register(&service, email, "hank").await;
+            let user_id = body["user"]["id"].as_i64().unwrap() as i32;
+            let conn = &mut db::get().unwrap();
+            diesel::update(users::table.find(user_id))
+                .set(users::totp_enabled.eq(true))
+                .execute(conn)
+                .unwrap();
+            let recovery = "recovery-code".to_owned();
+            crate::auth::two_factor::replace_recovery_codes(
+                conn,
+                user_id,
+                std::slice::from_ref(&recovery),
+            )
+            .unwrap();
+
+            let mut phone = Client::default();
+            let mut res = phone.send(login(email, json!({})), &service).await;
+            assert_eq!(res.status_code, Some(StatusCode::UNAUTHORIZED));
+            let body: Value = res.take_json().await.unwrap();
+            assert_eq!(body["code"], "two_factor_required");
+            let remember =
+                json!({ "mfaCode": recovery, "rememberDevice": true });
+            let res = phone.send(login(email, remember), &service).await;
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+            assert!(phone.cookie(TRUSTED_DEVICE_COOKIE_NAME).is_some());
+
+            // Every login without a Session creates one with a new device_id
+            for _ in 0..2 {
+                let before = phone.cookie("device_id").unwrap().to_owned();
+                diesel::delete(sessions::table).execute(conn).unwrap();
+                let res = phone.send(login(email, json!({})), &service).await;
+                assert_eq!(res.status_code, Some(StatusCode::OK));
+                let after = phone.cookie("device_id").unwrap();
+                assert_ne!(after, before);
+                let trusted: String = trusted_devices::table
+                    .filter(trusted_devices::user_id.eq(user_id))
+                    .select(trusted_devices::device_id)
+                    .first(conn)
+                    .unwrap();
+                assert_eq!(trusted, after);
+            }
+        })
+        .await;
+    }
+}
@@ -0,0 +1,266 @@
+//! Cache of the Sessions loaded by the access hoop.
+//!
+//! Every authenticated request loads its Session and whether the user is
+//! banned or locked, so all are kept for [`TTL`]. Code that changes a session
+//! row, a ban or a lock has to call [`invalidate`] or [`invalidate_user`]
+//! after the write, or [`touch`] for `last_used_at`.
+//!
+//! A load that raced with an invalidation is not cached. A write in a
+//! transaction is only visible after its commit though, so a request between
+//! the invalidation and the commit can still cache the old row until [`TTL`].
+//! Cached rows whose token hash doesn't match the access token are reloaded,
+//! so this can't break a rotation.
+
+use std::sync::LazyLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use chrono::NaiveDateTime;
+use quick_cache::sync::Cache;
+
+use crate::auth::session_token::SessionTokenHashTruncated;
+use crate::models::Session;
+use crate::utils::metrics;
+
+const TTL: Duration = Duration::from_secs(60);
+/// Sessions cached at the same time.
+const CAPACITY: usize = 10_000;
+
+#[derive(Debug, Clone)]
+pub struct CachedSession {
+    pub session: Session,
+    pub banned: bool,
+    pub locked: bool,
+    cached_at: Instant,
+}
+
+static SESSIONS: LazyLock<Cache<i32, CachedSession>> =
+    LazyLock::new(|| Cache::new(CAPACITY));
+/// Bumped by every invalidation, see [`Miss::insert`].
+static EPOCH: AtomicU64 = AtomicU64::new(0);
+
+/// A cache miss, to store the session once it is loaded.
+#[must_use]
+pub struct Miss {
+    epoch: u64,
+}
+
+impl Miss {
+    pub fn insert(self, session: Session, banned: bool, locked: bool) {
+        if EPOCH.load(Ordering::SeqCst) != self.epoch {
+            return;
+        }
+        let session_id = session.id;
+        SESSIONS.insert(
+            session_id,
+            CachedSession {
+                session,
+                banned,
+                locked,
+                cached_at: Instant::now(),
+            },
+        );
+        // An invalidation between the check and the insert may have missed
+        // the new entry
+        if EPOCH.load(Ordering::SeqCst) != self.epoch {
+            SESSIONS.remove(&session_id);
+        }
+    }
+}
+
+/// The cached Session with `session_id`, if it was issued the access token
+/// with `jti`.
+pub fn lookup(
+    session_id: i32,
+    jti: &SessionTokenHashTruncated,
+) -> Result<CachedSession, Miss> {
+    let miss = Miss {
+        epoch: EPOCH.load(Ordering::SeqCst),
+    };
+    let cached = SESSIONS.get(&session_id).filter(|cached| {
+        cached.cached_at.elapsed() < TTL && cached.session.token_hash == *jti
+    });
+    metrics::record_session_cache(cached.is_some());
+    cached.ok_or(miss)
+}
+
+/// Set `last_used_at` of the cached Session with `session_id`.
+///
+/// Unlike [`invalidate`] this keeps the entry and doesn't make concurrent
+/// loads skip the cache, so a busy server doesn't lose its cache every time
+/// `last_used_at` is written.
+pub fn touch(session_id: i32, last_used_at: NaiveDateTime) {
+    let epoch = EPOCH.load(Ordering::SeqCst);
+    let Some(mut cached) = SESSIONS.peek(&session_id) else {
+        return;
+    };
+    cached.session.last_used_at = last_used_at;
+    let _ = SESSIONS.replace(session_id, cached, true);
+    // An invalidation since the peek may have been overwritten
+    if EPOCH.load(Ordering::SeqCst) != epoch {
+        SESSIONS.remove(&session_id);
+    }
+}
+
+/// Drop the cached Session with `session_id`.
+pub fn invalidate(session_id: i32) {
+    EPOCH.fetch_add(1, Ordering::SeqCst);
+    SESSIONS.remove(&session_id);
+}
+
+/// Drop all cached Sessions of a user, e.g. after a ban or lock changed.
+pub fn invalidate_user(user_id: i32) {
+    EPOCH.fetch_add(1, Ordering::SeqCst);
+    SESSIONS.retain(|_, cached| cached.session.user_id != user_id);
+}
+
+#[cfg(test)]
+mod tests {
+    use salvo::test::{ResponseExt, TestClient};
+    use serde_json::Value;
+
+    use super::*;
+    use crate::auth::session_token::SessionToken;
+    use crate::prelude::*;
+    use crate::test_db::{self, Client};
+
+    /// A Session with a negative id, which no test database has.
+    fn session(id: i32) -> Session {
+        let now = chrono::Utc::now().naive_utc();
+        Session {
+            id,
+            user_id: id,
+            token_hash: SessionToken::generate().to_hash(),
+            device_id: String::new(),
+            device_name: None,
+            ip_address: None,
+            created_at: now,
+            refreshed_at: now,
+            last_used_at: now,
+            last_authenticated_at: now,
+            device_name_auto: None,
+            prev_token_hash: None,
+        }
+    }
+
+    /// Cache `session` by taking the [Miss] until no invalidation of a
+    /// parallel test interferes.
+    fn cache(session: &Session) -> CachedSession {
+        let jti = session.token_hash.to_truncated();
+        loop {
+            match lookup(session.id, &jti) {
+                Ok(cached) => return cached,
+                Err(miss) => miss.insert(session.clone(), false, false),
+            }
+        }
+    }
+
+    #[test]
+    fn lookup_hits_for_the_issued_token_only() {
+        let session = session(-1_001);
+        let cached = cache(&session);
+        assert_eq!(cached.session.id, session.id);
+
+        let other = SessionToken::generate().to_hash().to_truncated();
+        assert!(lookup(session.id, &other).is_err());
+    }
+
+    #[test]
+    fn entries_expire_after_ttl() {
+        let session = session(-1_002);
+        let mut cached = cache(&session);
+        cached.cached_at = Instant::now() - TTL;
+        SESSIONS.insert(session.id, cached);
+        let jti = session.token_hash.to_truncated();
+        assert!(lookup(session.id, &jti).is_err());
+    }
+
+    #[test]
+    fn touch_updates_the_entry_in_place() {
+        let session = session(-1_003);
+        let cached = cache(&session);
+        let later = session.last_used_at + chrono::Duration::minutes(10);
+        touch(session.id, later);
+        let jti = session.token_hash.to_truncated();
+        // Unless a parallel test invalidated in between
+        if let Ok(touched) = lookup(session.id, &jti) {
+            assert_eq!(touched.session.last_used_at, later);
+            assert_eq!(touched.cached_at, cached.cached_at);
+        }
+
+        // Entries are not created
+        touch(-1_004, later);
+        assert!(SESSIONS.peek(&-1_004).is_none());
+    }
+
+    const BASE: &str = "http://localhost/api";
+
+    /// Register a user and an admin, with the user's Session cached.
+    async fn user_and_admin(service: &Service) -> (Client, i64, Client) {
+        let (mut user, body) =
+            Client::register(service, "user@example.com", "user").await;
+        let user_id = body["user"]["id"].as_i64().unwrap();
+        let res = user
+            .send(TestClient::get(format!("{BASE}/user/me")), service)
+            .await;
+        assert_eq!(res.status_code, Some(StatusCode::OK));
+
+        let (admin, body) =
+            Client::register(service, "admin@example.com", "admin").await;
+        let admin_id = body["user"]["id"].as_i64().unwrap() as i32;
+        {
+            use crate::schema::users;
+            diesel::update(users::table.find(admin_id))
+                .set(users::is_admin.eq(true))
+                .execute(&mut db::get().unwrap())
+                .unwrap();
+        }
+        (user, user_id, admin)
+    }
+
+    async fn me_code(client: &mut Client, service: &Service) -> Value {
+        let mut res = client
+            .send(TestClient::get(format!("{BASE}/user/me")), service)
+            .await;
+        let body: Value = res.take_json().await.unwrap();
+        body["code"].clone()
+    }
+
+    #[tokio::test]
+    async fn ban_invalidates_cached_sessions() {
+        test_db::with_service(|service| async move {
+            let (mut user, user_id, mut admin) = user_and_admin(&service).await;
+            let res = admin
+                .send(
+                    TestClient::post(format!(
+                        "{BASE}/admin/users/{user_id}/ban"
+                    ))
+                    .json(&serde_json::json!({ "reason": "test" })),
+                    &service,
+                )
+                .await;
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+            assert_eq!(me_code(&mut user, &service).await, "banned");
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn lock_invalidates_cached_sessions() {
+        test_db::with_service(|service| async move {
+            let (mut user, user_id, mut admin) = user_and_admin(&service).await;
+            let res = admin
+                .send(
+                    TestClient::post(format!(
+                        "{BASE}/admin/users/{user_id}/lock"
+                    ))
+                    .json(&serde_json::json!({ "reason": "test" })),
+                    &service,
+                )
+                .await;
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+            assert_eq!(me_code(&mut user, &service).await, "account_locked");
+        })
+        .await;
+    }
+}
@@ -25,12 +25,12 @@ impl SessionToken {
         SessionToken(rand::random())
     }
 
-    pub fn to_hash(&self) -> SessionTokenHash {
-        SessionTokenHash::from(*self)
+    pub fn to_hash(self) -> SessionTokenHash {
+        SessionTokenHash::from(self)
     }
 
     pub fn encoded(&self) -> String {
-        base64url.encode(&self.0)
+        base64url.encode(self.0)
     }
 }
 
@@ -87,17 +87,27 @@ where
 {
     fn from_sql(bytes: DB::RawValue<'_>) -> diesel::deserialize::Result<Self> {
         let hash = <Vec<u8>>::from_sql(bytes)?;
-        Ok(SessionTokenHash(hash.try_into().unwrap()))
+        let len = hash.len();
+        let hash = hash.try_into().map_err(|_| {
+            format!("session token hash has {len} bytes instead of 32")
+        })?;
+        Ok(SessionTokenHash(hash))
     }
 }
 
 impl SessionTokenHash {
-    pub fn to_truncated(&self) -> SessionTokenHashTruncated {
-        SessionTokenHashTruncated::from(*self)
+    pub fn to_truncated(self) -> SessionTokenHashTruncated {
+        SessionTokenHashTruncated::from(self)
     }
 
     pub fn encoded(&self) -> String {
-        base64url.encode(&self.0)
+        base64url.encode(self.0)
+    }
+
+    /// Compare in constant time, for hashes that are checked in memory
+    /// instead of looked up in the database.
+    pub fn ct_eq(&self, other: &Self) -> bool {
+        blake3::Hash::from(self.0) == blake3::Hash::from(other.0)
     }
 }
 
@@ -128,7 +138,7 @@ pub struct SessionTokenHashTruncated([u8; 16]);
 
 impl SessionTokenHashTruncated {
     pub fn encoded(&self) -> String {
-        base64url.encode(&self.0)
+        base64url.encode(self.0)
     }
 }
 
@@ -166,12 +176,59 @@ impl From<SessionTokenHashTruncated> for String {
 // implement comparison between SessionTokenHash and SessionTokenHashTruncated where only the first 16 bytes are compared
 impl PartialEq<SessionTokenHashTruncated> for SessionTokenHash {
     fn eq(&self, other: &SessionTokenHashTruncated) -> bool {
-        &self.0[..16] == &other.0[..]
+        self.0[..16] == other.0[..]
     }
 }
 
 impl PartialEq<SessionTokenHash> for SessionTokenHashTruncated {
     fn eq(&self, other: &SessionTokenHash) -> bool {
-        &self.0[..] == &other.0[..16]
+        self.0[..] == other.0[..16]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use salvo::test::TestClient;
+
+    use crate::models::Session;
+    use crate::prelude::*;
+    use crate::test_db::{self, Client};
+
+    #[tokio::test]
+    async fn hashes_of_the_wrong_length_are_rejected() {
+        test_db::with_service(|service| async move {
+            let (mut client, body) =
+                Client::register(&service, "ivy@example.com", "ivy").await;
+            let session_id = body["session"]["sessionId"].as_i64().unwrap();
+            let conn = &mut db::get().unwrap();
+            diesel::sql_query(
+                "UPDATE sessions SET token_hash = randomblob(16)",
+            )
+            .execute(conn)
+            .unwrap();
+
+            let err = crate::schema::sessions::table
+                .find(session_id as i32)
+                .first::<Session>(conn)
+                .unwrap_err();
+            assert!(
+                matches!(err, diesel::result::Error::DeserializationError(_)),
+                "{err:?}"
+            );
+            assert!(err.to_string().contains("has 16 bytes"), "{err}");
+
+            // A request reading the row fails instead of panicking
+            let res = client
+                .send(
+                    TestClient::post("http://localhost/api/user/sessions"),
+                    &service,
+                )
+                .await;
+            assert_eq!(
+                res.status_code,
+                Some(StatusCode::INTERNAL_SERVER_ERROR)
+            );
+        })
+        .await;
     }
 }
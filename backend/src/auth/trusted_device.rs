@@ -0,0 +1,216 @@
+//! "Remember this device" for two-factor authentication.
+//!
+//! After a login with a valid MFA code the client can ask to trust its device.
+//! The device then gets a random secret in the trusted-device cookie, and
+//! logins from the same `device_id` with that cookie skip the MFA code for
+//! [`TRUSTED_DEVICE_TTL`]. Only the login does this, reauth and credentialed
+//! operations still ask for a code.
+//!
+//! All trusted devices of a user are forgotten when they disable 2FA or change
+//! their password.
+
+use crate::auth::audit::{self, AuditContext, SecurityEvent};
+use crate::auth::session_token::SessionToken;
+use crate::models::{NewTrustedDevice, TrustedDevice};
+use crate::prelude::*;
+
+use super::util;
+
+pub const TRUSTED_DEVICE_TTL: chrono::TimeDelta = chrono::TimeDelta::days(30);
+
+/// The secret of the trusted-device cookie of a request.
+pub fn cookie_token(req: &Request) -> Option<SessionToken> {
+    req.cookie(super::TRUSTED_DEVICE_COOKIE_NAME)
+        .and_then(|cookie| SessionToken::try_from(cookie.value()).ok())
+}
+
+/// Whether `device` is a trusted device of the user with the secret `token`,
+/// see [cookie_token].
+///
+/// Bumps `last_used_at` of the matching device.
+pub fn is_trusted(
+    conn: &mut DbConn,
+    target_user: i32,
+    device: &str,
+    token: Option<&SessionToken>,
+) -> AppResult<bool> {
+    use crate::schema::trusted_devices::dsl::*;
+
+    let Some(token) = token else {
+        return Ok(false);
+    };
+    let now = chrono::Utc::now().naive_utc();
+
+    let Some(device) = trusted_devices
+        .filter(user_id.eq(target_user))
+        .filter(device_id.eq(device))
+        .first::<TrustedDevice>(conn)
+        .optional()?
+    else {
+        return Ok(false);
+    };
+    if device.token_hash != token.to_hash() || device.expires_at <= now {
+        return Ok(false);
+    }
+
+    diesel::update(trusted_devices.find(device.id))
+        .set(last_used_at.eq(now))
+        .execute(conn)?;
+    Ok(true)
+}
+
+/// Trust `device`, replacing an earlier secret of it.
+///
+/// Returns the trusted-device cookie to set.
+pub fn remember(
+    conn: &mut DbConn,
+    target_user: i32,
+    device: &str,
+) -> AppResult<salvo::http::cookie::Cookie<'static>> {
+    use crate::schema::trusted_devices::dsl::*;
+
+    let token = SessionToken::generate();
+    let now = chrono::Utc::now().naive_utc();
+    conn.transaction::<_, ApiError, _>(|conn| {
+        diesel::delete(
+            trusted_devices
+                .filter(user_id.eq(target_user))
+                .filter(device_id.eq(device).or(expires_at.le(now))),
+        )
+        .execute(conn)?;
+        diesel::insert_into(trusted_devices)
+            .values(&NewTrustedDevice {
+                user_id: target_user,
+                device_id: device.to_owned(),
+                token_hash: token.to_hash(),
+                created_at: now,
+                last_used_at: now,
+                expires_at: now + TRUSTED_DEVICE_TTL,
+            })
+            .execute(conn)?;
+        Ok(())
+    })?;
+
+    Ok(util::trusted_device_cookie(token))
+}
+
+/// Move the trusted device `from` of a user to the new `device_id` `to`,
+/// after the `device_id` cookie was reissued.
+pub fn rekey(
+    conn: &mut DbConn,
+    target_user: i32,
+    from: &str,
+    to: &str,
+) -> AppResult<()> {
+    use crate::schema::trusted_devices::dsl::*;
+
+    diesel::update(
+        trusted_devices
+            .filter(user_id.eq(target_user))
+            .filter(device_id.eq(from)),
+    )
+    .set(device_id.eq(to))
+    .execute(conn)?;
+    Ok(())
+}
+
+/// Forget all trusted devices of a user.
+pub fn forget_all(conn: &mut DbConn, target_user: i32) -> AppResult<usize> {
+    use crate::schema::trusted_devices::dsl::*;
+
+    Ok(
+        diesel::delete(trusted_devices.filter(user_id.eq(target_user)))
+            .execute(conn)?,
+    )
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct TrustedDeviceInfo {
+    pub id: i32,
+    /// Whether this is the device of the current Session
+    pub current: bool,
+    pub created_at: chrono::NaiveDateTime,
+    pub last_used_at: chrono::NaiveDateTime,
+    pub expires_at: chrono::NaiveDateTime,
+}
+
+/// Retrieve the trusted devices of the current User
+///
+/// Logins from these devices don't need a 2FA code.
+#[endpoint]
+pub fn list_trusted_devices(
+    depot: &mut Depot,
+) -> JsonResult<Vec<TrustedDeviceInfo>> {
+    use crate::schema::trusted_devices::dsl::*;
+
+    let conn = &mut db::get()?;
+    let devices: Vec<TrustedDevice> = trusted_devices
+        .filter(user_id.eq(depot.user_id()))
+        .filter(expires_at.gt(chrono::Utc::now().naive_utc()))
+        .order(last_used_at.desc())
+        .load(conn)?;
+
+    json_ok(
+        devices
+            .into_iter()
+            .map(|device| TrustedDeviceInfo {
+                id: device.id,
+                current: device.device_id == depot.device_id(),
+                created_at: device.created_at,
+                last_used_at: device.last_used_at,
+                expires_at: device.expires_at,
+            })
+            .collect(),
+    )
+}
+
+/// Stop trusting a device of the current User
+///
+/// The next login from it needs a 2FA code again.
+#[endpoint]
+pub fn delete_trusted_device(
+    id: PathParam<i32>,
+    req: &mut Request,
+    depot: &mut Depot,
+) -> JsonResult<()> {
+    use crate::schema::trusted_devices::dsl as trusted_dsl;
+
+    let conn = &mut db::get()?;
+    let target = id.into_inner();
+    conn.transaction::<_, ApiError, _>(|conn| {
+        let deleted = diesel::delete(
+            trusted_dsl::trusted_devices
+                .find(target)
+                .filter(trusted_dsl::user_id.eq(depot.user_id())),
+        )
+        .execute(conn)?;
+        if deleted != 1 {
+            return Err(diesel::result::Error::NotFound.into());
+        }
+        audit::record(
+            conn,
+            depot.user_id(),
+            SecurityEvent::TrustedDeviceRemoved {
+                trusted_device_id: target,
+            },
+            &AuditContext::from_request(req),
+        )
+    })?;
+    json_ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wire_compat::{golden, timestamp};
+
+    golden! {
+        trusted_device_info => super::TrustedDeviceInfo {
+            id: 3,
+            current: true,
+            created_at: timestamp(),
+            last_used_at: timestamp(),
+            expires_at: timestamp(),
+        },
+    }
+}
@@ -10,16 +10,21 @@ use thiserror::Error;
 use totp_rs::{Algorithm, Secret, TOTP};
 
 use crate::auth::AuthError;
+use crate::auth::audit::{AuditContext, SecurityEvent};
 use crate::models::{NewTwoFaRecoveryCode, User};
 use crate::prelude::*;
+use crate::stream::{Notification, StreamManager};
+use crate::utils::keys::parse_32_byte_key;
 
-const TOTP_ISSUER: &str = "Transcendence";
 const ENV_TOTP_ENC_KEY: &str = "TOTP_ENC_KEY";
 
 const RECOVERY_CODE_BYTES: usize = 16; // 128-bit
 const DEFAULT_RECOVERY_CODE_COUNT: usize = 10;
+/// Users are warned once no more than this many recovery codes are left.
+const LOW_RECOVERY_CODE_COUNT: i64 = 2;
 
 #[derive(Error, Debug, strum::IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
 pub enum TwoFactorError {
     #[error("Two-factor authentication is not enabled for this user")]
     NotEnabled,
@@ -36,30 +41,6 @@ pub enum TwoFactorError {
     Internal(String),
 }
 
-fn parse_32_byte_key(s: &str) -> Option<[u8; 32]> {
-    let trimmed = s.trim();
-
-    // Hex (64 chars)
-    if trimmed.len() == 64 {
-        if let Ok(bytes) = hex::decode(trimmed) {
-            if bytes.len() == 32 {
-                return Some(bytes.try_into().ok()?);
-            }
-        }
-    }
-
-    // Base64url no pad or standard base64
-    for engine in [base64url, base64std] {
-        if let Ok(bytes) = engine.decode(trimmed.as_bytes()) {
-            if bytes.len() == 32 {
-                return Some(bytes.try_into().ok()?);
-            }
-        }
-    }
-
-    None
-}
-
 static TOTP_ENC_KEY: LazyLock<Option<[u8; 32]>> = LazyLock::new(|| {
     let raw = std::env::var(ENV_TOTP_ENC_KEY).ok()?;
     parse_32_byte_key(&raw)
@@ -122,7 +103,7 @@ pub fn decrypt_totp_secret(
 
     cipher()?
         .decrypt(
-            XNonce::from_slice(nonce),
+            <&XNonce>::from(nonce),
             Payload {
                 msg: ciphertext,
                 aad: &user_id.to_le_bytes(),
@@ -138,7 +119,7 @@ pub fn decrypt_totp_secret(
 
 pub fn totp_for_user(user: &User, secret_raw: Vec<u8>) -> TOTP {
     // Use SHA1 for broad authenticator compatibility.
-    let issuer = Some(TOTP_ISSUER.to_string());
+    let issuer = Some(super::config().totp_issuer.clone());
     let account_name = user.email.clone();
 
     TOTP::new(Algorithm::SHA1, 6, 1, 30, secret_raw, issuer, account_name)
@@ -164,10 +145,12 @@ pub fn require_mfa_if_enabled(
             return Ok(());
         }
         if consume_recovery_code(conn, user.id, code)? {
+            recovery_code_used(conn, user.id)?;
             return Ok(());
         }
     } else {
         if consume_recovery_code(conn, user.id, code)? {
+            recovery_code_used(conn, user.id)?;
             return Ok(());
         }
         if check_totp_code(user, code)? {
@@ -273,3 +256,43 @@ pub fn consume_recovery_code(
 
     Ok(updated == 1)
 }
+
+/// Total and used recovery codes of a user.
+pub fn recovery_code_counts(
+    conn: &mut DbConn,
+    user_id_val: i32,
+) -> AppResult<(i64, i64)> {
+    use crate::schema::two_fa_recovery_codes::dsl::*;
+
+    let total = two_fa_recovery_codes
+        .filter(user_id.eq(user_id_val))
+        .count()
+        .get_result(conn)?;
+    let used = two_fa_recovery_codes
+        .filter(user_id.eq(user_id_val))
+        .filter(used_at.is_not_null())
+        .count()
+        .get_result(conn)?;
+    Ok((total, used))
+}
+
+/// Record the use of a recovery code and notify the user to regenerate them
+/// before they run out.
+///
+/// Only users connected right now get the notification, the count is also
+/// available from the recovery code status endpoint.
+fn recovery_code_used(conn: &mut DbConn, user_id_val: i32) -> AppResult<()> {
+    let (total, used) = recovery_code_counts(conn, user_id_val)?;
+    let remaining = total - used;
+    super::audit::record(
+        conn,
+        user_id_val,
+        SecurityEvent::RecoveryCodeUsed { remaining },
+        &AuditContext::default(),
+    )?;
+    if remaining <= LOW_RECOVERY_CODE_COUNT {
+        StreamManager::global()
+            .notify(user_id_val, Notification::RecoveryCodesLow { remaining });
+    }
+    Ok(())
+}
@@ -1,5 +1,9 @@
 use std::collections::HashSet;
 
+use super::audit::{self, AuditContext, SecurityEvent};
+use super::export;
+use super::notification_counts::{self, NotificationCounts};
+use super::trusted_device;
 use super::two_factor;
 use super::util;
 use crate::auth::TwoFactorError;
@@ -7,6 +11,13 @@ use crate::auth::router::PasswordInput;
 use crate::auth::util::session_requires_reauth_at;
 use crate::models::{Session, User};
 use crate::prelude::*;
+use crate::stream::{CloseCode, ConnectionInfo, StreamManager};
+
+/// How long after authenticating with credentials a Session may do sensitive
+/// actions without entering them again, see
+/// [RouterAuthExt::requires_fresh_auth].
+const FRESH_AUTH_WINDOW: std::time::Duration =
+    std::time::Duration::from_secs(10 * 60);
 
 pub fn router(path: &str) -> Router {
     Router::with_path(path)
@@ -15,28 +26,70 @@ pub fn router(path: &str) -> Router {
         .user_rate_limit(&RateLimit::per_minute(15))
         .append(&mut vec![
             Router::with_path("me").get(get_me),
+            Router::with_path("notification-counts")
+                .get(notification_counts::get_notification_counts),
+            Router::with_path("me")
+                .requires_fresh_auth(FRESH_AUTH_WINDOW)
+                .delete(delete_me),
             Router::with_path("2fa")
                 .push(Router::with_path("start").post(two_fa_start))
                 .push(Router::with_path("confirm").post(two_fa_confirm))
-                .push(Router::with_path("disable").post(two_fa_disable)),
+                .push(
+                    Router::with_path("disable")
+                        .requires_fresh_auth(FRESH_AUTH_WINDOW)
+                        .post(two_fa_disable),
+                )
+                .push(
+                    Router::with_path("recovery-codes")
+                        .push(
+                            Router::with_path("status")
+                                .get(recovery_codes_status),
+                        )
+                        .push(
+                            Router::with_path("regenerate")
+                                .post(regenerate_recovery_codes),
+                        ),
+                ),
             Router::with_path("change-password")
                 .user_rate_limit(&RateLimit::per_15_minutes(10))
                 .post(change_pw),
+            Router::with_path("export")
+                .user_rate_limit(&RateLimit::per_hour(1))
+                .with_timeout(std::time::Duration::from_secs(5 * 60))
+                .post(export::export_data),
             Router::with_path("logout").post(logout),
             Router::with_path("logout-sessions").post(logout_sessions),
             Router::with_path("logout-other-sessions")
                 .post(logout_other_sessions),
             Router::with_path("session").get(current_session),
+            Router::with_path("realtime/disconnect").post(realtime_disconnect),
             Router::with_path("sessions")
+                .requires_fresh_auth(FRESH_AUTH_WINDOW)
                 .post(all_sessions)
                 .delete(delete_sessions),
+            // Deleting the current Session needs no fresh authentication
+            Router::with_path("sessions/{id}")
+                .delete(delete_session)
+                .push(Router::with_path("name").patch(rename_session)),
+            Router::with_path("security-events")
+                .get(audit::list_security_events),
+            Router::with_path("trusted-devices")
+                .get(trusted_device::list_trusted_devices)
+                .push(
+                    Router::with_path("{id}")
+                        .delete(trusted_device::delete_trusted_device),
+                ),
         ])
 }
 
 #[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
 pub struct UserSessionInfo {
     pub user: User,
     pub session: SessionInfo,
+    /// Badge counts, only included by `GET /api/user/me`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub notification_counts: Option<NotificationCounts>,
 }
 
 impl UserSessionInfo {
@@ -44,6 +97,7 @@ impl UserSessionInfo {
         Self {
             user,
             session: SessionInfo::from(session),
+            notification_counts: None,
         }
     }
 
@@ -57,6 +111,7 @@ impl UserSessionInfo {
         Ok(Self {
             user,
             session: SessionInfo::from(session),
+            notification_counts: None,
         })
     }
 }
@@ -67,15 +122,149 @@ fn get_me(depot: &mut Depot) -> JsonResult<UserSessionInfo> {
     let conn = &mut db::get()?;
     let session = depot.session();
 
-    json_ok(UserSessionInfo::from_session(conn, session.to_owned())?)
+    let mut info = UserSessionInfo::from_session(conn, session.to_owned())?;
+    info.notification_counts =
+        Some(notification_counts::get(conn, info.user.id)?);
+    json_ok(info)
+}
+
+/// Body of the endpoints behind [RouterAuthExt::requires_fresh_auth].
+///
+/// Send `{}` after authenticating within the last 10 minutes.
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct FreshAuthInput {
+    /// Deprecated, reauthenticate instead. Only checked if the Session has
+    /// not authenticated recently.
+    #[serde(default)]
+    password: Option<String>,
+    #[serde(default)]
+    mfa_code: Option<String>,
+}
+
+/// Delete the current User and all data belonging to them
+///
+/// Requires a recent authentication, or (deprecated) the current password.
+/// Closes the realtime connection and removes the auth cookies.
+#[endpoint]
+fn delete_me(
+    json: JsonBody<FreshAuthInput>,
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> JsonResult<()> {
+    let conn = &mut db::get()?;
+    let session = depot.session();
+    let FreshAuthInput { password, mfa_code } = json.into_inner();
+    util::check_fresh_auth_or_password(
+        depot.has_fresh_auth(),
+        session.user_id,
+        password.as_deref(),
+        mfa_code.as_deref(),
+        conn,
+    )?;
+
+    conn.transaction::<_, ApiError, _>(|conn| {
+        delete_user_data(conn, session.user_id)
+    })?;
+    // The security events are deleted with the account
+    audit::log_account_deleted(
+        session.user_id,
+        &AuditContext::from_request(req),
+    );
+
+    StreamManager::global().close_stream(session.user_id);
+    util::delete_auth_cookies(res);
+    json_ok(())
+}
+
+/// Delete every row belonging to a user, ending with the user row itself.
+///
+/// Call this inside a transaction. Tables referencing `users` must be cleaned
+/// up here (children first) instead of relying on `ON DELETE CASCADE`, so the
+/// deletion order stays explicit when new tables are added.
+///
+/// Rows shared with other users are not deleted but anonymized by setting the
+/// user's side to NULL, so the history of the other participants stays
+/// intact: the players of game sessions, and chat messages once that table
+/// exists.
+pub(crate) fn delete_user_data(
+    conn: &mut db::DbConn,
+    target_user: i32,
+) -> AppResult<()> {
+    use crate::schema::{
+        game_history, game_invites, game_sessions, idempotency_keys,
+        security_events, sessions, trusted_devices, two_fa_recovery_codes,
+        user_bans, user_stats, users,
+    };
+
+    diesel::delete(sessions::table.filter(sessions::user_id.eq(target_user)))
+        .execute(conn)?;
+    super::session_cache::invalidate_user(target_user);
+    diesel::delete(
+        game_history::table.filter(game_history::user_id.eq(target_user)),
+    )
+    .execute(conn)?;
+    diesel::delete(user_stats::table.find(target_user)).execute(conn)?;
+    diesel::delete(
+        game_invites::table.filter(
+            game_invites::from_user_id
+                .eq(target_user)
+                .or(game_invites::to_user_id.eq(target_user)),
+        ),
+    )
+    .execute(conn)?;
+    diesel::update(
+        game_sessions::table.filter(game_sessions::player_a.eq(target_user)),
+    )
+    .set(game_sessions::player_a.eq(None::<i32>))
+    .execute(conn)?;
+    diesel::update(
+        game_sessions::table.filter(game_sessions::player_b.eq(target_user)),
+    )
+    .set(game_sessions::player_b.eq(None::<i32>))
+    .execute(conn)?;
+    diesel::delete(
+        idempotency_keys::table
+            .filter(idempotency_keys::user_id.eq(target_user)),
+    )
+    .execute(conn)?;
+    diesel::delete(user_bans::table.filter(user_bans::user_id.eq(target_user)))
+        .execute(conn)?;
+    diesel::update(
+        user_bans::table.filter(user_bans::banned_by.eq(target_user)),
+    )
+    .set(user_bans::banned_by.eq(None::<i32>))
+    .execute(conn)?;
+    diesel::delete(
+        two_fa_recovery_codes::table
+            .filter(two_fa_recovery_codes::user_id.eq(target_user)),
+    )
+    .execute(conn)?;
+    diesel::delete(
+        trusted_devices::table.filter(trusted_devices::user_id.eq(target_user)),
+    )
+    .execute(conn)?;
+    diesel::delete(
+        security_events::table.filter(security_events::user_id.eq(target_user)),
+    )
+    .execute(conn)?;
+
+    let deleted =
+        diesel::delete(users::table.find(target_user)).execute(conn)?;
+    if deleted != 1 {
+        return Err(diesel::result::Error::NotFound.into());
+    }
+    Ok(())
 }
 
 #[derive(Debug, Deserialize, Validate, ToSchema)]
+#[serde(rename_all = "camelCase")]
 struct ChangePasswordInput {
     password: String,
     #[serde(default)]
     mfa_code: Option<String>,
-    #[validate(custom(function = "crate::validate::password"))]
+    // Checked in change_pw(), it must not match the nickname or email
     new_password: String,
     #[serde(default)]
     keep_other_sessions_logged_in: bool,
@@ -88,6 +277,7 @@ struct ChangePasswordInput {
 #[endpoint]
 fn change_pw(
     json: JsonBody<ChangePasswordInput>,
+    req: &mut Request,
     depot: &mut Depot,
 ) -> JsonResult<()> {
     let conn = &mut db::get()?;
@@ -98,8 +288,24 @@ fn change_pw(
         new_password,
         keep_other_sessions_logged_in,
     } = {
+        use crate::schema::users::dsl::*;
+
         let input = json.into_inner();
-        input.validate()?;
+        let (user_email, user_nickname): (String, String) = users
+            .find(session.user_id)
+            .select((email, nickname))
+            .first(conn)?;
+        crate::validate::with_field_check(
+            input.validate(),
+            "new_password",
+            crate::validate::password_with_context(
+                &input.new_password,
+                &[
+                    crate::validate::email_local_part(&user_email),
+                    &user_nickname,
+                ],
+            ),
+        )?;
         input
     };
     util::check_password_and_mfa_if_enabled(
@@ -116,11 +322,19 @@ fn change_pw(
         diesel::update(users.find(session.user_id))
             .set(password_hash.eq(&new_hash))
             .execute(conn)?;
+        trusted_device::forget_all(conn, session.user_id)?;
 
         if !keep_other_sessions_logged_in {
             deauth_other_sessions(conn, session.user_id, session.id)?;
         }
-        Ok(())
+        audit::record(
+            conn,
+            session.user_id,
+            SecurityEvent::PasswordChanged {
+                other_sessions_logged_out: !keep_other_sessions_logged_in,
+            },
+            &AuditContext::from_request(req),
+        )
     })?;
 
     json_ok(())
@@ -132,11 +346,12 @@ fn logout(depot: &mut Depot, res: &mut Response) -> JsonResult<()> {
     let conn = &mut db::get()?;
     let session = depot.session();
     deauth_sessions(conn, session.user_id, [session.id].into_iter())?;
-    delete_auth_cookies(res);
+    util::delete_auth_cookies(res);
     json_ok(())
 }
 
 #[derive(Debug, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
 struct SessionsInput {
     password: String,
     #[serde(default)]
@@ -150,6 +365,7 @@ struct SessionsInput {
 #[endpoint]
 fn logout_sessions(
     json: JsonBody<SessionsInput>,
+    req: &mut Request,
     depot: &mut Depot,
     res: &mut Response,
 ) -> JsonResult<()> {
@@ -167,10 +383,20 @@ fn logout_sessions(
         conn,
     )?;
 
-    deauth_sessions(conn, session.user_id, session_ids.iter().copied())?;
+    conn.transaction::<_, ApiError, _>(|conn| {
+        deauth_sessions(conn, session.user_id, session_ids.iter().copied())?;
+        audit::record(
+            conn,
+            session.user_id,
+            SecurityEvent::SessionsLoggedOut {
+                session_ids: sorted_ids(&session_ids),
+            },
+            &AuditContext::from_request(req),
+        )
+    })?;
 
     if session_ids.contains(&session.id) {
-        delete_auth_cookies(res);
+        util::delete_auth_cookies(res);
         Err(super::AuthError::DidLogout.into())
     } else {
         json_ok(())
@@ -183,6 +409,7 @@ fn logout_sessions(
 #[endpoint]
 fn logout_other_sessions(
     json: JsonBody<PasswordInput>,
+    req: &mut Request,
     depot: &mut Depot,
 ) -> JsonResult<()> {
     let conn = &mut db::get()?;
@@ -195,20 +422,69 @@ fn logout_other_sessions(
         conn,
     )?;
 
-    deauth_other_sessions(conn, session.user_id, session.id)?;
+    conn.transaction::<_, ApiError, _>(|conn| {
+        let count = deauth_other_sessions(conn, session.user_id, session.id)?;
+        audit::record(
+            conn,
+            session.user_id,
+            SecurityEvent::OtherSessionsLoggedOut { count },
+            &AuditContext::from_request(req),
+        )
+    })?;
     json_ok(())
 }
 
 #[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
 pub struct SessionInfo {
     pub session_id: i32,
     pub user_id: i32,
     pub device_name: Option<String>,
+    /// Whether `deviceName` was picked by the user instead of derived from
+    /// the User-Agent
+    pub is_custom_name: bool,
     pub ip_address: Option<String>,
     pub created_at: chrono::NaiveDateTime,
     pub last_used_at: chrono::NaiveDateTime,
     pub jwt_valid_until: chrono::NaiveDateTime,
     pub logged_in_until: chrono::NaiveDateTime,
+    /// Whether this is the Session making the request, `None` where that is
+    /// not known
+    pub is_current: Option<bool>,
+    /// The realtime connections of this Session, `None` if there are none or
+    /// where that is not known
+    pub realtime_connection: Option<RealtimeConnection>,
+}
+
+/// The WebTransport or WebSocket connections of a Session.
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RealtimeConnection {
+    /// When the oldest one was established
+    pub connected_at: chrono::NaiveDateTime,
+    /// E.g. one per browser tab
+    pub connections: usize,
+}
+
+impl SessionInfo {
+    pub fn with_current(mut self, current_session_id: i32) -> Self {
+        self.is_current = Some(self.session_id == current_session_id);
+        self
+    }
+
+    /// Add the connections of this Session among the `connections` of the
+    /// user.
+    fn with_realtime(mut self, connections: &[ConnectionInfo]) -> Self {
+        let mut own = connections
+            .iter()
+            .filter(|connection| connection.session_id == self.session_id);
+        self.realtime_connection =
+            own.next().map(|oldest| RealtimeConnection {
+                connected_at: oldest.connected_at,
+                connections: 1 + own.count(),
+            });
+        self
+    }
 }
 
 impl From<&Session> for SessionInfo {
@@ -218,11 +494,14 @@ impl From<&Session> for SessionInfo {
             session_id: session.id,
             user_id: session.user_id,
             device_name: session.device_name.clone(),
+            is_custom_name: session.has_custom_name(),
             ip_address: session.ip_address.clone(),
             created_at: session.created_at,
             last_used_at: session.last_used_at,
-            jwt_valid_until: session.refreshed_at + super::ACCESS_EXPIRY,
+            jwt_valid_until: session.access_valid_until(),
             logged_in_until: logged_in.1,
+            is_current: None,
+            realtime_connection: None,
         }
     }
 }
@@ -237,41 +516,212 @@ impl From<Session> for SessionInfo {
 #[endpoint]
 pub fn current_session(depot: &mut Depot) -> JsonResult<SessionInfo> {
     let session = depot.session();
-    json_ok(SessionInfo::from(session))
+    let connections = StreamManager::global().connection_info(session.user_id);
+    json_ok(
+        SessionInfo::from(session)
+            .with_current(session.id)
+            .with_realtime(&connections),
+    )
 }
 
 /// Retrieve all Sessions for the current User
 ///
-/// Requires current password for verification.
+/// Requires a recent authentication, or (deprecated) the current password.
 #[endpoint]
 pub fn all_sessions(
-    json: JsonBody<PasswordInput>,
+    json: JsonBody<FreshAuthInput>,
     depot: &mut Depot,
 ) -> JsonResult<Vec<SessionInfo>> {
     use crate::schema::sessions::dsl::*;
 
     let conn = &mut db::get()?;
     let session = depot.session();
-    let PasswordInput { password, mfa_code } = json.into_inner();
-    util::check_password_and_mfa_if_enabled(
+    let FreshAuthInput { password, mfa_code } = json.into_inner();
+    util::check_fresh_auth_or_password(
+        depot.has_fresh_auth(),
         session.user_id,
-        &password,
+        password.as_deref(),
         mfa_code.as_deref(),
         conn,
     )?;
 
     let user_sessions: Vec<Session> =
         sessions.filter(user_id.eq(session.user_id)).load(conn)?;
+    let connections = StreamManager::global().connection_info(session.user_id);
+
+    json_ok(
+        user_sessions
+            .into_iter()
+            .map(|s| {
+                SessionInfo::from(s)
+                    .with_current(session.id)
+                    .with_realtime(&connections)
+            })
+            .collect(),
+    )
+}
 
-    json_ok(user_sessions.into_iter().map(Into::into).collect())
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct RealtimeDisconnectInput {
+    /// Only close the connections of this Session, all if `null`
+    #[serde(default)]
+    session_id: Option<i32>,
+}
+
+/// Close the realtime connections of the current User
+///
+/// The clients are told to not reconnect until the user acts, e.g. when
+/// another tab took over the connection. Without `sessionId` this includes
+/// the connections of the current Session.
+#[endpoint]
+fn realtime_disconnect(
+    json: JsonBody<RealtimeDisconnectInput>,
+    depot: &mut Depot,
+) -> JsonResult<()> {
+    const REASON: &str = "Disconnected by the user";
+    let streams = StreamManager::global();
+    let user = depot.user_id();
+    match json.into_inner().session_id {
+        Some(session_id) => streams.close_session_stream_with(
+            user,
+            session_id,
+            CloseCode::UserRequested,
+            REASON,
+        ),
+        None => {
+            streams.close_stream_with(user, CloseCode::UserRequested, REASON)
+        }
+    }
+    json_ok(())
+}
+
+#[derive(Debug, Deserialize, Validate, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct RenameSessionInput {
+    /// 1 to 48 characters, `null` to use the name derived from the
+    /// User-Agent again
+    #[validate(custom(function = "crate::validate::device_name"))]
+    name: Option<String>,
+}
+
+/// Rename a Session of the current User
+///
+/// The name is kept when the Session is refreshed.
+#[endpoint]
+fn rename_session(
+    id: PathParam<i32>,
+    json: JsonBody<RenameSessionInput>,
+    depot: &mut Depot,
+) -> JsonResult<SessionInfo> {
+    use crate::schema::sessions::dsl as sessions_dsl;
+
+    let conn = &mut db::get()?;
+    let session = depot.session();
+    let target = id.into_inner();
+    let mut input = json.into_inner();
+    input.name = input.name.map(|name| name.trim().to_owned());
+    input.validate()?;
+
+    let own_session = sessions_dsl::sessions
+        .find(target)
+        .filter(sessions_dsl::user_id.eq(session.user_id));
+    let renamed: Session = match input.name {
+        Some(name) => diesel::update(own_session)
+            .set(sessions_dsl::device_name.eq(name))
+            .get_result(conn),
+        None => diesel::update(own_session)
+            .set(sessions_dsl::device_name.eq(sessions_dsl::device_name_auto))
+            .get_result(conn),
+    }?;
+    super::session_cache::invalidate(target);
+    json_ok(SessionInfo::from(renamed).with_current(session.id))
+}
+
+/// Delete one Session of the current User
+///
+/// Requires a recent authentication, or (deprecated) the current password,
+/// unless it is the current Session, which is just logged out.
+#[endpoint]
+fn delete_session(
+    id: PathParam<i32>,
+    json: JsonBody<FreshAuthInput>,
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> JsonResult<()> {
+    use crate::schema::sessions::dsl as sessions_dsl;
+
+    let conn = &mut db::get()?;
+    let session = depot.session();
+    let target = id.into_inner();
+    if target == session.id {
+        conn.transaction::<_, ApiError, _>(|conn| {
+            deauth_sessions(conn, session.user_id, [session.id].into_iter())?;
+            audit::record(
+                conn,
+                session.user_id,
+                SecurityEvent::SessionsLoggedOut {
+                    session_ids: vec![session.id],
+                },
+                &AuditContext::from_request(req),
+            )
+        })?;
+        util::delete_auth_cookies(res);
+        return json_ok(());
+    }
+
+    let FreshAuthInput { password, mfa_code } = json.into_inner();
+    util::check_fresh_auth_or_password(
+        util::is_freshly_authenticated(session, FRESH_AUTH_WINDOW),
+        session.user_id,
+        password.as_deref(),
+        mfa_code.as_deref(),
+        conn,
+    )?;
+
+    conn.transaction::<_, ApiError, _>(|conn| {
+        let deleted = diesel::delete(
+            sessions_dsl::sessions
+                .find(target)
+                .filter(sessions_dsl::user_id.eq(session.user_id)),
+        )
+        .execute(conn)?;
+        if deleted != 1 {
+            return Err(diesel::result::Error::NotFound.into());
+        }
+        super::session_cache::invalidate(target);
+        audit::record(
+            conn,
+            session.user_id,
+            SecurityEvent::SessionsDeleted {
+                session_ids: vec![target],
+            },
+            &AuditContext::from_request(req),
+        )
+    })?;
+    StreamManager::global().close_session_stream(session.user_id, target);
+    json_ok(())
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct DeleteSessionsInput {
+    /// Deprecated, see [FreshAuthInput]
+    #[serde(default)]
+    password: Option<String>,
+    #[serde(default)]
+    mfa_code: Option<String>,
+    session_ids: HashSet<i32>,
 }
 
 /// Delete specific Sessions for the current User
 ///
-/// Requires current password for verification.
+/// Requires a recent authentication, or (deprecated) the current password.
 #[endpoint]
 fn delete_sessions(
-    json: JsonBody<SessionsInput>,
+    json: JsonBody<DeleteSessionsInput>,
+    req: &mut Request,
     depot: &mut Depot,
     res: &mut Response,
 ) -> JsonResult<()> {
@@ -279,36 +729,56 @@ fn delete_sessions(
 
     let conn = &mut db::get()?;
     let session = depot.session();
-    let SessionsInput {
+    let DeleteSessionsInput {
         password,
         mfa_code,
         session_ids,
     } = json.into_inner();
-    util::check_password_and_mfa_if_enabled(
+    util::check_fresh_auth_or_password(
+        depot.has_fresh_auth(),
         session.user_id,
-        &password,
+        password.as_deref(),
         mfa_code.as_deref(),
         conn,
     )?;
 
-    diesel::delete(
-        sessions
-            .filter(user_id.eq(session.user_id))
-            .filter(id.eq_any(&session_ids)),
-    )
-    .execute(conn)?;
+    conn.transaction::<_, ApiError, _>(|conn| {
+        diesel::delete(
+            sessions
+                .filter(user_id.eq(session.user_id))
+                .filter(id.eq_any(&session_ids)),
+        )
+        .execute(conn)?;
+        for session_id in &session_ids {
+            super::session_cache::invalidate(*session_id);
+        }
+        audit::record(
+            conn,
+            session.user_id,
+            SecurityEvent::SessionsDeleted {
+                session_ids: sorted_ids(&session_ids),
+            },
+            &AuditContext::from_request(req),
+        )
+    })?;
+    for session_id in &session_ids {
+        StreamManager::global()
+            .close_session_stream(session.user_id, *session_id);
+    }
 
     if session_ids.contains(&session.id) {
-        delete_auth_cookies(res);
+        util::delete_auth_cookies(res);
         Err(super::AuthError::DidLogout.into())
     } else {
         json_ok(())
     }
 }
 
-fn delete_auth_cookies(res: &mut Response) {
-    res.remove_cookie(super::SESSION_COOKIE_NAME);
-    res.remove_cookie(super::JWT_COOKIE_NAME);
+/// Session ids in a stable order for the audit log.
+fn sorted_ids(session_ids: &HashSet<i32>) -> Vec<i32> {
+    let mut ids: Vec<i32> = session_ids.iter().copied().collect();
+    ids.sort_unstable();
+    ids
 }
 
 fn deauth_other_sessions(
@@ -327,10 +797,25 @@ fn deauth_other_sessions(
     deauth_sessions(conn, target_user, other_sessions.into_iter())
 }
 
-fn deauth_sessions(
+pub(crate) fn deauth_sessions(
     conn: &mut db::DbConn,
     target_user: i32,
     session_ids: impl Iterator<Item = i32>,
+) -> AppResult<usize> {
+    let session_ids: Vec<i32> = session_ids.collect();
+    let result = expire_sessions(conn, target_user, &session_ids)?;
+    for session_id in session_ids {
+        StreamManager::global().close_session_stream(target_user, session_id);
+    }
+    Ok(result)
+}
+
+/// Like [deauth_sessions], but leaves closing the connections of the
+/// Sessions to the caller, to tell the client why.
+pub(crate) fn expire_sessions(
+    conn: &mut db::DbConn,
+    target_user: i32,
+    session_ids: &[i32],
 ) -> AppResult<usize> {
     use crate::schema::sessions::dsl::*;
     let epoch = chrono::DateTime::UNIX_EPOCH.naive_utc();
@@ -342,15 +827,20 @@ fn deauth_sessions(
     .set(last_authenticated_at.eq(epoch))
     .execute(conn)?;
 
+    for &session_id in session_ids {
+        super::session_cache::invalidate(session_id);
+    }
     Ok(result)
 }
 
 #[derive(Debug, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
 struct TwoFaStartInput {
     password: String,
 }
 
 #[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
 struct TwoFaStartOutput {
     /// The raw base32-encoded TOTP secret for users to be manually added to authenticator apps
     base32_secret: String,
@@ -364,6 +854,7 @@ struct TwoFaStartOutput {
 #[endpoint]
 fn two_fa_start(
     json: JsonBody<TwoFaStartInput>,
+    req: &mut Request,
     depot: &mut Depot,
 ) -> JsonResult<TwoFaStartOutput> {
     use crate::schema::users::dsl::*;
@@ -394,18 +885,26 @@ fn two_fa_start(
     let secret_enc = two_factor::encrypt_totp_secret(user.id, &secret_raw)?;
     // we dont filter for totp_secret_enc.eq(None) here to allow users to restart the process even when
     // they already started the process once before, but didnt complete it
-    let updated = diesel::update(
-        users.filter(id.eq(user.id)).filter(totp_enabled.eq(false)),
-    )
-    .set((
-        totp_secret_enc.eq(Some(secret_enc)),
-        totp_confirmed_at.eq::<Option<chrono::NaiveDateTime>>(None),
-    ))
-    .execute(conn)?;
+    conn.transaction::<_, ApiError, _>(|conn| {
+        let updated = diesel::update(
+            users.filter(id.eq(user.id)).filter(totp_enabled.eq(false)),
+        )
+        .set((
+            totp_secret_enc.eq(Some(secret_enc)),
+            totp_confirmed_at.eq::<Option<chrono::NaiveDateTime>>(None),
+        ))
+        .execute(conn)?;
 
-    if updated == 0 {
-        return Err(ApiError::TwoFa(TwoFactorError::AlreadyEnabled));
-    }
+        if updated == 0 {
+            return Err(ApiError::TwoFa(TwoFactorError::AlreadyEnabled));
+        }
+        audit::record(
+            conn,
+            user.id,
+            SecurityEvent::TwoFaStarted,
+            &AuditContext::from_request(req),
+        )
+    })?;
 
     json_ok(TwoFaStartOutput {
         base32_secret,
@@ -415,12 +914,14 @@ fn two_fa_start(
 }
 
 #[derive(Debug, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
 struct TwoFaConfirmInput {
     password: String,
     code: String,
 }
 
 #[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
 struct TwoFaConfirmOutput {
     recovery_codes: Vec<String>,
 }
@@ -431,6 +932,7 @@ struct TwoFaConfirmOutput {
 #[endpoint]
 fn two_fa_confirm(
     json: JsonBody<TwoFaConfirmInput>,
+    req: &mut Request,
     depot: &mut Depot,
 ) -> JsonResult<TwoFaConfirmOutput> {
     use crate::schema::users::dsl::*;
@@ -481,6 +983,12 @@ fn two_fa_confirm(
 
         let recovery_codes = two_factor::generate_recovery_codes();
         two_factor::replace_recovery_codes(conn, user.id, &recovery_codes)?;
+        audit::record(
+            conn,
+            user.id,
+            SecurityEvent::TwoFaConfirmed,
+            &AuditContext::from_request(req),
+        )?;
 
         Ok(recovery_codes)
     })?;
@@ -489,17 +997,85 @@ fn two_fa_confirm(
 }
 
 #[derive(Debug, Deserialize, ToSchema)]
-struct TwoFaDisableInput {
+#[serde(rename_all = "camelCase")]
+struct PasswordAndMfaInput {
     password: String,
     mfa_code: String,
 }
 
-/// Disable 2FA for the current user.
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct RecoveryCodesStatus {
+    total: i64,
+    used: i64,
+    remaining: i64,
+}
+
+/// Count the unused recovery codes of the current User
+#[endpoint]
+fn recovery_codes_status(depot: &mut Depot) -> JsonResult<RecoveryCodesStatus> {
+    use crate::schema::users::dsl::*;
+
+    let conn = &mut db::get()?;
+    let user_id = depot.user_id();
+    let enabled: bool = users.find(user_id).select(totp_enabled).first(conn)?;
+    if !enabled {
+        return Err(ApiError::TwoFa(TwoFactorError::NotEnabled));
+    }
+
+    let (total, used) = two_factor::recovery_code_counts(conn, user_id)?;
+    json_ok(RecoveryCodesStatus {
+        total,
+        used,
+        remaining: total - used,
+    })
+}
+
+/// Replace all recovery codes of the current User with new ones
 ///
 /// Requires password + either a TOTP code or a recovery code.
+/// The new codes are returned once, the old ones stop working.
+#[endpoint]
+fn regenerate_recovery_codes(
+    json: JsonBody<PasswordAndMfaInput>,
+    req: &mut Request,
+    depot: &mut Depot,
+) -> JsonResult<TwoFaConfirmOutput> {
+    let conn = &mut db::get()?;
+    let session = depot.session();
+    let PasswordAndMfaInput { password, mfa_code } = json.into_inner();
+
+    let user = util::check_password_and_mfa_if_enabled(
+        session.user_id,
+        &password,
+        Some(mfa_code.as_str()),
+        conn,
+    )?;
+    if !user.totp_enabled {
+        return Err(ApiError::TwoFa(TwoFactorError::NotEnabled));
+    }
+
+    let recovery_codes = two_factor::generate_recovery_codes();
+    conn.transaction::<_, ApiError, _>(|conn| {
+        two_factor::replace_recovery_codes(conn, user.id, &recovery_codes)?;
+        audit::record(
+            conn,
+            user.id,
+            SecurityEvent::RecoveryCodesRegenerated,
+            &AuditContext::from_request(req),
+        )
+    })?;
+    json_ok(TwoFaConfirmOutput { recovery_codes })
+}
+
+/// Disable 2FA for the current user.
+///
+/// Requires a recent authentication, which included the second factor, or
+/// (deprecated) password + either a TOTP code or a recovery code.
 #[endpoint]
 fn two_fa_disable(
-    json: JsonBody<TwoFaDisableInput>,
+    json: JsonBody<FreshAuthInput>,
+    req: &mut Request,
     depot: &mut Depot,
 ) -> JsonResult<()> {
     use crate::schema::two_fa_recovery_codes::dsl as recovery_dsl;
@@ -507,12 +1083,13 @@ fn two_fa_disable(
 
     let conn = &mut db::get()?;
     let session = depot.session();
-    let TwoFaDisableInput { password, mfa_code } = json.into_inner();
+    let FreshAuthInput { password, mfa_code } = json.into_inner();
 
-    let user = util::check_password_and_mfa_if_enabled(
+    let user = util::check_fresh_auth_or_password(
+        depot.has_fresh_auth(),
         session.user_id,
-        &password,
-        Some(mfa_code.as_str()),
+        password.as_deref(),
+        mfa_code.as_deref(),
         conn,
     )?;
 
@@ -544,9 +1121,595 @@ fn two_fa_disable(
                 .filter(recovery_dsl::user_id.eq(user.id)),
         )
         .execute(conn)?;
+        trusted_device::forget_all(conn, user.id)?;
 
-        Ok(())
+        audit::record(
+            conn,
+            user.id,
+            SecurityEvent::TwoFaDisabled,
+            &AuditContext::from_request(req),
+        )
     })?;
 
     json_ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use salvo::http::header::USER_AGENT;
+    use salvo::test::{ResponseExt, TestClient};
+    use serde_json::{Value, json};
+
+    use crate::auth::notification_counts::NotificationCounts;
+    use crate::models::Session;
+    use crate::prelude::*;
+    use crate::stream::testing::{ConnectionSession, FakeClient, wait_until};
+    use crate::stream::{CloseCode, StreamManager};
+    use crate::test_db::{self, Client, PASSWORD};
+    use crate::wire_compat::{golden, sample_user, timestamp};
+
+    const BASE: &str = "http://localhost/api";
+
+    #[tokio::test]
+    async fn change_password_errors_use_wire_field_names() {
+        test_db::with_service(|service| async move {
+            let (mut client, _) =
+                Client::register(&service, "erin@example.com", "erin").await;
+            let mut res = client
+                .send(
+                    TestClient::post(format!("{BASE}/user/change-password"))
+                        .json(&json!({
+                            "password": PASSWORD,
+                            "newPassword": "erin",
+                        })),
+                    &service,
+                )
+                .await;
+            assert_eq!(res.status_code, Some(StatusCode::BAD_REQUEST));
+            let body: Value = res.take_json().await.unwrap();
+            assert_eq!(body["code"], "validation");
+            let fields = body["fields"].as_object().unwrap();
+            assert_eq!(
+                fields.keys().collect::<Vec<_>>(),
+                ["newPassword"],
+                "{body}"
+            );
+        })
+        .await;
+    }
+
+    /// Log in to the account of [Client::register] again, returns the new
+    /// Session's client and id.
+    async fn login(service: &Service, email: &str) -> (Client, i64) {
+        let mut client = Client::default();
+        let mut res = client
+            .send(
+                TestClient::post(format!("{BASE}/auth/login"))
+                    .json(&json!({ "email": email, "password": PASSWORD })),
+                service,
+            )
+            .await;
+        assert_eq!(res.status_code, Some(StatusCode::OK));
+        let body: Value = res.take_json().await.unwrap();
+        (client, body["session"]["sessionId"].as_i64().unwrap())
+    }
+
+    async fn me_status(client: &mut Client, service: &Service) -> StatusCode {
+        let res = client
+            .send(TestClient::get(format!("{BASE}/user/me")), service)
+            .await;
+        res.status_code.unwrap()
+    }
+
+    #[tokio::test]
+    async fn only_the_callers_session_is_current() {
+        test_db::with_service(|service| async move {
+            let (mut client, body) =
+                Client::register(&service, "cora@example.com", "cora").await;
+            let mine = body["session"]["sessionId"].as_i64().unwrap();
+            let (_, other) = login(&service, "cora@example.com").await;
+
+            let mut res = client
+                .send(
+                    TestClient::post(format!("{BASE}/user/sessions"))
+                        .json(&json!({})),
+                    &service,
+                )
+                .await;
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+            let sessions: Vec<Value> = res.take_json().await.unwrap();
+            let mut current: Vec<_> = sessions
+                .iter()
+                .map(|s| {
+                    (s["sessionId"].as_i64().unwrap(), s["isCurrent"].clone())
+                })
+                .collect();
+            current.sort_by_key(|(id, _)| *id);
+            assert_eq!(current, [(mine, json!(true)), (other, json!(false))]);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn deleting_another_session_requires_the_password() {
+        test_db::with_service(|service| async move {
+            let (mut client, body) =
+                Client::register(&service, "dave@example.com", "dave").await;
+            let mine = body["session"]["sessionId"].as_i64().unwrap() as i32;
+            let (mut other, other_id) =
+                login(&service, "dave@example.com").await;
+            // Long after the last authentication
+            {
+                use crate::schema::sessions;
+                diesel::update(sessions::table.find(mine))
+                    .set(
+                        sessions::last_authenticated_at
+                            .eq(chrono::Utc::now().naive_utc()
+                                - chrono::TimeDelta::days(1)),
+                    )
+                    .execute(&mut db::get().unwrap())
+                    .unwrap();
+                crate::auth::session_cache::invalidate(mine);
+            }
+            let delete = |body: Value| {
+                TestClient::delete(format!("{BASE}/user/sessions/{other_id}"))
+                    .json(&body)
+            };
+
+            let mut res = client.send(delete(json!({})), &service).await;
+            assert_eq!(res.status_code, Some(StatusCode::UNAUTHORIZED));
+            let body: Value = res.take_json().await.unwrap();
+            assert_eq!(body["code"], "fresh_auth_required", "{body}");
+            let res = client
+                .send(
+                    delete(json!({ "password": "wrong password 123" })),
+                    &service,
+                )
+                .await;
+            assert_eq!(res.status_code, Some(StatusCode::UNAUTHORIZED));
+            assert_eq!(me_status(&mut other, &service).await, StatusCode::OK);
+
+            let res = client
+                .send(delete(json!({ "password": PASSWORD })), &service)
+                .await;
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+            assert_eq!(
+                me_status(&mut other, &service).await,
+                StatusCode::UNAUTHORIZED
+            );
+            assert_eq!(me_status(&mut client, &service).await, StatusCode::OK);
+
+            // Sessions of other users are not found
+            let (_, stranger) =
+                Client::register(&service, "erna@example.com", "erna").await;
+            let stranger = stranger["session"]["sessionId"].as_i64().unwrap();
+            let res = client
+                .send(
+                    TestClient::delete(format!(
+                        "{BASE}/user/sessions/{stranger}"
+                    ))
+                    .json(&json!({ "password": PASSWORD })),
+                    &service,
+                )
+                .await;
+            assert_eq!(res.status_code, Some(StatusCode::NOT_FOUND));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn deleting_the_current_session_logs_out() {
+        test_db::with_service(|service| async move {
+            let (mut client, body) =
+                Client::register(&service, "gina@example.com", "gina").await;
+            let mine = body["session"]["sessionId"].as_i64().unwrap();
+
+            let res = client
+                .send(
+                    TestClient::delete(format!("{BASE}/user/sessions/{mine}"))
+                        .json(&json!({})),
+                    &service,
+                )
+                .await;
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+            assert_eq!(
+                client.cookie(crate::auth::SESSION_COOKIE_NAME),
+                Some("")
+            );
+            assert_eq!(client.cookie(crate::auth::JWT_COOKIE_NAME), Some(""));
+            assert_eq!(
+                me_status(&mut client, &service).await,
+                StatusCode::UNAUTHORIZED
+            );
+        })
+        .await;
+    }
+
+    async fn rename(
+        client: &mut Client,
+        service: &Service,
+        id: i64,
+        name: Value,
+    ) -> Response {
+        client
+            .send(
+                TestClient::patch(format!("{BASE}/user/sessions/{id}/name"))
+                    .json(&json!({ "name": name })),
+                service,
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn renamed_sessions_keep_their_name_until_reverted() {
+        test_db::with_service(|service| async move {
+            let (mut client, body) =
+                Client::register(&service, "hana@example.com", "hana").await;
+            let mine = body["session"]["sessionId"].as_i64().unwrap();
+
+            let mut res =
+                rename(&mut client, &service, mine, json!("  Laptop  ")).await;
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+            let info: Value = res.take_json().await.unwrap();
+            assert_eq!(info["deviceName"], "Laptop");
+            assert_eq!(info["isCustomName"], true);
+
+            // Refreshing from another browser updates only the derived name.
+            let firefox = "Mozilla/5.0 (X11; Linux x86_64; rv:128.0) \
+                Gecko/20100101 Firefox/128.0";
+            let mut res = client
+                .with_cookies(TestClient::post(format!(
+                    "{BASE}/auth/session-management/refresh-jwt"
+                )))
+                .add_header(USER_AGENT, firefox, true)
+                .send(&service)
+                .await;
+            client.store(&res);
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+            let info: Value = res.take_json().await.unwrap();
+            assert_eq!(info["sessionId"], mine);
+            assert_eq!(info["deviceName"], "Laptop");
+            assert_eq!(info["isCustomName"], true);
+
+            let mut res =
+                rename(&mut client, &service, mine, Value::Null).await;
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+            let info: Value = res.take_json().await.unwrap();
+            let name = info["deviceName"].as_str().unwrap();
+            assert!(name.starts_with("Firefox on Linux"), "{info}");
+            assert_eq!(info["isCustomName"], false);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn session_names_are_validated_and_owned() {
+        test_db::with_service(|service| async move {
+            let (mut client, body) =
+                Client::register(&service, "ines@example.com", "ines").await;
+            let mine = body["session"]["sessionId"].as_i64().unwrap();
+
+            for name in ["", "   ", &"x".repeat(49), "Lap\ntop"] {
+                let mut res =
+                    rename(&mut client, &service, mine, json!(name)).await;
+                assert_eq!(
+                    res.status_code,
+                    Some(StatusCode::BAD_REQUEST),
+                    "{name:?}"
+                );
+                let body: Value = res.take_json().await.unwrap();
+                assert_eq!(body["code"], "validation");
+                assert!(body["fields"]["name"].is_array(), "{body}");
+            }
+            let res =
+                rename(&mut client, &service, mine, json!("x".repeat(48)))
+                    .await;
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+
+            let (_, body) =
+                Client::register(&service, "jade@example.com", "jade").await;
+            let theirs = body["session"]["sessionId"].as_i64().unwrap();
+            let res =
+                rename(&mut client, &service, theirs, json!("Mine now")).await;
+            assert_eq!(res.status_code, Some(StatusCode::NOT_FOUND));
+        })
+        .await;
+    }
+
+    /// Rows of `user_id` in the `column` of `table`.
+    fn count_rows(
+        conn: &mut DbConn,
+        table: &str,
+        column: &str,
+        user_id: i32,
+    ) -> i64 {
+        #[derive(QueryableByName)]
+        struct Count {
+            #[diesel(sql_type = diesel::sql_types::BigInt)]
+            count: i64,
+        }
+        diesel::sql_query(format!(
+            "SELECT COUNT(*) AS count FROM {table} WHERE {column} = ?"
+        ))
+        .bind::<diesel::sql_types::Integer, _>(user_id)
+        .get_result::<Count>(conn)
+        .unwrap()
+        .count
+    }
+
+    /// Give `me` a row in every table referencing users, next to `opponent`.
+    fn fill_account(conn: &mut DbConn, me: i32, opponent: i32) -> i32 {
+        use crate::auth::session_token::SessionToken;
+        use crate::models::{
+            GameSessionState, InviteStatus, NewGameInvite, NewGameSession,
+            NewIdempotencyKey, NewTrustedDevice, NewTwoFaRecoveryCode,
+            NewUserBan,
+        };
+        use crate::routers::game::{PlayerResult, record_game};
+        use crate::schema::{
+            game_invites, game_sessions, idempotency_keys, trusted_devices,
+            two_fa_recovery_codes, user_bans,
+        };
+
+        let now = chrono::Utc::now().naive_utc();
+        let game: i32 = diesel::insert_into(game_sessions::table)
+            .values(&NewGameSession {
+                player_a: Some(me),
+                player_b: Some(opponent),
+                state: GameSessionState::Finished,
+                created_at: now,
+            })
+            .returning(game_sessions::id)
+            .get_result(conn)
+            .unwrap();
+        for (user_id, won) in [(me, true), (opponent, false)] {
+            let result = PlayerResult {
+                kills: 3,
+                deaths: 1,
+                time_played: 60,
+                won: Some(won),
+            };
+            record_game(conn, user_id, result, Some(game)).unwrap();
+        }
+        diesel::insert_into(game_invites::table)
+            .values(&NewGameInvite {
+                from_user_id: opponent,
+                to_user_id: me,
+                status: InviteStatus::Pending,
+                created_at: now,
+                expires_at: now + chrono::Duration::minutes(5),
+            })
+            .execute(conn)
+            .unwrap();
+        diesel::insert_into(idempotency_keys::table)
+            .values(&NewIdempotencyKey {
+                user_id: me,
+                key: "delete-me".into(),
+                request_hash: vec![1],
+                status: Some(200),
+                response_body: None,
+                created_at: now,
+            })
+            .execute(conn)
+            .unwrap();
+        diesel::insert_into(two_fa_recovery_codes::table)
+            .values(&NewTwoFaRecoveryCode {
+                user_id: me,
+                code_hash: vec![2],
+                used_at: None,
+                created_at: now,
+            })
+            .execute(conn)
+            .unwrap();
+        diesel::insert_into(trusted_devices::table)
+            .values(&NewTrustedDevice {
+                user_id: me,
+                device_id: "device".into(),
+                token_hash: SessionToken::generate().to_hash(),
+                created_at: now,
+                last_used_at: now,
+                expires_at: now + chrono::Duration::days(30),
+            })
+            .execute(conn)
+            .unwrap();
+        diesel::insert_into(user_bans::table)
+            .values(&NewUserBan {
+                user_id: opponent,
+                banned_by: Some(me),
+                reason: "test".into(),
+                until: Some(now),
+                created_at: now,
+                lifted_at: None,
+            })
+            .execute(conn)
+            .unwrap();
+        game
+    }
+
+    #[tokio::test]
+    async fn deleting_the_account_removes_its_rows_but_not_the_opponents() {
+        test_db::with_service(|service| async move {
+            let (mut client, body) =
+                Client::register(&service, "dora@example.com", "dora").await;
+            let me = body["user"]["id"].as_i64().unwrap() as i32;
+            let conn = &mut db::get().unwrap();
+            let opponent = test_db::create_user(conn, "otto").id;
+            let game = fill_account(conn, me, opponent);
+
+            let res = client
+                .send(
+                    TestClient::delete(format!("{BASE}/user/me"))
+                        .json(&json!({ "password": PASSWORD })),
+                    &service,
+                )
+                .await;
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+
+            for (table, column) in [
+                ("users", "id"),
+                ("sessions", "user_id"),
+                ("user_stats", "user_id"),
+                ("game_history", "user_id"),
+                ("two_fa_recovery_codes", "user_id"),
+                ("trusted_devices", "user_id"),
+                ("security_events", "user_id"),
+                ("user_bans", "user_id"),
+                ("user_bans", "banned_by"),
+                ("idempotency_keys", "user_id"),
+                ("game_invites", "from_user_id"),
+                ("game_invites", "to_user_id"),
+                ("game_sessions", "player_a"),
+                ("game_sessions", "player_b"),
+            ] {
+                assert_eq!(
+                    count_rows(conn, table, column, me),
+                    0,
+                    "{table}.{column}"
+                );
+            }
+
+            // The opponent keeps the game, without the deleted player
+            let players = {
+                use crate::schema::game_sessions;
+                game_sessions::table
+                    .find(game)
+                    .select((game_sessions::player_a, game_sessions::player_b))
+                    .first::<(Option<i32>, Option<i32>)>(conn)
+                    .unwrap()
+            };
+            assert_eq!(players, (None, Some(opponent)));
+            assert_eq!(
+                count_rows(conn, "game_history", "user_id", opponent),
+                1
+            );
+            assert_eq!(count_rows(conn, "user_stats", "user_id", opponent), 1);
+            assert_eq!(count_rows(conn, "user_bans", "user_id", opponent), 1);
+
+            let res = TestClient::post(format!("{BASE}/auth/login"))
+                .json(&json!({
+                    "email": "dora@example.com",
+                    "password": PASSWORD,
+                }))
+                .send(&service)
+                .await;
+            assert_eq!(res.status_code, Some(StatusCode::UNAUTHORIZED));
+        })
+        .await;
+    }
+
+    /// A live connection of the Session `session_id`.
+    async fn connect(session_id: i64) -> FakeClient {
+        let conn = &mut db::get().unwrap();
+        let session: Session = crate::schema::sessions::table
+            .find(session_id as i32)
+            .first(conn)
+            .unwrap();
+        let session = ConnectionSession::from(&session);
+        FakeClient::connect(StreamManager::global(), Some(session)).await
+    }
+
+    async fn realtime(client: &mut Client, service: &Service) -> Value {
+        let mut res = client
+            .send(TestClient::get(format!("{BASE}/user/session")), service)
+            .await;
+        let info: Value = res.take_json().await.unwrap();
+        info["realtimeConnection"].clone()
+    }
+
+    #[tokio::test]
+    async fn sessions_show_their_realtime_connections() {
+        test_db::with_service(|service| async move {
+            let (mut client, body) =
+                Client::register(&service, "rita@example.com", "rita").await;
+            let user_id = body["user"]["id"].as_i64().unwrap() as i32;
+            let mine = body["session"]["sessionId"].as_i64().unwrap();
+            let (_, other) = login(&service, "rita@example.com").await;
+            assert_eq!(realtime(&mut client, &service).await, Value::Null);
+
+            let stream = connect(mine).await;
+            wait_until(|| StreamManager::global().is_connected(user_id)).await;
+            let connection = realtime(&mut client, &service).await;
+            assert_eq!(connection["connections"], 1);
+            assert!(connection["connectedAt"].is_string(), "{connection}");
+
+            let mut res = client
+                .send(
+                    TestClient::post(format!("{BASE}/user/sessions"))
+                        .json(&json!({ "password": PASSWORD })),
+                    &service,
+                )
+                .await;
+            let sessions: Value = res.take_json().await.unwrap();
+            let realtime_of = |id: i64| {
+                sessions
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .find(|session| session["sessionId"] == id)
+                    .unwrap()["realtimeConnection"]
+                    .clone()
+            };
+            assert_eq!(realtime_of(mine), connection);
+            assert_eq!(realtime_of(other), Value::Null);
+
+            // Closing the connections of another Session leaves this one
+            let disconnect = |session_id: Value| {
+                TestClient::post(format!("{BASE}/user/realtime/disconnect"))
+                    .json(&json!({ "sessionId": session_id }))
+            };
+            let res = client.send(disconnect(json!(other)), &service).await;
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+            assert!(StreamManager::global().is_connected(user_id));
+
+            let res = client.send(disconnect(Value::Null), &service).await;
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+            assert_eq!(stream.closed().await, Some(CloseCode::UserRequested));
+            assert!(!StreamManager::global().is_connected(user_id));
+            assert_eq!(realtime(&mut client, &service).await, Value::Null);
+        })
+        .await;
+    }
+
+    fn session_info() -> super::SessionInfo {
+        super::SessionInfo {
+            session_id: 5,
+            user_id: 7,
+            device_name: Some("Firefox on Linux".into()),
+            is_custom_name: false,
+            ip_address: Some("192.0.2.1".into()),
+            created_at: timestamp(),
+            last_used_at: timestamp(),
+            jwt_valid_until: timestamp(),
+            logged_in_until: timestamp(),
+            is_current: Some(true),
+            realtime_connection: Some(super::RealtimeConnection {
+                connected_at: timestamp(),
+                connections: 2,
+            }),
+        }
+    }
+
+    golden! {
+        user_session_info => super::UserSessionInfo {
+            user: sample_user(),
+            session: session_info(),
+            notification_counts: Some(NotificationCounts {
+                pending_game_invites: 1,
+            }),
+        },
+        two_fa_start_output => super::TwoFaStartOutput {
+            base32_secret: "JBSWY3DPEHPK3PXP".into(),
+            url: "otpauth://totp/Transcendence:ann?secret=JBSWY3DPEHPK3PXP"
+                .into(),
+            qr_base64: "iVBORw0KGgo=".into(),
+        },
+        two_fa_confirm_output => super::TwoFaConfirmOutput {
+            recovery_codes: vec!["abcd-efgh".into(), "ijkl-mnop".into()],
+        },
+        recovery_codes_status => super::RecoveryCodesStatus {
+            total: 10,
+            used: 3,
+            remaining: 7,
+        },
+    }
+}
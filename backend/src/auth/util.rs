@@ -6,18 +6,27 @@ use cookie::Cookie;
 
 use crate::auth::session_token::{SessionToken, SessionTokenHashTruncated};
 use crate::auth::{JwtClaims, jwt_encoding_key};
+use crate::config::AuthConfig;
 use crate::models::{Session, User};
 use crate::prelude::*;
+use crate::utils::client_ip;
 
-use super::ACCESS_EXPIRY;
 use super::two_factor;
 
 pub fn session_requires_reauth_at(
     session: &Session,
+) -> (chrono::NaiveDateTime, chrono::NaiveDateTime) {
+    reauth_deadlines(session, super::config())
+}
+
+/// [`session_requires_reauth_at`] with the windows of `config`.
+fn reauth_deadlines(
+    session: &Session,
+    config: &AuthConfig,
 ) -> (chrono::NaiveDateTime, chrono::NaiveDateTime) {
     (
-        session.refreshed_at + super::SESSION_EXPIRY,
-        session.last_authenticated_at + super::SESSION_FORCED_EXPIRY,
+        session.refreshed_at + config.session_expiry(),
+        session.last_authenticated_at + config.forced_expiry(),
     )
 }
 
@@ -35,7 +44,7 @@ pub fn prune_excess_sessions(
         .select(id)
         .load(conn)?;
 
-    let max_to_keep = super::MAX_SESSIONS_PER_USER as usize;
+    let max_to_keep = super::config().max_sessions_per_user as usize;
 
     // Ensure we never delete the explicitly kept session, and adjust how many
     // other sessions are allowed to remain.
@@ -55,7 +64,12 @@ pub fn prune_excess_sessions(
         return Ok(0);
     }
 
-    Ok(diesel::delete(sessions.filter(id.eq_any(to_delete))).execute(conn)?)
+    let deleted =
+        diesel::delete(sessions.filter(id.eq_any(&to_delete))).execute(conn)?;
+    for session_id in to_delete {
+        super::session_cache::invalidate(session_id);
+    }
+    Ok(deleted)
 }
 
 /*
@@ -68,11 +82,17 @@ pub fn prune_excess_sessions(
 }
 */
 
-pub fn device_id_cookie(depot: &Depot) -> Cookie<'static> {
-    cookie::Cookie::build(("device_id", depot.device_id().to_owned()))
+/// Cookies are only sent over https, unless the server runs in plain
+/// http development mode.
+fn secure_cookies() -> bool {
+    !crate::config::get().dev_insecure_http
+}
+
+pub fn device_id_cookie(device_id: &str) -> Cookie<'static> {
+    cookie::Cookie::build(("device_id", device_id.to_owned()))
         .path("/")
         .http_only(true)
-        .secure(true)
+        .secure(secure_cookies())
         .same_site(cookie::SameSite::Lax)
         .max_age(cookie::time::Duration::seconds(
             crate::auth::SESSION_COOKIE_MAX_AGE.as_secs() as i64,
@@ -80,11 +100,14 @@ pub fn device_id_cookie(depot: &Depot) -> Cookie<'static> {
         .build()
 }
 
+const SESSION_COOKIE_PATH: &str = "/api/auth/session-management/";
+const JWT_COOKIE_PATH: &str = "/api/";
+
 pub fn session_cookie(token: SessionToken) -> Cookie<'static> {
     Cookie::build((super::SESSION_COOKIE_NAME, token.encoded()))
-        .path("/api/auth/session-management/")
+        .path(SESSION_COOKIE_PATH)
         .http_only(true)
-        .secure(true)
+        .secure(secure_cookies())
         .same_site(cookie::SameSite::Lax)
         .max_age(cookie::time::Duration::seconds(
             super::SESSION_COOKIE_MAX_AGE.as_secs() as i64,
@@ -92,18 +115,58 @@ pub fn session_cookie(token: SessionToken) -> Cookie<'static> {
         .build()
 }
 
+pub fn trusted_device_cookie(token: SessionToken) -> Cookie<'static> {
+    Cookie::build((super::TRUSTED_DEVICE_COOKIE_NAME, token.encoded()))
+        .path("/api/auth/login")
+        .http_only(true)
+        .secure(secure_cookies())
+        .same_site(cookie::SameSite::Lax)
+        .max_age(cookie::time::Duration::seconds(
+            super::trusted_device::TRUSTED_DEVICE_TTL.num_seconds(),
+        ))
+        .build()
+}
+
 pub fn jwt_cookie(token: impl Into<Cow<'static, str>>) -> Cookie<'static> {
+    jwt_cookie_for(token, super::config())
+}
+
+/// [`jwt_cookie`] lasting as long as the access tokens of `config`.
+fn jwt_cookie_for(
+    token: impl Into<Cow<'static, str>>,
+    config: &AuthConfig,
+) -> Cookie<'static> {
     Cookie::build((super::JWT_COOKIE_NAME, token))
-        .path("/api/")
+        .path(JWT_COOKIE_PATH)
         .http_only(true)
-        .secure(true)
+        .secure(secure_cookies())
         .same_site(cookie::SameSite::Lax)
         .max_age(cookie::time::Duration::seconds(
-            ACCESS_EXPIRY.as_secs() as i64
+            config.access_expiry().as_secs() as i64,
         ))
         .build()
 }
 
+/// Expire the session and access token cookies.
+///
+/// [Response::remove_cookie] only drops cookies added to the response itself,
+/// so expired cookies are sent for the paths the cookies were set on.
+pub fn delete_auth_cookies(res: &mut Response) {
+    for (name, path) in [
+        (super::SESSION_COOKIE_NAME, SESSION_COOKIE_PATH),
+        (super::JWT_COOKIE_NAME, JWT_COOKIE_PATH),
+    ] {
+        let mut cookie = Cookie::build((name, ""))
+            .path(path)
+            .http_only(true)
+            .secure(secure_cookies())
+            .same_site(cookie::SameSite::Lax)
+            .build();
+        cookie.make_removal();
+        res.add_cookie(cookie);
+    }
+}
+
 pub fn jwt_create(
     session: &Session,
     jti: SessionTokenHashTruncated,
@@ -113,7 +176,7 @@ pub fn jwt_create(
         sub: session.user_id,
         sid: session.id,
         jti,
-        exp: (now + ACCESS_EXPIRY).timestamp() as usize,
+        exp: (now + super::config().access_expiry()).timestamp() as usize,
         iat: now.timestamp() as usize,
     };
     Ok(jsonwebtoken::encode(
@@ -153,6 +216,37 @@ pub fn check_password_and_mfa_if_enabled(
     Ok(user)
 }
 
+/// Whether `session` was created or reauthenticated with credentials within
+/// `max_age`.
+pub fn is_freshly_authenticated(
+    session: &Session,
+    max_age: std::time::Duration,
+) -> bool {
+    let max_age =
+        chrono::TimeDelta::from_std(max_age).unwrap_or(chrono::TimeDelta::MAX);
+    chrono::Utc::now().naive_utc() - session.last_authenticated_at <= max_age
+}
+
+/// Verify a sensitive request of `user_id`.
+///
+/// Passes if the Session authenticated recently (`fresh`), otherwise the
+/// password (and a 2FA code if enabled) are checked. Without a password the
+/// client has to reauthenticate first.
+pub fn check_fresh_auth_or_password(
+    fresh: bool,
+    user_id_value: i32,
+    password: Option<&str>,
+    mfa_code: Option<&str>,
+    conn: &mut DbConn,
+) -> AppResult<User> {
+    if fresh {
+        use crate::schema::users::dsl::users;
+        return Ok(users.find(user_id_value).first(conn)?);
+    }
+    let password = password.ok_or(super::AuthError::FreshAuthRequired)?;
+    check_password_and_mfa_if_enabled(user_id_value, password, mfa_code, conn)
+}
+
 pub fn get_user_by_credentials(
     email: &str,
     password: &str,
@@ -173,21 +267,20 @@ pub fn get_user_by_credentials(
     Ok(user)
 }
 
+/// The device name derived from the User-Agent and the truncated client
+/// address, see [client_ip::truncate].
 pub fn get_device_and_ip(req: &Request) -> (Option<String>, Option<String>) {
-    let device = req
-        .header::<&str>("User-Agent")
-        .map(|ua| {
-            woothee::parser::Parser::new().parse(ua).map(|info| {
-                format!("{} on {} ({})", info.name, info.os, info.category)
-            })
+    let ip =
+        client_ip::client_ip(req).map(|ip| client_ip::truncate(ip).to_string());
+    (get_device_name(req), ip)
+}
+
+pub fn get_device_name(req: &Request) -> Option<String> {
+    req.header::<&str>("User-Agent").and_then(|ua| {
+        woothee::parser::Parser::new().parse(ua).map(|info| {
+            format!("{} on {} ({})", info.name, info.os, info.category)
         })
-        .flatten();
-    let ip = req
-        .remote_addr()
-        .to_owned()
-        .into_std()
-        .map(|addr| addr.ip().to_string());
-    (device, ip)
+    })
 }
 
 static RANDOM_PASSWORD_HASH: LazyLock<String> = LazyLock::new(|| {
@@ -196,7 +289,7 @@ static RANDOM_PASSWORD_HASH: LazyLock<String> = LazyLock::new(|| {
         .to_string()
 });
 
-static ARGON2: LazyLock<Argon2<'static>> = LazyLock::new(|| Argon2::default());
+static ARGON2: LazyLock<Argon2<'static>> = LazyLock::new(Argon2::default);
 
 /// Constant-time password verification
 pub fn verify_password(
@@ -204,7 +297,7 @@ pub fn verify_password(
     password_hash: Option<&str>,
 ) -> Result<(), password_hash::Error> {
     let hash =
-        PasswordHash::new(&password_hash.unwrap_or(&RANDOM_PASSWORD_HASH))?;
+        PasswordHash::new(password_hash.unwrap_or(&RANDOM_PASSWORD_HASH))?;
     let res = ARGON2.verify_password(password.as_bytes(), &hash);
     match password_hash {
         Some(_) => res,
@@ -218,3 +311,78 @@ pub fn hash_password(password: &str) -> Result<String, password_hash::Error> {
         .hash_password(password.as_bytes(), &salt)
         .map(|ph| ph.to_string())
 }
+
+#[cfg(test)]
+mod tests {
+    use figment::Figment;
+    use figment::providers::{Format, Toml};
+
+    use super::*;
+
+    fn auth_config(toml: &str) -> AuthConfig {
+        Figment::new()
+            .merge(Toml::string(toml))
+            .extract()
+            .expect("the auth config should parse")
+    }
+
+    fn session_at(
+        refreshed_at: chrono::NaiveDateTime,
+        last_authenticated_at: chrono::NaiveDateTime,
+    ) -> Session {
+        Session {
+            id: 1,
+            user_id: 1,
+            token_hash: SessionToken::generate().to_hash(),
+            device_id: "device".into(),
+            device_name: None,
+            ip_address: None,
+            created_at: last_authenticated_at,
+            refreshed_at,
+            last_used_at: refreshed_at,
+            last_authenticated_at,
+            device_name_auto: None,
+            prev_token_hash: None,
+        }
+    }
+
+    #[test]
+    fn reauth_deadlines_follow_the_config() {
+        let authenticated = chrono::NaiveDate::from_ymd_opt(2026, 1, 1)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap();
+        let refreshed = authenticated + chrono::TimeDelta::days(1);
+        let session = session_at(refreshed, authenticated);
+
+        let defaults = reauth_deadlines(&session, &AuthConfig::default());
+        assert_eq!(
+            defaults,
+            (
+                refreshed + chrono::TimeDelta::days(7),
+                authenticated + chrono::TimeDelta::days(30),
+            )
+        );
+        let config = auth_config("session_days = 2\nforced_reauth_days = 3");
+        assert_eq!(
+            reauth_deadlines(&session, &config),
+            (
+                refreshed + chrono::TimeDelta::days(2),
+                authenticated + chrono::TimeDelta::days(3),
+            )
+        );
+    }
+
+    #[test]
+    fn the_access_cookie_lasts_as_long_as_the_token() {
+        crate::config::init_for_tests();
+        let max_age = |config: &AuthConfig| {
+            jwt_cookie_for("token", config)
+                .max_age()
+                .unwrap()
+                .whole_seconds()
+        };
+        assert_eq!(max_age(&AuthConfig::default()), 15 * 60);
+        assert_eq!(max_age(&auth_config("access_token_minutes = 5")), 5 * 60);
+    }
+}
@@ -1,13 +1,31 @@
+use std::sync::OnceLock;
+
 use serde::Deserialize;
 use tracing_appender::non_blocking::WorkerGuard;
-use tracing_subscriber::fmt;
+use tracing_subscriber::filter::ParseError;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{EnvFilter, Registry, fmt, reload};
 
 const FORMAT_PRETTY: &str = "pretty";
 const FORMAT_COMPACT: &str = "compact";
 const FORMAT_FULL: &str = "full";
 
+/// Targets worth filtering on, the module paths; logged on startup.
+pub const LOG_TARGETS: &[&str] = &[
+    "transcendence_backend::auth",
+    "transcendence_backend::db",
+    "transcendence_backend::game",
+    "transcendence_backend::routers",
+    "transcendence_backend::stream",
+    "transcendence_backend::stream::compress_cbor_codec",
+    "transcendence_backend::utils",
+];
+
 #[derive(Deserialize, Clone, Debug)]
 pub struct LogConfig {
+    /// [`EnvFilter`] directives, e.g. `info` or
+    /// `info,transcendence_backend::stream=debug` for one module, see
+    /// [`LOG_TARGETS`]
     #[serde(default = "default_filter_level")]
     pub filter_level: String,
     #[serde(default = "default_true")]
@@ -24,12 +42,20 @@ pub struct LogConfig {
     pub with_thread_names: bool,
     #[serde(default = "default_true")]
     pub with_source_location: bool,
+    /// Database closures of [`crate::db::with_conn`] taking longer than this
+    /// are logged as a warning
+    #[serde(default = "default_slow_query_ms")]
+    pub slow_query_ms: u64,
 }
 
 fn default_filter_level() -> String {
     "info".into()
 }
 
+fn default_slow_query_ms() -> u64 {
+    100
+}
+
 fn default_format() -> String {
     FORMAT_FULL.into()
 }
@@ -49,6 +75,7 @@ impl Default for LogConfig {
             with_thread_ids: true,
             with_thread_names: true,
             with_source_location: true,
+            slow_query_ms: default_slow_query_ms(),
         }
     }
 }
@@ -56,38 +83,106 @@ impl Default for LogConfig {
 impl LogConfig {
     /// Init tracing.
     ///
-    /// Caller should hold the guard.
+    /// Caller should hold the guard. The filter can be changed later with
+    /// [`set_log_filter`].
     pub fn guard(&self) -> WorkerGuard {
         let (non_blocking, guard) =
             tracing_appender::non_blocking(std::io::stdout());
 
-        // Tracing subscriber init.
-        let subscriber = tracing_subscriber::fmt()
-            .with_env_filter(
-                tracing_subscriber::EnvFilter::try_from_default_env()
-                    .unwrap_or(tracing_subscriber::EnvFilter::new(
-                        &self.filter_level,
-                    )),
-            )
+        let filter = EnvFilter::try_from_default_env()
+            .unwrap_or(EnvFilter::new(&self.filter_level));
+        let (filter, handle) = reload::Layer::new(filter);
+        FILTER_HANDLE
+            .set(handle)
+            .expect("tracing should be initialized once");
+
+        let layer = fmt::layer()
             .with_ansi(self.with_ansi)
-            .with_writer(non_blocking);
-
-        let subscriber = subscriber.event_format(
-            fmt::format()
-                .with_level(self.with_level)
-                .with_target(self.with_target)
-                .with_thread_ids(self.with_thread_ids)
-                .with_thread_names(self.with_thread_names)
-                .with_source_location(self.with_source_location),
-        );
-
-        match &*self.format {
-            FORMAT_PRETTY => subscriber.pretty().init(),
-            FORMAT_COMPACT => subscriber.compact().init(),
-            _ => subscriber.init(),
-        }
+            .with_writer(non_blocking)
+            .event_format(
+                fmt::format()
+                    .with_level(self.with_level)
+                    .with_target(self.with_target)
+                    .with_thread_ids(self.with_thread_ids)
+                    .with_thread_names(self.with_thread_names)
+                    .with_source_location(self.with_source_location),
+            );
+        let layer = match &*self.format {
+            FORMAT_PRETTY => layer.pretty().boxed(),
+            FORMAT_COMPACT => layer.compact().boxed(),
+            _ => layer.boxed(),
+        };
+
+        tracing_subscriber::registry()
+            .with(filter)
+            .with(layer)
+            .init();
 
         // Caller should hold this handler.
         guard
     }
 }
+
+static FILTER_HANDLE: OnceLock<reload::Handle<EnvFilter, Registry>> =
+    OnceLock::new();
+
+/// Replace the filter directives of the running subscriber, e.g.
+/// `info,transcendence_backend=debug`.
+pub fn set_log_filter(directives: &str) -> Result<(), ParseError> {
+    reload_filter(FILTER_HANDLE.get(), directives)
+}
+
+/// Parse `directives` and apply them with `handle`, if there is one.
+fn reload_filter<S>(
+    handle: Option<&reload::Handle<EnvFilter, S>>,
+    directives: &str,
+) -> Result<(), ParseError> {
+    let filter = EnvFilter::try_new(directives)?;
+    if let Some(handle) = handle
+        && let Err(err) = handle.reload(filter)
+    {
+        tracing::error!(%err, "Failed to reload the log filter");
+    }
+    Ok(())
+}
+
+/// The filter directives in effect, `None` before tracing is initialized.
+pub fn current_log_filter() -> Option<String> {
+    FILTER_HANDLE
+        .get()?
+        .with_current(|filter| filter.to_string())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_db::Captured;
+
+    #[test]
+    fn reloaded_filters_apply_to_the_running_subscriber() {
+        let captured = Captured::default();
+        let (filter, handle) = reload::Layer::new(EnvFilter::new("info"));
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::registry()
+            .with(filter)
+            .with(fmt::layer().with_writer(move || writer.clone()));
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::debug!(target: "log_reload_test", "before");
+            assert!(!captured.contains("before"));
+
+            assert!(reload_filter(Some(&handle), "info,[{=").is_err());
+            tracing::debug!(target: "log_reload_test", "invalid");
+            assert!(!captured.contains("invalid"));
+            assert_eq!(
+                handle.with_current(|filter| filter.to_string()).unwrap(),
+                "info"
+            );
+
+            reload_filter(Some(&handle), "debug").unwrap();
+            tracing::debug!(target: "log_reload_test", "after");
+            assert!(captured.contains("after"));
+        });
+    }
+}
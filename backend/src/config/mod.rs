@@ -1,23 +1,31 @@
-use std::sync::OnceLock;
+use std::collections::HashSet;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
 
 use figment::Figment;
 use figment::providers::{Env, Format, Toml};
 use serde::Deserialize;
 
+use crate::utils::keys::parse_32_byte_key;
+
 mod log_config;
-pub use log_config::LogConfig;
+pub use log_config::{
+    LOG_TARGETS, LogConfig, current_log_filter, set_log_filter,
+};
 
 pub static CONFIG: OnceLock<ServerConfig> = OnceLock::new();
 
-pub fn init() {
-    let raw_config = Figment::new()
+fn figment() -> Figment {
+    Figment::new()
         .merge(Toml::file(
             Env::var("APP_CONFIG").as_deref().unwrap_or("config.toml"),
         ))
         .merge(Env::raw().only(&["database_url"]))
-        .merge(Env::prefixed("APP_").global());
+        .merge(Env::prefixed("APP_").global())
+}
 
-    let config = match raw_config.extract::<ServerConfig>() {
+pub fn init() {
+    let mut config = match figment().extract::<ServerConfig>() {
         Ok(s) => s,
         Err(e) => {
             eprintln!(
@@ -30,6 +38,32 @@ pub fn init() {
         eprintln!("DATABASE_URL is not set");
         std::process::exit(1);
     }
+    if let Err(e) = config.auth.validate() {
+        eprintln!("Invalid auth config: {e}");
+        std::process::exit(1);
+    }
+    if let Err(e) = config.auth.load_blocked_email_domains() {
+        eprintln!("Failed to load auth.blocked_email_domains_file: {e}");
+        std::process::exit(1);
+    }
+    if let Err(e) = config.stream.load_dictionary() {
+        eprintln!("Failed to load stream.dictionary_file: {e}");
+        std::process::exit(1);
+    }
+    if config.limits.json_body_kb == 0 {
+        eprintln!("Invalid limits config: json_body_kb must be at least 1");
+        std::process::exit(1);
+    }
+    if let Some(cors) = &config.cors
+        && let Err(e) = cors.validate()
+    {
+        eprintln!("Invalid cors config: {e}");
+        std::process::exit(1);
+    }
+    if let Err(e) = config.check_serving() {
+        eprintln!("⚠️  {e}. Exiting.");
+        std::process::exit(1);
+    }
     crate::config::CONFIG
         .set(config)
         .expect("config should be set");
@@ -39,6 +73,50 @@ pub fn get() -> &'static ServerConfig {
     CONFIG.get().expect("config should be set")
 }
 
+/// Config of the unit tests, shared by all of them. The database comes from
+/// [crate::test_db] instead of `database_url`.
+#[cfg(test)]
+pub const TEST_CONFIG: &str = r#"
+database_url = "unused"
+admin_token = "test-admin-token"
+dev_insecure_http = true
+listen_addr = "127.0.0.1"
+trusted_proxies = ["192.0.2.0/24"]
+
+[log]
+with_ansi = false
+"#;
+
+/// Set [TEST_CONFIG] unless a test already did.
+#[cfg(test)]
+pub fn init_for_tests() -> &'static ServerConfig {
+    CONFIG.get_or_init(|| {
+        Figment::new()
+            .merge(Toml::string(TEST_CONFIG))
+            .extract()
+            .expect("TEST_CONFIG should be valid")
+    })
+}
+
+/// Read `log.filter_level` from the config sources again and apply it.
+///
+/// The rest of the config is only read on startup.
+pub fn reload_log_filter() {
+    let filter_level = match figment().extract_inner::<LogConfig>("log") {
+        Ok(log) => log.filter_level,
+        Err(err) => {
+            tracing::error!(%err, "Failed to read the log config");
+            return;
+        }
+    };
+    match set_log_filter(&filter_level) {
+        Ok(()) => tracing::info!("log level: {filter_level}"),
+        Err(err) => {
+            tracing::error!(%err, filter_level, "Invalid log.filter_level");
+        }
+    }
+}
+
 #[derive(Deserialize, Clone, Debug)]
 pub struct ServerConfig {
     #[serde(default = "default_listen_addr")]
@@ -51,6 +129,77 @@ pub struct ServerConfig {
     pub database_url: String,
     pub log: LogConfig,
     pub tls: Option<TlsConfig>,
+    #[serde(default)]
+    pub heartbeat: HeartbeatConfig,
+    #[serde(default)]
+    pub stream: StreamConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    #[serde(default)]
+    pub maintenance: MaintenanceConfig,
+    /// CORS for the API, for clients on other origins. Without it no CORS
+    /// headers are sent, so only same-origin pages can use the API.
+    pub cors: Option<CorsConfig>,
+    /// Serve Prometheus metrics at `/metrics` (unauthenticated, so only
+    /// enable this if the path is not reachable from outside)
+    #[serde(default)]
+    pub metrics_enabled: bool,
+    /// Reverse proxies whose `X-Forwarded-For` and `Forwarded` headers are
+    /// used to find the client address, e.g. `["172.16.0.0/12"]`. Without
+    /// them every request comes from its peer.
+    #[serde(default)]
+    pub trusted_proxies: Vec<ipnet::IpNet>,
+    /// Token for the `/api/admin` operator endpoints, sent in the
+    /// `x-admin-token` header. The endpoints are disabled if unset.
+    pub admin_token: Option<String>,
+    /// Serve plain HTTP on `listen_http_port` without TLS and without the
+    /// `Secure` cookie attribute, for local frontend development. Only
+    /// allowed with a loopback `listen_addr`. WebTransport is unavailable.
+    #[serde(default)]
+    pub dev_insecure_http: bool,
+}
+
+impl ServerConfig {
+    /// How HTTPS is served, `None` if nothing is configured.
+    ///
+    /// `dev_insecure_http` takes precedence over `tls`, and `tls` over
+    /// `domain`.
+    pub fn cert_source(&self) -> Option<CertSource<'_>> {
+        if self.dev_insecure_http {
+            Some(CertSource::DevPlain)
+        } else if let Some(tls) = &self.tls {
+            Some(CertSource::TlsFiles(tls))
+        } else {
+            self.domain
+                .as_deref()
+                .map(|domain| CertSource::Acme { domain })
+        }
+    }
+
+    /// Check that there is a way to serve and plain HTTP stays local.
+    fn check_serving(&self) -> Result<(), String> {
+        if self.cert_source().is_none() {
+            return Err("No TLS configuration and no domain provided".into());
+        }
+        if self.dev_insecure_http && !self.listens_on_loopback() {
+            return Err(format!(
+                "dev_insecure_http is only allowed with a loopback \
+                 listen_addr, not {}",
+                self.listen_addr
+            ));
+        }
+        Ok(())
+    }
+
+    fn listens_on_loopback(&self) -> bool {
+        self.listen_addr == "localhost"
+            || self
+                .listen_addr
+                .parse::<std::net::IpAddr>()
+                .is_ok_and(|ip| ip.is_loopback())
+    }
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -59,6 +208,285 @@ pub struct TlsConfig {
     pub key: String,
 }
 
+/// Where the certificate for HTTPS and HTTP/3 comes from.
+#[derive(Debug, Clone, Copy)]
+pub enum CertSource<'a> {
+    /// Certificate and key files of `tls`
+    TlsFiles(&'a TlsConfig),
+    /// Certificates for `domain` from Let's Encrypt
+    Acme { domain: &'a str },
+    /// `dev_insecure_http`, plain HTTP without certificate
+    DevPlain,
+}
+
+impl CertSource<'_> {
+    /// The protocols served, for the startup banner.
+    pub fn protocols(&self) -> &'static str {
+        match self {
+            Self::TlsFiles(_) | Self::Acme { .. } => {
+                "HTTP/1.1, HTTP/2, HTTP/3 (WebTransport)"
+            }
+            Self::DevPlain => "HTTP/1.1",
+        }
+    }
+}
+
+/// Ping settings of the WebTransport heartbeat stream.
+#[derive(Deserialize, Clone, Debug)]
+pub struct HeartbeatConfig {
+    #[serde(default = "default_heartbeat_interval_secs")]
+    pub interval_secs: u64,
+    /// Consecutive unanswered pings after which the connection is closed
+    #[serde(default = "default_heartbeat_max_missed")]
+    pub max_missed: u32,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: default_heartbeat_interval_secs(),
+            max_missed: default_heartbeat_max_missed(),
+        }
+    }
+}
+
+/// Connection policy of the WebTransport streams.
+#[derive(Deserialize, Clone, Debug)]
+pub struct StreamConfig {
+    /// Keep several connections per user (e.g. one per tab) instead of
+    /// replacing the previous one. Stream requests go to the most recent
+    /// connection, notifications to all of them.
+    #[serde(default)]
+    pub allow_multiple_connections: bool,
+    /// With `allow_multiple_connections`, the oldest connection is replaced
+    /// once a user opens more than this
+    #[serde(default = "default_max_connections_per_user")]
+    pub max_connections_per_user: usize,
+    /// zstd dictionary (trained or raw content) that clients may accept in
+    /// the handshake, see [`crate::stream::control`]. Clients have to ship
+    /// the same file.
+    pub dictionary_file: Option<String>,
+    /// Content of `dictionary_file`, loaded in [init]
+    #[serde(skip)]
+    pub dictionary: Option<Arc<[u8]>>,
+}
+
+impl StreamConfig {
+    /// How many connections a user can have at the same time.
+    pub fn connections_per_user(&self) -> usize {
+        if self.allow_multiple_connections {
+            self.max_connections_per_user.max(1)
+        } else {
+            1
+        }
+    }
+
+    fn load_dictionary(&mut self) -> std::io::Result<()> {
+        if let Some(path) = &self.dictionary_file {
+            self.dictionary = Some(std::fs::read(path)?.into());
+        }
+        Ok(())
+    }
+}
+
+impl Default for StreamConfig {
+    fn default() -> Self {
+        Self {
+            allow_multiple_connections: false,
+            max_connections_per_user: default_max_connections_per_user(),
+            dictionary_file: None,
+            dictionary: None,
+        }
+    }
+}
+
+/// Size limits of request bodies.
+#[derive(Deserialize, Clone, Debug)]
+pub struct LimitsConfig {
+    /// Maximum body size of the JSON API routes, larger requests are
+    /// rejected with 413
+    #[serde(default = "default_json_body_kb")]
+    pub json_body_kb: usize,
+}
+
+impl LimitsConfig {
+    pub fn json_body_bytes(&self) -> usize {
+        self.json_body_kb * 1024
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            json_body_kb: default_json_body_kb(),
+        }
+    }
+}
+
+/// Maintenance tasks run on startup.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct MaintenanceConfig {
+    /// Delete rows of users that no longer exist, see
+    /// [`crate::utils::integrity`]
+    #[serde(default)]
+    pub integrity_sweep: bool,
+    /// Only log what the sweep would delete
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// Origins allowed to call the API, see [`crate::utils::cors`].
+#[derive(Deserialize, Clone, Debug)]
+pub struct CorsConfig {
+    /// Exact origins, e.g. `https://app.example.com`; `*` is not allowed
+    pub allowed_origins: Vec<String>,
+    /// Let the allowed origins send cookies, i.e. authenticate
+    #[serde(default)]
+    pub allow_credentials: bool,
+    /// How long browsers may cache a preflight response
+    #[serde(default = "default_cors_max_age_secs")]
+    pub max_age_secs: u64,
+}
+
+impl CorsConfig {
+    fn validate(&self) -> Result<(), String> {
+        for origin in &self.allowed_origins {
+            if origin == "*" {
+                return Err("allowed_origins must list exact origins".into());
+            }
+            if salvo::http::HeaderValue::from_str(origin).is_err() {
+                return Err(format!("invalid origin {origin:?}"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Token lifetimes and other tunables of the auth module.
+///
+/// Read through [`crate::auth::config`].
+#[derive(Deserialize, Clone, Debug)]
+pub struct AuthConfig {
+    /// Lifetime of the JWT access token
+    #[serde(default = "default_access_token_minutes")]
+    pub access_token_minutes: u64,
+    /// Rolling session window, a session not refreshed for this long
+    /// needs reauthentication
+    #[serde(default = "default_session_days")]
+    pub session_days: u64,
+    /// Sessions need reauthentication this long after the last credential
+    /// authentication, even when refreshed regularly
+    #[serde(default = "default_forced_reauth_days")]
+    pub forced_reauth_days: u64,
+    /// Older sessions are pruned when a user exceeds this limit
+    #[serde(default = "default_max_sessions_per_user")]
+    pub max_sessions_per_user: u32,
+    /// Issuer shown in authenticator apps
+    #[serde(default = "default_totp_issuer")]
+    pub totp_issuer: String,
+    /// 32-byte key (hex or base64) for signing access tokens. Set it to keep
+    /// access tokens valid across restarts and shared between instances.
+    /// A random key is used if unset.
+    pub jwt_secret: Option<String>,
+    /// The replaced `jwt_secret` while rotating, tokens signed with it are
+    /// still accepted until they expire
+    pub jwt_secret_previous: Option<String>,
+    /// File with one email domain per line (e.g. disposable email providers)
+    /// that can't be used to register, `#` starts a comment. Subdomains are
+    /// blocked too. All domains are allowed if unset.
+    pub blocked_email_domains_file: Option<String>,
+    /// Tell on `POST /api/users/availability` whether an email is taken,
+    /// e.g. for closed deployments. Off by default, so the endpoint can't be
+    /// used to find out who has an account.
+    #[serde(default)]
+    pub expose_email_availability: bool,
+    /// Lowercase domains of `blocked_email_domains_file`, loaded in [init]
+    #[serde(skip)]
+    pub blocked_email_domains: HashSet<String>,
+}
+
+impl AuthConfig {
+    pub fn access_expiry(&self) -> Duration {
+        Duration::from_secs(self.access_token_minutes * 60)
+    }
+
+    pub fn session_expiry(&self) -> Duration {
+        Duration::from_secs(self.session_days * 24 * 60 * 60)
+    }
+
+    pub fn forced_expiry(&self) -> Duration {
+        Duration::from_secs(self.forced_reauth_days * 24 * 60 * 60)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.access_token_minutes == 0 {
+            return Err("access_token_minutes must be at least 1".into());
+        }
+        if self.access_expiry() >= self.session_expiry() {
+            return Err(
+                "access_token_minutes must be shorter than session_days".into(),
+            );
+        }
+        if self.session_expiry() >= self.forced_expiry() {
+            return Err(
+                "session_days must be shorter than forced_reauth_days".into()
+            );
+        }
+        if self.max_sessions_per_user == 0 {
+            return Err("max_sessions_per_user must be at least 1".into());
+        }
+        if self.totp_issuer.is_empty() || self.totp_issuer.contains(':') {
+            return Err("totp_issuer must be non-empty without colons".into());
+        }
+        for (name, secret) in [
+            ("jwt_secret", &self.jwt_secret),
+            ("jwt_secret_previous", &self.jwt_secret_previous),
+        ] {
+            if let Some(secret) = secret
+                && parse_32_byte_key(secret).is_none()
+            {
+                return Err(format!(
+                    "{name} must be a 32-byte hex or base64 key"
+                ));
+            }
+        }
+        if self.jwt_secret.is_none() && self.jwt_secret_previous.is_some() {
+            return Err("jwt_secret_previous requires jwt_secret".into());
+        }
+        Ok(())
+    }
+
+    fn load_blocked_email_domains(&mut self) -> std::io::Result<()> {
+        let Some(path) = &self.blocked_email_domains_file else {
+            return Ok(());
+        };
+        self.blocked_email_domains = std::fs::read_to_string(path)?
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or_default().trim())
+            .filter(|domain| !domain.is_empty())
+            .map(str::to_lowercase)
+            .collect();
+        Ok(())
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            access_token_minutes: default_access_token_minutes(),
+            session_days: default_session_days(),
+            forced_reauth_days: default_forced_reauth_days(),
+            max_sessions_per_user: default_max_sessions_per_user(),
+            totp_issuer: default_totp_issuer(),
+            jwt_secret: None,
+            jwt_secret_previous: None,
+            blocked_email_domains_file: None,
+            expose_email_availability: false,
+            blocked_email_domains: HashSet::new(),
+        }
+    }
+}
+
 fn default_listen_addr() -> String {
     "127.0.0.1".into()
 }
@@ -70,3 +498,137 @@ fn default_listen_http_port() -> u16 {
 fn default_listen_https_port() -> u16 {
     8443
 }
+
+fn default_heartbeat_interval_secs() -> u64 {
+    20
+}
+
+fn default_heartbeat_max_missed() -> u32 {
+    2
+}
+
+fn default_max_connections_per_user() -> usize {
+    4
+}
+
+fn default_json_body_kb() -> usize {
+    64
+}
+
+fn default_cors_max_age_secs() -> u64 {
+    600
+}
+
+fn default_access_token_minutes() -> u64 {
+    15
+}
+
+fn default_session_days() -> u64 {
+    7
+}
+
+fn default_forced_reauth_days() -> u64 {
+    30
+}
+
+fn default_max_sessions_per_user() -> u32 {
+    10
+}
+
+fn default_totp_issuer() -> String {
+    "Transcendence".into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth_config(toml: &str) -> AuthConfig {
+        Figment::new()
+            .merge(Toml::string(toml))
+            .extract()
+            .expect("the auth config should parse")
+    }
+
+    fn server_config(toml: &str) -> ServerConfig {
+        Figment::new()
+            .merge(Toml::string("database_url = \"unused\"\n[log]"))
+            .merge(Toml::string(toml))
+            .extract()
+            .expect("the server config should parse")
+    }
+
+    #[test]
+    fn plain_http_is_only_served_on_loopback() {
+        for addr in ["127.0.0.1", "::1", "localhost"] {
+            let config = server_config(&format!(
+                "dev_insecure_http = true\nlisten_addr = \"{addr}\""
+            ));
+            assert_eq!(config.check_serving(), Ok(()), "{addr}");
+            assert!(matches!(config.cert_source(), Some(CertSource::DevPlain)));
+        }
+        for addr in ["0.0.0.0", "::", "192.0.2.1", "example.com"] {
+            let config = server_config(&format!(
+                "dev_insecure_http = true\nlisten_addr = \"{addr}\""
+            ));
+            let err = config.check_serving().unwrap_err();
+            assert!(err.contains("loopback"), "{addr}: {err}");
+        }
+    }
+
+    #[test]
+    fn serving_needs_tls_a_domain_or_plain_http() {
+        let err = server_config("").check_serving().unwrap_err();
+        assert!(err.contains("No TLS"), "{err}");
+        let config = server_config("domain = \"example.com\"");
+        assert_eq!(config.check_serving(), Ok(()));
+        assert!(matches!(
+            config.cert_source(),
+            Some(CertSource::Acme {
+                domain: "example.com"
+            })
+        ));
+    }
+
+    #[test]
+    fn cert_sources_take_precedence_in_order() {
+        let tls = "[tls]\ncert = \"cert.pem\"\nkey = \"key.pem\"";
+        let domain = "domain = \"example.com\"\n";
+        let config = server_config(&format!("{domain}{tls}"));
+        assert!(matches!(
+            config.cert_source(),
+            Some(CertSource::TlsFiles(TlsConfig { cert, .. })) if cert == "cert.pem"
+        ));
+        assert_eq!(config.check_serving(), Ok(()));
+
+        let config = server_config(&format!(
+            "dev_insecure_http = true\nlisten_addr = \"127.0.0.1\"\n{tls}"
+        ));
+        assert!(matches!(config.cert_source(), Some(CertSource::DevPlain)));
+        assert_eq!(CertSource::DevPlain.protocols(), "HTTP/1.1");
+    }
+
+    #[test]
+    fn auth_lifetimes_must_be_ordered() {
+        assert_eq!(AuthConfig::default().validate(), Ok(()));
+        assert_eq!(
+            auth_config("access_token_minutes = 30\nsession_days = 1")
+                .validate(),
+            Ok(())
+        );
+        for (toml, error) in [
+            ("access_token_minutes = 0", "access_token_minutes"),
+            ("access_token_minutes = 10080", "session_days"),
+            ("session_days = 30", "forced_reauth_days"),
+            (
+                "session_days = 40\nforced_reauth_days = 30",
+                "forced_reauth",
+            ),
+            ("max_sessions_per_user = 0", "max_sessions_per_user"),
+            ("totp_issuer = \"a:b\"", "totp_issuer"),
+        ] {
+            let err = auth_config(toml).validate().unwrap_err();
+            assert!(err.contains(error), "{toml}: {err}");
+        }
+    }
+}
@@ -1,22 +1,22 @@
 use std::sync::LazyLock;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use diesel::connection::SimpleConnection;
 use diesel::r2d2::{ConnectionManager, Pool, PooledConnection};
 use diesel_migrations::{
     EmbeddedMigrations, MigrationHarness, embed_migrations,
 };
-use tracing::info;
+use tracing::{info, warn};
 
 use crate::prelude::*;
 
 pub type DbConn = PooledConnection<ConnectionManager<SqliteConnection>>;
+pub type DbPool = Pool<ConnectionManager<SqliteConnection>>;
 
 pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
 
 /// The global connection pool
-static DB: LazyLock<Pool<ConnectionManager<SqliteConnection>>> =
-    LazyLock::new(init_pool);
+static DB: LazyLock<DbPool> = LazyLock::new(init_pool);
 
 /// Custom connection customizer to set SQLite pragmas on each connection
 #[derive(Debug)]
@@ -42,23 +42,27 @@ impl diesel::r2d2::CustomizeConnection<SqliteConnection, diesel::r2d2::Error>
     }
 }
 
-fn init_pool() -> Pool<ConnectionManager<SqliteConnection>> {
-    let database_url = database_url();
-    let manager = ConnectionManager::<SqliteConnection>::new(&database_url);
+fn init_pool() -> DbPool {
+    let pool = build_pool(&database_url());
+    info!("Database connection pool initialized with WAL mode");
+    migrate(&mut pool.get().expect("Failed to get connection for migration"));
+    pool
+}
+
+/// A pool for `database_url` with the [SqliteConnectionCustomizer] pragmas.
+pub(crate) fn build_pool(database_url: &str) -> DbPool {
+    let manager = ConnectionManager::<SqliteConnection>::new(database_url);
 
-    let pool = Pool::builder()
+    Pool::builder()
         .max_size(10) // Maximum number of connections in the pool
         .min_idle(Some(1)) // Keep at least 1 connection ready
         .connection_timeout(Duration::from_secs(30))
         .connection_customizer(Box::new(SqliteConnectionCustomizer))
         .build(manager)
-        .expect("Failed to create database connection pool");
-    info!("Database connection pool initialized with WAL mode");
-    migrate(&mut pool.get().expect("Failed to get connection for migration"));
-    pool
+        .expect("Failed to create database connection pool")
 }
 
-fn migrate(conn: &mut DbConn) {
+pub(crate) fn migrate(conn: &mut DbConn) {
     info!(
         "Has pending migration: {}",
         conn.has_pending_migration(MIGRATIONS).unwrap()
@@ -67,13 +71,96 @@ fn migrate(conn: &mut DbConn) {
         .expect("migrate db should worked");
 }
 
+/// The pool connections come from, the current test's one in tests (see
+/// [crate::test_db]).
+fn with_pool<T>(f: impl FnOnce(&DbPool) -> T) -> T {
+    #[cfg(test)]
+    if let Some(pool) = crate::test_db::current_pool() {
+        return f(&pool);
+    }
+    f(&DB)
+}
+
 pub fn get() -> Result<DbConn, diesel::r2d2::PoolError> {
-    DB.get()
+    with_pool(|pool| pool.get())
+}
+
+/// The pool of [get], for taking connections on another thread.
+pub fn pool() -> DbPool {
+    with_pool(DbPool::clone)
+}
+
+/// Connections of the pool, for the [gauges](crate::utils::gauges).
+pub fn pool_state() -> diesel::r2d2::State {
+    with_pool(|pool| pool.state())
+}
+
+/// Whether migrations embedded in the binary haven't been applied to `conn`.
+pub fn has_pending_migration(conn: &mut DbConn) -> anyhow::Result<bool> {
+    conn.has_pending_migration(MIGRATIONS)
+        .map_err(|err| anyhow::anyhow!(err))
+}
+
+/// Run `f` with a pooled connection on the blocking thread pool.
+///
+/// Diesel is synchronous, and waiting for a connection or the SQLite write
+/// lock (up to the busy timeout) would block a tokio worker. Prefer this over
+/// [get] in async handlers.
+///
+/// `f` taking longer than `log.slow_query_ms` is logged as a warning, named
+/// by the type of the closure.
+pub async fn with_conn<T, F>(f: F) -> AppResult<T>
+where
+    F: FnOnce(&mut DbConn) -> AppResult<T> + Send + 'static,
+    T: Send + 'static,
+{
+    let threshold =
+        Duration::from_millis(crate::config::get().log.slow_query_ms);
+    // The blocking thread doesn't know about the test's pool
+    let pool = pool();
+    let (result, elapsed) =
+        tokio::task::spawn_blocking(move || -> AppResult<_> {
+            let conn = &mut pool.get()?;
+            let started = Instant::now();
+            let result = f(conn);
+            Ok((result, started.elapsed()))
+        })
+        .await??;
+    // Logged here to be in the span of the caller, e.g. the request
+    if elapsed >= threshold {
+        warn!(
+            elapsed_ms = elapsed.as_millis() as u64,
+            closure = std::any::type_name::<F>(),
+            "Slow database query"
+        );
+    }
+    result
+}
+
+/// Like [with_conn], but runs `f` in a transaction.
+pub async fn with_transaction<T, F>(f: F) -> AppResult<T>
+where
+    F: FnOnce(&mut DbConn) -> AppResult<T> + Send + 'static,
+    T: Send + 'static,
+{
+    with_conn(|conn| conn.transaction(f)).await
 }
 
 const TEST_DATABASE_URL: &str =
     "file:transcendence_test?mode=memory&cache=shared";
 
+/// Escape the wildcards of a `LIKE` pattern, for use with `.escape('\\')`.
+pub fn escape_like(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    for c in input.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
 fn database_url() -> String {
     if cfg!(test) {
         TEST_DATABASE_URL.to_string()
@@ -81,3 +168,53 @@ fn database_url() -> String {
         crate::config::get().database_url.clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::test_db::Captured;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn slow_queries_dont_stall_the_workers() {
+        crate::config::init_for_tests();
+        // More slow closures than workers
+        let slow: Vec<_> = (0..3)
+            .map(|_| {
+                tokio::spawn(super::with_conn(|_| {
+                    std::thread::sleep(Duration::from_millis(500));
+                    Ok(())
+                }))
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let started = Instant::now();
+        tokio::spawn(tokio::time::sleep(Duration::from_millis(10)))
+            .await
+            .unwrap();
+        assert!(started.elapsed() < Duration::from_millis(300));
+        for task in slow {
+            task.await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn closures_over_the_threshold_are_logged() {
+        crate::config::init_for_tests();
+        let threshold = crate::config::get().log.slow_query_ms;
+        let (captured, _default) = Captured::install();
+
+        super::with_conn(|_| Ok(())).await.unwrap();
+        assert!(!captured.contains("Slow database query"));
+
+        super::with_conn(move |_| {
+            std::thread::sleep(Duration::from_millis(threshold + 20));
+            Ok(())
+        })
+        .await
+        .unwrap();
+        assert!(captured.contains("Slow database query"));
+        assert!(captured.contains("closures_over_the_threshold_are_logged"));
+    }
+}
@@ -1,9 +1,19 @@
-use salvo::http::{StatusCode, StatusError};
+use std::collections::BTreeMap;
+
+use salvo::http::header::{HeaderValue, RETRY_AFTER};
+use salvo::http::{ParseError, ResBody, StatusCode, StatusError};
 use salvo::oapi::{self, EndpointOutRegister, ToSchema};
 use salvo::prelude::*;
+use serde::Serialize;
 use thiserror::Error;
+use validator::{ValidationErrors, ValidationErrorsKind};
 
 use crate::auth::{AuthError, TwoFactorError};
+use crate::game::matchmaking::MatchmakingError;
+use crate::routers::game::invites::InviteError;
+use crate::routers::game::{GameResultError, ResumeError};
+use crate::stream::BindError;
+use crate::utils::idempotency::IdempotencyError;
 
 #[derive(Error, Debug)]
 #[error(transparent)]
@@ -17,15 +27,116 @@ pub enum ApiError {
     Jwt(#[from] jsonwebtoken::errors::Error),
     Auth(#[from] AuthError),
     TwoFa(#[from] TwoFactorError),
+    Bind(#[from] BindError),
+    GameResult(#[from] GameResultError),
+    Resume(#[from] ResumeError),
+    Invite(#[from] InviteError),
+    Matchmaking(#[from] MatchmakingError),
+    Idempotency(#[from] IdempotencyError),
+    Blocking(#[from] tokio::task::JoinError),
+}
+
+/// JSON body of every error response.
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorResponse {
+    /// Stable machine-readable error code, e.g. `not_found` or `need_reauth`
+    pub code: String,
+    /// Human-readable description
+    pub message: String,
+    /// Same as the `X-Request-Id` response header
+    pub request_id: String,
+    /// Messages per invalid input field, only set for `validation`.
+    ///
+    /// Fields are named as in the request body. Nested fields use dotted
+    /// paths, list items their index, e.g. `items[0].displayName`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fields: Option<FieldErrors>,
+}
+
+pub type FieldErrors = BTreeMap<String, Vec<String>>;
+
+/// Flatten (nested) validation errors into messages per field path.
+///
+/// The errors are keyed by the Rust field names, the paths use the camelCase
+/// names of the wire format.
+fn field_errors(errs: &ValidationErrors) -> FieldErrors {
+    fn collect(prefix: &str, errs: &ValidationErrors, out: &mut FieldErrors) {
+        for (field, kind) in errs.errors() {
+            let field = camel_case(field);
+            let path = if prefix.is_empty() {
+                field
+            } else {
+                format!("{prefix}.{field}")
+            };
+            match kind {
+                ValidationErrorsKind::Field(errors) => {
+                    out.entry(path).or_default().extend(errors.iter().map(
+                        |err| match &err.message {
+                            Some(message) => message.to_string(),
+                            None => err.code.to_string(),
+                        },
+                    ));
+                }
+                ValidationErrorsKind::Struct(errs) => collect(&path, errs, out),
+                ValidationErrorsKind::List(items) => {
+                    for (index, errs) in items {
+                        collect(&format!("{path}[{index}]"), errs, out);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut out = FieldErrors::new();
+    collect("", errs, &mut out);
+    out
+}
+
+/// "game_session_id" -> "gameSessionId"
+fn camel_case(field: &str) -> String {
+    let mut parts = field.split('_');
+    let mut out = parts.next().unwrap_or_default().to_owned();
+    for part in parts {
+        let mut chars = part.chars();
+        if let Some(first) = chars.next() {
+            out.extend(first.to_uppercase());
+            out.push_str(chars.as_str());
+        }
+    }
+    out
+}
+
+/// Build the StatusError for an [ApiError].
+///
+/// The code goes into `brief` and the message into `detail`, which
+/// [error_catcher] turns into an [ErrorResponse].
+fn api_error(
+    status: StatusError,
+    code: &'static str,
+    message: impl Into<String>,
+) -> StatusError {
+    status.brief(code).detail(message.into())
+}
+
+fn internal_error() -> StatusError {
+    api_error(
+        StatusError::internal_server_error(),
+        "internal_error",
+        "Internal server error",
+    )
 }
 
 impl Scribe for ApiError {
     fn render(self, res: &mut Response) {
         let status_error = match self {
             // Validation errors -> 400 Bad Request with field details
-            Self::Validation(errs) => {
-                StatusError::bad_request().brief(errs.to_string())
-            }
+            Self::Validation(errs) => api_error(
+                StatusError::bad_request(),
+                "validation",
+                "Invalid input",
+            )
+            .origin(field_errors(&errs)),
             // Argon2 password hash errors
             Self::PasswordHash(err) => {
                 use argon2::password_hash::Error;
@@ -38,7 +149,7 @@ impl Scribe for ApiError {
                     // Other hashing errors are internal
                     err => {
                         tracing::error!(error = ?err, "Argon2 password hash error");
-                        StatusError::internal_server_error()
+                        internal_error()
                     }
                 }
             }
@@ -47,9 +158,11 @@ impl Scribe for ApiError {
                 use diesel::result::{DatabaseErrorKind, Error};
                 match err {
                     // Not found -> 404
-                    Error::NotFound => {
-                        StatusError::not_found().brief("Resource not found")
-                    }
+                    Error::NotFound => api_error(
+                        StatusError::not_found(),
+                        "not_found",
+                        "Resource not found",
+                    ),
                     // Database constraint errors
                     Error::DatabaseError(kind, info) => {
                         let message = info.message().to_string();
@@ -59,80 +172,250 @@ impl Scribe for ApiError {
                             DatabaseErrorKind::UniqueViolation => {
                                 let field = message
                                     .strip_prefix("UNIQUE constraint failed: ")
-                                    .and_then(|s| s.split('.').last())
+                                    .and_then(|s| s.split('.').next_back())
                                     .unwrap_or("Value");
-                                StatusError::conflict()
-                                    .brief(format!("{} already exists", field))
+                                api_error(
+                                    StatusError::conflict(),
+                                    "already_exists",
+                                    format!("{} already exists", field),
+                                )
                             }
                             // Foreign key violation -> 400 Bad Request
                             DatabaseErrorKind::ForeignKeyViolation => {
-                                StatusError::bad_request()
-                                    .brief("Referenced resource does not exist")
+                                api_error(
+                                    StatusError::bad_request(),
+                                    "invalid_reference",
+                                    "Referenced resource does not exist",
+                                )
                             }
                             // Check constraint violation -> 400 Bad Request
-                            DatabaseErrorKind::CheckViolation => {
-                                StatusError::bad_request().brief(format!(
-                                    "Constraint violation: {}",
-                                    message
-                                ))
-                            }
+                            DatabaseErrorKind::CheckViolation => api_error(
+                                StatusError::bad_request(),
+                                "constraint_violation",
+                                format!("Constraint violation: {}", message),
+                            ),
                             // Not null violation -> 400 Bad Request
-                            DatabaseErrorKind::NotNullViolation => {
-                                StatusError::bad_request()
-                                    .brief("A required field is missing")
-                            }
+                            DatabaseErrorKind::NotNullViolation => api_error(
+                                StatusError::bad_request(),
+                                "missing_field",
+                                "A required field is missing",
+                            ),
                             // Other database errors are internal
                             _ => {
+                                // SQLITE_BUSY and SQLITE_LOCKED, the busy
+                                // timeout passed
+                                if message.contains("is locked") {
+                                    crate::utils::metrics::record_sqlite_busy();
+                                }
                                 tracing::error!(error = message, kind = ?kind, "Database error");
-                                StatusError::internal_server_error()
+                                internal_error()
                             }
                         }
                     }
                     // All other diesel errors are internal
                     err => {
                         tracing::error!(error = ?err, "Diesel error");
-                        StatusError::internal_server_error()
+                        internal_error()
                     }
                 }
             }
             // Connection errors -> 500 Internal
             Self::DatabaseConnection(err) => {
                 tracing::error!(error = ?err, "Database connection error");
-                StatusError::internal_server_error()
+                internal_error()
             }
             // Pool errors -> 500 Internal
             Self::DatabaseConnectionPool(err) => {
                 tracing::error!(error = ?err, "Database connection pool error");
-                StatusError::internal_server_error()
+                internal_error()
+            }
+            // Panicked blocking task -> 500 Internal
+            Self::Blocking(err) => {
+                tracing::error!(error = ?err, "Blocking task failed");
+                internal_error()
             }
             Self::Stream(err) => {
                 tracing::error!(error = ?err, "H3 stream error");
-                StatusError::internal_server_error()
-            }
-            Self::Jwt(err) => {
-                tracing::error!(error = ?err, "JWT error");
-                StatusError::internal_server_error()
+                internal_error()
             }
+            Self::Jwt(err) => match err.kind() {
+                jsonwebtoken::errors::ErrorKind::ExpiredSignature => {
+                    return ApiError::Auth(AuthError::ExpiredJwt).render(res);
+                }
+                _ => {
+                    tracing::error!(error = ?err, "JWT error");
+                    internal_error()
+                }
+            },
+            // Auth errors -> 401 (403 for known but unpermitted users, 423
+            // for locked accounts, 429 for locked logins) with the snake_case
+            // variant name as code
             Self::Auth(err) => {
-                let variant: &'static str = err.into();
-                StatusError::unauthorized().brief(variant)
+                let message = err.to_string();
+                let status = match err {
+                    AuthError::Banned | AuthError::NotAdmin => {
+                        StatusError::forbidden()
+                    }
+                    AuthError::AccountLocked => StatusError::locked(),
+                    AuthError::LoginLocked { retry_after_secs } => {
+                        res.headers_mut().insert(
+                            RETRY_AFTER,
+                            HeaderValue::from(retry_after_secs),
+                        );
+                        StatusError::too_many_requests()
+                    }
+                    _ => StatusError::unauthorized(),
+                };
+                api_error(status, err.into(), message)
             }
             Self::TwoFa(err) => match err {
                 TwoFactorError::Internal(msg) => {
                     tracing::error!(error = %msg, "2FA internal error");
-                    StatusError::internal_server_error()
+                    internal_error()
                 }
                 variant => {
-                    let variant: &'static str = variant.into();
-                    StatusError::unauthorized().brief(variant)
+                    let message = variant.to_string();
+                    api_error(
+                        StatusError::unauthorized(),
+                        variant.into(),
+                        message,
+                    )
                 }
             },
+            Self::Bind(err) => {
+                let message = err.to_string();
+                let status = match err {
+                    BindError::UnknownConnection => StatusError::not_found(),
+                    BindError::NonceMismatch => StatusError::forbidden(),
+                    BindError::AlreadyBound => StatusError::conflict(),
+                    BindError::Expired => StatusError::gone(),
+                };
+                api_error(status, err.into(), message)
+            }
+            Self::GameResult(err) => {
+                let message = err.to_string();
+                let status = match err {
+                    GameResultError::NotParticipant => StatusError::forbidden(),
+                    GameResultError::NotFinished => StatusError::conflict(),
+                    GameResultError::NoResult => StatusError::not_found(),
+                };
+                api_error(status, err.into(), message)
+            }
+            Self::Resume(err) => {
+                let message = err.to_string();
+                let status = match err {
+                    ResumeError::NotParticipant | ResumeError::InvalidToken => {
+                        StatusError::forbidden()
+                    }
+                    ResumeError::GameOver => StatusError::gone(),
+                };
+                api_error(status, err.into(), message)
+            }
+            Self::Invite(err) => {
+                let message = err.to_string();
+                let status = match err {
+                    InviteError::SelfInvite => StatusError::bad_request(),
+                    InviteError::AlreadyInvited
+                    | InviteError::AlreadyAnswered => StatusError::conflict(),
+                    InviteError::TooManyOutstanding => {
+                        StatusError::too_many_requests()
+                    }
+                    InviteError::Expired => StatusError::gone(),
+                };
+                api_error(status, err.into(), message)
+            }
+            Self::Matchmaking(err) => {
+                let message = err.to_string();
+                let status = match err {
+                    MatchmakingError::NotConnected
+                    | MatchmakingError::AlreadyQueued => {
+                        StatusError::conflict()
+                    }
+                    MatchmakingError::NotQueued => StatusError::not_found(),
+                };
+                api_error(status, err.into(), message)
+            }
+            Self::Idempotency(err) => {
+                let message = err.to_string();
+                let status = match err {
+                    IdempotencyError::InvalidKey => StatusError::bad_request(),
+                    IdempotencyError::KeyReused
+                    | IdempotencyError::InProgress => StatusError::conflict(),
+                };
+                api_error(status, err.into(), message)
+            }
         };
 
         res.render(status_error);
     }
 }
 
+/// Render every error response as an [ErrorResponse].
+///
+/// StatusErrors built by [ApiError] carry the code in `brief` and the message
+/// in `detail`. Other errors (raised by salvo or plain StatusErrors) get a
+/// code derived from the status, e.g. `too_many_requests`. Request bodies
+/// that fail to parse (rejected by `JsonBody`) get the code `invalid_json`.
+#[handler]
+pub fn error_catcher(depot: &mut Depot, res: &mut Response) {
+    let status = res.status_code.unwrap_or(StatusCode::NOT_FOUND);
+    let (code, message, fields) = match &res.body {
+        ResBody::Error(StatusError {
+            brief,
+            detail: Some(detail),
+            origin,
+            ..
+        }) => (
+            brief.clone(),
+            detail.clone(),
+            origin
+                .as_ref()
+                .and_then(|origin| origin.downcast_ref::<FieldErrors>())
+                .cloned(),
+        ),
+        ResBody::Error(StatusError {
+            cause: Some(cause), ..
+        }) if matches!(
+            cause.downcast_ref::<ParseError>(),
+            Some(
+                ParseError::SerdeJson(_)
+                    | ParseError::EmptyBody
+                    | ParseError::InvalidContentType
+            )
+        ) =>
+        {
+            ("invalid_json".to_owned(), cause.to_string(), None)
+        }
+        ResBody::Error(err) => {
+            (status_code_name(status), err.brief.clone(), None)
+        }
+        ResBody::None => (
+            status_code_name(status),
+            status.canonical_reason().unwrap_or_default().to_owned(),
+            None,
+        ),
+        _ => return,
+    };
+    let request_id = crate::utils::request_id::ensure_request_id(depot, res);
+
+    res.status_code(status);
+    res.render(Json(ErrorResponse {
+        code,
+        message,
+        request_id,
+        fields,
+    }));
+}
+
+/// "Too Many Requests" -> "too_many_requests"
+fn status_code_name(status: StatusCode) -> String {
+    status
+        .canonical_reason()
+        .unwrap_or("Unknown")
+        .to_lowercase()
+        .replace([' ', '-'], "_")
+}
+
 impl EndpointOutRegister for ApiError {
     fn register(
         components: &mut oapi::Components,
@@ -143,6 +426,7 @@ impl EndpointOutRegister for ApiError {
             (StatusCode::NOT_FOUND, "Resource not found"),
             (StatusCode::CONFLICT, "Resource already exists"),
             (StatusCode::UNAUTHORIZED, "Unauthorized"),
+            (StatusCode::FORBIDDEN, "Forbidden"),
             (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error"),
         ];
 
@@ -151,9 +435,75 @@ impl EndpointOutRegister for ApiError {
                 status.as_str(),
                 oapi::Response::new(description).add_content(
                     "application/json",
-                    StatusError::to_schema(components),
+                    ErrorResponse::to_schema(components),
                 ),
             );
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use validator::Validate;
+
+    use super::*;
+
+    #[derive(Validate)]
+    struct Inner {
+        #[validate(length(min = 3, message = "Too short."))]
+        display_name: String,
+    }
+
+    #[derive(Validate)]
+    struct Outer {
+        #[validate(nested)]
+        new_profile: Inner,
+        #[validate(nested)]
+        items: Vec<Inner>,
+    }
+
+    #[test]
+    fn nested_field_errors_use_dotted_camel_case_paths() {
+        let input = Outer {
+            new_profile: Inner {
+                display_name: "a".into(),
+            },
+            items: vec![
+                Inner {
+                    display_name: "long enough".into(),
+                },
+                Inner {
+                    display_name: "b".into(),
+                },
+            ],
+        };
+        let fields = field_errors(&input.validate().unwrap_err());
+        assert_eq!(
+            fields,
+            FieldErrors::from([
+                (
+                    "items[1].displayName".to_owned(),
+                    vec!["Too short.".to_owned()]
+                ),
+                (
+                    "newProfile.displayName".to_owned(),
+                    vec!["Too short.".to_owned()]
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn codes_are_snake_case() {
+        assert_eq!(
+            status_code_name(StatusCode::TOO_MANY_REQUESTS),
+            "too_many_requests"
+        );
+        assert_eq!(status_code_name(StatusCode::MULTI_STATUS), "multi_status");
+        assert_eq!(
+            <&str>::from(AuthError::FreshAuthRequired),
+            "fresh_auth_required"
+        );
+        assert_eq!(<&str>::from(AuthError::ExpiredJwt), "token_expired");
+    }
+}
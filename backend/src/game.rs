@@ -0,0 +1,9 @@
+//! Games run on the server.
+//!
+//! Players paired by the [`matchmaking`] queue or an invite play [`pong`] in
+//! a [`session`] that owns the game state, the clients only send their inputs
+//! and draw the received state.
+
+pub mod matchmaking;
+pub mod pong;
+pub mod session;
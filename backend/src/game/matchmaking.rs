@@ -0,0 +1,308 @@
+//! Matchmaking queue for games against random opponents.
+//!
+//! Only users with a WebTransport connection can join, because the match is
+//! announced with a [`Notification::MatchFound`](crate::stream::Notification)
+//! on it. A background task started with [`periodic_matching`] pairs the two
+//! longest-waiting players and [starts](super::session::start) their game.
+//! Players whose connection closed are dropped from the queue once the task
+//! notices.
+
+use std::collections::VecDeque;
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+use dashmap::DashSet;
+use thiserror::Error;
+use tokio::sync::{Mutex, Notify};
+
+use crate::prelude::*;
+use crate::stream::StreamManager;
+
+/// How often the queue is checked for disconnected players, besides after
+/// every join.
+const MATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Error, Debug, Clone, Copy, strum::IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum MatchmakingError {
+    #[error("Joining the queue needs a WebTransport connection")]
+    NotConnected,
+    #[error("You are already in the queue")]
+    AlreadyQueued,
+    #[error("You are not in the queue")]
+    NotQueued,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct QueueStatus {
+    queued: bool,
+    /// 1 for the next player to be matched
+    position: Option<usize>,
+    waiting_secs: Option<u64>,
+    /// All players in the queue
+    players_waiting: usize,
+    /// Heartbeat round-trip time of the player's connection, `null` until
+    /// it answered the first ping
+    latency_ms: Option<u32>,
+}
+
+struct QueueEntry {
+    user_id: i32,
+    joined_at: Instant,
+}
+
+#[derive(Default)]
+struct MatchmakingQueue {
+    /// Waiting players, longest-waiting first
+    queue: Mutex<VecDeque<QueueEntry>>,
+    /// The players in `queue`, only changed while holding its lock
+    queued: DashSet<i32>,
+    /// Wakes the matcher after a join
+    joined: Notify,
+}
+
+static QUEUE: LazyLock<MatchmakingQueue> =
+    LazyLock::new(MatchmakingQueue::default);
+
+impl MatchmakingQueue {
+    async fn join(&self, user_id: i32) -> Result<(), MatchmakingError> {
+        if !StreamManager::global().is_connected(user_id) {
+            return Err(MatchmakingError::NotConnected);
+        }
+        let mut queue = self.queue.lock().await;
+        if !self.queued.insert(user_id) {
+            return Err(MatchmakingError::AlreadyQueued);
+        }
+        queue.push_back(QueueEntry {
+            user_id,
+            joined_at: Instant::now(),
+        });
+        drop(queue);
+        self.joined.notify_one();
+        Ok(())
+    }
+
+    async fn leave(&self, user_id: i32) -> Result<(), MatchmakingError> {
+        let mut queue = self.queue.lock().await;
+        self.queued
+            .remove(&user_id)
+            .ok_or(MatchmakingError::NotQueued)?;
+        queue.retain(|entry| entry.user_id != user_id);
+        Ok(())
+    }
+
+    async fn status(&self, user_id: i32) -> QueueStatus {
+        let queue = self.queue.lock().await;
+        let entry = queue
+            .iter()
+            .enumerate()
+            .find(|(_, entry)| entry.user_id == user_id);
+        QueueStatus {
+            queued: entry.is_some(),
+            position: entry.map(|(index, _)| index + 1),
+            waiting_secs: entry
+                .map(|(_, entry)| entry.joined_at.elapsed().as_secs()),
+            players_waiting: queue.len(),
+            latency_ms: StreamManager::global().latency_ms(user_id),
+        }
+    }
+
+    /// Drop disconnected players and take the pairs to match.
+    async fn take_pairs(&self) -> Vec<(i32, i32)> {
+        let mut queue = self.queue.lock().await;
+        let manager = StreamManager::global();
+        queue.retain(|entry| {
+            let connected = manager.is_connected(entry.user_id);
+            if !connected {
+                self.queued.remove(&entry.user_id);
+                tracing::debug!(
+                    user_id = entry.user_id,
+                    "Dropped disconnected player from the matchmaking queue"
+                );
+            }
+            connected
+        });
+
+        let mut pairs = Vec::with_capacity(queue.len() / 2);
+        while queue.len() >= 2 {
+            let (Some(a), Some(b)) = (queue.pop_front(), queue.pop_front())
+            else {
+                unreachable!("checked the length");
+            };
+            self.queued.remove(&a.user_id);
+            self.queued.remove(&b.user_id);
+            pairs.push((a.user_id, b.user_id));
+        }
+        pairs
+    }
+}
+
+/// Put `user_id` in the queue, they need a WebTransport connection.
+pub async fn join(user_id: i32) -> Result<(), MatchmakingError> {
+    QUEUE.join(user_id).await
+}
+
+pub async fn leave(user_id: i32) -> Result<(), MatchmakingError> {
+    QUEUE.leave(user_id).await
+}
+
+/// The queue position of `user_id`.
+pub async fn status(user_id: i32) -> QueueStatus {
+    QUEUE.status(user_id).await
+}
+
+/// Start the game of two matched players.
+///
+/// If that fails, both players are out of the queue and have to join again.
+async fn start_match(player_a: i32, player_b: i32) {
+    match super::session::start(player_a, player_b).await {
+        Ok(game_session_id) => tracing::info!(
+            game_session_id,
+            player_a,
+            player_b,
+            "Players matched"
+        ),
+        Err(err) => {
+            tracing::error!(%err, player_a, player_b, "Failed to create matched game");
+        }
+    }
+}
+
+/// Players in the queue, for the [gauges](crate::utils::gauges).
+pub fn players_waiting() -> usize {
+    QUEUE.queued.len()
+}
+
+/// Start the task that matches the queued players.
+pub fn periodic_matching() {
+    tokio::spawn(async move {
+        let queue = &*QUEUE;
+        let mut interval = tokio::time::interval(MATCH_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = queue.joined.notified() => {}
+            }
+            for (player_a, player_b) in queue.take_pairs().await {
+                start_match(player_a, player_b).await;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+    use tokio::io::AsyncWriteExt as _;
+
+    use super::*;
+    use crate::stream::testing::{ConnectionSession, FakeClient};
+    use crate::test_db;
+    use crate::wire_compat::golden;
+
+    async fn connect(nickname: &str) -> (i32, FakeClient) {
+        let user_id =
+            test_db::create_user(&mut db::get().unwrap(), nickname).id;
+        let client = FakeClient::connect(
+            StreamManager::global(),
+            Some(ConnectionSession::for_user(user_id)),
+        )
+        .await;
+        (user_id, client)
+    }
+
+    /// The next `MatchFound` notification, skipping other messages.
+    async fn match_found(client: &mut FakeClient) -> Value {
+        loop {
+            let message = client.recv_control().await;
+            if message["data"]["type"] == "MatchFound" {
+                return message["data"]["data"].clone();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn two_players_are_matched() {
+        test_db::with_db(|| async {
+            let queue = MatchmakingQueue::default();
+            let (ann, mut ann_client) = connect("ann").await;
+            let (ben, mut ben_client) = connect("ben").await;
+            queue.join(ann).await.unwrap();
+            queue.join(ben).await.unwrap();
+            let status = queue.status(ben).await;
+            assert_eq!(status.position, Some(2));
+            assert_eq!(status.players_waiting, 2);
+            // The fake clients don't echo pings
+            assert_eq!(status.latency_ms, None);
+
+            let pairs = queue.take_pairs().await;
+            assert_eq!(pairs, [(ann, ben)]);
+            assert!(!queue.status(ann).await.queued);
+            assert_eq!(queue.status(ann).await.players_waiting, 0);
+
+            start_match(ann, ben).await;
+            let found = [
+                match_found(&mut ann_client).await,
+                match_found(&mut ben_client).await,
+            ];
+            assert_eq!(found[0]["opponentUserId"], ben);
+            assert_eq!(found[1]["opponentUserId"], ann);
+            assert_eq!(found[0]["gameSessionId"], found[1]["gameSessionId"]);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn players_can_leave() {
+        test_db::with_db(|| async {
+            let queue = MatchmakingQueue::default();
+            let (cat, _client) = connect("cat").await;
+            queue.join(cat).await.unwrap();
+            assert!(matches!(
+                queue.join(cat).await,
+                Err(MatchmakingError::AlreadyQueued)
+            ));
+            queue.leave(cat).await.unwrap();
+            assert!(matches!(
+                queue.leave(cat).await,
+                Err(MatchmakingError::NotQueued)
+            ));
+            assert!(!queue.status(cat).await.queued);
+            assert!(queue.take_pairs().await.is_empty());
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn disconnected_players_are_dropped() {
+        test_db::with_db(|| async {
+            let queue = MatchmakingQueue::default();
+            let (dan, mut dan_client) = connect("dan").await;
+            let (eve, _eve_client) = connect("eve").await;
+            queue.join(dan).await.unwrap();
+            queue.join(eve).await.unwrap();
+
+            dan_client.heartbeat.shutdown().await.unwrap();
+            dan_client.closed().await;
+            assert!(queue.take_pairs().await.is_empty());
+            assert!(!queue.status(dan).await.queued);
+            assert_eq!(queue.status(eve).await.position, Some(1));
+            assert!(matches!(
+                queue.join(dan).await,
+                Err(MatchmakingError::NotConnected)
+            ));
+        })
+        .await;
+    }
+
+    golden! {
+        queue_status => QueueStatus {
+            queued: true,
+            position: Some(1),
+            waiting_secs: Some(12),
+            players_waiting: 3,
+            latency_ms: Some(40),
+        },
+    }
+}
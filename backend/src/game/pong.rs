@@ -0,0 +1,221 @@
+//! Pong rules, advanced in fixed ticks by the [game session](super::session).
+//!
+//! The field is [`FIELD_WIDTH`] x [`FIELD_HEIGHT`] units with the origin in
+//! the top left corner, positions are centers. Player A plays on the left.
+//! There is no randomness, the same inputs always give the same game.
+
+use serde::{Deserialize, Serialize};
+
+pub const FIELD_WIDTH: f32 = 800.0;
+pub const FIELD_HEIGHT: f32 = 600.0;
+pub const PADDLE_WIDTH: f32 = 10.0;
+pub const PADDLE_HEIGHT: f32 = 100.0;
+/// Distance between a paddle and its side of the field.
+pub const PADDLE_MARGIN: f32 = 20.0;
+pub const BALL_RADIUS: f32 = 6.0;
+pub const WINNING_SCORE: u8 = 5;
+
+/// Paddle movement per tick.
+const PADDLE_SPEED: f32 = 10.0;
+/// Horizontal ball speed per tick after a serve.
+const SERVE_SPEED: f32 = 6.0;
+/// Vertical ball speed per tick after a serve.
+const SERVE_SLOPE: f32 = 2.0;
+/// Factor applied to the horizontal ball speed on every paddle hit.
+const SPEEDUP: f32 = 1.05;
+const MAX_BALL_SPEED: f32 = 15.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Side {
+    A,
+    B,
+}
+
+impl Side {
+    pub fn index(self) -> usize {
+        match self {
+            Self::A => 0,
+            Self::B => 1,
+        }
+    }
+
+    pub fn other(self) -> Self {
+        match self {
+            Self::A => Self::B,
+            Self::B => Self::A,
+        }
+    }
+}
+
+/// Movement of a paddle until the next input.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub enum PaddleDirection {
+    Up,
+    Down,
+    #[default]
+    Stop,
+}
+
+impl PaddleDirection {
+    fn delta(self) -> f32 {
+        match self {
+            Self::Up => -PADDLE_SPEED,
+            Self::Down => PADDLE_SPEED,
+            Self::Stop => 0.0,
+        }
+    }
+}
+
+/// What the players see of a game, sent every tick.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Snapshot {
+    pub ball: [f32; 2],
+    pub ball_velocity: [f32; 2],
+    /// Vertical centers of the paddles of A and B
+    pub paddles: [f32; 2],
+    pub scores: [u8; 2],
+}
+
+#[derive(Debug, Clone)]
+pub struct Pong {
+    ball: [f32; 2],
+    velocity: [f32; 2],
+    paddles: [f32; 2],
+    scores: [u8; 2],
+    serves: u32,
+}
+
+impl Default for Pong {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Pong {
+    pub fn new() -> Self {
+        let mut pong = Self {
+            ball: [0.0; 2],
+            velocity: [0.0; 2],
+            paddles: [FIELD_HEIGHT / 2.0; 2],
+            scores: [0; 2],
+            serves: 0,
+        };
+        pong.serve(Side::B);
+        pong
+    }
+
+    /// Put the ball in the center, moving towards `towards`.
+    ///
+    /// The vertical direction alternates between serves.
+    fn serve(&mut self, towards: Side) {
+        self.ball = [FIELD_WIDTH / 2.0, FIELD_HEIGHT / 2.0];
+        let x = match towards {
+            Side::A => -SERVE_SPEED,
+            Side::B => SERVE_SPEED,
+        };
+        let y = if self.serves.is_multiple_of(2) {
+            SERVE_SLOPE
+        } else {
+            -SERVE_SLOPE
+        };
+        self.velocity = [x, y];
+        self.serves += 1;
+    }
+
+    /// Advance the game by one tick with the inputs of A and B.
+    ///
+    /// Does nothing once the game has a winner.
+    pub fn tick(&mut self, inputs: [PaddleDirection; 2]) {
+        if self.winner().is_some() {
+            return;
+        }
+        let half_paddle = PADDLE_HEIGHT / 2.0;
+        for (paddle, input) in self.paddles.iter_mut().zip(inputs) {
+            *paddle = (*paddle + input.delta())
+                .clamp(half_paddle, FIELD_HEIGHT - half_paddle);
+        }
+
+        self.ball[0] += self.velocity[0];
+        self.ball[1] += self.velocity[1];
+        if self.ball[1] < BALL_RADIUS {
+            self.ball[1] = 2.0 * BALL_RADIUS - self.ball[1];
+            self.velocity[1] = -self.velocity[1];
+        } else if self.ball[1] > FIELD_HEIGHT - BALL_RADIUS {
+            self.ball[1] = 2.0 * (FIELD_HEIGHT - BALL_RADIUS) - self.ball[1];
+            self.velocity[1] = -self.velocity[1];
+        }
+
+        // Hits only count while the ball moves towards the paddle, so it
+        // can't get stuck inside of it
+        let paddle_a = PADDLE_MARGIN + PADDLE_WIDTH;
+        let paddle_b = FIELD_WIDTH - PADDLE_MARGIN - PADDLE_WIDTH;
+        if self.velocity[0] < 0.0 && self.ball[0] - BALL_RADIUS <= paddle_a {
+            if self.ball[0] + BALL_RADIUS >= PADDLE_MARGIN && self.hits(Side::A)
+            {
+                self.ball[0] = paddle_a + BALL_RADIUS;
+                self.bounce(Side::A);
+            } else if self.ball[0] + BALL_RADIUS < 0.0 {
+                self.score(Side::B);
+            }
+        } else if self.velocity[0] > 0.0
+            && self.ball[0] + BALL_RADIUS >= paddle_b
+        {
+            if self.ball[0] - BALL_RADIUS <= FIELD_WIDTH - PADDLE_MARGIN
+                && self.hits(Side::B)
+            {
+                self.ball[0] = paddle_b - BALL_RADIUS;
+                self.bounce(Side::B);
+            } else if self.ball[0] - BALL_RADIUS > FIELD_WIDTH {
+                self.score(Side::A);
+            }
+        }
+    }
+
+    fn hits(&self, paddle: Side) -> bool {
+        (self.ball[1] - self.paddles[paddle.index()]).abs()
+            <= PADDLE_HEIGHT / 2.0 + BALL_RADIUS
+    }
+
+    /// Send the ball back, steeper the further from the paddle center it
+    /// hit.
+    fn bounce(&mut self, paddle: Side) {
+        let speed = (self.velocity[0].abs() * SPEEDUP).min(MAX_BALL_SPEED);
+        let offset = ((self.ball[1] - self.paddles[paddle.index()])
+            / (PADDLE_HEIGHT / 2.0))
+            .clamp(-1.0, 1.0);
+        self.velocity = [
+            match paddle {
+                Side::A => speed,
+                Side::B => -speed,
+            },
+            offset * speed * 0.75,
+        ];
+    }
+
+    fn score(&mut self, scorer: Side) {
+        self.scores[scorer.index()] += 1;
+        self.serve(scorer.other());
+    }
+
+    pub fn scores(&self) -> [u8; 2] {
+        self.scores
+    }
+
+    pub fn winner(&self) -> Option<Side> {
+        [Side::A, Side::B]
+            .into_iter()
+            .find(|side| self.scores[side.index()] >= WINNING_SCORE)
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            ball: self.ball,
+            ball_velocity: self.velocity,
+            paddles: self.paddles,
+            scores: self.scores,
+        }
+    }
+}
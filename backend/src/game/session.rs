@@ -0,0 +1,776 @@
+//! The server loop of a matched game.
+//!
+//! [`spawn`] starts a task per game. It requests a [`StreamType::Game`] stream
+//! from both players, applies their latest inputs every [`TICK`] and sends the
+//! new state to both. While a player has no working stream the game is
+//! paused, and they get a new stream once they are connected again. A player
+//! who stays away for [`RECONNECT_TIMEOUT`] forfeits. If both do, the game
+//! ends without a result.
+//!
+//! A returning client, e.g. after a reload, can't tell from the stream that
+//! it still has a seat, and the server may still wait for the old
+//! connection. Each player gets a resume token with the match, and
+//! [`resume`] with it drops their old stream and opens a new one right away.
+//! A client can also open a [`StreamType::Game`] stream itself, which
+//! replaces the stream of its seat, see [`accept_game_streams`].
+//! The tokens are only kept in memory and expire with the game, games don't
+//! survive a restart of the server.
+//!
+//! Inputs over [`INPUT_RATE`] are dropped, and a player who keeps sending
+//! them loses their stream like a disconnected one. So does a player sending
+//! an invalid frame.
+//!
+//! Results are recorded as verified games, see
+//! [`record_game`](crate::routers::game::record_game): a player's points are
+//! their kills, the opponent's their deaths. A game without a winner counts
+//! as neither a win nor a loss. Players get theirs by submitting the result with the
+//! `gameSessionId`.
+
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use dashmap::DashMap;
+use tokio::sync::mpsc;
+// The clock of tokio, so tests can pause it
+use tokio::time::{Instant, MissedTickBehavior};
+
+use super::pong::{PaddleDirection, Pong, Side, Snapshot};
+use crate::auth::session_token::{SessionToken, SessionTokenHash};
+use crate::models::{GameSession, GameSessionState, NewGameSession};
+use crate::prelude::*;
+use crate::routers::game::{PlayerResult, ResumeError};
+use crate::stream::control::ControlServerMessage;
+use crate::stream::{
+    Notification, Receiver, Sender, SinkExt as _, StreamExt as _,
+    StreamManager, StreamType,
+};
+use crate::utils::stream_limiter::{Admission, StreamLimiter, StreamRate};
+
+const TICK: Duration = Duration::from_nanos(1_000_000_000 / 30);
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+/// Inputs a player may send, twice the tick rate to allow for jitter.
+const INPUT_RATE: StreamRate = StreamRate::new(60, 60);
+
+/// Messages sent from the server to a player on the game stream.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", content = "data", rename_all_fields = "camelCase")]
+pub enum GameServerMsg {
+    /// Sent first on every new stream, followed by the current `State`
+    Start {
+        game_session_id: i32,
+        /// The paddle of the receiver
+        side: Side,
+        opponent_user_id: i32,
+    },
+    State {
+        tick: u64,
+        snapshot: Snapshot,
+    },
+    /// The opponent lost their stream. They forfeit if they aren't back
+    /// within `timeoutSecs`.
+    Paused {
+        timeout_secs: u64,
+    },
+    Resumed,
+    Finished {
+        /// `None` if both players left
+        winner: Option<Side>,
+        /// Whether the loser left the game
+        forfeit: bool,
+        scores: [u8; 2],
+    },
+}
+
+/// Messages sent from a player to the server on the game stream.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all_fields = "camelCase")]
+pub enum GameClientMsg {
+    /// Move the own paddle in a direction until the next input.
+    ///
+    /// `seq` has to increase with every input on a stream, older inputs are
+    /// ignored.
+    Input { seq: u32, payload: PaddleDirection },
+}
+
+type GameSender = Sender<GameServerMsg>;
+type GameReceiver = Receiver<GameClientMsg>;
+
+enum Event {
+    Connected {
+        side: Side,
+        attempt: u32,
+        stream: Option<Box<(GameSender, GameReceiver)>>,
+    },
+    Input {
+        side: Side,
+        generation: u32,
+        seq: u32,
+        direction: PaddleDirection,
+    },
+    Lost {
+        side: Side,
+        generation: u32,
+    },
+    /// The player asked for a new stream with their resume token
+    Resume {
+        side: Side,
+    },
+    /// The player opened a game stream
+    Opened {
+        side: Side,
+        stream: Box<(GameSender, GameReceiver)>,
+    },
+}
+
+/// A game running on this server, to hand resumes to its task.
+struct Running {
+    events: mpsc::Sender<Event>,
+    /// The user and the hash of the resume token of each side
+    seats: [(i32, SessionTokenHash); 2],
+}
+
+static RUNNING: LazyLock<DashMap<i32, Running, ahash::RandomState>> =
+    LazyLock::new(DashMap::default);
+
+struct Player {
+    user_id: i32,
+    sender: Option<GameSender>,
+    /// Incremented for every stream, to ignore events of older ones
+    generation: u32,
+    /// Since when the player has no stream, `None` while connected
+    away_since: Option<Instant>,
+    /// Whether a stream was requested and the answer is pending
+    connecting: bool,
+    /// Incremented for every stream request to ignore answers to older ones
+    attempt: u32,
+    input: PaddleDirection,
+    last_seq: Option<u32>,
+}
+
+impl Player {
+    fn new(user_id: i32, now: Instant) -> Self {
+        Self {
+            user_id,
+            sender: None,
+            generation: 0,
+            away_since: Some(now),
+            connecting: false,
+            attempt: 0,
+            input: PaddleDirection::Stop,
+            last_seq: None,
+        }
+    }
+
+    fn lose_stream(&mut self) {
+        if self.sender.take().is_some() {
+            self.generation += 1;
+            self.away_since = Some(Instant::now());
+            self.input = PaddleDirection::Stop;
+        }
+    }
+}
+
+enum Outcome {
+    Won { winner: Side, forfeit: bool },
+    Abandoned,
+}
+
+struct Game {
+    id: i32,
+    players: [Player; 2],
+    pong: Pong,
+    tick: u64,
+    /// Whether both players were connected once
+    started: Option<Instant>,
+    paused: bool,
+    events: mpsc::Sender<Event>,
+}
+
+/// Create the game of two players, start it and send both a
+/// [`Notification::MatchFound`] with their resume token.
+///
+/// Returns the id of the game.
+pub async fn start(player_a: i32, player_b: i32) -> AppResult<i32> {
+    let (game, [nickname_a, nickname_b]) = db::with_transaction(move |conn| {
+        use crate::schema::{game_sessions, users};
+
+        let game: GameSession = diesel::insert_into(game_sessions::table)
+            .values(&NewGameSession {
+                player_a: Some(player_a),
+                player_b: Some(player_b),
+                state: GameSessionState::Created,
+                created_at: chrono::Utc::now().naive_utc(),
+            })
+            .get_result(conn)?;
+        let nickname = |conn: &mut DbConn, user_id: i32| {
+            users::table
+                .find(user_id)
+                .select(users::nickname)
+                .first::<String>(conn)
+        };
+        let nicknames = [nickname(conn, player_a)?, nickname(conn, player_b)?];
+        Ok((game, nicknames))
+    })
+    .await?;
+
+    let game_session_id = game.id;
+    let [token_a, token_b] = spawn(game_session_id, [player_a, player_b]);
+    // Only the most recent connection gets the game stream, so the token
+    // goes there too instead of to every connection
+    let manager = StreamManager::global();
+    let match_found = |opponent_user_id, opponent_nickname, resume_token| {
+        ControlServerMessage::Notification(Notification::MatchFound {
+            game_session_id,
+            opponent_user_id,
+            opponent_nickname,
+            resume_token,
+        })
+    };
+    let sent = tokio::join!(
+        manager.send_control(
+            player_a,
+            match_found(player_b, nickname_b, token_a.encoded()),
+        ),
+        manager.send_control(
+            player_b,
+            match_found(player_a, nickname_a, token_b.encoded()),
+        ),
+    );
+    for (user_id, sent) in [(player_a, sent.0), (player_b, sent.1)] {
+        if let Err(err) = sent {
+            // They forfeit unless they come back in time
+            tracing::warn!(
+                game_session_id,
+                user_id,
+                %err,
+                "Failed to send MatchFound"
+            );
+        }
+    }
+    Ok(game_session_id)
+}
+
+/// Run the new game `id` of the matched players A and B in the background.
+///
+/// Returns the resume tokens of player A and B, see [`resume`].
+pub fn spawn(id: i32, [player_a, player_b]: [i32; 2]) -> [SessionToken; 2] {
+    let tokens = [SessionToken::generate(), SessionToken::generate()];
+    let (events, event_rx) = mpsc::channel(256);
+    RUNNING.insert(
+        id,
+        Running {
+            events: events.clone(),
+            seats: [
+                (player_a, tokens[0].to_hash()),
+                (player_b, tokens[1].to_hash()),
+            ],
+        },
+    );
+    tokio::spawn(async move {
+        tracing::info!(game_session_id = id, "Game started");
+        let running = db::with_conn(move |conn| {
+            mark_state(conn, id, GameSessionState::Running)
+        });
+        if let Err(err) = running.await {
+            RUNNING.remove(&id);
+            tracing::error!(%err, game_session_id = id, "Failed to start game");
+            return;
+        }
+        run(id, [player_a, player_b], events, event_rx).await;
+    });
+    tokens
+}
+
+/// Have the player `user_id` of a running game get a new stream.
+///
+/// The stream they had is dropped, they are away until the new one is
+/// accepted and don't get more time to come back. Returns `false` if the
+/// game doesn't run (anymore).
+pub async fn resume(
+    game_id: i32,
+    user_id: i32,
+    token: &str,
+) -> Result<bool, ResumeError> {
+    let (events, side) = {
+        let Some(running) = RUNNING.get(&game_id) else {
+            return Ok(false);
+        };
+        let side = [Side::A, Side::B]
+            .into_iter()
+            .find(|side| running.seats[side.index()].0 == user_id)
+            .ok_or(ResumeError::NotParticipant)?;
+        let token = SessionToken::try_from(token)
+            .map_err(|_| ResumeError::InvalidToken)?;
+        if !token.to_hash().ct_eq(&running.seats[side.index()].1) {
+            return Err(ResumeError::InvalidToken);
+        }
+        (running.events.clone(), side)
+    };
+    Ok(events.send(Event::Resume { side }).await.is_ok())
+}
+
+async fn run(
+    id: i32,
+    [player_a, player_b]: [i32; 2],
+    events: mpsc::Sender<Event>,
+    mut event_rx: mpsc::Receiver<Event>,
+) {
+    let now = Instant::now();
+    let mut game = Game {
+        id,
+        players: [Player::new(player_a, now), Player::new(player_b, now)],
+        pong: Pong::new(),
+        tick: 0,
+        started: None,
+        paused: false,
+        events,
+    };
+
+    let mut interval = tokio::time::interval(TICK);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let outcome = loop {
+        interval.tick().await;
+        while let Ok(event) = event_rx.try_recv() {
+            game.handle(event).await;
+        }
+        if let Some(outcome) = game.step().await {
+            break outcome;
+        }
+    };
+    RUNNING.remove(&game.id);
+    game.finish(outcome).await;
+}
+
+impl Game {
+    async fn handle(&mut self, event: Event) {
+        match event {
+            Event::Connected {
+                side,
+                attempt,
+                stream,
+            } => {
+                let player = &mut self.players[side.index()];
+                if player.attempt != attempt {
+                    return;
+                }
+                player.connecting = false;
+                if let Some(stream) = stream {
+                    let (sender, receiver) = *stream;
+                    self.attach(side, sender, receiver).await;
+                }
+            }
+            Event::Input {
+                side,
+                generation,
+                seq,
+                direction,
+            } => {
+                let player = &mut self.players[side.index()];
+                if player.generation == generation
+                    && player.sender.is_some()
+                    && player.last_seq.is_none_or(|last| seq > last)
+                {
+                    player.last_seq = Some(seq);
+                    player.input = direction;
+                }
+            }
+            Event::Lost { side, generation } => {
+                let player = &mut self.players[side.index()];
+                if player.generation == generation {
+                    player.lose_stream();
+                }
+            }
+            Event::Resume { side } => {
+                let player = &mut self.players[side.index()];
+                player.lose_stream();
+                // Don't wait for a request to a connection that may be gone
+                player.connecting = false;
+                player.attempt += 1;
+            }
+            Event::Opened { side, stream } => {
+                let player = &mut self.players[side.index()];
+                player.lose_stream();
+                // A pending request is not needed anymore
+                player.connecting = false;
+                player.attempt += 1;
+                let (sender, receiver) = *stream;
+                self.attach(side, sender, receiver).await;
+            }
+        }
+    }
+
+    /// Make `sender` the stream of `side` and read its inputs, after sending
+    /// `Start` and the current `State`.
+    async fn attach(
+        &mut self,
+        side: Side,
+        mut sender: GameSender,
+        receiver: GameReceiver,
+    ) {
+        let start = GameServerMsg::Start {
+            game_session_id: self.id,
+            side,
+            opponent_user_id: self.players[side.other().index()].user_id,
+        };
+        let state = GameServerMsg::State {
+            tick: self.tick,
+            snapshot: self.pong.snapshot(),
+        };
+        if sender.send(start).await.is_err()
+            || sender.send(state).await.is_err()
+        {
+            return;
+        }
+        let player = &mut self.players[side.index()];
+        player.generation += 1;
+        player.sender = Some(sender);
+        player.away_since = None;
+        player.last_seq = None;
+        tokio::spawn(read_inputs(
+            side,
+            player.generation,
+            receiver,
+            self.events.clone(),
+        ));
+    }
+
+    /// Advance the game by a tick, or wait for missing players.
+    async fn step(&mut self) -> Option<Outcome> {
+        let now = Instant::now();
+        for side in [Side::A, Side::B] {
+            let player = &mut self.players[side.index()];
+            if player.away_since.is_some()
+                && !player.connecting
+                && StreamManager::global().is_connected(player.user_id)
+            {
+                player.connecting = true;
+                tokio::spawn(request_stream(
+                    side,
+                    player.attempt,
+                    player.user_id,
+                    self.events.clone(),
+                ));
+            }
+        }
+
+        let timed_out = |player: &Player| {
+            player
+                .away_since
+                .is_some_and(|since| now - since >= RECONNECT_TIMEOUT)
+        };
+        match self.players.each_ref().map(timed_out) {
+            [true, true] => return Some(Outcome::Abandoned),
+            [true, false] => {
+                return Some(Outcome::Won {
+                    winner: Side::B,
+                    forfeit: true,
+                });
+            }
+            [false, true] => {
+                return Some(Outcome::Won {
+                    winner: Side::A,
+                    forfeit: true,
+                });
+            }
+            [false, false] => {}
+        }
+
+        if self
+            .players
+            .iter()
+            .any(|player| player.away_since.is_some())
+        {
+            if self.started.is_some() && !self.paused {
+                self.paused = true;
+                self.broadcast(GameServerMsg::Paused {
+                    timeout_secs: RECONNECT_TIMEOUT.as_secs(),
+                })
+                .await;
+            }
+            return None;
+        }
+        if self.started.is_none() {
+            self.started = Some(now);
+        }
+        if self.paused {
+            self.paused = false;
+            self.broadcast(GameServerMsg::Resumed).await;
+        }
+
+        self.pong
+            .tick(self.players.each_ref().map(|player| player.input));
+        self.tick += 1;
+        self.broadcast(GameServerMsg::State {
+            tick: self.tick,
+            snapshot: self.pong.snapshot(),
+        })
+        .await;
+        self.pong.winner().map(|winner| Outcome::Won {
+            winner,
+            forfeit: false,
+        })
+    }
+
+    /// Send `message` to all connected players.
+    async fn broadcast(&mut self, message: GameServerMsg) {
+        let [a, b] = &mut self.players;
+        let send = async |player: &mut Player| {
+            if let Some(sender) = &mut player.sender
+                && sender.send(message.clone()).await.is_err()
+            {
+                player.lose_stream();
+            }
+        };
+        tokio::join!(send(a), send(b));
+    }
+
+    async fn finish(mut self, outcome: Outcome) {
+        let (winner, forfeit) = match outcome {
+            Outcome::Won { winner, forfeit } => (Some(winner), forfeit),
+            Outcome::Abandoned => (None, false),
+        };
+        self.broadcast(GameServerMsg::Finished {
+            winner,
+            forfeit,
+            scores: self.pong.scores(),
+        })
+        .await;
+        tracing::info!(
+            game_session_id = self.id,
+            ?winner,
+            forfeit,
+            "Game finished"
+        );
+
+        let id = self.id;
+        let played = self.started.map(|started| {
+            let time_played = started.elapsed().as_secs() as i32;
+            let scores = self.pong.scores();
+            [Side::A, Side::B].map(|side| {
+                let result = PlayerResult {
+                    kills: scores[side.index()].into(),
+                    deaths: scores[side.other().index()].into(),
+                    time_played,
+                    won: winner.map(|winner| winner == side),
+                };
+                (self.players[side.index()].user_id, result)
+            })
+        });
+        let result = db::with_transaction(move |conn| {
+            for (user_id, result) in played.into_iter().flatten() {
+                crate::routers::game::record_game(
+                    conn,
+                    user_id,
+                    result,
+                    Some(id),
+                )?;
+            }
+            mark_state(conn, id, GameSessionState::Finished)
+        })
+        .await;
+        match result {
+            Ok(()) => crate::routers::game::invalidate_leaderboard(),
+            Err(err) => {
+                tracing::error!(%err, game_session_id = id, "Failed to record game");
+            }
+        }
+    }
+}
+
+/// Hand the [`StreamType::Game`] streams clients open to the game they have a
+/// seat in, replacing the stream of their seat.
+///
+/// Streams of users without a running game are dropped.
+pub fn accept_game_streams() {
+    StreamManager::global().set_incoming_handler(
+        StreamType::Game,
+        |user_id, stream| {
+            let seat = RUNNING.iter().find_map(|running| {
+                let side = [Side::A, Side::B]
+                    .into_iter()
+                    .find(|side| running.seats[side.index()].0 == user_id)?;
+                Some((running.events.clone(), side))
+            });
+            let Some((events, side)) = seat else {
+                tracing::debug!(user_id, "Dropped game stream without a game");
+                return;
+            };
+            let stream = Box::new(stream.into_typed());
+            tokio::spawn(async move {
+                let _ = events.send(Event::Opened { side, stream }).await;
+            });
+        },
+    );
+}
+
+/// Ask a player for a game stream and hand it to the game.
+async fn request_stream(
+    side: Side,
+    attempt: u32,
+    user_id: i32,
+    events: mpsc::Sender<Event>,
+) {
+    let stream = tokio::time::timeout(
+        RECONNECT_TIMEOUT,
+        StreamManager::global().request_stream(user_id, StreamType::Game),
+    )
+    .await;
+    let stream = match stream {
+        Ok(Ok(stream)) => Some(Box::new(stream)),
+        Ok(Err(err)) => {
+            tracing::debug!(user_id, error = %err, "Failed to open game stream");
+            None
+        }
+        Err(_) => {
+            tracing::debug!(user_id, "Game stream was not accepted in time");
+            None
+        }
+    };
+    let _ = events
+        .send(Event::Connected {
+            side,
+            attempt,
+            stream,
+        })
+        .await;
+}
+
+/// Forward the inputs of a player until their stream ends.
+async fn read_inputs(
+    side: Side,
+    generation: u32,
+    mut receiver: GameReceiver,
+    events: mpsc::Sender<Event>,
+) {
+    let mut limiter = StreamLimiter::new(INPUT_RATE);
+    while let Some(msg) = receiver.next().await {
+        let GameClientMsg::Input { seq, payload } = match msg {
+            Ok(msg) => msg,
+            Err(err) => {
+                tracing::debug!(?side, error = %err, "Invalid game input");
+                break;
+            }
+        };
+        match limiter.check() {
+            Admission::Allowed => {}
+            Admission::Limited { .. } => continue,
+            Admission::Abusive => {
+                tracing::warn!(
+                    ?side,
+                    "Closing game stream flooded with inputs"
+                );
+                break;
+            }
+        }
+        let input = Event::Input {
+            side,
+            generation,
+            seq,
+            direction: payload,
+        };
+        if events.send(input).await.is_err() {
+            // The game is over
+            return;
+        }
+    }
+    let _ = events.send(Event::Lost { side, generation }).await;
+}
+
+fn mark_state(
+    conn: &mut DbConn,
+    game_id: i32,
+    new_state: GameSessionState,
+) -> AppResult<()> {
+    use crate::schema::game_sessions::dsl::*;
+
+    diesel::update(game_sessions.find(game_id))
+        .set(state.eq(new_state))
+        .execute(conn)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tokio::io::AsyncWriteExt as _;
+
+    use super::*;
+    use crate::stream::testing::{
+        CLIENT_TIMEOUT, DEFAULT_MAX_FRAME, InvalidFrame, one_way_stream,
+    };
+
+    /// Run [read_inputs] on `receiver` until it reports the stream lost,
+    /// returns the `seq` of the forwarded inputs.
+    async fn forwarded(receiver: GameReceiver) -> Vec<u32> {
+        let (events, mut rx) = mpsc::channel(128);
+        tokio::spawn(read_inputs(Side::A, 1, receiver, events));
+        let mut seqs = Vec::new();
+        loop {
+            let event = tokio::time::timeout(CLIENT_TIMEOUT, rx.recv())
+                .await
+                .expect("the stream should be lost")
+                .expect("the reader should report");
+            match event {
+                Event::Input { side, seq, .. } => {
+                    assert_eq!(side, Side::A);
+                    seqs.push(seq);
+                }
+                Event::Lost { side, generation } => {
+                    assert_eq!((side, generation), (Side::A, 1));
+                    return seqs;
+                }
+                _ => panic!("unexpected event"),
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn flooding_inputs_loses_the_stream() {
+        let (mut send, receiver) = one_way_stream();
+        for seq in 1..=70 {
+            send.send(json!({
+                "type": "Input",
+                "data": { "seq": seq, "payload": "Up" },
+            }))
+            .await
+            .unwrap();
+        }
+        // The stream stays open, the flood alone closes it
+        let seqs = forwarded(receiver).await;
+        assert_eq!(seqs, (1..=60).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn oversized_frames_are_invalid() {
+        let (mut send, mut receiver) = one_way_stream::<GameClientMsg>();
+        let too_large = u32::try_from(DEFAULT_MAX_FRAME + 1).unwrap();
+        send.get_mut()
+            .write_all(&too_large.to_be_bytes())
+            .await
+            .unwrap();
+        let err = receiver.next().await.unwrap().unwrap_err();
+        assert!(
+            matches!(
+                err.downcast_ref(),
+                Some(InvalidFrame::TooLarge {
+                    max: DEFAULT_MAX_FRAME,
+                    ..
+                })
+            ),
+            "{err:?}"
+        );
+
+        let (mut send, receiver) = one_way_stream();
+        send.send(json!({
+            "type": "Input",
+            "data": { "seq": 1, "payload": "Down" },
+        }))
+        .await
+        .unwrap();
+        send.get_mut()
+            .write_all(&too_large.to_be_bytes())
+            .await
+            .unwrap();
+        assert_eq!(forwarded(receiver).await, [1]);
+    }
+}
@@ -11,17 +11,22 @@ mod auth;
 mod config;
 pub mod db;
 mod error;
+mod game;
 mod models;
 mod prelude;
 mod routers;
 mod schema;
 mod stream;
+#[cfg(test)]
+mod test_db;
 mod utils;
 mod validate;
+#[cfg(test)]
+mod wire_compat;
 
 pub use error::ApiError;
 
-use crate::config::{ServerConfig, TlsConfig};
+use crate::config::{CertSource, ServerConfig, TlsConfig};
 
 #[tokio::main]
 async fn main() -> ExitCode {
@@ -30,23 +35,47 @@ async fn main() -> ExitCode {
     let config = crate::config::get();
     let _guard = config.log.guard();
     crate::utils::limiter::periodic_rate_limit_report();
+    crate::auth::presence::periodic_flush();
+    crate::game::matchmaking::periodic_matching();
+    crate::game::session::accept_game_streams();
+    crate::utils::idempotency::periodic_cleanup();
+    crate::utils::gauges::periodic_sampling();
+    crate::utils::integrity::sweep_on_startup();
 
     tracing::info!("log level: {}", &config.log.filter_level);
+    tracing::info!("log targets: {}", crate::config::LOG_TARGETS.join(", "));
 
-    let mut router = routers::root()
-        .hoop(ForceHttps::new().https_port(config.listen_https_port))
-        .hoop(crate::auth::device_id_inserter_hoop);
+    let mut router = routers::root();
+    if !config.dev_insecure_http {
+        router =
+            router.hoop(ForceHttps::new().https_port(config.listen_https_port));
+    }
+    let mut router = router.hoop(crate::auth::device_id_inserter_hoop);
 
-    if let Some(tls) = &config.tls {
-        let acceptor = setup_acceptor_socket(&config, tls).await;
-        run_server(acceptor, router, config).await;
-    } else if let Some(domain) = &config.domain {
-        let acceptor =
-            setup_acme_acceptor_socket(&config, domain, &mut router).await;
-        run_server(acceptor, router, &config).await;
-    } else {
-        eprintln!("⚠️  No TLS configuration and no domain provided. Exiting.");
-        return ExitCode::FAILURE;
+    let https_port = config.listen_https_port;
+    let source = config.cert_source().expect("checked in config::init");
+    match source {
+        CertSource::DevPlain => {
+            eprintln!(
+                "⚠️  dev_insecure_http is enabled: serving plain HTTP without TLS and \
+                 Secure cookies, WebTransport is unavailable. NEVER use this in production!"
+            );
+            tracing::warn!("dev_insecure_http is enabled, serving plain HTTP");
+            let acceptor = setup_plain_acceptor_socket(config).await;
+            let port = config.listen_http_port;
+            run_server(acceptor, router, config, source, "http", port).await;
+        }
+        CertSource::TlsFiles(tls) => {
+            let acceptor = setup_acceptor_socket(config, tls).await;
+            run_server(acceptor, router, config, source, "https", https_port)
+                .await;
+        }
+        CertSource::Acme { domain } => {
+            let acceptor =
+                setup_acme_acceptor_socket(config, domain, &mut router).await;
+            run_server(acceptor, router, config, source, "https", https_port)
+                .await;
+        }
     }
 
     ExitCode::SUCCESS
@@ -55,7 +84,7 @@ async fn main() -> ExitCode {
 async fn setup_acceptor_socket(
     cfg: &ServerConfig,
     tls: &TlsConfig,
-) -> impl Acceptor {
+) -> impl Acceptor + use<> {
     // Load TLS certificates for https from files
     let (cert, key) =
         tokio::join!(tokio::fs::read(&tls.cert), tokio::fs::read(&tls.key));
@@ -75,14 +104,13 @@ async fn setup_acceptor_socket(
         (cfg.listen_addr.clone(), cfg.listen_https_port),
     );
     // Combine HTTP, HTTPS, and HTTP3 listeners into a single acceptor
-    let acceptor = http3.join(https).join(http).bind().await;
-    acceptor
+    http3.join(https).join(http).bind().await
 }
 
 async fn setup_acme_acceptor_socket(
     cfg: &ServerConfig,
-    domain: &String,
-    mut router: &mut Router,
+    domain: &str,
+    router: &mut Router,
 ) -> impl Acceptor + use<> {
     // Set up a TCP listener on port 80 for HTTP
     let http =
@@ -92,33 +120,55 @@ async fn setup_acme_acceptor_socket(
             .acme() // Enable ACME for automatic SSL certificate management
             .cache_path("temp/letsencrypt") // Path to store the certificate cache
             .add_domain(domain)
-            .http01_challenge(&mut router) // Add routes to handle ACME challenge requests
-            .quinn((cfg.listen_addr.clone(), cfg.listen_https_port)); // Enable QUIC/HTTP3 support
+            .http01_challenge(router) // Add routes to handle ACME challenge requests
+            // Enable QUIC/HTTP3 support with the same certificate, salvo
+            // advertises it with an Alt-Svc header like for the files
+            .quinn((cfg.listen_addr.clone(), cfg.listen_https_port));
     // Combine HTTP, HTTPS, and HTTP3 listeners into a single acceptor
-    let acceptor = https.join(http).bind().await;
-    acceptor
+    https.join(http).bind().await
+}
+
+/// Plain HTTP only, for `dev_insecure_http`.
+async fn setup_plain_acceptor_socket(
+    cfg: &ServerConfig,
+) -> impl Acceptor + use<> {
+    TcpListener::new((cfg.listen_addr.clone(), cfg.listen_http_port))
+        .bind()
+        .await
 }
 
 // generic helper to enable using different acceptor types
-async fn run_server<A>(acceptor: A, router: Router, config: &ServerConfig)
-where
+async fn run_server<A>(
+    acceptor: A,
+    router: Router,
+    config: &ServerConfig,
+    source: CertSource<'_>,
+    scheme: &str,
+    port: u16,
+) where
     A: Acceptor + Send,
 {
     let server = Server::new(acceptor);
     tokio::spawn(shutdown_signal(server.handle()));
+    #[cfg(unix)]
+    tokio::spawn(reload_signal());
 
     let listen_addr = &config.listen_addr;
-    let port = config.listen_https_port;
     eprintln!(
-        "🚀 Server Listening on https://{}:{port}/",
+        "🚀 Server Listening on {scheme}://{}:{port}/",
         listen_addr.replace("0.0.0.0", "127.0.0.1"),
     );
+    eprintln!("🔌 Protocols: {}", source.protocols());
     eprintln!(
-        "📖 Open API Pages:\nhttps://{0}:{port}/scalar\nhttps://{0}:{port}/swagger-ui\nhttps://{0}:{port}/rapidoc\nhttps://{0}:{port}/redoc",
+        "📖 Open API Pages:\n{scheme}://{0}:{port}/scalar\n{scheme}://{0}:{port}/swagger-ui\n{scheme}://{0}:{port}/rapidoc\n{scheme}://{0}:{port}/redoc",
         listen_addr.replace("0.0.0.0", "127.0.0.1")
     );
 
-    let service = Service::new(router).catcher(Catcher::default());
+    let mut service =
+        Service::new(router).catcher(Catcher::new(crate::error::error_catcher));
+    if let Some(cors) = &config.cors {
+        service = service.hoop(crate::utils::cors::CorsHoop::new(cors));
+    }
 
     server.serve(service).await;
 }
@@ -145,5 +195,122 @@ async fn shutdown_signal(handle: ServerHandle) {
         _ = ctrl_c => tracing::info!("ctrl_c signal received"),
         _ = terminate => tracing::info!("terminate signal received"),
     }
+    // Long-lived connections would hold up the graceful stop
+    crate::stream::StreamManager::global().close_all(
+        crate::stream::CloseCode::ServerShutdown,
+        "Server shutting down",
+    );
     handle.stop_graceful(std::time::Duration::from_secs(60));
 }
+
+/// Reload the log filter from the config on SIGHUP.
+#[cfg(unix)]
+async fn reload_signal() {
+    let mut hangup = signal::unix::signal(signal::unix::SignalKind::hangup())
+        .expect("failed to install signal handler");
+    while hangup.recv().await.is_some() {
+        tracing::info!("hangup signal received, reloading the log filter");
+        crate::config::reload_log_filter();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use figment::Figment;
+    use figment::providers::{Format as _, Toml};
+    use salvo::http::uri::Scheme;
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+    use super::*;
+
+    /// A config listening on free loopback ports, with `toml` added.
+    fn server_config(toml: &str) -> ServerConfig {
+        Figment::new()
+            .merge(Toml::string(
+                "database_url = \"unused\"\nlisten_addr = \"127.0.0.1\"\n\
+                 listen_http_port = 0\nlisten_https_port = 0\n[log]",
+            ))
+            .merge(Toml::string(toml))
+            .extract()
+            .unwrap()
+    }
+
+    /// The address of the listener of `acceptor` that serves `scheme`.
+    fn address_of(
+        acceptor: &impl Acceptor,
+        scheme: Scheme,
+        version: salvo::http::Version,
+    ) -> std::net::SocketAddr {
+        acceptor
+            .holdings()
+            .iter()
+            .find(|holding| {
+                holding.http_scheme == scheme
+                    && holding.http_versions.contains(&version)
+            })
+            .and_then(|holding| holding.local_addr.clone().into_std())
+            .expect("the acceptor should listen for the scheme")
+    }
+
+    /// GET / over plain HTTP/1.1, returns the raw response.
+    async fn get(addr: std::net::SocketAddr) -> String {
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn plain_http_is_served_on_the_http_port() {
+        let config = server_config("dev_insecure_http = true");
+        assert!(matches!(config.cert_source(), Some(CertSource::DevPlain)));
+
+        let acceptor = setup_plain_acceptor_socket(&config).await;
+        let addr =
+            address_of(&acceptor, Scheme::HTTP, salvo::http::Version::HTTP_11);
+        tokio::spawn(Server::new(acceptor).serve(Router::new().get(hello)));
+
+        let response = get(addr).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(!response.to_lowercase().contains("alt-svc"), "{response}");
+        assert!(response.ends_with("plain"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn http3_is_advertised_with_its_port() {
+        let config = server_config(
+            "[tls]\ncert = \"certs/cert.pem\"\nkey = \"certs/key.pem\"",
+        );
+        let Some(CertSource::TlsFiles(tls)) = config.cert_source() else {
+            panic!("the tls files should be used");
+        };
+
+        let acceptor = setup_acceptor_socket(&config, tls).await;
+        let http3 =
+            address_of(&acceptor, Scheme::HTTPS, salvo::http::Version::HTTP_3);
+        let http =
+            address_of(&acceptor, Scheme::HTTP, salvo::http::Version::HTTP_11);
+        tokio::spawn(Server::new(acceptor).serve(Router::new().get(hello)));
+
+        let response = get(http).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        let port = http3.port();
+        assert!(
+            response
+                .to_lowercase()
+                .contains(&format!("alt-svc: h3=\":{port}\"")),
+            "{response}"
+        );
+    }
+
+    #[handler]
+    fn hello() -> &'static str {
+        "plain"
+    }
+}
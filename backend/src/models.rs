@@ -1,5 +1,9 @@
 use chrono::NaiveDateTime;
+use diesel::deserialize::{FromSql, FromSqlRow};
+use diesel::expression::AsExpression;
 use diesel::prelude::*;
+use diesel::serialize::ToSql;
+use diesel::sql_types::Text;
 use diesel_autoincrement_new_struct::{NewInsertable, apply};
 use salvo::oapi::ToSchema;
 use serde::Serialize;
@@ -8,6 +12,7 @@ use crate::auth::session_token::SessionTokenHash;
 
 #[apply(NewInsertable!)]
 #[derive(Queryable, Selectable, ToSchema, Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
 #[diesel(table_name = crate::schema::users)]
 #[diesel(check_for_backend(diesel::sqlite::Sqlite))]
 pub struct User {
@@ -21,6 +26,12 @@ pub struct User {
     #[serde(skip)]
     pub password_hash: String,
     pub created_at: NaiveDateTime,
+    pub is_admin: bool,
+    /// Last REST activity, flushed periodically, see [`crate::auth::presence`]
+    pub last_seen: Option<NaiveDateTime>,
+    /// Set while an admin locks the account, independent of bans
+    pub locked_at: Option<NaiveDateTime>,
+    pub lock_reason: Option<String>,
 }
 
 #[apply(NewInsertable!)]
@@ -33,12 +44,19 @@ pub struct Session {
     pub user_id: i32,
     pub token_hash: SessionTokenHash,
     pub device_id: String,
+    /// The name shown to the user, either `device_name_auto` or a name the
+    /// user picked
     pub device_name: Option<String>,
     pub ip_address: Option<String>,
     pub created_at: NaiveDateTime,
     pub refreshed_at: NaiveDateTime,
     pub last_used_at: NaiveDateTime,
     pub last_authenticated_at: NaiveDateTime,
+    /// Derived from the User-Agent at the last rotation
+    pub device_name_auto: Option<String>,
+    /// Replaced by `token_hash` at the last rotation, the token must not be
+    /// presented again
+    pub prev_token_hash: Option<SessionTokenHash>,
 }
 
 #[apply(NewInsertable!)]
@@ -54,6 +72,293 @@ pub struct TwoFaRecoveryCode {
     pub created_at: NaiveDateTime,
 }
 
+/// A recorded [`SecurityEvent`](crate::auth::audit::SecurityEvent).
+#[apply(NewInsertable!)]
+#[derive(Queryable, Selectable, Associations, Debug, Clone)]
+#[diesel(table_name = crate::schema::security_events)]
+#[diesel(belongs_to(User))]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct SecurityEventEntry {
+    pub id: i32,
+    pub user_id: i32,
+    pub event_type: String,
+    /// JSON object with details of the event
+    pub metadata: Option<String>,
+    pub ip_address: Option<String>,
+    pub device_name: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+/// A device on which 2FA is skipped at login until `expires_at`.
+#[apply(NewInsertable!)]
+#[derive(Queryable, Selectable, Associations, Debug, Clone)]
+#[diesel(table_name = crate::schema::trusted_devices)]
+#[diesel(belongs_to(User))]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct TrustedDevice {
+    pub id: i32,
+    pub user_id: i32,
+    pub device_id: String,
+    pub token_hash: SessionTokenHash,
+    pub created_at: NaiveDateTime,
+    pub last_used_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+}
+
+/// The stored response of a request, see [`crate::utils::idempotency`].
+///
+/// Only the columns a retry is answered from, the key is looked up by
+/// `user_id` and `key`.
+#[derive(Queryable, Selectable, Debug, Clone)]
+#[diesel(table_name = crate::schema::idempotency_keys)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct IdempotencyKey {
+    pub request_hash: Vec<u8>,
+    /// `None` while the first request is still being handled
+    pub status: Option<i32>,
+    pub response_body: Option<Vec<u8>>,
+}
+
+/// A key claimed by the first request using it.
+#[derive(Insertable, Debug, Clone)]
+#[diesel(table_name = crate::schema::idempotency_keys)]
+pub struct NewIdempotencyKey {
+    pub user_id: i32,
+    pub key: String,
+    pub request_hash: Vec<u8>,
+    pub status: Option<i32>,
+    pub response_body: Option<Vec<u8>>,
+    pub created_at: NaiveDateTime,
+}
+
+/// A feature toggled at runtime, see [`crate::utils::flags`].
+#[derive(Queryable, Selectable, Insertable, AsChangeset, Debug, Clone)]
+#[diesel(table_name = crate::schema::feature_flags)]
+#[diesel(primary_key(key))]
+#[diesel(treat_none_as_null = true)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct FeatureFlag {
+    pub key: String,
+    pub enabled: bool,
+    /// JSON settings of the feature
+    pub payload: Option<String>,
+    pub updated_at: NaiveDateTime,
+}
+
+#[apply(NewInsertable!)]
+#[derive(
+    Queryable, Selectable, Associations, ToSchema, Serialize, Debug, Clone,
+)]
+#[serde(rename_all = "camelCase")]
+#[diesel(table_name = crate::schema::game_history)]
+#[diesel(belongs_to(User))]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct GameHistory {
+    pub id: i32,
+    pub user_id: i32,
+    pub kills: i32,
+    /// Duration of the game in seconds
+    pub time_played: i32,
+    pub played_at: NaiveDateTime,
+    /// The server-side game, `None` for practice games
+    pub game_session_id: Option<i32>,
+    /// Recorded by the server, only verified games count for the stats and
+    /// the leaderboard
+    pub verified: bool,
+    /// Kills the client submitted for a verified game
+    pub reported_kills: Option<i32>,
+    /// Duration the client submitted for a verified game
+    pub reported_time_played: Option<i32>,
+    /// Points the opponent scored, 0 for practice games
+    pub deaths: i32,
+    /// Whether the player won, `false` also for practice games and games
+    /// without a winner
+    pub won: bool,
+}
+
+/// Lifetime totals of a user, updated on every recorded game.
+///
+/// The row is created together with the first recorded game.
+#[derive(
+    Queryable,
+    Selectable,
+    Insertable,
+    Associations,
+    ToSchema,
+    Serialize,
+    Debug,
+    Clone,
+)]
+#[serde(rename_all = "camelCase")]
+#[diesel(table_name = crate::schema::user_stats)]
+#[diesel(primary_key(user_id))]
+#[diesel(belongs_to(User))]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct UserStats {
+    pub user_id: i32,
+    pub games_played: i32,
+    pub total_kills: i32,
+    /// Total time played in seconds
+    pub total_time_played: i32,
+    pub last_game_kills: i32,
+    /// Duration of the last game in seconds
+    pub last_game_time_played: i32,
+    pub last_game_at: NaiveDateTime,
+    pub wins: i32,
+    /// Games the opponent won, games without a winner are neither won nor
+    /// lost
+    pub losses: i32,
+    pub total_deaths: i32,
+}
+
+/// A ban issued by an admin. It is in effect until `until` (forever if
+/// `None`) unless it was lifted early.
+#[apply(NewInsertable!)]
+#[derive(
+    Queryable, Selectable, Associations, ToSchema, Serialize, Debug, Clone,
+)]
+#[serde(rename_all = "camelCase")]
+#[diesel(table_name = crate::schema::user_bans)]
+#[diesel(belongs_to(User))]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct UserBan {
+    pub id: i32,
+    pub user_id: i32,
+    /// The admin who issued the ban, `None` if their account was deleted
+    pub banned_by: Option<i32>,
+    pub reason: String,
+    pub until: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+    pub lifted_at: Option<NaiveDateTime>,
+}
+
+/// An invitation to play, see [`crate::routers::game`].
+#[apply(NewInsertable!)]
+#[derive(Queryable, Selectable, ToSchema, Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+#[diesel(table_name = crate::schema::game_invites)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct GameInvite {
+    pub id: i32,
+    pub from_user_id: i32,
+    pub to_user_id: i32,
+    pub status: InviteStatus,
+    pub created_at: NaiveDateTime,
+    /// A pending invite can't be accepted after this
+    pub expires_at: NaiveDateTime,
+}
+
+/// Stored as the lowercase variant name.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    FromSqlRow,
+    AsExpression,
+    ToSchema,
+    Serialize,
+    strum::IntoStaticStr,
+    strum::EnumString,
+)]
+#[diesel(sql_type = Text)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum InviteStatus {
+    Pending,
+    Accepted,
+    Declined,
+    /// Set lazily once an expired invite is answered
+    Expired,
+}
+
+/// A game between two players, see [`crate::game::matchmaking`].
+#[apply(NewInsertable!)]
+#[derive(Queryable, Selectable, ToSchema, Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+#[diesel(table_name = crate::schema::game_sessions)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct GameSession {
+    pub id: i32,
+    /// `None` once the player deleted their account
+    pub player_a: Option<i32>,
+    /// `None` once the player deleted their account
+    pub player_b: Option<i32>,
+    pub state: GameSessionState,
+    pub created_at: NaiveDateTime,
+}
+
+/// Stored as the lowercase variant name.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    FromSqlRow,
+    AsExpression,
+    ToSchema,
+    Serialize,
+    strum::IntoStaticStr,
+    strum::EnumString,
+)]
+#[diesel(sql_type = Text)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum GameSessionState {
+    /// The players were matched, the game didn't start yet
+    Created,
+    Running,
+    Finished,
+}
+
+impl<DB> ToSql<Text, DB> for GameSessionState
+where
+    DB: diesel::backend::Backend,
+    str: ToSql<Text, DB>,
+{
+    fn to_sql<'b>(
+        &'b self,
+        out: &mut diesel::serialize::Output<'b, '_, DB>,
+    ) -> diesel::serialize::Result {
+        <&'static str>::from(self).to_sql(out)
+    }
+}
+
+impl<DB> FromSql<Text, DB> for GameSessionState
+where
+    DB: diesel::backend::Backend,
+    String: FromSql<Text, DB>,
+{
+    fn from_sql(bytes: DB::RawValue<'_>) -> diesel::deserialize::Result<Self> {
+        Ok(String::from_sql(bytes)?.parse()?)
+    }
+}
+
+impl<DB> ToSql<Text, DB> for InviteStatus
+where
+    DB: diesel::backend::Backend,
+    str: ToSql<Text, DB>,
+{
+    fn to_sql<'b>(
+        &'b self,
+        out: &mut diesel::serialize::Output<'b, '_, DB>,
+    ) -> diesel::serialize::Result {
+        <&'static str>::from(self).to_sql(out)
+    }
+}
+
+impl<DB> FromSql<Text, DB> for InviteStatus
+where
+    DB: diesel::backend::Backend,
+    String: FromSql<Text, DB>,
+{
+    fn from_sql(bytes: DB::RawValue<'_>) -> diesel::deserialize::Result<Self> {
+        Ok(String::from_sql(bytes)?.parse()?)
+    }
+}
+
 impl Session {
     pub fn rotate(
         &self,
@@ -63,20 +368,38 @@ impl Session {
         ip_address: Option<String>,
     ) -> Self {
         let now = chrono::Utc::now().naive_utc();
+        let device_name_auto =
+            device_name.or_else(|| self.device_name_auto.clone());
 
         Self {
             id: self.id,
             user_id: self.user_id,
             token_hash,
             device_id,
-            device_name: device_name.or_else(|| self.device_name.clone()),
+            // A name picked by the user is kept
+            device_name: if self.has_custom_name() {
+                self.device_name.clone()
+            } else {
+                device_name_auto.clone()
+            },
             ip_address: ip_address.or_else(|| self.ip_address.clone()),
             created_at: self.created_at,
             refreshed_at: now,
             last_used_at: now,
             last_authenticated_at: self.last_authenticated_at,
+            device_name_auto,
+            prev_token_hash: Some(self.token_hash),
         }
     }
+
+    pub fn has_custom_name(&self) -> bool {
+        self.device_name != self.device_name_auto
+    }
+
+    /// When the access token issued at the last rotation expires.
+    pub fn access_valid_until(&self) -> NaiveDateTime {
+        self.refreshed_at + crate::auth::config().access_expiry()
+    }
 }
 
 impl NewSession {
@@ -92,12 +415,91 @@ impl NewSession {
             user_id,
             token_hash,
             device_id,
-            device_name,
+            device_name: device_name.clone(),
             ip_address,
             created_at: now,
             refreshed_at: now,
             last_used_at: now,
             last_authenticated_at: now,
+            device_name_auto: device_name,
+            prev_token_hash: None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use diesel::sql_types::Text;
+
+    use super::*;
+    use crate::auth::session_token::SessionToken;
+    use crate::db;
+
+    #[derive(QueryableByName)]
+    struct Name {
+        #[diesel(sql_type = Text)]
+        name: String,
+    }
+
+    /// Load every model from a migrated database, so a model or `schema.rs`
+    /// drifting from the migrations fails here instead of at runtime.
+    #[tokio::test]
+    async fn schema_matches_models() {
+        crate::test_db::with_db(|| async {
+            let conn = &mut db::get().unwrap();
+            let user = crate::test_db::create_user(conn, "schema");
+            diesel::insert_into(crate::schema::sessions::table)
+                .values(NewSession::new(
+                    user.id,
+                    SessionToken::generate().to_hash(),
+                    "device".into(),
+                    None,
+                    None,
+                ))
+                .execute(conn)
+                .unwrap();
+
+            let mut checked = Vec::new();
+            macro_rules! check {
+                ($($table:ident => $model:ty),* $(,)?) => {$(
+                    crate::schema::$table::table
+                        .select(<$model>::as_select())
+                        .load::<$model>(conn)
+                        .unwrap_or_else(|err| {
+                            panic!("{}: {err}", stringify!($model))
+                        });
+                    checked.push(stringify!($table));
+                )*};
+            }
+            check! {
+                users => User,
+                sessions => Session,
+                two_fa_recovery_codes => TwoFaRecoveryCode,
+                security_events => SecurityEventEntry,
+                trusted_devices => TrustedDevice,
+                idempotency_keys => IdempotencyKey,
+                feature_flags => FeatureFlag,
+                game_history => GameHistory,
+                user_stats => UserStats,
+                user_bans => UserBan,
+                game_invites => GameInvite,
+                game_sessions => GameSession,
+            }
+
+            let mut tables: Vec<String> = diesel::sql_query(
+                "SELECT name FROM sqlite_master WHERE type = 'table'
+                 AND name NOT LIKE 'sqlite_%'
+                 AND name != '__diesel_schema_migrations'",
+            )
+            .load::<Name>(conn)
+            .unwrap()
+            .into_iter()
+            .map(|table| table.name)
+            .collect();
+            tables.sort();
+            checked.sort();
+            assert_eq!(tables, checked, "every table has a model");
+        })
+        .await;
+    }
+}
@@ -1,5 +1,6 @@
 pub use diesel::prelude::*;
-pub use salvo::oapi::{ToSchema, endpoint, extract::JsonBody};
+pub use salvo::oapi::extract::{JsonBody, PathParam, QueryParam};
+pub use salvo::oapi::{ToSchema, endpoint};
 pub use salvo::prelude::*;
 pub use serde::{Deserialize, Serialize};
 pub use validator::Validate;
@@ -8,6 +9,7 @@ pub use crate::auth::{DepotAuthExt as _, RouterAuthExt as _};
 pub use crate::db::{self, DbConn};
 pub use crate::error::ApiError;
 pub use crate::utils::limiter::{RateLimit, RouterRateLimitExt as _};
+pub use crate::utils::timeout::RouterTimeoutExt as _;
 
 pub type AppResult<T> = Result<T, ApiError>;
 pub type JsonResult<T> = Result<Json<T>, ApiError>;
@@ -3,30 +3,63 @@ use salvo::oapi::security::{ApiKey, ApiKeyValue, SecurityScheme};
 use salvo::routing::MethodFilter;
 
 use crate::prelude::*;
+use crate::utils::body_limit::BodyLimit;
+use crate::utils::flags::feature_gate;
+use crate::utils::logger::Logger;
+use crate::utils::timeout::RequestTimeout;
 
+pub mod admin;
+pub mod game;
+pub mod health;
+pub mod stream;
 pub mod users;
 
 const OPENAPI_JSON: &str = "/api-doc/openapi.json";
+/// Time limit of API requests, unless their router sets its own
+const DEFAULT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+/// Polled by probes and the metrics scraper, logging them would drown out
+/// the requests of users
+const UNLOGGED_PATHS: &[&str] = &["/healthz", "/readyz", "/metrics"];
 
 pub fn root() -> Router {
-    let api_routes = Router::with_path("api")
-        .hoop(crate::utils::logger::Logger)
-        .hoop(Timeout::new(std::time::Duration::from_secs(30)))
+    let api_routes = api_middleware("api")
+        .hoop(BodyLimit(crate::config::get().limits.json_body_bytes()))
         .append(&mut vec![
             crate::auth::router("auth"),
             crate::auth::user_router("user"),
             users::router("users"),
+            game::router("game"),
+            admin::router("admin"),
+            stream::router("stream"),
         ]);
     // TODO test whether allowing only CONNECT is sufficient
-    let wt_route = Router::with_path("api/wt")
-        .hoop(crate::utils::logger::Logger)
-        .requires_user_login()
-        .user_rate_limit(&RateLimit::per_minute(10))
+    let wt_route = api_middleware("api/wt")
+        // The handler runs for as long as the connection is open
+        .no_timeout()
+        // Connections without a valid access token are bound later,
+        // see /api/stream/bind
+        .hoop(crate::auth::optional_access_hoop)
+        .ip_rate_limit(&RateLimit::per_minute(30))
         .filter(MethodFilter::new(Method::CONNECT))
         .goal(crate::stream::connect_stream);
-    let api_routes = Router::new().push(api_routes).push(wt_route);
+    // Fallback for clients that can't use WebTransport, dark until the
+    // client speaks it
+    let ws_route = api_middleware("api/ws")
+        .hoop(feature_gate(crate::stream::WEBSOCKET_FALLBACK_FLAG))
+        .hoop(crate::auth::optional_access_hoop)
+        .ip_rate_limit(&RateLimit::per_minute(30))
+        .get(crate::stream::connect_websocket);
+    let api_routes =
+        Router::new().push(api_routes).push(wt_route).push(ws_route);
     let doc = openapi_doc(&api_routes);
-    let router = Router::new().push(api_routes);
+    let mut router = Router::new()
+        .hoop(crate::utils::request_id::request_id_hoop)
+        .hoop(Logger::new().excluding(UNLOGGED_PATHS))
+        .push(health::router())
+        .push(api_routes);
+    if let Some(prometheus) = admin::prometheus_router() {
+        router = router.push(prometheus);
+    }
     router
         .unshift(doc.into_router(OPENAPI_JSON))
         .unshift(Scalar::new(OPENAPI_JSON).into_router("scalar"))
@@ -35,6 +68,14 @@ pub fn root() -> Router {
         .unshift(ReDoc::new(OPENAPI_JSON).into_router("redoc"))
 }
 
+/// Router at `path` with the hoops shared by all API routes.
+///
+/// Request ids, logging and the error envelope are applied to the whole
+/// service, see [`root`] and [`crate::error::error_catcher`].
+fn api_middleware(path: &str) -> Router {
+    Router::with_path(path).hoop(RequestTimeout(DEFAULT_TIMEOUT))
+}
+
 fn openapi_doc(to_document: &Router) -> OpenApi {
     OpenApi::new("Transcendence API", "0.0.1")
         .add_security_scheme(
@@ -66,5 +107,13 @@ fn openapi_doc(to_document: &Router) -> OpenApi {
                 Short-lived (a few minutes) and rotated on each refresh."),
             )),
         )
-        .merge_router(&to_document)
+        .add_security_scheme(
+            "admin_token",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::with_description(
+                admin::ADMIN_TOKEN_HEADER,
+                "Operator token configured as admin_token in the server config. \
+                 The admin endpoints are disabled if it is not set.",
+            ))),
+        )
+        .merge_router(to_document)
 }
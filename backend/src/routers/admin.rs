@@ -0,0 +1,855 @@
+//! Provides operator routes and handlers.
+//!
+//! With this you can inspect the request metrics and WebTransport connections,
+//! toggle feature flags, sweep orphaned rows, announce messages and moderate
+//! users. The metrics, connections, flags, maintenance and announcements are
+//! guarded by the configured admin token, user moderation by
+//! the `is_admin` flag of the logged in user. Admins are promoted directly in
+//! the database.
+//!
+
+use std::borrow::Cow;
+
+use chrono::NaiveDateTime;
+use salvo::oapi::SecurityRequirement;
+use validator::{ValidationError, ValidationErrors};
+
+use crate::auth::AuthError;
+use crate::auth::audit::{self, AuditContext, SecurityEvent};
+use crate::models::{FeatureFlag, NewUserBan, User, UserBan};
+use crate::prelude::*;
+use crate::stream::{CloseCode, ConnectionStats, Notification, StreamManager};
+use crate::utils::flags;
+use crate::utils::integrity::{self, SweepReport};
+use crate::utils::metrics::{self, MetricsSnapshot};
+
+pub const ADMIN_TOKEN_HEADER: &str = "x-admin-token";
+
+const DEFAULT_USERS_LIMIT: i64 = 50;
+const MAX_USERS_LIMIT: i64 = 200;
+
+pub fn router(path: &str) -> Router {
+    // Rate limited before the token is checked, so it can't be guessed
+    let operator = Router::new()
+        .ip_rate_limit(&RateLimit::per_minute(30))
+        .hoop(admin_token_hoop)
+        .oapi_security(SecurityRequirement::new(
+            "admin_token",
+            Vec::<String>::new(),
+        ))
+        .push(Router::with_path("metrics").get(get_metrics))
+        .push(Router::with_path("streams").get(get_streams))
+        .push(
+            Router::with_path("log-level")
+                .get(get_log_level)
+                .put(set_log_level),
+        )
+        .push(Router::with_path("flags/{key}").get(get_flag).put(set_flag))
+        .push(
+            Router::with_path("maintenance/sweep")
+                .ip_rate_limit(&RateLimit::per_minute(5))
+                .with_timeout(std::time::Duration::from_secs(5 * 60))
+                .post(sweep),
+        )
+        .push(
+            Router::with_path("chat/announce")
+                .ip_rate_limit(&RateLimit::per_minute(5))
+                .post(announce),
+        );
+    Router::with_path(path)
+        .oapi_tag("admin")
+        .push(operator)
+        .push(
+            Router::with_path("users")
+                .requires_admin()
+                .user_rate_limit(&RateLimit::per_minute(60))
+                .get(list_users)
+                .push(Router::with_path("{id}/ban").post(ban_user))
+                .push(Router::with_path("{id}/unban").post(unban_user))
+                .push(Router::with_path("{id}/lock").post(lock_user))
+                .push(Router::with_path("{id}/unlock").post(unlock_user)),
+        )
+}
+
+/// Serves the Prometheus metrics at `/metrics` if enabled in the config.
+pub fn prometheus_router() -> Option<Router> {
+    crate::config::get()
+        .metrics_enabled
+        .then(|| Router::with_path("metrics").get(get_prometheus_metrics))
+}
+
+/// Only lets requests with the configured admin token pass.
+///
+/// Responds with 404 if no admin token is configured.
+#[handler]
+fn admin_token_hoop(req: &Request, res: &mut Response, ctrl: &mut FlowCtrl) {
+    let Some(expected) = crate::config::get().admin_token.as_deref() else {
+        res.render(StatusError::not_found());
+        ctrl.skip_rest();
+        return;
+    };
+
+    // Compare hashes to not leak the token through timing
+    let valid = req.header::<&str>(ADMIN_TOKEN_HEADER).is_some_and(|token| {
+        blake3::hash(token.as_bytes()) == blake3::hash(expected.as_bytes())
+    });
+    if !valid {
+        ApiError::Auth(AuthError::InvalidAdminToken).render(res);
+        ctrl.skip_rest();
+    }
+}
+
+/// Retrieve the request metrics
+///
+/// Requires the admin token.
+#[endpoint]
+fn get_metrics() -> JsonResult<MetricsSnapshot> {
+    json_ok(metrics::snapshot())
+}
+
+#[derive(Debug, Default, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct StreamTotals {
+    connections: usize,
+    streams_opened: u64,
+    streams_accepted: u64,
+    bytes_sent: u64,
+    bytes_received: u64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct StreamsOverview {
+    totals: StreamTotals,
+    /// Longest-connected first
+    connections: Vec<ConnectionStats>,
+}
+
+/// Retrieve the registered WebTransport connections
+///
+/// Requires the admin token. Connections waiting to be bound are not
+/// included.
+#[endpoint]
+fn get_streams() -> JsonResult<StreamsOverview> {
+    let mut connections = StreamManager::global().stats();
+    connections.sort_by_key(|stats| std::cmp::Reverse(stats.connected_secs));
+    let totals = connections.iter().fold(
+        StreamTotals {
+            connections: connections.len(),
+            ..Default::default()
+        },
+        |mut totals, stats| {
+            totals.streams_opened += stats.streams_opened;
+            totals.streams_accepted += stats.streams_accepted;
+            totals.bytes_sent += stats.bytes_sent;
+            totals.bytes_received += stats.bytes_received;
+            totals
+        },
+    );
+    json_ok(StreamsOverview {
+        totals,
+        connections,
+    })
+}
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct LogLevel {
+    /// Tracing filter directives, e.g. `info,transcendence_backend=debug`
+    filter: String,
+}
+
+/// Retrieve the log filter in effect
+///
+/// Requires the admin token.
+#[endpoint]
+fn get_log_level() -> JsonResult<LogLevel> {
+    json_ok(LogLevel {
+        filter: crate::config::current_log_filter().unwrap_or_default(),
+    })
+}
+
+/// Change the log filter until the next restart
+///
+/// Requires the admin token. Sending SIGHUP to the server applies
+/// `log.filter_level` of the config again.
+#[endpoint]
+fn set_log_level(json: JsonBody<LogLevel>) -> JsonResult<LogLevel> {
+    let LogLevel { filter } = json.into_inner();
+    if let Err(err) = crate::config::set_log_filter(&filter) {
+        let mut errs = ValidationErrors::new();
+        errs.add(
+            "filter",
+            ValidationError::new("log_filter")
+                .with_message(Cow::Owned(format!("Invalid filter: {err}"))),
+        );
+        return Err(errs.into());
+    }
+    tracing::info!(filter, "Log filter changed");
+    json_ok(LogLevel {
+        filter: crate::config::current_log_filter().unwrap_or_default(),
+    })
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct FeatureFlagInfo {
+    key: String,
+    enabled: bool,
+    payload: Option<serde_json::Value>,
+    /// `None` for flags that were never set, they are off
+    updated_at: Option<NaiveDateTime>,
+}
+
+impl From<FeatureFlag> for FeatureFlagInfo {
+    fn from(flag: FeatureFlag) -> Self {
+        Self {
+            key: flag.key,
+            enabled: flag.enabled,
+            payload: flag
+                .payload
+                .and_then(|payload| serde_json::from_str(&payload).ok()),
+            updated_at: Some(flag.updated_at),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct FlagInput {
+    enabled: bool,
+    /// Settings of the feature, any JSON value
+    #[serde(default)]
+    payload: Option<serde_json::Value>,
+}
+
+fn flag_key(key: &str) -> Result<(), ValidationErrors> {
+    let valid = (1..=64).contains(&key.len())
+        && key.chars().all(|c| {
+            c.is_ascii_lowercase() || c.is_ascii_digit() || "_-.".contains(c)
+        });
+    if valid {
+        return Ok(());
+    }
+    let mut errs = ValidationErrors::new();
+    errs.add(
+        "key",
+        ValidationError::new("invalid_chars").with_message(Cow::Borrowed(
+            "Must be 1 to 64 lowercase letters, digits, '_', '-' or '.'.",
+        )),
+    );
+    Err(errs)
+}
+
+/// Retrieve a feature flag
+///
+/// Requires the admin token. Flags that were never set are off.
+#[endpoint]
+fn get_flag(key: PathParam<String>) -> JsonResult<FeatureFlagInfo> {
+    let key = key.into_inner();
+    flag_key(&key)?;
+    let flag = flags::load(&mut db::get()?, &key)?;
+    json_ok(flag.map_or(
+        FeatureFlagInfo {
+            key,
+            enabled: false,
+            payload: None,
+            updated_at: None,
+        },
+        FeatureFlagInfo::from,
+    ))
+}
+
+/// Turn a feature on or off
+///
+/// Requires the admin token. Applies immediately on this server and within a
+/// minute on others sharing the database.
+#[endpoint]
+fn set_flag(
+    key: PathParam<String>,
+    json: JsonBody<FlagInput>,
+) -> JsonResult<FeatureFlagInfo> {
+    let key = key.into_inner();
+    flag_key(&key)?;
+    let FlagInput { enabled, payload } = json.into_inner();
+    let payload = payload.map(|payload| payload.to_string());
+    let flag = flags::store(&mut db::get()?, key, enabled, payload)?;
+    tracing::info!(key = flag.key, enabled, "Feature flag changed");
+    json_ok(flag.into())
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct SweepInput {
+    /// Only count the rows that would be deleted
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// Delete rows of users that no longer exist
+///
+/// Requires the admin token. Returns the orphaned rows per table, with
+/// `dryRun` without deleting them.
+#[endpoint]
+async fn sweep(json: JsonBody<SweepInput>) -> JsonResult<SweepReport> {
+    json_ok(integrity::sweep(json.into_inner().dry_run).await?)
+}
+
+#[derive(Debug, Deserialize, Validate, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct AnnounceInput {
+    #[validate(length(min = 1, max = 500))]
+    message: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct AnnounceOutput {
+    /// Connected users the announcement was sent to
+    recipients: usize,
+}
+
+/// Announce a message to every connected user
+///
+/// Requires the admin token. The announcement is sent as a notification on
+/// the realtime connections and not stored, users who are offline don't get
+/// it later.
+#[endpoint]
+fn announce(json: JsonBody<AnnounceInput>) -> JsonResult<AnnounceOutput> {
+    let input = json.into_inner();
+    input.validate()?;
+    let recipients =
+        StreamManager::global().notify_all(Notification::Announcement {
+            message: input.message,
+            created_at: chrono::Utc::now().naive_utc(),
+        });
+    tracing::info!(recipients, "Announcement sent");
+    json_ok(AnnounceOutput { recipients })
+}
+
+#[handler]
+fn get_prometheus_metrics(res: &mut Response) {
+    res.render(Text::Plain(
+        metrics::render_prometheus(&metrics::snapshot()),
+    ));
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct AdminUser {
+    #[serde(flatten)]
+    user: User,
+    banned: bool,
+}
+
+/// List users, optionally filtered by nickname or email
+///
+/// Requires admin privileges. Includes the email of every user.
+#[endpoint]
+fn list_users(
+    query: QueryParam<String, false>,
+    limit: QueryParam<i64, false>,
+    offset: QueryParam<i64, false>,
+) -> JsonResult<Vec<AdminUser>> {
+    use crate::schema::{user_bans, users};
+    let conn = &mut db::get()?;
+    let limit = limit
+        .into_inner()
+        .unwrap_or(DEFAULT_USERS_LIMIT)
+        .clamp(1, MAX_USERS_LIMIT);
+    let offset = offset.into_inner().unwrap_or(0).max(0);
+    let now = chrono::Utc::now().naive_utc();
+
+    let mut select = users::table
+        .select((
+            User::as_select(),
+            diesel::dsl::exists(
+                user_bans::table
+                    .filter(user_bans::user_id.eq(users::id))
+                    .filter(crate::auth::ban::ban_in_effect(now)),
+            ),
+        ))
+        .order(users::id.asc())
+        .limit(limit)
+        .offset(offset)
+        .into_boxed();
+    if let Some(query) = query.into_inner().filter(|q| !q.is_empty()) {
+        let pattern = format!("%{}%", db::escape_like(&query));
+        select = select.filter(
+            users::nickname
+                .like(pattern.clone())
+                .escape('\\')
+                .or(users::email.like(pattern).escape('\\')),
+        );
+    }
+
+    let result = select.load::<(User, bool)>(conn)?;
+
+    json_ok(
+        result
+            .into_iter()
+            .map(|(user, banned)| AdminUser { user, banned })
+            .collect(),
+    )
+}
+
+#[derive(Debug, Deserialize, Validate, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct BanInput {
+    #[validate(length(min = 1, max = 500))]
+    reason: String,
+    /// Lift the ban automatically at this time (UTC), ban forever if unset
+    #[serde(default)]
+    #[validate(custom(function = "in_future"))]
+    until: Option<NaiveDateTime>,
+}
+
+fn in_future(until: &NaiveDateTime) -> Result<(), ValidationError> {
+    if *until <= chrono::Utc::now().naive_utc() {
+        return Err(ValidationError::new("future")
+            .with_message(Cow::Borrowed("Must be in the future.")));
+    }
+    Ok(())
+}
+
+fn not_self(target_user: i32, depot: &Depot) -> Result<(), ValidationErrors> {
+    if target_user != depot.user_id() {
+        return Ok(());
+    }
+    let mut errs = ValidationErrors::new();
+    errs.add(
+        "id",
+        ValidationError::new("self")
+            .with_message(Cow::Borrowed("Must not be yourself.")),
+    );
+    Err(errs)
+}
+
+/// Ban a user
+///
+/// Requires admin privileges. All sessions and the WebTransport connection
+/// of the user are terminated immediately.
+#[endpoint]
+fn ban_user(
+    id: PathParam<i32>,
+    json: JsonBody<BanInput>,
+    depot: &mut Depot,
+) -> JsonResult<UserBan> {
+    use crate::schema::{sessions, user_bans, users};
+    let conn = &mut db::get()?;
+    let target_user = id.into_inner();
+    let input = json.into_inner();
+    input.validate()?;
+    not_self(target_user, depot)?;
+
+    let ban = conn.transaction::<_, ApiError, _>(|conn| {
+        // Fails with NotFound -> 404 if the user doesn't exist
+        users::table
+            .find(target_user)
+            .select(users::id)
+            .first::<i32>(conn)?;
+
+        let ban = diesel::insert_into(user_bans::table)
+            .values(NewUserBan {
+                user_id: target_user,
+                banned_by: Some(depot.user_id()),
+                reason: input.reason,
+                until: input.until,
+                created_at: chrono::Utc::now().naive_utc(),
+                lifted_at: None,
+            })
+            .get_result::<UserBan>(conn)?;
+
+        let session_ids = sessions::table
+            .filter(sessions::user_id.eq(target_user))
+            .select(sessions::id)
+            .load::<i32>(conn)?;
+        // The connections are closed below, telling the client why
+        crate::auth::expire_sessions(conn, target_user, &session_ids)?;
+        Ok(ban)
+    })?;
+
+    crate::auth::session_cache::invalidate_user(target_user);
+    StreamManager::global().close_stream_with(
+        target_user,
+        CloseCode::Banned,
+        "Banned",
+    );
+    tracing::info!(
+        user_id = target_user,
+        admin_id = depot.user_id(),
+        "User banned"
+    );
+
+    json_ok(ban)
+}
+
+/// Lift all active bans of a user
+///
+/// Requires admin privileges. Responds with 404 if the user isn't banned.
+#[endpoint]
+fn unban_user(id: PathParam<i32>, depot: &mut Depot) -> JsonResult<()> {
+    use crate::schema::user_bans;
+    let conn = &mut db::get()?;
+    let target_user = id.into_inner();
+    let now = chrono::Utc::now().naive_utc();
+
+    let lifted = diesel::update(
+        user_bans::table
+            .filter(user_bans::user_id.eq(target_user))
+            .filter(crate::auth::ban::ban_in_effect(now)),
+    )
+    .set(user_bans::lifted_at.eq(now))
+    .execute(conn)?;
+    if lifted == 0 {
+        return Err(diesel::result::Error::NotFound.into());
+    }
+    crate::auth::session_cache::invalidate_user(target_user);
+    tracing::info!(
+        user_id = target_user,
+        admin_id = depot.user_id(),
+        "User unbanned"
+    );
+
+    json_ok(())
+}
+
+#[derive(Debug, Deserialize, Validate, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct LockInput {
+    #[validate(length(min = 1, max = 500))]
+    reason: String,
+}
+
+/// Lock a user's account
+///
+/// Requires admin privileges. Like a ban, all sessions and the WebTransport
+/// connection of the user are terminated immediately and the user can't log
+/// in until unlocked. Locks are meant to be temporary, e.g. pending a review,
+/// and are independent of bans: unbanning leaves a lock in effect and vice
+/// versa.
+#[endpoint]
+fn lock_user(
+    id: PathParam<i32>,
+    json: JsonBody<LockInput>,
+    depot: &mut Depot,
+) -> JsonResult<User> {
+    use crate::schema::{sessions, users};
+    let conn = &mut db::get()?;
+    let target_user = id.into_inner();
+    let input = json.into_inner();
+    input.validate()?;
+    not_self(target_user, depot)?;
+
+    let user = conn.transaction::<_, ApiError, _>(|conn| {
+        // Fails with NotFound -> 404 if the user doesn't exist
+        let user = users::table.find(target_user).first::<User>(conn)?;
+        if user.locked_at.is_some() {
+            let mut errs = ValidationErrors::new();
+            errs.add(
+                "id",
+                ValidationError::new("locked")
+                    .with_message(Cow::Borrowed("Is already locked.")),
+            );
+            return Err(errs.into());
+        }
+
+        let user = diesel::update(users::table.find(target_user))
+            .set((
+                users::locked_at.eq(chrono::Utc::now().naive_utc()),
+                users::lock_reason.eq(input.reason),
+            ))
+            .get_result::<User>(conn)?;
+
+        let session_ids = sessions::table
+            .filter(sessions::user_id.eq(target_user))
+            .select(sessions::id)
+            .load::<i32>(conn)?;
+        // The connections are closed below, telling the client why
+        crate::auth::expire_sessions(conn, target_user, &session_ids)?;
+        audit::record(
+            conn,
+            target_user,
+            SecurityEvent::AccountLocked,
+            &AuditContext::default(),
+        )?;
+        Ok(user)
+    })?;
+
+    crate::auth::session_cache::invalidate_user(target_user);
+    StreamManager::global().close_stream_with(
+        target_user,
+        CloseCode::Banned,
+        "Account locked",
+    );
+    tracing::info!(
+        user_id = target_user,
+        admin_id = depot.user_id(),
+        "User locked"
+    );
+
+    json_ok(user)
+}
+
+/// Unlock a user's account
+///
+/// Requires admin privileges. Responds with 404 if the account isn't locked.
+/// Bans of the user stay in effect.
+#[endpoint]
+fn unlock_user(id: PathParam<i32>, depot: &mut Depot) -> JsonResult<User> {
+    use crate::schema::users;
+    let conn = &mut db::get()?;
+    let target_user = id.into_inner();
+
+    let user = conn.transaction::<_, ApiError, _>(|conn| {
+        // NotFound -> 404 if the user doesn't exist or isn't locked
+        let user = diesel::update(
+            users::table
+                .find(target_user)
+                .filter(users::locked_at.is_not_null()),
+        )
+        .set((
+            users::locked_at.eq(None::<NaiveDateTime>),
+            users::lock_reason.eq(None::<String>),
+        ))
+        .get_result::<User>(conn)?;
+        audit::record(
+            conn,
+            target_user,
+            SecurityEvent::AccountUnlocked,
+            &AuditContext::default(),
+        )?;
+        Ok(user)
+    })?;
+    crate::auth::session_cache::invalidate_user(target_user);
+    tracing::info!(
+        user_id = target_user,
+        admin_id = depot.user_id(),
+        "User unlocked"
+    );
+
+    json_ok(user)
+}
+
+#[cfg(test)]
+mod tests {
+    use salvo::test::{ResponseExt, TestClient};
+    use serde_json::{Value, json};
+
+    use super::*;
+    use crate::stream::testing::{ConnectionSession, FakeClient, wait_until};
+    use crate::test_db::{self, Client, PASSWORD};
+    use crate::wire_compat::{golden, sample_user, timestamp};
+
+    const BASE: &str = "http://localhost/api/admin";
+
+    #[tokio::test]
+    async fn operator_routes_need_the_admin_token() {
+        test_db::with_service(|service| async move {
+            let routes = [
+                TestClient::get(format!("{BASE}/metrics")),
+                TestClient::get(format!("{BASE}/streams")),
+                TestClient::get(format!("{BASE}/log-level")),
+                TestClient::get(format!("{BASE}/flags/new_lobby")),
+                TestClient::post(format!("{BASE}/maintenance/sweep"))
+                    .json(&json!({ "dryRun": true })),
+                TestClient::post(format!("{BASE}/chat/announce"))
+                    .json(&json!({ "message": "Hello" })),
+            ];
+            for req in routes {
+                let mut res = req
+                    .add_header(ADMIN_TOKEN_HEADER, "wrong-token", true)
+                    .send(&service)
+                    .await;
+                assert_eq!(res.status_code, Some(StatusCode::UNAUTHORIZED));
+                let body: Value = res.take_json().await.unwrap();
+                assert_eq!(body["code"], "invalid_admin_token");
+            }
+
+            let mut res = TestClient::get(format!("{BASE}/flags/new_lobby"))
+                .add_header(ADMIN_TOKEN_HEADER, "test-admin-token", true)
+                .send(&service)
+                .await;
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+            let flag: Value = res.take_json().await.unwrap();
+            assert_eq!(flag["enabled"], false);
+            assert_eq!(flag["updatedAt"], Value::Null);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn announcements_reach_connected_users() {
+        test_db::with_service(|service| async move {
+            let conn = &mut db::get().unwrap();
+            let mut clients = Vec::new();
+            for nickname in ["ann", "bob"] {
+                let user_id = test_db::create_user(conn, nickname).id;
+                let session = ConnectionSession::for_user(user_id);
+                let streams = StreamManager::global();
+                clients.push(FakeClient::connect(streams, Some(session)).await);
+                wait_until(|| streams.is_connected(user_id)).await;
+            }
+            let announcement = |message: &str| {
+                TestClient::post(format!("{BASE}/chat/announce"))
+                    .add_header(ADMIN_TOKEN_HEADER, "test-admin-token", true)
+                    .json(&json!({ "message": message }))
+            };
+
+            let mut res = announcement("Restart at noon").send(&service).await;
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+            let body: Value = res.take_json().await.unwrap();
+            // Connections of tests running at the same time count as well
+            assert!(body["recipients"].as_u64().unwrap() >= 2, "{body}");
+            for client in &mut clients {
+                let message = client.recv_control().await;
+                assert_eq!(message["data"]["type"], "Announcement");
+                assert_eq!(
+                    message["data"]["data"]["message"],
+                    "Restart at noon"
+                );
+            }
+
+            let res = announcement("").send(&service).await;
+            assert_eq!(res.status_code, Some(StatusCode::BAD_REQUEST));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn unparsable_log_filters_are_rejected() {
+        test_db::with_service(|service| async move {
+            let put = |filter: &str| {
+                TestClient::put(format!("{BASE}/log-level"))
+                    .add_header(ADMIN_TOKEN_HEADER, "test-admin-token", true)
+                    .json(&json!({ "filter": filter }))
+            };
+            let mut res = put("info,[{=").send(&service).await;
+            assert_eq!(res.status_code, Some(StatusCode::BAD_REQUEST));
+            let body: Value = res.take_json().await.unwrap();
+            assert_eq!(body["code"], "validation");
+            assert!(body["fields"]["filter"].is_array(), "{body}");
+
+            let res =
+                put("info,transcendence_backend=debug").send(&service).await;
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+        })
+        .await;
+    }
+
+    /// Register `nickname` and promote them to admin.
+    async fn register_admin(service: &Service, nickname: &str) -> Client {
+        let email = format!("{nickname}@example.com");
+        let (client, body) = Client::register(service, &email, nickname).await;
+        let admin_id = body["user"]["id"].as_i64().unwrap() as i32;
+        diesel::update(crate::schema::users::table.find(admin_id))
+            .set(crate::schema::users::is_admin.eq(true))
+            .execute(&mut db::get().unwrap())
+            .unwrap();
+        client
+    }
+
+    async fn login_status(
+        service: &Service,
+        email: &str,
+    ) -> (StatusCode, Value) {
+        let mut res = TestClient::post("http://localhost/api/auth/login")
+            .json(&json!({ "email": email, "password": PASSWORD }))
+            .send(service)
+            .await;
+        (res.status_code.unwrap(), res.take_json().await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn locked_accounts_are_rejected_until_unlocked() {
+        test_db::with_service(|service| async move {
+            let mut admin = register_admin(&service, "ada").await;
+            let (mut user, body) =
+                Client::register(&service, "lou@example.com", "lou").await;
+            let user_id = body["user"]["id"].as_i64().unwrap() as i32;
+            let stream = FakeClient::connect(
+                StreamManager::global(),
+                Some(ConnectionSession::for_user(user_id)),
+            )
+            .await;
+
+            let lock = |reason: &str| {
+                TestClient::post(format!("{BASE}/users/{user_id}/lock"))
+                    .json(&json!({ "reason": reason }))
+            };
+            let res = user.send(lock("Review"), &service).await;
+            assert_eq!(res.status_code, Some(StatusCode::FORBIDDEN));
+            let res = admin.send(lock("Review"), &service).await;
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+            assert_eq!(stream.closed().await, Some(CloseCode::Banned));
+
+            let mut res = user
+                .send(TestClient::get("http://localhost/api/user/me"), &service)
+                .await;
+            assert_eq!(res.status_code, Some(StatusCode::LOCKED));
+            let error: Value = res.take_json().await.unwrap();
+            assert_eq!(error["code"], "account_locked");
+            let (status, error) =
+                login_status(&service, "lou@example.com").await;
+            assert_eq!(status, StatusCode::LOCKED);
+            assert_eq!(error["code"], "account_locked");
+
+            // Locks and bans are separate states
+            let res = admin.send(lock("Again"), &service).await;
+            assert_eq!(res.status_code, Some(StatusCode::BAD_REQUEST));
+            let unban =
+                TestClient::post(format!("{BASE}/users/{user_id}/unban"));
+            let res = admin.send(unban, &service).await;
+            assert_eq!(res.status_code, Some(StatusCode::NOT_FOUND));
+            let (status, _) = login_status(&service, "lou@example.com").await;
+            assert_eq!(status, StatusCode::LOCKED);
+
+            let unlock =
+                || TestClient::post(format!("{BASE}/users/{user_id}/unlock"));
+            let res = admin.send(unlock(), &service).await;
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+            let (status, _) = login_status(&service, "lou@example.com").await;
+            assert_eq!(status, StatusCode::OK);
+            let res = admin.send(unlock(), &service).await;
+            assert_eq!(res.status_code, Some(StatusCode::NOT_FOUND));
+
+            use crate::schema::security_events;
+            let events: Vec<String> = security_events::table
+                .filter(security_events::user_id.eq(user_id))
+                .filter(security_events::event_type.like("Account%"))
+                .order(security_events::id)
+                .select(security_events::event_type)
+                .load(&mut db::get().unwrap())
+                .unwrap();
+            assert_eq!(events, ["AccountLocked", "AccountUnlocked"]);
+        })
+        .await;
+    }
+
+    golden! {
+        streams_overview => StreamsOverview {
+            totals: StreamTotals {
+                connections: 1,
+                streams_opened: 3,
+                streams_accepted: 1,
+                bytes_sent: 4096,
+                bytes_received: 512,
+            },
+            connections: Vec::new(),
+        },
+        log_level => LogLevel {
+            filter: "info,transcendence_backend=debug".into(),
+        },
+        feature_flag_info => FeatureFlagInfo {
+            key: "new_lobby".into(),
+            enabled: true,
+            payload: Some(json!({ "rollout": 0.5 })),
+            updated_at: Some(timestamp()),
+        },
+        announce_output => AnnounceOutput { recipients: 12 },
+        admin_user => AdminUser {
+            user: sample_user(),
+            banned: true,
+        },
+    }
+}
@@ -0,0 +1,1695 @@
+//! Provides game-related routes and handlers.
+//!
+//! With this you can submit results of finished games, query or export the game history
+//! and stats, view the leaderboard, invite other users to play and queue for
+//! random opponents.
+//!
+
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
+
+use chrono::{NaiveDate, NaiveDateTime};
+use diesel::dsl::count_star;
+use diesel::sql_types::Date;
+use salvo::http::body::BodySender;
+use salvo::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE, HeaderValue};
+use thiserror::Error;
+use tokio::sync::RwLock;
+use validator::{ValidationError, ValidationErrors};
+
+use crate::models::{
+    GameHistory, GameSession, GameSessionState, NewGameHistory, UserStats,
+};
+use crate::prelude::*;
+use crate::utils::idempotency::idempotent;
+
+pub mod invites;
+pub mod matchmaking;
+
+/// Upper bound for the kills of a single game, to prevent stat-stuffing.
+const MAX_KILLS_PER_GAME: i32 = 1000;
+/// Upper bound for the duration of a single game in seconds (4 hours).
+const MAX_TIME_PLAYED: i32 = 4 * 60 * 60;
+/// Upper bound for the kill rate of a practice game.
+const MAX_PRACTICE_KILLS_PER_MINUTE: i64 = 30;
+/// Games a player needs to be ranked by win rate.
+const MIN_WINRATE_GAMES: i32 = 10;
+
+const DEFAULT_HISTORY_LIMIT: i64 = 20;
+const MAX_HISTORY_LIMIT: i64 = 100;
+/// Rows loaded at once by the history export.
+const EXPORT_BATCH_SIZE: i64 = 1000;
+
+/// How long a computed leaderboard is served before it is recomputed.
+const LEADERBOARD_TTL: Duration = Duration::from_secs(30);
+/// Only the top entries are ranked and cached.
+const MAX_LEADERBOARD_ENTRIES: i64 = 1000;
+const DEFAULT_LEADERBOARD_LIMIT: usize = 20;
+const MAX_LEADERBOARD_LIMIT: usize = 100;
+
+pub fn router(path: &str) -> Router {
+    Router::with_path(path)
+        .oapi_tag("game")
+        .requires_user_login()
+        .append(&mut vec![
+            Router::with_path("result")
+                .user_rate_limit(&RateLimit::per_minute(5))
+                .hoop(idempotent)
+                .post(record_result),
+            Router::with_path("history/export")
+                .user_rate_limit(&RateLimit::per_minute(5))
+                .get(export_history),
+            Router::with_path("history")
+                .user_rate_limit(&RateLimit::per_minute(30))
+                .get(get_history),
+            Router::with_path("stats")
+                .user_rate_limit(&RateLimit::per_minute(30))
+                .get(get_stats),
+            Router::with_path("leaderboard")
+                .user_rate_limit(&RateLimit::per_minute(60))
+                .get(get_leaderboard),
+            Router::with_path("{id}/resume")
+                .user_rate_limit(&RateLimit::per_minute(10))
+                .post(resume_game),
+            invites::router("invites"),
+            matchmaking::router("queue"),
+        ])
+}
+
+#[derive(Error, Debug, Clone, Copy, strum::IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum GameResultError {
+    #[error("You didn't play in this game")]
+    NotParticipant,
+    #[error("The game is not finished yet")]
+    NotFinished,
+    #[error("No result was recorded for you in this game")]
+    NoResult,
+}
+
+#[derive(Error, Debug, Clone, Copy, strum::IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum ResumeError {
+    #[error("You don't play in this game")]
+    NotParticipant,
+    #[error("The resume token is not valid for your seat")]
+    InvalidToken,
+    #[error("The game is over")]
+    GameOver,
+}
+
+#[derive(Debug, Deserialize, Validate, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct GameResultInput {
+    /// The server-side game to get the result of. Unset for practice games.
+    game_session_id: Option<i32>,
+    /// A game played without a server-side game, must be set without
+    /// `gameSessionId`
+    #[serde(default)]
+    practice: bool,
+    /// Required for practice games. For server-side games only stored as
+    /// telemetry, the result is what the server recorded.
+    #[validate(range(min = 0, max = MAX_KILLS_PER_GAME))]
+    kills: Option<i32>,
+    /// Duration of the game in seconds, required for practice games
+    #[validate(range(min = 0, max = MAX_TIME_PLAYED))]
+    time_played: Option<i32>,
+}
+
+impl GameResultInput {
+    /// The kills and duration of a valid practice game, `None` for a
+    /// server-side game.
+    fn practice_result(&self) -> AppResult<Option<(i32, i32)>> {
+        if self.game_session_id.is_some() {
+            if self.practice {
+                return Err(invalid(
+                    "practice",
+                    "Must not be set with gameSessionId.",
+                ));
+            }
+            return Ok(None);
+        }
+        if !self.practice {
+            return Err(invalid(
+                "game_session_id",
+                "Required unless practice is set.",
+            ));
+        }
+        let Some(kills) = self.kills else {
+            return Err(invalid("kills", "Required for practice games."));
+        };
+        let Some(time_played) = self.time_played else {
+            return Err(invalid("time_played", "Required for practice games."));
+        };
+        if i64::from(kills) * 60
+            > i64::from(time_played) * MAX_PRACTICE_KILLS_PER_MINUTE
+        {
+            return Err(invalid(
+                "kills",
+                "Too many kills for the duration of the game.",
+            ));
+        }
+        Ok(Some((kills, time_played)))
+    }
+}
+
+fn invalid(field: &'static str, message: &'static str) -> ApiError {
+    let mut errs = ValidationErrors::new();
+    errs.add(
+        field,
+        ValidationError::new("result").with_message(Cow::Borrowed(message)),
+    );
+    errs.into()
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct GameResultOutput {
+    pub game: GameHistory,
+    /// `None` if the user has no verified games yet
+    pub stats: Option<UserStats>,
+}
+
+/// Submit the result of a finished game for the current User
+///
+/// With `gameSessionId`, returns the result the server recorded for the
+/// game, `kills` and `timePlayed` are stored with it as reported by the
+/// client. Practice games without a server-side game need `practice: true`,
+/// they are added to the history as not verified and don't count for the
+/// stats or the leaderboard. Their kill rate is capped at 30 per minute.
+///
+/// Retries with the same `Idempotency-Key` header get the first response
+/// instead of recording the game again.
+#[endpoint]
+fn record_result(
+    json: JsonBody<GameResultInput>,
+    depot: &mut Depot,
+) -> JsonResult<GameResultOutput> {
+    let input = json.into_inner();
+    input.validate()?;
+    let practice = input.practice_result()?;
+    let me = depot.user_id();
+    let conn = &mut db::get()?;
+
+    let result = conn.transaction::<_, ApiError, _>(|conn| match practice {
+        Some((kills, time_played)) => {
+            let result = PlayerResult {
+                kills,
+                deaths: 0,
+                time_played,
+                won: None,
+            };
+            record_game(conn, me, result, None)
+        }
+        None => session_result(conn, me, &input),
+    })?;
+
+    json_ok(result)
+}
+
+/// The result the game task recorded for `me`, with the reported numbers of
+/// `input` stored next to it.
+fn session_result(
+    conn: &mut DbConn,
+    me: i32,
+    input: &GameResultInput,
+) -> AppResult<GameResultOutput> {
+    use crate::schema::{game_history, game_sessions, user_stats};
+
+    let session_id = input.game_session_id.unwrap_or_default();
+    let session: GameSession =
+        game_sessions::table.find(session_id).first(conn)?;
+    if session.player_a != Some(me) && session.player_b != Some(me) {
+        return Err(GameResultError::NotParticipant.into());
+    }
+    if session.state != GameSessionState::Finished {
+        return Err(GameResultError::NotFinished.into());
+    }
+    let mut game: GameHistory = game_history::table
+        .filter(game_history::game_session_id.eq(session_id))
+        .filter(game_history::user_id.eq(me))
+        .first(conn)
+        .optional()?
+        .ok_or(GameResultError::NoResult)?;
+    if input.kills.is_some() || input.time_played.is_some() {
+        game = diesel::update(game_history::table.find(game.id))
+            .set((
+                game_history::reported_kills.eq(input.kills),
+                game_history::reported_time_played.eq(input.time_played),
+            ))
+            .get_result(conn)?;
+    }
+    let stats = user_stats::table.find(me).first(conn).optional()?;
+
+    Ok(GameResultOutput { game, stats })
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct ResumeInput {
+    /// The `resume_token` of the `MatchFound` notification
+    resume_token: String,
+}
+
+/// Resume a running game of the current User
+///
+/// For a client that lost its game stream, e.g. after a reload. The game
+/// drops the old stream and opens a new one, which starts with `Start` and
+/// the current `State`. Fails with 410 once the game is over, also if it was
+/// forfeited while the player was away.
+#[endpoint]
+async fn resume_game(
+    id: PathParam<i32>,
+    json: JsonBody<ResumeInput>,
+    depot: &mut Depot,
+) -> JsonResult<()> {
+    use crate::schema::game_sessions;
+
+    let id = id.into_inner();
+    let me = depot.user_id();
+    let token = json.into_inner().resume_token;
+    if crate::game::session::resume(id, me, &token).await? {
+        return json_ok(());
+    }
+    let session: GameSession = db::with_conn(move |conn| {
+        Ok(game_sessions::table.find(id).first(conn)?)
+    })
+    .await?;
+    if session.player_a != Some(me) && session.player_b != Some(me) {
+        return Err(ResumeError::NotParticipant.into());
+    }
+    Err(ResumeError::GameOver.into())
+}
+
+/// What a player did in a finished game, see [`record_game`].
+#[derive(Debug, Clone, Copy)]
+pub struct PlayerResult {
+    pub kills: i32,
+    /// Points the opponent scored
+    pub deaths: i32,
+    /// Duration of the game in seconds
+    pub time_played: i32,
+    /// `None` for practice games and games without a winner, they count as
+    /// neither a win nor a loss
+    pub won: Option<bool>,
+}
+
+/// Insert a game_history row and, for a server-side game, upsert the user's
+/// stats.
+///
+/// Games with a `game_session_id` are verified, the others are practice
+/// games, which leave the stats untouched. The stats are updated with atomic
+/// update expressions, so concurrent games can't lose increments. Call this
+/// inside a transaction.
+pub fn record_game(
+    conn: &mut DbConn,
+    target_user: i32,
+    result: PlayerResult,
+    game_session_id: Option<i32>,
+) -> AppResult<GameResultOutput> {
+    use crate::schema::game_history::dsl::game_history;
+    use crate::schema::user_stats::dsl as stats;
+
+    let PlayerResult {
+        kills,
+        deaths,
+        time_played,
+        won,
+    } = result;
+    let now = chrono::Utc::now().naive_utc();
+
+    let game: GameHistory = diesel::insert_into(game_history)
+        .values(&NewGameHistory {
+            user_id: target_user,
+            kills,
+            time_played,
+            played_at: now,
+            game_session_id,
+            verified: game_session_id.is_some(),
+            reported_kills: None,
+            reported_time_played: None,
+            deaths,
+            won: won == Some(true),
+        })
+        .get_result(conn)?;
+    if !game.verified {
+        let stats =
+            stats::user_stats.find(target_user).first(conn).optional()?;
+        return Ok(GameResultOutput { game, stats });
+    }
+
+    let win = i32::from(won == Some(true));
+    let loss = i32::from(won == Some(false));
+    let first_game = UserStats {
+        user_id: target_user,
+        games_played: 1,
+        total_kills: kills,
+        total_time_played: time_played,
+        last_game_kills: kills,
+        last_game_time_played: time_played,
+        last_game_at: now,
+        wins: win,
+        losses: loss,
+        total_deaths: deaths,
+    };
+    let stats: UserStats = diesel::insert_into(stats::user_stats)
+        .values(&first_game)
+        .on_conflict(stats::user_id)
+        .do_update()
+        .set((
+            stats::games_played.eq(stats::games_played + 1),
+            stats::total_kills.eq(stats::total_kills + kills),
+            stats::total_time_played.eq(stats::total_time_played + time_played),
+            stats::last_game_kills.eq(kills),
+            stats::last_game_time_played.eq(time_played),
+            stats::last_game_at.eq(now),
+            stats::wins.eq(stats::wins + win),
+            stats::losses.eq(stats::losses + loss),
+            stats::total_deaths.eq(stats::total_deaths + deaths),
+        ))
+        .get_result(conn)?;
+
+    Ok(GameResultOutput {
+        game,
+        stats: Some(stats),
+    })
+}
+
+/// Retrieve the recent games of the current User, newest first
+///
+/// For the next page, pass the `id` of the last game as `after_id`.
+/// `offset` is deprecated: games recorded while paging shift its pages, so
+/// games can show up twice.
+#[endpoint]
+fn get_history(
+    limit: QueryParam<i64, false>,
+    after_id: QueryParam<i32, false>,
+    offset: QueryParam<i64, false>,
+    depot: &mut Depot,
+) -> JsonResult<Vec<GameHistory>> {
+    use crate::schema::game_history::dsl::*;
+    let conn = &mut db::get()?;
+    let me = depot.user_id();
+    let limit = limit
+        .into_inner()
+        .unwrap_or(DEFAULT_HISTORY_LIMIT)
+        .clamp(1, MAX_HISTORY_LIMIT);
+    let offset = offset.into_inner().unwrap_or(0).max(0);
+
+    let mut query = game_history.filter(user_id.eq(me)).into_boxed();
+    if let Some(after_id) = after_id.into_inner() {
+        // Fails with NotFound -> 404 if the game isn't in the history
+        let after_played_at: NaiveDateTime = game_history
+            .find(after_id)
+            .filter(user_id.eq(me))
+            .select(played_at)
+            .first(conn)?;
+        query = query.filter(
+            played_at
+                .lt(after_played_at)
+                .or(played_at.eq(after_played_at).and(id.lt(after_id))),
+        );
+    }
+    let games = query
+        .order((played_at.desc(), id.desc()))
+        .limit(limit)
+        .offset(offset)
+        .load::<GameHistory>(conn)?;
+
+    json_ok(games)
+}
+
+/// Download the whole game history of the current User
+///
+/// One game per line as JSON (`application/x-ndjson`), oldest first. With
+/// `since` (UTC) only the games played since then are included.
+#[endpoint(responses(
+    (status_code = 200, description = "One GameHistory per line"),
+))]
+fn export_history(
+    since: QueryParam<NaiveDateTime, false>,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> AppResult<()> {
+    let me = depot.user_id();
+    let since = since.into_inner();
+    let filename = format!("attachment; filename=\"game-history-{me}.ndjson\"");
+    let headers = res.headers_mut();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("application/x-ndjson"),
+    );
+    headers.insert(
+        CONTENT_DISPOSITION,
+        HeaderValue::from_str(&filename).expect("ASCII header value"),
+    );
+    let mut sender = res.channel();
+    tokio::spawn(async move {
+        if let Err(err) = write_history(&mut sender, me, since).await {
+            tracing::error!(%err, user_id = me, "Failed to export game history");
+            // Abort the download instead of ending it like a complete one
+            sender.send_error(std::io::Error::other(err.to_string()));
+        }
+    });
+    Ok(())
+}
+
+/// Write the games of `target_user` in batches, so memory use doesn't grow
+/// with the history.
+async fn write_history(
+    sender: &mut BodySender,
+    target_user: i32,
+    since: Option<NaiveDateTime>,
+) -> anyhow::Result<()> {
+    let mut last_id = 0;
+    loop {
+        let batch = db::with_conn(move |conn| {
+            use crate::schema::game_history::dsl::*;
+            let mut query = game_history
+                .filter(user_id.eq(target_user))
+                .filter(id.gt(last_id))
+                .into_boxed();
+            if let Some(since) = since {
+                query = query.filter(played_at.ge(since));
+            }
+            Ok(query
+                .order(id.asc())
+                .limit(EXPORT_BATCH_SIZE)
+                .load::<GameHistory>(conn)?)
+        })
+        .await?;
+        let Some(last) = batch.last() else {
+            return Ok(());
+        };
+        last_id = last.id;
+
+        let mut lines = Vec::new();
+        for game in &batch {
+            serde_json::to_writer(&mut lines, game)?;
+            lines.push(b'\n');
+        }
+        sender.send_data(lines).await?;
+        if (batch.len() as i64) < EXPORT_BATCH_SIZE {
+            return Ok(());
+        }
+    }
+}
+
+/// The UTC day of `game_history.played_at`.
+///
+/// A literal, since diesel can't group by a function call.
+fn utc_day() -> diesel::expression::SqlLiteral<Date> {
+    diesel::dsl::sql("date(game_history.played_at)")
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, ToSchema)]
+enum StatsWindow {
+    /// Lifetime totals
+    #[default]
+    #[serde(rename = "all")]
+    All,
+    /// Today and the 6 days before (UTC)
+    #[serde(rename = "7d")]
+    Days7,
+    /// Today and the 29 days before (UTC)
+    #[serde(rename = "30d")]
+    Days30,
+}
+
+impl StatsWindow {
+    fn days(self) -> Option<u32> {
+        match self {
+            Self::All => None,
+            Self::Days7 => Some(7),
+            Self::Days30 => Some(30),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct DailyStats {
+    /// UTC day
+    date: NaiveDate,
+    games: i64,
+    kills: i64,
+}
+
+/// Sums over the games of a [`StatsWindow`].
+#[derive(Debug, Default)]
+struct Totals {
+    games_played: i64,
+    wins: i64,
+    losses: i64,
+    kills: i64,
+    deaths: i64,
+    time_played: i64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct GameStatsOutput {
+    window: StatsWindow,
+    games_played: i64,
+    wins: i64,
+    /// Games the opponent won, games without a winner are neither won nor
+    /// lost
+    losses: i64,
+    /// Share of the games won, 0 if no games were played
+    win_rate: f64,
+    total_kills: i64,
+    /// Points the opponents scored
+    total_deaths: i64,
+    /// Kills per death, the kills if there were no deaths
+    kd_ratio: f64,
+    /// Total time played in seconds
+    total_time_played: i64,
+    /// 0 if no games were played
+    avg_kills: f64,
+    /// Average duration of a game in seconds, 0 if no games were played
+    avg_time_played: f64,
+    /// One entry per day of the window, oldest first (also days without
+    /// games). Empty for `all`.
+    days: Vec<DailyStats>,
+}
+
+/// Retrieve the stats of the current User
+///
+/// `all` (the default) returns the lifetime totals. `7d` and `30d` aggregate
+/// the games of the last 7 or 30 UTC days including today, with a per-day
+/// breakdown.
+#[endpoint]
+fn get_stats(
+    window: QueryParam<StatsWindow, false>,
+    depot: &mut Depot,
+) -> JsonResult<GameStatsOutput> {
+    use crate::schema::game_history::dsl as history;
+    let conn = &mut db::get()?;
+    let window = window.into_inner().unwrap_or_default();
+    let me = depot.user_id();
+
+    let Some(window_days) = window.days() else {
+        use crate::schema::user_stats::dsl as stats;
+        let totals = stats::user_stats
+            .find(me)
+            .first::<UserStats>(conn)
+            .optional()?
+            .map(|stats| Totals {
+                games_played: stats.games_played.into(),
+                wins: stats.wins.into(),
+                losses: stats.losses.into(),
+                kills: stats.total_kills.into(),
+                deaths: stats.total_deaths.into(),
+                time_played: stats.total_time_played.into(),
+            })
+            .unwrap_or_default();
+        return json_ok(GameStatsOutput::new(window, totals, Vec::new()));
+    };
+
+    let today = chrono::Utc::now().date_naive();
+    let first_day = today - chrono::Days::new(u64::from(window_days) - 1);
+    let in_window = history::game_history
+        .filter(history::user_id.eq(me))
+        .filter(history::verified.eq(true))
+        .filter(history::played_at.ge(first_day.and_time(Default::default())));
+
+    let (games, kills_sum, deaths_sum, time_sum) = in_window
+        .select((
+            count_star(),
+            diesel::dsl::sum(history::kills),
+            diesel::dsl::sum(history::deaths),
+            diesel::dsl::sum(history::time_played),
+        ))
+        .first::<(i64, Option<i64>, Option<i64>, Option<i64>)>(conn)?;
+    let wins = in_window
+        .filter(history::won.eq(true))
+        .count()
+        .get_result::<i64>(conn)?;
+    // Lost are the games the opponent's row of the same game won
+    let opponent = diesel::alias!(crate::schema::game_history as opponent);
+    let losses = in_window
+        .filter(history::won.eq(false))
+        .filter(diesel::dsl::exists(
+            opponent
+                .filter(
+                    opponent
+                        .field(history::game_session_id)
+                        .eq(history::game_session_id),
+                )
+                .filter(opponent.field(history::user_id).ne(me))
+                .filter(opponent.field(history::won).eq(true)),
+        ))
+        .count()
+        .get_result::<i64>(conn)?;
+
+    let per_day = in_window
+        .group_by(utc_day())
+        .select((utc_day(), count_star(), diesel::dsl::sum(history::kills)))
+        .order(utc_day())
+        .load::<(NaiveDate, i64, Option<i64>)>(conn)?;
+    let mut per_day = per_day.into_iter().peekable();
+    let days = first_day
+        .iter_days()
+        .take(window_days as usize)
+        .map(|day| match per_day.next_if(|(d, _, _)| *d == day) {
+            Some((_, games, kills_sum)) => DailyStats {
+                date: day,
+                games,
+                kills: kills_sum.unwrap_or(0),
+            },
+            None => DailyStats {
+                date: day,
+                games: 0,
+                kills: 0,
+            },
+        })
+        .collect();
+
+    let totals = Totals {
+        games_played: games,
+        wins,
+        losses,
+        kills: kills_sum.unwrap_or(0),
+        deaths: deaths_sum.unwrap_or(0),
+        time_played: time_sum.unwrap_or(0),
+    };
+    json_ok(GameStatsOutput::new(window, totals, days))
+}
+
+impl GameStatsOutput {
+    fn new(window: StatsWindow, totals: Totals, days: Vec<DailyStats>) -> Self {
+        let games_played = totals.games_played;
+        let per_game = |total: i64| {
+            if games_played == 0 {
+                0.0
+            } else {
+                total as f64 / games_played as f64
+            }
+        };
+        Self {
+            window,
+            games_played,
+            wins: totals.wins,
+            losses: totals.losses,
+            win_rate: per_game(totals.wins),
+            total_kills: totals.kills,
+            total_deaths: totals.deaths,
+            kd_ratio: totals.kills as f64 / totals.deaths.max(1) as f64,
+            total_time_played: totals.time_played,
+            avg_kills: per_game(totals.kills),
+            avg_time_played: per_game(totals.time_played),
+            days,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum LeaderboardSort {
+    /// By total kills
+    #[default]
+    Kills,
+    /// By games played
+    Games,
+    /// By total time played
+    Time,
+    /// By games won
+    Wins,
+    /// By the share of games won, only players with at least 10 games are
+    /// ranked
+    Winrate,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct LeaderboardEntry {
+    /// 1-based position in the ranking, ties are broken by user id
+    pub rank: i64,
+    pub user_id: i32,
+    pub nickname: String,
+    pub games_played: i32,
+    pub wins: i32,
+    pub losses: i32,
+    /// Share of the games won
+    pub win_rate: f64,
+    pub total_kills: i32,
+    pub total_deaths: i32,
+    /// Total time played in seconds
+    pub total_time_played: i32,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct LeaderboardOutput {
+    by: LeaderboardSort,
+    /// When the ranking was computed; it may be up to 30 seconds stale
+    generated_at: NaiveDateTime,
+    entries: Vec<LeaderboardEntry>,
+}
+
+struct CachedLeaderboard {
+    generation: u64,
+    computed_at: Instant,
+    generated_at: NaiveDateTime,
+    entries: Vec<LeaderboardEntry>,
+}
+
+impl CachedLeaderboard {
+    fn is_fresh(&self, generation: u64) -> bool {
+        self.generation == generation
+            && self.computed_at.elapsed() < LEADERBOARD_TTL
+    }
+}
+
+/// Bumped to invalidate every cached leaderboard.
+static LEADERBOARD_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// One slot per [`LeaderboardSort`] variant.
+static LEADERBOARD_CACHE: LazyLock<
+    [RwLock<Option<Arc<CachedLeaderboard>>>; 5],
+> = LazyLock::new(Default::default);
+
+/// Discard all cached leaderboards, so the next request recomputes them.
+pub fn invalidate_leaderboard() {
+    LEADERBOARD_GENERATION.fetch_add(1, Ordering::AcqRel);
+}
+
+async fn leaderboard(by: LeaderboardSort) -> AppResult<Arc<CachedLeaderboard>> {
+    let slot = &LEADERBOARD_CACHE[by as usize];
+    // Read before computing, so an invalidation during the query is not lost.
+    let generation = LEADERBOARD_GENERATION.load(Ordering::Acquire);
+
+    if let Some(cached) = slot.read().await.as_ref()
+        && cached.is_fresh(generation)
+    {
+        return Ok(cached.clone());
+    }
+
+    let mut slot = slot.write().await;
+    // Another request might have recomputed it while we waited for the lock.
+    if let Some(cached) = slot.as_ref()
+        && cached.is_fresh(generation)
+    {
+        return Ok(cached.clone());
+    }
+
+    let fresh = Arc::new(CachedLeaderboard {
+        generation,
+        computed_at: Instant::now(),
+        generated_at: chrono::Utc::now().naive_utc(),
+        entries: db::with_conn(move |conn| compute_leaderboard(conn, by))
+            .await?,
+    });
+    *slot = Some(fresh.clone());
+    Ok(fresh)
+}
+
+fn compute_leaderboard(
+    conn: &mut DbConn,
+    by: LeaderboardSort,
+) -> AppResult<Vec<LeaderboardEntry>> {
+    use crate::schema::{user_stats, users};
+
+    let query = user_stats::table
+        .inner_join(users::table)
+        .select((users::nickname, UserStats::as_select()))
+        .into_boxed();
+    let query = match by {
+        LeaderboardSort::Kills => query.order(user_stats::total_kills.desc()),
+        LeaderboardSort::Games => query.order(user_stats::games_played.desc()),
+        LeaderboardSort::Time => {
+            query.order(user_stats::total_time_played.desc())
+        }
+        LeaderboardSort::Wins => query.order(user_stats::wins.desc()),
+        LeaderboardSort::Winrate => query
+            .filter(user_stats::games_played.ge(MIN_WINRATE_GAMES))
+            .order(win_rate().desc()),
+    };
+
+    let rows = query
+        .then_order_by(user_stats::user_id.asc())
+        .limit(MAX_LEADERBOARD_ENTRIES)
+        .load::<(String, UserStats)>(conn)?;
+
+    Ok(rows
+        .into_iter()
+        .zip(1..)
+        .map(|((nickname, stats), rank)| LeaderboardEntry {
+            rank,
+            user_id: stats.user_id,
+            nickname,
+            games_played: stats.games_played,
+            wins: stats.wins,
+            losses: stats.losses,
+            win_rate: f64::from(stats.wins)
+                / f64::from(stats.games_played.max(1)),
+            total_kills: stats.total_kills,
+            total_deaths: stats.total_deaths,
+            total_time_played: stats.total_time_played,
+        })
+        .collect())
+}
+
+/// The share of games won of `user_stats`.
+///
+/// A literal like [`utc_day`], SQLite divides integers without the cast.
+fn win_rate() -> diesel::expression::SqlLiteral<diesel::sql_types::Double> {
+    diesel::dsl::sql("CAST(user_stats.wins AS REAL) / user_stats.games_played")
+}
+
+/// Retrieve the leaderboard
+///
+/// Ranks players by total kills, games played, time played, wins or win
+/// rate. Only players with at least 10 games are ranked by win rate. The
+/// ranking is cached for up to 30 seconds.
+#[endpoint]
+async fn get_leaderboard(
+    by: QueryParam<LeaderboardSort, false>,
+    limit: QueryParam<usize, false>,
+    offset: QueryParam<usize, false>,
+) -> JsonResult<LeaderboardOutput> {
+    let by = by.into_inner().unwrap_or_default();
+    let limit = limit
+        .into_inner()
+        .unwrap_or(DEFAULT_LEADERBOARD_LIMIT)
+        .clamp(1, MAX_LEADERBOARD_LIMIT);
+    let offset = offset.into_inner().unwrap_or(0);
+
+    let board = leaderboard(by).await?;
+    let entries = board.entries.iter().skip(offset).take(limit).cloned();
+
+    json_ok(LeaderboardOutput {
+        by,
+        generated_at: board.generated_at,
+        entries: entries.collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use salvo::test::{ResponseExt, TestClient};
+    use serde_json::{Value, json};
+    use tokio::io::AsyncWriteExt as _;
+
+    use super::*;
+    use crate::game::pong::Pong;
+    use crate::stream::testing::{
+        ClientReceiver, ClientSender, ConnectionSession, FakeClient,
+    };
+    use crate::stream::{StreamExt as _, StreamManager};
+    use crate::test_db::{self, Client};
+    use crate::wire_compat::{golden, timestamp};
+
+    /// A registered user with a connection.
+    pub(super) struct Player {
+        pub id: i32,
+        pub client: Client,
+        pub stream: FakeClient,
+    }
+
+    impl Player {
+        pub async fn connect(service: &Service, nickname: &str) -> Self {
+            let email = format!("{nickname}@example.com");
+            let (client, body) =
+                Client::register(service, &email, nickname).await;
+            let id = body["user"]["id"].as_i64().unwrap() as i32;
+            let stream = FakeClient::connect(
+                StreamManager::global(),
+                Some(ConnectionSession::for_user(id)),
+            )
+            .await;
+            Self { id, client, stream }
+        }
+
+        /// Skip control messages up to the next notification of `kind`.
+        pub async fn notification(&mut self, kind: &str) -> Value {
+            loop {
+                let message = self.stream.recv_control().await;
+                if message["type"] == "Notification"
+                    && message["data"]["type"] == kind
+                {
+                    return message["data"]["data"].clone();
+                }
+            }
+        }
+
+        /// Accept a game stream and check its `Start`, returns the stream
+        /// and the `State` that follows.
+        pub async fn join(
+            &mut self,
+            game_session_id: i32,
+            side: &str,
+            opponent: i32,
+        ) -> (ClientSender, ClientReceiver, Value) {
+            let (stream_type, send, mut recv) =
+                self.stream.accept_stream().await;
+            assert_eq!(stream_type, "Game");
+            assert_eq!(
+                next(&mut recv).await,
+                json!({
+                    "type": "Start",
+                    "data": {
+                        "gameSessionId": game_session_id,
+                        "side": side,
+                        "opponentUserId": opponent,
+                    },
+                })
+            );
+            let state = next(&mut recv).await;
+            assert_eq!(state["type"], "State");
+            (send, recv, state)
+        }
+    }
+
+    pub(super) async fn next(recv: &mut ClientReceiver) -> Value {
+        recv.next()
+            .await
+            .expect("the game stream should be open")
+            .expect("the message should decode")
+    }
+
+    pub(super) fn state(tick: u64, pong: &Pong) -> Value {
+        json!({
+            "type": "State",
+            "data": { "tick": tick, "snapshot": pong.snapshot() },
+        })
+    }
+
+    fn insert_stats(conn: &mut DbConn, user_id: i32, kills: i32) {
+        use crate::schema::user_stats;
+        let now = chrono::Utc::now().naive_utc();
+        diesel::insert_into(user_stats::table)
+            .values(&UserStats {
+                user_id,
+                games_played: 1,
+                total_kills: kills,
+                total_time_played: 60,
+                last_game_kills: kills,
+                last_game_time_played: 60,
+                last_game_at: now,
+                wins: 1,
+                losses: 0,
+                total_deaths: 0,
+            })
+            .execute(conn)
+            .unwrap();
+    }
+
+    /// A finished game of `a` and `b`, without results.
+    fn finished_session(conn: &mut DbConn, a: i32, b: i32) -> i32 {
+        use crate::models::NewGameSession;
+        use crate::schema::game_sessions;
+        diesel::insert_into(game_sessions::table)
+            .values(&NewGameSession {
+                player_a: Some(a),
+                player_b: Some(b),
+                state: GameSessionState::Finished,
+                created_at: chrono::Utc::now().naive_utc(),
+            })
+            .returning(game_sessions::id)
+            .get_result(conn)
+            .unwrap()
+    }
+
+    fn result(kills: i32, deaths: i32, won: Option<bool>) -> PlayerResult {
+        PlayerResult {
+            kills,
+            deaths,
+            time_played: 60,
+            won,
+        }
+    }
+
+    /// Record a game of `a` and `b` like the game task does, `a` scoring
+    /// `scores[0]`.
+    fn play(
+        conn: &mut DbConn,
+        [a, b]: [i32; 2],
+        scores: [i32; 2],
+        a_won: Option<bool>,
+    ) -> UserStats {
+        let session = finished_session(conn, a, b);
+        let a_result = result(scores[0], scores[1], a_won);
+        let b_result = result(scores[1], scores[0], a_won.map(|won| !won));
+        let stats = record_game(conn, a, a_result, Some(session))
+            .unwrap()
+            .stats
+            .unwrap();
+        record_game(conn, b, b_result, Some(session)).unwrap();
+        stats
+    }
+
+    fn stats_of(conn: &mut DbConn, user_id: i32) -> Option<UserStats> {
+        use crate::schema::user_stats;
+        user_stats::table
+            .find(user_id)
+            .first(conn)
+            .optional()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn the_first_verified_game_creates_the_stats() {
+        test_db::with_service(|service| async move {
+            let (mut client, body) =
+                Client::register(&service, "ada@example.com", "ada").await;
+            let me = body["user"]["id"].as_i64().unwrap() as i32;
+            let conn = &mut db::get().unwrap();
+            let opponent = test_db::create_user(conn, "bea").id;
+            let session = finished_session(conn, me, opponent);
+            assert!(stats_of(conn, me).is_none());
+
+            let recorded =
+                record_game(conn, me, result(3, 1, Some(true)), Some(session))
+                    .unwrap();
+            let stats = recorded.stats.unwrap();
+            assert_eq!(stats.games_played, 1);
+            assert_eq!(stats.total_kills, 3);
+            assert_eq!(stats.total_time_played, 60);
+            assert_eq!(stats.last_game_kills, 3);
+            assert_eq!(stats.last_game_at, recorded.game.played_at);
+
+            let mut res = client
+                .send(
+                    TestClient::post("http://localhost/api/game/result")
+                        .json(&json!({ "gameSessionId": session })),
+                    &service,
+                )
+                .await;
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+            let body: Value = res.take_json().await.unwrap();
+            assert_eq!(body["game"]["verified"], true);
+            assert_eq!(body["stats"]["gamesPlayed"], 1);
+        })
+        .await;
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_results_add_up() {
+        test_db::with_service(|service| async move {
+            let (client, body) =
+                Client::register(&service, "cyd@example.com", "cyd").await;
+            let me = body["user"]["id"].as_i64().unwrap() as i32;
+            let sessions: Vec<i32> = {
+                let conn = &mut db::get().unwrap();
+                let opponent = test_db::create_user(conn, "dee").id;
+                (0..5)
+                    .map(|_| finished_session(conn, me, opponent))
+                    .collect()
+            };
+
+            // Games ending at the same time, each in a transaction of its own
+            let recorded = sessions.iter().zip(1..).map(|(&session, kills)| {
+                db::with_transaction(move |conn| {
+                    let result = PlayerResult {
+                        kills,
+                        deaths: 0,
+                        time_played: 10 * kills,
+                        won: Some(true),
+                    };
+                    record_game(conn, me, result, Some(session))
+                })
+            });
+            for recorded in futures::future::join_all(recorded).await {
+                recorded.unwrap();
+            }
+            let submitted = sessions.iter().map(|session| {
+                let mut client = client.clone();
+                let req = TestClient::post("http://localhost/api/game/result")
+                    .json(&json!({ "gameSessionId": session }));
+                let service = &service;
+                async move { client.send(req, service).await.status_code }
+            });
+            for status in futures::future::join_all(submitted).await {
+                assert_eq!(status, Some(StatusCode::OK));
+            }
+
+            let stats = stats_of(&mut db::get().unwrap(), me).unwrap();
+            assert_eq!(stats.games_played, 5);
+            assert_eq!(stats.total_kills, 1 + 2 + 3 + 4 + 5);
+            assert_eq!(stats.total_time_played, 10 * (1 + 2 + 3 + 4 + 5));
+            assert_eq!(stats.wins, 5);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn wins_and_losses_are_counted() {
+        test_db::with_db(|| async {
+            let conn = &mut db::get().unwrap();
+            let players = [
+                test_db::create_user(conn, "eli").id,
+                test_db::create_user(conn, "fay").id,
+            ];
+
+            let stats = play(conn, players, [3, 1], Some(true));
+            assert_eq!(
+                (stats.games_played, stats.wins, stats.losses),
+                (1, 1, 0)
+            );
+            assert_eq!((stats.total_kills, stats.total_deaths), (3, 1));
+            let stats = play(conn, players, [2, 3], Some(false));
+            assert_eq!(
+                (stats.games_played, stats.wins, stats.losses),
+                (2, 1, 1)
+            );
+            assert_eq!((stats.total_kills, stats.total_deaths), (5, 4));
+            let opponent = stats_of(conn, players[1]).unwrap();
+            assert_eq!((opponent.wins, opponent.losses), (1, 1));
+
+            // Neither a win nor a loss
+            let stats = play(conn, players, [1, 1], None);
+            assert_eq!(
+                (stats.games_played, stats.wins, stats.losses),
+                (3, 1, 1)
+            );
+            let practice =
+                record_game(conn, players[0], result(4, 0, None), None)
+                    .unwrap();
+            assert!(!practice.game.verified);
+            let stats = practice.stats.unwrap();
+            assert_eq!(
+                (stats.games_played, stats.wins, stats.losses),
+                (3, 1, 1)
+            );
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn history_and_stats_carry_deaths_and_wins() {
+        test_db::with_service(|service| async move {
+            let (mut client, body) =
+                Client::register(&service, "gus@example.com", "gus").await;
+            let me = body["user"]["id"].as_i64().unwrap() as i32;
+            {
+                let conn = &mut db::get().unwrap();
+                let opponent = test_db::create_user(conn, "hal").id;
+                play(conn, [me, opponent], [3, 1], Some(true));
+                play(conn, [me, opponent], [0, 3], Some(false));
+                play(conn, [me, opponent], [2, 2], None);
+            }
+
+            let mut res = client
+                .send(
+                    TestClient::get("http://localhost/api/game/history"),
+                    &service,
+                )
+                .await;
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+            let history: Value = res.take_json().await.unwrap();
+            let rows: Vec<_> = history
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|game| (game["deaths"].clone(), game["won"].clone()))
+                .collect();
+            assert_eq!(
+                rows,
+                [
+                    (json!(2), json!(false)),
+                    (json!(3), json!(false)),
+                    (json!(1), json!(true)),
+                ]
+            );
+
+            for window in ["all", "7d"] {
+                let mut res = client
+                    .send(
+                        TestClient::get("http://localhost/api/game/stats")
+                            .query("window", window),
+                        &service,
+                    )
+                    .await;
+                assert_eq!(res.status_code, Some(StatusCode::OK));
+                let stats: Value = res.take_json().await.unwrap();
+                assert_eq!(stats["gamesPlayed"], 3, "{window}");
+                assert_eq!(stats["wins"], 1, "{window}");
+                assert_eq!(stats["losses"], 1, "{window}");
+                assert_eq!(stats["totalDeaths"], 6, "{window}");
+            }
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn winrate_needs_ten_games() {
+        test_db::with_db(|| async {
+            use crate::schema::user_stats;
+
+            let conn = &mut db::get().unwrap();
+            let ranked = [("ivy", 10, 5), ("jon", 9, 9), ("kat", 12, 9)].map(
+                |(nickname, games_played, wins)| {
+                    let user_id = test_db::create_user(conn, nickname).id;
+                    insert_stats(conn, user_id, 0);
+                    diesel::update(user_stats::table.find(user_id))
+                        .set((
+                            user_stats::games_played.eq(games_played),
+                            user_stats::wins.eq(wins),
+                            user_stats::losses.eq(games_played - wins),
+                        ))
+                        .execute(conn)
+                        .unwrap();
+                    user_id
+                },
+            );
+
+            let entries =
+                compute_leaderboard(conn, LeaderboardSort::Winrate).unwrap();
+            let board: Vec<_> = entries
+                .iter()
+                .map(|entry| (entry.user_id, entry.win_rate))
+                .collect();
+            assert_eq!(board, [(ranked[2], 0.75), (ranked[0], 0.5)]);
+            // Ranked by wins without the threshold
+            let entries =
+                compute_leaderboard(conn, LeaderboardSort::Wins).unwrap();
+            assert_eq!(entries[0].user_id, ranked[1]);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn leaderboard_breaks_ties_by_user_id() {
+        test_db::with_db(|| async {
+            let conn = &mut db::get().unwrap();
+            let ids: Vec<i32> = ["ann", "ben", "cat"]
+                .map(|nickname| test_db::create_user(conn, nickname).id)
+                .into();
+            insert_stats(conn, ids[2], 5);
+            insert_stats(conn, ids[1], 7);
+            insert_stats(conn, ids[0], 5);
+
+            let entries =
+                compute_leaderboard(conn, LeaderboardSort::Kills).unwrap();
+            let ranked: Vec<_> = entries
+                .iter()
+                .map(|entry| (entry.rank, entry.user_id))
+                .collect();
+            assert_eq!(ranked, [(1, ids[1]), (2, ids[0]), (3, ids[2])]);
+        })
+        .await;
+    }
+
+    /// Only this test uses the cache, other tests may still invalidate it.
+    #[tokio::test]
+    async fn leaderboard_is_cached_until_invalidated() {
+        test_db::with_service(|service| async move {
+            let (mut client, _) =
+                Client::register(&service, "kim@example.com", "kim").await;
+            let (first, second) = {
+                let conn = &mut db::get().unwrap();
+                (
+                    test_db::create_user(conn, "lee").id,
+                    test_db::create_user(conn, "max").id,
+                )
+            };
+            insert_stats(&mut db::get().unwrap(), first, 3);
+            invalidate_leaderboard();
+
+            let generation = LEADERBOARD_GENERATION.load(Ordering::Acquire);
+            let cached = leaderboard(LeaderboardSort::Kills).await.unwrap();
+            insert_stats(&mut db::get().unwrap(), second, 9);
+            let reused = leaderboard(LeaderboardSort::Kills).await.unwrap();
+            if LEADERBOARD_GENERATION.load(Ordering::Acquire) == generation {
+                assert!(Arc::ptr_eq(&cached, &reused));
+                assert_eq!(reused.entries.len(), 1);
+            }
+
+            // As after a game was recorded
+            invalidate_leaderboard();
+            let mut res = client
+                .send(
+                    TestClient::get("http://localhost/api/game/leaderboard")
+                        .query("by", "kills"),
+                    &service,
+                )
+                .await;
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+            let body: Value = res.take_json().await.unwrap();
+            assert!(body["generatedAt"].is_string());
+            let ranked: Vec<_> = body["entries"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|entry| entry["userId"].as_i64().unwrap() as i32)
+                .collect();
+            assert_eq!(ranked, [second, first]);
+        })
+        .await;
+    }
+
+    type GameStream = (ClientSender, ClientReceiver, Value);
+
+    /// Start a game of `alice` and `bob` and join it, returns the game, the
+    /// resume tokens and the streams.
+    async fn start_game(
+        alice: &mut Player,
+        bob: &mut Player,
+    ) -> (i32, [String; 2], [GameStream; 2]) {
+        let game = crate::game::session::start(alice.id, bob.id).await.unwrap();
+        let mut tokens = Vec::new();
+        for player in [&mut *alice, &mut *bob] {
+            let found = player.notification("MatchFound").await;
+            tokens.push(found["resumeToken"].as_str().unwrap().to_owned());
+        }
+        let streams = [
+            alice.join(game, "A", bob.id).await,
+            bob.join(game, "B", alice.id).await,
+        ];
+        (game, tokens.try_into().unwrap(), streams)
+    }
+
+    async fn resume(
+        player: &mut Player,
+        service: &Service,
+        game: i32,
+        token: &str,
+    ) -> Response {
+        let req = TestClient::post(format!(
+            "http://localhost/api/game/{game}/resume"
+        ))
+        .json(&json!({ "resumeToken": token }));
+        player.client.send(req, service).await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn resume_moves_the_game_to_a_new_connection() {
+        test_db::with_service(|service| async move {
+            let mut alice = Player::connect(&service, "nia").await;
+            let mut bob = Player::connect(&service, "oto").await;
+            let (game, [_, token], _streams) =
+                start_game(&mut alice, &mut bob).await;
+
+            // After a reload, while the old connection is still open
+            let old = std::mem::replace(
+                &mut bob.stream,
+                FakeClient::connect(
+                    StreamManager::global(),
+                    Some(ConnectionSession::for_user(bob.id)),
+                )
+                .await,
+            );
+            let res = resume(&mut bob, &service, game, &token).await;
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+            bob.join(game, "B", alice.id).await;
+            drop(old);
+        })
+        .await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn resume_needs_the_token_of_the_seat() {
+        test_db::with_service(|service| async move {
+            let mut alice = Player::connect(&service, "pia").await;
+            let mut bob = Player::connect(&service, "quy").await;
+            let (game, [alice_token, _], _streams) =
+                start_game(&mut alice, &mut bob).await;
+
+            for token in [alice_token.as_str(), "not a token"] {
+                let mut res = resume(&mut bob, &service, game, token).await;
+                assert_eq!(res.status_code, Some(StatusCode::FORBIDDEN));
+                let body: Value = res.take_json().await.unwrap();
+                assert_eq!(body["code"], "invalid_token");
+            }
+        })
+        .await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn resume_after_a_forfeit_is_gone() {
+        test_db::with_service(|service| async move {
+            let mut alice = Player::connect(&service, "rey").await;
+            let mut bob = Player::connect(&service, "sol").await;
+            let (game, [_, token], [(_, mut alice_recv, _), bob_stream]) =
+                start_game(&mut alice, &mut bob).await;
+
+            drop(bob_stream);
+            bob.stream.heartbeat.shutdown().await.unwrap();
+            while next(&mut alice_recv).await["type"] != "Finished" {}
+
+            let mut res = resume(&mut bob, &service, game, &token).await;
+            assert_eq!(res.status_code, Some(StatusCode::GONE));
+            let body: Value = res.take_json().await.unwrap();
+            assert_eq!(body["code"], "game_over");
+        })
+        .await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn players_can_open_their_game_stream() {
+        test_db::with_service(|service| async move {
+            crate::game::session::accept_game_streams();
+            let mut alice = Player::connect(&service, "tam").await;
+            let mut bob = Player::connect(&service, "uma").await;
+            let (game, _, _streams) = start_game(&mut alice, &mut bob).await;
+
+            let (_, mut recv) = alice
+                .stream
+                .open_stream(json!({ "streamType": "Game" }))
+                .await;
+            let start = next(&mut recv).await;
+            assert_eq!(start["type"], "Start");
+            assert_eq!(start["data"]["gameSessionId"], game);
+            assert_eq!(start["data"]["side"], "A");
+            assert_eq!(next(&mut recv).await["type"], "State");
+        })
+        .await;
+    }
+
+    /// Unverified games of `user_id`, one a minute from `start`, returns
+    /// their ids.
+    fn seed_history(
+        conn: &mut DbConn,
+        user_id: i32,
+        start: NaiveDateTime,
+        count: i64,
+    ) -> Vec<i32> {
+        use crate::schema::game_history;
+        conn.transaction(|conn| {
+            (0..count)
+                .map(|minute| {
+                    diesel::insert_into(game_history::table)
+                        .values(&NewGameHistory {
+                            user_id,
+                            kills: 1,
+                            time_played: 60,
+                            played_at: start
+                                + chrono::Duration::minutes(minute),
+                            game_session_id: None,
+                            verified: false,
+                            reported_kills: None,
+                            reported_time_played: None,
+                            deaths: 0,
+                            won: false,
+                        })
+                        .returning(game_history::id)
+                        .get_result(conn)
+                })
+                .collect::<QueryResult<_>>()
+        })
+        .unwrap()
+    }
+
+    fn jan_first() -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2026, 1, 1)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap()
+    }
+
+    async fn export(
+        client: &mut Client,
+        service: &Service,
+        since: Option<&str>,
+    ) -> Response {
+        let mut req =
+            TestClient::get("http://localhost/api/game/history/export");
+        if let Some(since) = since {
+            req = req.query("since", since);
+        }
+        client.send(req, service).await
+    }
+
+    #[tokio::test]
+    async fn the_export_streams_every_game_across_batches() {
+        test_db::with_service(|service| async move {
+            let (mut client, body) =
+                Client::register(&service, "ivy@example.com", "ivy").await;
+            let me = body["user"]["id"].as_i64().unwrap() as i32;
+            let seeded = {
+                let conn = &mut db::get().unwrap();
+                let other = test_db::create_user(conn, "jon").id;
+                let seeded = seed_history(
+                    conn,
+                    me,
+                    jan_first(),
+                    2 * EXPORT_BATCH_SIZE + 500,
+                );
+                seed_history(conn, other, jan_first(), 3);
+                seeded
+            };
+
+            let mut res = export(&mut client, &service, None).await;
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+            assert_eq!(res.headers()[CONTENT_TYPE], "application/x-ndjson");
+            assert_eq!(
+                res.headers()[CONTENT_DISPOSITION],
+                format!("attachment; filename=\"game-history-{me}.ndjson\"")
+            );
+            let body = res.take_string().await.unwrap();
+            let ids: Vec<_> = body
+                .lines()
+                .map(|line| {
+                    let game: Value = serde_json::from_str(line).unwrap();
+                    assert_eq!(game["userId"], me);
+                    game["id"].as_i64().unwrap() as i32
+                })
+                .collect();
+            assert_eq!(ids, seeded);
+
+            // The last 500 games, played from minute 2000 on
+            let mut res =
+                export(&mut client, &service, Some("2026-01-02T09:20:00"))
+                    .await;
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+            let body = res.take_string().await.unwrap();
+            assert_eq!(body.lines().count(), 500);
+            let first: Value =
+                serde_json::from_str(body.lines().next().unwrap()).unwrap();
+            assert_eq!(first["id"], seeded[2000]);
+
+            let res = export(&mut Client::default(), &service, None).await;
+            assert_eq!(res.status_code, Some(StatusCode::UNAUTHORIZED));
+        })
+        .await;
+    }
+
+    async fn history_page(
+        client: &mut Client,
+        service: &Service,
+        query: &[(&str, i64)],
+    ) -> (StatusCode, Vec<i32>) {
+        let mut req = TestClient::get("http://localhost/api/game/history")
+            .query("limit", 2);
+        for (name, value) in query {
+            req = req.query(name, value);
+        }
+        let mut res = client.send(req, service).await;
+        let status = res.status_code.unwrap();
+        if status != StatusCode::OK {
+            return (status, Vec::new());
+        }
+        let games: Vec<Value> = res.take_json().await.unwrap();
+        let ids = games
+            .iter()
+            .map(|game| game["id"].as_i64().unwrap() as i32)
+            .collect();
+        (status, ids)
+    }
+
+    #[tokio::test]
+    async fn keyset_pages_do_not_overlap_while_games_are_added() {
+        test_db::with_service(|service| async move {
+            let (mut client, body) =
+                Client::register(&service, "kim@example.com", "kim").await;
+            let me = body["user"]["id"].as_i64().unwrap() as i32;
+            let (seeded, foreign) = {
+                let conn = &mut db::get().unwrap();
+                let other = test_db::create_user(conn, "lee").id;
+                let mut seeded = seed_history(conn, me, jan_first(), 3);
+                // Ties on played_at are broken by id
+                seeded.extend(seed_history(conn, me, jan_first(), 2));
+                (seeded, seed_history(conn, other, jan_first(), 1)[0])
+            };
+            // Newest first, the ties by descending id
+            let expected =
+                [seeded[2], seeded[4], seeded[1], seeded[3], seeded[0]];
+
+            let mut ids = Vec::new();
+            let mut after = None;
+            loop {
+                let query: Vec<_> =
+                    after.map(|id| ("after_id", id)).into_iter().collect();
+                let (status, page) =
+                    history_page(&mut client, &service, &query).await;
+                assert_eq!(status, StatusCode::OK);
+                let Some(&last) = page.last() else {
+                    break;
+                };
+                ids.extend(page);
+                after = Some(i64::from(last));
+                // A game recorded while paging is newer than every page
+                let conn = &mut db::get().unwrap();
+                seed_history(conn, me, chrono::Utc::now().naive_utc(), 1);
+            }
+            assert_eq!(ids, expected);
+
+            // offset keeps working, but shifts with the new games
+            let (status, page) =
+                history_page(&mut client, &service, &[("offset", 3)]).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(page, expected[..2]);
+
+            let (status, _) = history_page(
+                &mut client,
+                &service,
+                &[("after_id", i64::from(foreign))],
+            )
+            .await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+        })
+        .await;
+    }
+
+    golden! {
+        stats_windows => [
+            StatsWindow::All,
+            StatsWindow::Days7,
+            StatsWindow::Days30,
+        ],
+        game_stats_output => GameStatsOutput {
+            window: StatsWindow::Days7,
+            games_played: 4,
+            wins: 3,
+            losses: 1,
+            win_rate: 0.75,
+            total_kills: 20,
+            total_deaths: 6,
+            kd_ratio: 3.5,
+            total_time_played: 480,
+            avg_kills: 5.0,
+            avg_time_played: 120.0,
+            days: vec![DailyStats {
+                date: timestamp().date(),
+                games: 4,
+                kills: 20,
+            }],
+        },
+        leaderboard_sorts => [
+            LeaderboardSort::Kills,
+            LeaderboardSort::Games,
+            LeaderboardSort::Time,
+            LeaderboardSort::Wins,
+            LeaderboardSort::Winrate,
+        ],
+        leaderboard_output => LeaderboardOutput {
+            by: LeaderboardSort::Wins,
+            generated_at: timestamp(),
+            entries: vec![LeaderboardEntry {
+                rank: 1,
+                user_id: 7,
+                nickname: "ann".into(),
+                games_played: 4,
+                wins: 3,
+                losses: 1,
+                win_rate: 0.75,
+                total_kills: 20,
+                total_deaths: 6,
+                total_time_played: 480,
+            }],
+        },
+    }
+}
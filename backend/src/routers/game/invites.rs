@@ -0,0 +1,393 @@
+//! Invitations to play, e.g. sent from the chat.
+//!
+//! Invites are pending for [`INVITE_TTL`]. There is no background job:
+//! expired invites are ignored when listing and counting, and marked as
+//! expired once someone tries to answer them.
+
+use chrono::NaiveDateTime;
+use thiserror::Error;
+
+use crate::auth::notification_counts;
+use crate::models::{GameInvite, InviteStatus, NewGameInvite};
+use crate::prelude::*;
+use crate::schema::game_invites;
+use crate::stream::{Notification, StreamManager};
+use crate::utils::idempotency::idempotent;
+
+/// How long an invite can be accepted.
+const INVITE_TTL: chrono::TimeDelta = chrono::TimeDelta::minutes(2);
+/// Maximum pending invites a user can have sent at the same time.
+const MAX_OUTSTANDING_INVITES: i64 = 5;
+
+#[derive(Error, Debug, Clone, Copy, strum::IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum InviteError {
+    #[error("You can't invite yourself")]
+    SelfInvite,
+    #[error("This user already has a pending invite from you")]
+    AlreadyInvited,
+    #[error("Too many pending invites, wait for them to be answered")]
+    TooManyOutstanding,
+    #[error("The invite was already answered")]
+    AlreadyAnswered,
+    #[error("The invite has expired")]
+    Expired,
+}
+
+pub fn router(path: &str) -> Router {
+    Router::with_path(path)
+        .user_rate_limit(&RateLimit::per_minute(30))
+        .hoop(idempotent)
+        .get(list_invites)
+        .post(create_invite)
+        .push(Router::with_path("{id}/accept").post(accept_invite))
+        .push(Router::with_path("{id}/decline").post(decline_invite))
+}
+
+/// Pending invites that did not expire yet.
+#[diesel::dsl::auto_type]
+fn outstanding(now: NaiveDateTime) -> _ {
+    let pending: InviteStatus = InviteStatus::Pending;
+    game_invites::status
+        .eq(pending)
+        .and(game_invites::expires_at.gt(now))
+}
+
+/// Pending invites to `user_id`, see [`notification_counts`].
+pub(crate) fn pending_count(conn: &mut DbConn, user_id: i32) -> AppResult<i64> {
+    Ok(game_invites::table
+        .filter(game_invites::to_user_id.eq(user_id))
+        .filter(outstanding(chrono::Utc::now().naive_utc()))
+        .count()
+        .get_result(conn)?)
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct CreateInviteInput {
+    to_user_id: i32,
+}
+
+/// Invite another User to play
+///
+/// The invite expires after 2 minutes. A User can have up to 5 pending invites.
+/// Retries with the same `Idempotency-Key` header get the first response
+/// instead of sending another invite.
+#[endpoint]
+fn create_invite(
+    json: JsonBody<CreateInviteInput>,
+    depot: &mut Depot,
+) -> JsonResult<GameInvite> {
+    use crate::schema::game_invites::dsl::*;
+    use crate::schema::users;
+
+    let me = depot.user_id();
+    let recipient = json.into_inner().to_user_id;
+    if recipient == me {
+        return Err(InviteError::SelfInvite.into());
+    }
+    // TODO only allow inviting friends who did not block the sender, once
+    // friendships and blocks exist
+    let conn = &mut db::get()?;
+    let now = chrono::Utc::now().naive_utc();
+
+    let (invite, from_nickname) =
+        conn.transaction::<_, ApiError, _>(|conn| {
+            users::table
+                .find(recipient)
+                .select(users::id)
+                .first::<i32>(conn)?;
+            let pending: Vec<i32> = game_invites
+                .filter(from_user_id.eq(me))
+                .filter(outstanding(now))
+                .select(to_user_id)
+                .load(conn)?;
+            if pending.contains(&recipient) {
+                return Err(InviteError::AlreadyInvited.into());
+            }
+            if pending.len() as i64 >= MAX_OUTSTANDING_INVITES {
+                return Err(InviteError::TooManyOutstanding.into());
+            }
+
+            let invite: GameInvite = diesel::insert_into(game_invites)
+                .values(&NewGameInvite {
+                    from_user_id: me,
+                    to_user_id: recipient,
+                    status: InviteStatus::Pending,
+                    created_at: now,
+                    expires_at: now + INVITE_TTL,
+                })
+                .get_result(conn)?;
+            let from_nickname = users::table
+                .find(me)
+                .select(users::nickname)
+                .first::<String>(conn)?;
+            Ok((invite, from_nickname))
+        })?;
+    notification_counts::invalidate(recipient);
+
+    StreamManager::global().notify(
+        recipient,
+        Notification::GameInvite {
+            invite_id: invite.id,
+            from_user_id: me,
+            from_nickname,
+        },
+    );
+    json_ok(invite)
+}
+
+/// Retrieve the pending invites of the current User, newest first
+#[endpoint]
+fn list_invites(depot: &mut Depot) -> JsonResult<Vec<GameInvite>> {
+    use crate::schema::game_invites::dsl::*;
+    let conn = &mut db::get()?;
+
+    let invites = game_invites
+        .filter(to_user_id.eq(depot.user_id()))
+        .filter(outstanding(chrono::Utc::now().naive_utc()))
+        .order(id.desc())
+        .load::<GameInvite>(conn)?;
+
+    json_ok(invites)
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct AcceptedInvite {
+    invite: GameInvite,
+    /// The game started for the invite
+    game_session_id: i32,
+}
+
+/// Accept an invite sent to the current User
+///
+/// Starts a game with the sender, both get a `MatchFound` notification with
+/// their resume token, like players matched by the queue.
+#[endpoint]
+async fn accept_invite(
+    id: PathParam<i32>,
+    depot: &mut Depot,
+) -> JsonResult<AcceptedInvite> {
+    let invite = answer_invite(id.into_inner(), depot.user_id(), true)?;
+    let game_session_id =
+        crate::game::session::start(invite.from_user_id, invite.to_user_id)
+            .await?;
+    json_ok(AcceptedInvite {
+        invite,
+        game_session_id,
+    })
+}
+
+/// Decline an invite sent to the current User
+#[endpoint]
+fn decline_invite(
+    id: PathParam<i32>,
+    depot: &mut Depot,
+) -> JsonResult<GameInvite> {
+    json_ok(answer_invite(id.into_inner(), depot.user_id(), false)?)
+}
+
+/// Set the status of a pending invite and notify its sender.
+///
+/// Invites of other users are reported as not found.
+fn answer_invite(
+    invite_id: i32,
+    me: i32,
+    accepted: bool,
+) -> AppResult<GameInvite> {
+    use crate::schema::game_invites::dsl::*;
+    let conn = &mut db::get()?;
+    let now = chrono::Utc::now().naive_utc();
+
+    let invite = conn.transaction::<_, ApiError, _>(|conn| {
+        let invite: GameInvite = game_invites
+            .find(invite_id)
+            .filter(to_user_id.eq(me))
+            .first(conn)?;
+        if invite.status != InviteStatus::Pending {
+            return Err(match invite.status {
+                InviteStatus::Expired => InviteError::Expired,
+                _ => InviteError::AlreadyAnswered,
+            }
+            .into());
+        }
+        if invite.expires_at <= now {
+            diesel::update(game_invites.find(invite.id))
+                .set(status.eq(InviteStatus::Expired))
+                .execute(conn)?;
+            // Commit the expired status
+            return Ok(Err(InviteError::Expired));
+        }
+
+        let new_status = if accepted {
+            InviteStatus::Accepted
+        } else {
+            InviteStatus::Declined
+        };
+        let invite: GameInvite = diesel::update(
+            game_invites
+                .find(invite.id)
+                .filter(status.eq(InviteStatus::Pending)),
+        )
+        .set(status.eq(new_status))
+        .get_result(conn)
+        .optional()?
+        .ok_or(InviteError::AlreadyAnswered)?;
+        Ok(Ok(invite))
+    })??;
+    notification_counts::invalidate(me);
+
+    StreamManager::global().notify(
+        invite.from_user_id,
+        Notification::GameInviteAnswered {
+            invite_id: invite.id,
+            accepted,
+        },
+    );
+    Ok(invite)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use salvo::test::{ResponseExt, TestClient};
+    use serde_json::{Value, json};
+    use tokio::io::AsyncWriteExt as _;
+
+    use crate::game::pong::{PaddleDirection, Pong};
+    use crate::models::{GameSession, GameSessionState};
+    use crate::prelude::*;
+    use crate::routers::game::tests::{Player, next, state};
+    use crate::stream::testing::CLIENT_TIMEOUT;
+    use crate::test_db;
+    use crate::wire_compat::{golden, timestamp};
+
+    const BASE: &str = "http://localhost/api/game/invites";
+
+    /// Have `alice` invite `bob` and `bob` accept, returns the game.
+    async fn accept_invite(
+        service: &Service,
+        alice: &mut Player,
+        bob: &mut Player,
+    ) -> i32 {
+        let req = TestClient::post(BASE).json(&json!({ "toUserId": bob.id }));
+        let res = alice.client.send(req, service).await;
+        assert_eq!(res.status_code, Some(StatusCode::OK));
+        let invite = bob.notification("GameInvite").await;
+        assert_eq!(invite["fromUserId"], alice.id);
+
+        let req =
+            TestClient::post(format!("{BASE}/{}/accept", invite["inviteId"]));
+        let mut res = bob.client.send(req, service).await;
+        assert_eq!(res.status_code, Some(StatusCode::OK));
+        let body: Value = res.take_json().await.unwrap();
+        assert_eq!(body["invite"]["status"], "accepted");
+        let game_session_id = body["gameSessionId"].as_i64().unwrap() as i32;
+
+        let (alice_id, bob_id) = (alice.id, bob.id);
+        for (player, opponent) in [(alice, bob_id), (bob, alice_id)] {
+            let found = player.notification("MatchFound").await;
+            assert_eq!(found["gameSessionId"], game_session_id);
+            assert_eq!(found["opponentUserId"], opponent);
+            assert!(found["resumeToken"].is_string());
+        }
+        game_session_id
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn accepted_invites_start_a_game() {
+        test_db::with_service(|service| async move {
+            let mut alice = Player::connect(&service, "alice").await;
+            let mut bob = Player::connect(&service, "bob").await;
+            let game = accept_invite(&service, &mut alice, &mut bob).await;
+
+            let (_, mut alice_recv, first) =
+                alice.join(game, "A", bob.id).await;
+            assert_eq!(first, state(0, &Pong::new()));
+            let (_, mut bob_recv, first) = bob.join(game, "B", alice.id).await;
+            assert_eq!(first, state(0, &Pong::new()));
+            // The paused clock makes every tick exactly one game step
+            let mut pong = Pong::new();
+            for tick in 1..=3 {
+                pong.tick([PaddleDirection::Stop; 2]);
+                assert_eq!(next(&mut alice_recv).await, state(tick, &pong));
+                assert_eq!(next(&mut bob_recv).await, state(tick, &pong));
+            }
+        })
+        .await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn players_who_leave_forfeit() {
+        test_db::with_service(|service| async move {
+            let mut alice = Player::connect(&service, "carol").await;
+            let mut bob = Player::connect(&service, "dave").await;
+            let game = accept_invite(&service, &mut alice, &mut bob).await;
+            let (_, mut alice_recv, _) = alice.join(game, "A", bob.id).await;
+            let bob_stream = bob.join(game, "B", alice.id).await;
+
+            drop(bob_stream);
+            bob.stream.heartbeat.shutdown().await.unwrap();
+            bob.stream.closed().await;
+            let mut paused = false;
+            let finished = loop {
+                let message = next(&mut alice_recv).await;
+                match message["type"].as_str() {
+                    Some("State") => assert!(!paused, "{message}"),
+                    Some("Paused") => {
+                        assert_eq!(message["data"]["timeoutSecs"], 30);
+                        paused = true;
+                    }
+                    _ => break message,
+                }
+            };
+            assert!(paused);
+            assert_eq!(
+                finished,
+                json!({
+                    "type": "Finished",
+                    "data": {
+                        "winner": "A",
+                        "forfeit": true,
+                        "scores": [0, 0],
+                    },
+                })
+            );
+
+            // Recorded after the result was sent
+            let state = || {
+                db::with_conn(move |conn| {
+                    crate::schema::game_sessions::table
+                        .find(game)
+                        .first::<GameSession>(conn)
+                        .map(|game| game.state)
+                        .map_err(ApiError::from)
+                })
+            };
+            tokio::time::timeout(CLIENT_TIMEOUT, async {
+                while state().await.unwrap() != GameSessionState::Finished {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .expect("the game should be marked as finished");
+        })
+        .await;
+    }
+
+    golden! {
+        accepted_invite => super::AcceptedInvite {
+            invite: crate::models::GameInvite {
+                id: 9,
+                from_user_id: 7,
+                to_user_id: 8,
+                status: crate::models::InviteStatus::Accepted,
+                created_at: timestamp(),
+                expires_at: timestamp(),
+            },
+            game_session_id: 11,
+        },
+    }
+}
@@ -0,0 +1,36 @@
+//! Endpoints of the [matchmaking queue](crate::game::matchmaking).
+
+use crate::game::matchmaking::{self, QueueStatus};
+use crate::prelude::*;
+
+pub fn router(path: &str) -> Router {
+    Router::with_path(path)
+        .user_rate_limit(&RateLimit::per_minute(30))
+        .push(Router::with_path("join").post(join_queue))
+        .push(Router::with_path("leave").post(leave_queue))
+        .push(Router::with_path("status").get(queue_status))
+}
+
+/// Join the matchmaking queue
+///
+/// Needs an open WebTransport connection. The match is announced with a
+/// `MatchFound` notification on it.
+#[endpoint]
+async fn join_queue(depot: &mut Depot) -> JsonResult<QueueStatus> {
+    let me = depot.user_id();
+    matchmaking::join(me).await?;
+    json_ok(matchmaking::status(me).await)
+}
+
+/// Leave the matchmaking queue
+#[endpoint]
+async fn leave_queue(depot: &mut Depot) -> JsonResult<()> {
+    matchmaking::leave(depot.user_id()).await?;
+    json_ok(())
+}
+
+/// Retrieve the queue position of the current User
+#[endpoint]
+async fn queue_status(depot: &mut Depot) -> JsonResult<QueueStatus> {
+    json_ok(matchmaking::status(depot.user_id()).await)
+}
@@ -0,0 +1,202 @@
+//! Probes for deployments, outside of the `/api` stack.
+//!
+//! `/healthz` answers as long as the process serves requests. `/readyz`
+//! additionally checks the database and answers 503 while it is unusable, so
+//! load balancers can stop sending traffic. Neither needs a login nor is rate
+//! limited or logged.
+
+use std::time::Duration;
+
+use crate::db::DbPool;
+use crate::prelude::*;
+
+/// How long the readiness check waits for a database connection
+const READY_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub fn router() -> Router {
+    Router::new()
+        .push(Router::with_path("healthz").get(healthz))
+        .push(Router::with_path("readyz").get(readyz))
+}
+
+#[handler]
+fn healthz(res: &mut Response) {
+    res.render(Text::Plain("ok"));
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Readiness {
+    ready: bool,
+    version: &'static str,
+    /// Commit the binary was built from, if known
+    git_hash: Option<&'static str>,
+    checks: ReadinessChecks,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ReadinessChecks {
+    database: Check,
+    migrations: Check,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Check {
+    ok: bool,
+    error: Option<String>,
+}
+
+impl Check {
+    fn passed() -> Self {
+        Self {
+            ok: true,
+            error: None,
+        }
+    }
+
+    fn failed(error: impl ToString) -> Self {
+        Self {
+            ok: false,
+            error: Some(error.to_string()),
+        }
+    }
+
+    fn skipped() -> Self {
+        Self::failed("Needs a database connection")
+    }
+}
+
+#[handler]
+async fn readyz(res: &mut Response) {
+    let checks = match tokio::time::timeout(
+        READY_TIMEOUT,
+        // The blocking thread doesn't know about the test's pool
+        tokio::task::spawn_blocking({
+            let pool = db::pool();
+            move || check_database(&pool)
+        }),
+    )
+    .await
+    {
+        Ok(Ok(checks)) => checks,
+        Ok(Err(err)) => ReadinessChecks {
+            database: Check::failed(err),
+            migrations: Check::skipped(),
+        },
+        // The pool is still trying to connect
+        Err(_) => ReadinessChecks {
+            database: Check::failed("Timed out"),
+            migrations: Check::skipped(),
+        },
+    };
+    let ready = checks.database.ok && checks.migrations.ok;
+    if !ready {
+        tracing::warn!(?checks, "Not ready");
+        res.status_code(StatusCode::SERVICE_UNAVAILABLE);
+    }
+    res.render(Json(Readiness {
+        ready,
+        version: env!("CARGO_PKG_VERSION"),
+        git_hash: option_env!("GIT_HASH"),
+        checks,
+    }));
+}
+
+fn check_database(pool: &DbPool) -> ReadinessChecks {
+    let mut conn = match pool.get_timeout(READY_TIMEOUT) {
+        Ok(conn) => conn,
+        Err(err) => {
+            return ReadinessChecks {
+                database: Check::failed(err),
+                migrations: Check::skipped(),
+            };
+        }
+    };
+    let database = match diesel::sql_query("SELECT 1").execute(&mut conn) {
+        Ok(_) => Check::passed(),
+        Err(err) => Check::failed(err),
+    };
+    let migrations = match db::has_pending_migration(&mut conn) {
+        Ok(false) => Check::passed(),
+        Ok(true) => Check::failed("Pending migrations"),
+        Err(err) => Check::failed(err),
+    };
+    ReadinessChecks {
+        database,
+        migrations,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use salvo::test::{ResponseExt, TestClient};
+    use serde_json::Value;
+
+    use super::*;
+    use crate::test_db;
+
+    /// Status and body of `/readyz`.
+    async fn readiness(service: &Service) -> (StatusCode, Value) {
+        let mut res = TestClient::get("http://localhost/readyz")
+            .send(service)
+            .await;
+        (res.status_code.unwrap(), res.take_json().await.unwrap())
+    }
+
+    async fn healthz_status(service: &Service) -> Option<StatusCode> {
+        TestClient::get("http://localhost/healthz")
+            .send(service)
+            .await
+            .status_code
+    }
+
+    #[tokio::test]
+    async fn a_migrated_database_is_ready() {
+        test_db::with_service(|service| async move {
+            assert_eq!(healthz_status(&service).await, Some(StatusCode::OK));
+            let (status, body) = readiness(&service).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body["ready"], true);
+            assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+            assert_eq!(body["checks"]["database"]["ok"], true);
+            assert_eq!(body["checks"]["migrations"]["ok"], true);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn an_unusable_database_is_not_ready() {
+        let service = Service::new(router());
+        let missing = std::env::temp_dir()
+            .join("transcendence_missing_dir")
+            .join("db.sqlite");
+        test_db::with_unmigrated_db(missing, || async {
+            assert_eq!(healthz_status(&service).await, Some(StatusCode::OK));
+            let (status, body) = readiness(&service).await;
+            assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+            assert_eq!(body["ready"], false);
+            let database = &body["checks"]["database"];
+            assert_eq!(database["ok"], false);
+            assert!(database["error"].is_string(), "{body}");
+            assert_eq!(body["checks"]["migrations"]["ok"], false);
+        })
+        .await;
+
+        let empty = std::env::temp_dir().join(format!(
+            "transcendence_unmigrated_{}.sqlite",
+            ulid::Ulid::new()
+        ));
+        test_db::with_unmigrated_db(empty, || async {
+            let (status, body) = readiness(&service).await;
+            assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+            assert_eq!(body["checks"]["database"]["ok"], true);
+            assert_eq!(
+                body["checks"]["migrations"],
+                serde_json::json!({ "ok": false, "error": "Pending migrations" })
+            );
+        })
+        .await;
+    }
+}
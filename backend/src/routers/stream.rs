@@ -0,0 +1,52 @@
+//! Provides WebTransport-related routes and handlers.
+//!
+//! With this you can bind a WebTransport connection that was opened without
+//! an access token to the current user.
+//!
+
+use base64::Engine as _;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as base64url;
+
+use crate::prelude::*;
+use crate::stream::{BindError, StreamManager};
+
+pub fn router(path: &str) -> Router {
+    Router::with_path(path).oapi_tag("stream").push(
+        Router::with_path("bind")
+            .requires_user_login()
+            .user_rate_limit(&RateLimit::per_minute(10))
+            .post(bind),
+    )
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct BindInput {
+    connection_id: u64,
+    /// The nonce from the `BindChallenge`, as sent by the server
+    challenge_nonce: String,
+}
+
+/// Bind a pending WebTransport connection to the current User
+///
+/// Replaces any other WebTransport connection of the User.
+#[endpoint]
+fn bind(json: JsonBody<BindInput>, depot: &mut Depot) -> JsonResult<()> {
+    let BindInput {
+        connection_id,
+        challenge_nonce,
+    } = json.into_inner();
+    let nonce = base64url
+        .decode(challenge_nonce.as_bytes())
+        .map_err(|_| BindError::NonceMismatch)?;
+
+    let session = depot.session();
+    StreamManager::global().bind(connection_id, &nonce, session)?;
+    crate::auth::mark_session_used(
+        &mut db::get()?,
+        session.id,
+        chrono::Utc::now().naive_utc(),
+    )?;
+
+    json_ok(())
+}
@@ -1,12 +1,36 @@
 //! Provides user-related routes and handlers.
 //!
-//! With this you can query users by ID or nickname.
+//! With this you can query users by ID or nickname, search them and view
+//! their profiles. The signup form checks nicknames and emails before
+//! registering.
 //!
 
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+
+use validator::{ValidateEmail, ValidationError, ValidationErrors};
+
+use crate::auth::presence;
+use crate::models::{GameHistory, User};
 use crate::prelude::*;
-use crate::{models::User, stream::StreamManager};
+
+/// Number of games included in a public profile.
+const PROFILE_HISTORY_LEN: i64 = 10;
+const MAX_IDS_PER_REQUEST: usize = 200;
+const MAX_NICKNAMES_PER_REQUEST: usize = 100;
+const DEFAULT_SEARCH_LIMIT: i64 = 20;
+const MAX_SEARCH_LIMIT: i64 = 50;
+/// Free nicknames suggested for a taken one at most.
+const MAX_NICKNAME_SUGGESTIONS: usize = 5;
+
+#[diesel::declare_sql_function]
+extern "SQL" {
+    fn length(x: diesel::sql_types::Text) -> diesel::sql_types::Integer;
+}
 
 pub fn router(path: &str) -> Router {
+    // Shared, so the old path doesn't double the checks per IP
+    let availability_limit = RateLimit::per_15_minutes(60);
     Router::with_path(path)
         .oapi_tag("users")
         .push(Router::new().requires_user_login().append(&mut vec![
@@ -16,34 +40,88 @@ pub fn router(path: &str) -> Router {
                 Router::with_path("nickname")
                     .user_rate_limit(&RateLimit::per_5_minutes(50))
                     .post(get_users_by_nickname),
+                Router::with_path("search")
+                    .user_rate_limit(&RateLimit::per_5_minutes(100))
+                    .get(search_users),
+                Router::with_path("{id}/profile")
+                    .user_rate_limit(&RateLimit::per_5_minutes(200))
+                    .get(get_profile),
             ]))
+        .push(
+            Router::with_path("availability")
+                .ip_rate_limit(&availability_limit)
+                .post(check_availability),
+        )
         .push(
             Router::with_path("nickname-exists")
-                .ip_rate_limit(&RateLimit::per_15_minutes(60))
+                .ip_rate_limit(&availability_limit)
                 .post(check_nickname),
         )
 }
 
 #[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
 pub struct PublicUser {
     pub id: i32,
     pub nickname: String,
     pub created_at: chrono::NaiveDateTime,
+    /// Connected via WebTransport or recently active
     pub online: bool,
+    /// Last REST activity, `None` if never seen
+    pub last_seen: Option<chrono::NaiveDateTime>,
 }
 
 impl From<User> for PublicUser {
     fn from(user: User) -> Self {
+        let (online, last_seen) = presence_of(&user);
         Self {
             id: user.id,
             nickname: user.nickname,
             created_at: user.created_at,
-            online: StreamManager::global().is_connected(user.id),
+            online,
+            last_seen,
         }
     }
 }
 
+/// Online state and last activity of a user.
+fn presence_of(user: &User) -> (bool, Option<chrono::NaiveDateTime>) {
+    let online = presence::is_online(user.id);
+    // The cache is newer unless it was evicted
+    let last_seen = presence::last_seen(user.id).or(user.last_seen);
+    (online, last_seen)
+}
+
+/// Aggregate stats shown on a profile; all zero if the user never played.
+#[derive(Debug, Default, Serialize, ToSchema, Queryable)]
+#[serde(rename_all = "camelCase")]
+pub struct ProfileStats {
+    pub games_played: i32,
+    pub wins: i32,
+    pub losses: i32,
+    pub total_kills: i32,
+    pub total_deaths: i32,
+    /// Total time played in seconds
+    pub total_time_played: i32,
+}
+
 #[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PublicProfile {
+    pub id: i32,
+    pub nickname: String,
+    pub created_at: chrono::NaiveDateTime,
+    /// Connected via WebTransport or recently active
+    pub online: bool,
+    /// Last REST activity, `None` if never seen
+    pub last_seen: Option<chrono::NaiveDateTime>,
+    pub stats: ProfileStats,
+    /// The most recent games, newest first
+    pub recent_games: Vec<GameHistory>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
 struct CheckNicknameOutput {
     exists: bool,
     valid: bool,
@@ -51,46 +129,658 @@ struct CheckNicknameOutput {
 
 /// Check if a nickname is valid and doesn't exist yet
 ///
-/// Does not require authentication
+/// Does not require authentication. Superseded by `POST
+/// /api/users/availability`, and shares its rate limit.
 #[endpoint]
 fn check_nickname(json: JsonBody<String>) -> JsonResult<CheckNicknameOutput> {
-    use crate::schema::users::dsl::*;
+    let conn = &mut db::get()?;
+    let availability = nickname_availability(conn, &json.into_inner())?;
+
+    json_ok(CheckNicknameOutput {
+        exists: !availability.available,
+        valid: availability.valid,
+    })
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct AvailabilityInput {
+    nickname: Option<String>,
+    email: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct AvailabilityOutput {
+    /// Set if a nickname was given
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nickname: Option<FieldAvailability>,
+    /// Set if an email was given
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email: Option<FieldAvailability>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct FieldAvailability {
+    /// Whether registering would accept the value
+    valid: bool,
+    /// Whether no user has it yet. For emails always `true`, unless the
+    /// deployment exposes email availability.
+    available: bool,
+    /// Why the value is invalid
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<String>,
+    /// Free nicknames close to a taken one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    suggestions: Option<Vec<String>>,
+}
+
+impl FieldAvailability {
+    fn new(check: Result<(), ValidationError>, available: bool) -> Self {
+        let errors = match &check {
+            Ok(()) => Vec::new(),
+            Err(err) => vec![match &err.message {
+                Some(message) => message.to_string(),
+                None => err.code.to_string(),
+            }],
+        };
+        Self {
+            valid: check.is_ok(),
+            available,
+            errors,
+            suggestions: None,
+        }
+    }
+}
+
+/// Check a nickname and an email before registering
+///
+/// Does not require authentication. Each given field is validated like on
+/// registration. Up to 5 free nicknames are suggested for a taken one.
+/// Whether an email is taken is only revealed if the deployment enables
+/// `auth.expose_email_availability`, otherwise it is always available.
+#[endpoint]
+fn check_availability(
+    json: JsonBody<AvailabilityInput>,
+) -> JsonResult<AvailabilityOutput> {
     let conn = &mut db::get()?;
     let input = json.into_inner();
+    let mut output = AvailabilityOutput::default();
+
+    if let Some(name) = &input.nickname {
+        let mut availability = nickname_availability(conn, name)?;
+        if availability.valid && !availability.available {
+            availability.suggestions = Some(suggest_nicknames(conn, name)?);
+        }
+        output.nickname = Some(availability);
+    }
+    if let Some(address) = &input.email {
+        let expose = crate::config::get().auth.expose_email_availability;
+        output.email = Some(email_availability(conn, address, expose)?);
+    }
+
+    json_ok(output)
+}
+
+fn nickname_availability(
+    conn: &mut DbConn,
+    name: &str,
+) -> AppResult<FieldAvailability> {
+    use crate::schema::users::dsl::*;
 
     let exists =
-        diesel::select(diesel::dsl::exists(users.filter(nickname.eq(&input))))
-            .get_result(conn)?;
+        diesel::select(diesel::dsl::exists(users.filter(nickname.eq(name))))
+            .get_result::<bool>(conn)?;
+    Ok(FieldAvailability::new(
+        crate::validate::nickname(name),
+        !exists,
+    ))
+}
 
-    let valid = crate::validate::nickname(&input).is_ok();
+/// The email is looked up even if it is invalid or its availability is
+/// hidden, so the response time doesn't tell whether it is taken. Taken
+/// emails are only reported if `expose` is set.
+fn email_availability(
+    conn: &mut DbConn,
+    address: &str,
+    expose: bool,
+) -> AppResult<FieldAvailability> {
+    use crate::schema::users::dsl::*;
 
-    json_ok(CheckNicknameOutput { exists, valid })
+    let address = crate::validate::normalize_email(address);
+    let exists =
+        diesel::select(diesel::dsl::exists(users.filter(email.eq(&address))))
+            .get_result::<bool>(conn)?;
+    let check = if address.validate_email() {
+        crate::validate::email_domain(&address)
+    } else {
+        Err(ValidationError::new("email")
+            .with_message(Cow::Borrowed("Must be a valid email address.")))
+    };
+    Ok(FieldAvailability::new(check, !exists || !expose))
+}
+
+/// Free variants of the taken nickname `name` with a number or underscore
+/// appended, checked in one query.
+fn suggest_nicknames(conn: &mut DbConn, name: &str) -> AppResult<Vec<String>> {
+    use crate::schema::users::dsl::*;
+
+    let suffixes = (1..=9)
+        .map(|n| n.to_string())
+        .chain(std::iter::once("_".to_owned()))
+        .chain((1..=9).map(|n| format!("_{n}")))
+        .chain((10..=99).step_by(11).map(|n| n.to_string()));
+    let candidates: Vec<String> = suffixes
+        .map(|suffix| {
+            // Shorten the nickname to fit the suffix, nicknames are ASCII
+            let base = &name[..name.len().min(16 - suffix.len())];
+            format!("{base}{suffix}")
+        })
+        .filter(|candidate| crate::validate::nickname(candidate).is_ok())
+        .collect();
+
+    // The nickname column is COLLATE NOCASE, which only folds ASCII
+    let taken: HashSet<String> = users
+        .filter(nickname.eq_any(&candidates))
+        .select(nickname)
+        .load::<String>(conn)?
+        .into_iter()
+        .map(|taken| taken.to_ascii_lowercase())
+        .collect();
+    let mut seen = HashSet::new();
+    Ok(candidates
+        .into_iter()
+        .filter(|candidate| {
+            let lower = candidate.to_ascii_lowercase();
+            !taken.contains(&lower) && seen.insert(lower)
+        })
+        .take(MAX_NICKNAME_SUGGESTIONS)
+        .collect())
+}
+
+/// Reject request bodies with more than `max` entries.
+fn check_input_len(
+    field: &'static str,
+    len: usize,
+    max: usize,
+) -> AppResult<()> {
+    if len > max {
+        let mut errs = ValidationErrors::new();
+        errs.add(
+            field,
+            ValidationError::new("length").with_message(Cow::Owned(format!(
+                "Must not contain more than {max} entries."
+            ))),
+        );
+        return Err(errs.into());
+    }
+    Ok(())
 }
 
 /// Retrieve users by their IDs
+///
+/// At most 200 IDs. Users are returned in the order of the first occurrence
+/// of their ID, unknown IDs are skipped.
 #[endpoint]
 fn get_users_by_id(json: JsonBody<Vec<i32>>) -> JsonResult<Vec<PublicUser>> {
     use crate::schema::users::dsl::*;
     let conn = &mut db::get()?;
-    let user_ids = json.into_inner();
+    let mut user_ids = json.into_inner();
+    check_input_len("ids", user_ids.len(), MAX_IDS_PER_REQUEST)?;
+    let mut seen = HashSet::new();
+    user_ids.retain(|user_id| seen.insert(*user_id));
 
-    let result = users.filter(id.eq_any(user_ids)).load::<User>(conn)?;
+    let mut found: HashMap<i32, User> = users
+        .filter(id.eq_any(&user_ids))
+        .load::<User>(conn)?
+        .into_iter()
+        .map(|user| (user.id, user))
+        .collect();
 
-    json_ok(result.into_iter().map(PublicUser::from).collect())
+    json_ok(
+        user_ids
+            .iter()
+            .filter_map(|user_id| found.remove(user_id))
+            .map(PublicUser::from)
+            .collect(),
+    )
 }
 
 /// Retrieve users by their nicknames
+///
+/// At most 100 nicknames, matched case-insensitively. Users are returned in
+/// the order of the first occurrence of their nickname, unknown nicknames are
+/// skipped.
 #[endpoint]
 fn get_users_by_nickname(
     json: JsonBody<Vec<String>>,
 ) -> JsonResult<Vec<PublicUser>> {
     use crate::schema::users::dsl::*;
     let conn = &mut db::get()?;
-    let nicknames = json.into_inner();
+    let mut nicknames = json.into_inner();
+    check_input_len("nicknames", nicknames.len(), MAX_NICKNAMES_PER_REQUEST)?;
+    // The nickname column is COLLATE NOCASE, which only folds ASCII
+    let mut seen = HashSet::new();
+    nicknames.retain(|name| seen.insert(name.to_ascii_lowercase()));
+
+    let mut found: HashMap<String, User> = users
+        .filter(nickname.eq_any(&nicknames))
+        .load::<User>(conn)?
+        .into_iter()
+        .map(|user| (user.nickname.to_ascii_lowercase(), user))
+        .collect();
 
-    let result = users
-        .filter(nickname.eq_any(nicknames))
+    json_ok(
+        nicknames
+            .iter()
+            .filter_map(|name| found.remove(&name.to_ascii_lowercase()))
+            .map(PublicUser::from)
+            .collect(),
+    )
+}
+
+/// Search users by nickname
+///
+/// Matches `query` anywhere in the nickname, case-insensitively. The exact
+/// match comes first, then nicknames starting with `query`, then the rest,
+/// shorter nicknames first within each. `limit` defaults to 20, at most 50.
+#[endpoint]
+fn search_users(
+    query: QueryParam<String, true>,
+    limit: QueryParam<i64, false>,
+) -> JsonResult<Vec<PublicUser>> {
+    use crate::schema::users::dsl::*;
+    let conn = &mut db::get()?;
+    let limit = limit
+        .into_inner()
+        .unwrap_or(DEFAULT_SEARCH_LIMIT)
+        .clamp(1, MAX_SEARCH_LIMIT);
+    let query = query.into_inner();
+    let query = query.trim();
+    if query.is_empty() {
+        return json_ok(Vec::new());
+    }
+    // TODO leave out users blocked in either direction and tell whether a
+    // friend request can be sent, once blocks and friendships exist
+
+    // The exact match is the shortest prefix match
+    let prefix = format!("{}%", db::escape_like(query));
+    let mut found = users
+        .filter(nickname.like(&prefix).escape('\\'))
+        .order((length(nickname).asc(), nickname.asc()))
+        .limit(limit)
         .load::<User>(conn)?;
+    let remaining = limit - found.len() as i64;
+    if remaining > 0 {
+        let substring = format!("%{}", prefix);
+        found.extend(
+            users
+                .filter(nickname.like(substring).escape('\\'))
+                .filter(nickname.not_like(&prefix).escape('\\'))
+                .order((length(nickname).asc(), nickname.asc()))
+                .limit(remaining)
+                .load::<User>(conn)?,
+        );
+    }
+
+    json_ok(found.into_iter().map(PublicUser::from).collect())
+}
+
+/// Retrieve the public profile of a user
+///
+/// Includes lifetime stats and the most recent games.
+#[endpoint]
+fn get_profile(id: PathParam<i32>) -> JsonResult<PublicProfile> {
+    use crate::schema::{game_history, user_stats, users};
+    let conn = &mut db::get()?;
+    let target_user = id.into_inner();
+
+    let (user, stats) = users::table
+        .find(target_user)
+        .left_join(user_stats::table)
+        .select((
+            User::as_select(),
+            (
+                user_stats::games_played,
+                user_stats::wins,
+                user_stats::losses,
+                user_stats::total_kills,
+                user_stats::total_deaths,
+                user_stats::total_time_played,
+            )
+                .nullable(),
+        ))
+        .first::<(User, Option<ProfileStats>)>(conn)?;
+
+    let recent_games = game_history::table
+        .filter(game_history::user_id.eq(target_user))
+        .order((game_history::played_at.desc(), game_history::id.desc()))
+        .limit(PROFILE_HISTORY_LEN)
+        .load::<GameHistory>(conn)?;
+
+    let (online, last_seen) = presence_of(&user);
+    json_ok(PublicProfile {
+        id: user.id,
+        nickname: user.nickname,
+        created_at: user.created_at,
+        online,
+        last_seen,
+        stats: stats.unwrap_or_default(),
+        recent_games,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use salvo::test::{ResponseExt, TestClient};
+    use serde_json::{Value, json};
+
+    use super::*;
+    use crate::test_db::{self, Client};
+    use crate::wire_compat::golden;
+
+    const BASE: &str = "http://localhost/api/users";
+
+    /// POST `body` to `path`, returns the status and the json body.
+    async fn lookup(
+        client: &mut Client,
+        service: &Service,
+        path: &str,
+        body: Value,
+    ) -> (StatusCode, Value) {
+        let mut res = client
+            .send(
+                TestClient::post(format!("{BASE}/{path}")).json(&body),
+                service,
+            )
+            .await;
+        (res.status_code.unwrap(), res.take_json().await.unwrap())
+    }
+
+    fn nicknames(users: &Value) -> Vec<&str> {
+        users
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|user| user["nickname"].as_str().unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn users_by_id_are_deduped_and_keep_the_order() {
+        test_db::with_service(|service| async move {
+            let (mut client, _) =
+                Client::register(&service, "ida@example.com", "ida").await;
+            let conn = &mut db::get().unwrap();
+            let ann = test_db::create_user(conn, "ann").id;
+            let bob = test_db::create_user(conn, "bob").id;
+
+            let (status, users) = lookup(
+                &mut client,
+                &service,
+                "id",
+                json!([bob, 999_999, ann, bob]),
+            )
+            .await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(nicknames(&users), ["bob", "ann"]);
+            assert_eq!(users[0]["id"], bob);
+
+            let (status, _) =
+                lookup(&mut client, &service, "id", json!(vec![ann; 200]))
+                    .await;
+            assert_eq!(status, StatusCode::OK);
+            let (status, body) =
+                lookup(&mut client, &service, "id", json!(vec![ann; 201]))
+                    .await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{body}");
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn users_by_nickname_ignore_case() {
+        test_db::with_service(|service| async move {
+            let (mut client, _) =
+                Client::register(&service, "nia@example.com", "nia").await;
+            let conn = &mut db::get().unwrap();
+            test_db::create_user(conn, "Ann");
+            test_db::create_user(conn, "bob");
+
+            let (status, users) = lookup(
+                &mut client,
+                &service,
+                "nickname",
+                json!(["BOB", "nobody", "ann", "Bob"]),
+            )
+            .await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(nicknames(&users), ["bob", "Ann"]);
+
+            let (status, body) = lookup(
+                &mut client,
+                &service,
+                "nickname",
+                json!(vec!["ann"; 101]),
+            )
+            .await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{body}");
+        })
+        .await;
+    }
+
+    async fn search(
+        client: &mut Client,
+        service: &Service,
+        query: &str,
+        limit: Option<i64>,
+    ) -> Value {
+        let mut req =
+            TestClient::get(format!("{BASE}/search")).query("query", query);
+        if let Some(limit) = limit {
+            req = req.query("limit", limit);
+        }
+        let mut res = client.send(req, service).await;
+        assert_eq!(res.status_code, Some(StatusCode::OK));
+        res.take_json().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn search_ranks_exact_then_prefix_then_substring_matches() {
+        test_db::with_service(|service| async move {
+            let (mut client, _) =
+                Client::register(&service, "sam@example.com", "sam").await;
+            let conn = &mut db::get().unwrap();
+            for nickname in
+                ["joanne", "annabelle", "hannah", "Anna", "ann", "bob"]
+            {
+                test_db::create_user(conn, nickname);
+            }
+
+            let found = search(&mut client, &service, " ANN ", None).await;
+            assert_eq!(
+                nicknames(&found),
+                ["ann", "Anna", "annabelle", "hannah", "joanne"]
+            );
+            let found = search(&mut client, &service, "ann", Some(2)).await;
+            assert_eq!(nicknames(&found), ["ann", "Anna"]);
+            let found = search(&mut client, &service, "ann", Some(0)).await;
+            assert_eq!(nicknames(&found), ["ann"]);
+            // The substring matches fill up after the prefix ones
+            let found = search(&mut client, &service, "ann", Some(4)).await;
+            assert_eq!(
+                nicknames(&found),
+                ["ann", "Anna", "annabelle", "hannah"]
+            );
+            let found = search(&mut client, &service, "  ", None).await;
+            assert_eq!(found, json!([]));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn search_matches_wildcards_literally() {
+        test_db::with_service(|service| async move {
+            let (mut client, _) =
+                Client::register(&service, "tia@example.com", "tia").await;
+            let conn = &mut db::get().unwrap();
+            for nickname in ["x_y", "xzy", "x%z", "xaz"] {
+                test_db::create_user(conn, nickname);
+            }
+
+            let found = search(&mut client, &service, "x_", None).await;
+            assert_eq!(nicknames(&found), ["x_y"]);
+            let found = search(&mut client, &service, "%", None).await;
+            assert_eq!(nicknames(&found), ["x%z"]);
+
+            let res = TestClient::get(format!("{BASE}/search"))
+                .query("query", "x")
+                .send(&service)
+                .await;
+            assert_eq!(res.status_code, Some(StatusCode::UNAUTHORIZED));
+        })
+        .await;
+    }
 
-    json_ok(result.into_iter().map(PublicUser::from).collect())
+    async fn availability(service: &Service, body: Value) -> Value {
+        let mut res = TestClient::post(format!("{BASE}/availability"))
+            .json(&body)
+            .send(service)
+            .await;
+        assert_eq!(res.status_code, Some(StatusCode::OK));
+        res.take_json().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn suggestions_skip_taken_nicknames() {
+        test_db::with_service(|service| async move {
+            let conn = &mut db::get().unwrap();
+            for nickname in ["neo", "Neo1", "neo3", "neo_"] {
+                test_db::create_user(conn, nickname);
+            }
+
+            let body =
+                availability(&service, json!({ "nickname": "NEO" })).await;
+            assert_eq!(
+                body,
+                json!({ "nickname": {
+                    "valid": true,
+                    "available": false,
+                    "suggestions": ["NEO2", "NEO4", "NEO5", "NEO6", "NEO7"],
+                }})
+            );
+
+            // The suffix replaces the end of a nickname of maximal length
+            let long = "abcdefghijklmnop";
+            test_db::create_user(conn, long);
+            let body =
+                availability(&service, json!({ "nickname": long })).await;
+            let suggestions =
+                body["nickname"]["suggestions"].as_array().unwrap();
+            assert_eq!(suggestions[0], "abcdefghijklmno1");
+            assert_eq!(suggestions.len(), MAX_NICKNAME_SUGGESTIONS);
+
+            // The old path answers like before
+            let res = TestClient::post(format!("{BASE}/nickname-exists"))
+                .json(&"neo")
+                .send(&service)
+                .await
+                .take_json::<Value>()
+                .await
+                .unwrap();
+            assert_eq!(res, json!({ "exists": true, "valid": true }));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn invalid_fields_are_reported_separately() {
+        test_db::with_service(|service| async move {
+            let body = availability(
+                &service,
+                json!({ "nickname": "no spaces", "email": "zoe@example.com" }),
+            )
+            .await;
+            let nickname = &body["nickname"];
+            assert_eq!(nickname["valid"], false);
+            assert_eq!(nickname["available"], true);
+            assert_eq!(nickname["errors"].as_array().unwrap().len(), 1);
+            assert!(nickname.get("suggestions").is_none(), "{body}");
+            assert_eq!(
+                body["email"],
+                json!({ "valid": true, "available": true })
+            );
+
+            let body = availability(
+                &service,
+                json!({ "nickname": "zoe", "email": "not-an-email" }),
+            )
+            .await;
+            assert_eq!(
+                body["nickname"],
+                json!({ "valid": true, "available": true })
+            );
+            assert_eq!(
+                body["email"],
+                json!({
+                    "valid": false,
+                    "available": true,
+                    "errors": ["Must be a valid email address."],
+                })
+            );
+            assert_eq!(availability(&service, json!({})).await, json!({}));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn taken_emails_are_only_exposed_when_enabled() {
+        test_db::with_service(|service| async move {
+            Client::register(&service, "kim@example.com", "kim").await;
+
+            // The test config keeps the flag off
+            let body =
+                availability(&service, json!({ "email": " KIM@example.com" }))
+                    .await;
+            assert_eq!(
+                body["email"],
+                json!({ "valid": true, "available": true })
+            );
+
+            let conn = &mut db::get().unwrap();
+            let hidden =
+                email_availability(conn, "kim@example.com", false).unwrap();
+            assert!(hidden.available);
+            let exposed =
+                email_availability(conn, " KIM@example.com", true).unwrap();
+            assert!(exposed.valid);
+            assert!(!exposed.available);
+            let free =
+                email_availability(conn, "lee@example.com", true).unwrap();
+            assert!(free.available);
+        })
+        .await;
+    }
+
+    golden! {
+        check_nickname_output => CheckNicknameOutput {
+            exists: true,
+            valid: true,
+        },
+        availability_output => AvailabilityOutput {
+            nickname: Some(FieldAvailability {
+                valid: true,
+                available: false,
+                errors: Vec::new(),
+                suggestions: Some(vec!["ann1".into(), "ann_2".into()]),
+            }),
+            email: Some(FieldAvailability {
+                valid: false,
+                available: true,
+                errors: vec!["Invalid email".into()],
+                suggestions: None,
+            }),
+        },
+    }
 }
@@ -1,5 +1,75 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    feature_flags (key) {
+        key -> Text,
+        enabled -> Bool,
+        payload -> Nullable<Text>,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    game_history (id) {
+        id -> Integer,
+        user_id -> Integer,
+        kills -> Integer,
+        time_played -> Integer,
+        played_at -> Timestamp,
+        game_session_id -> Nullable<Integer>,
+        verified -> Bool,
+        reported_kills -> Nullable<Integer>,
+        reported_time_played -> Nullable<Integer>,
+        deaths -> Integer,
+        won -> Bool,
+    }
+}
+
+diesel::table! {
+    game_invites (id) {
+        id -> Integer,
+        from_user_id -> Integer,
+        to_user_id -> Integer,
+        status -> Text,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    game_sessions (id) {
+        id -> Integer,
+        player_a -> Nullable<Integer>,
+        player_b -> Nullable<Integer>,
+        state -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    idempotency_keys (id) {
+        id -> Integer,
+        user_id -> Integer,
+        key -> Text,
+        request_hash -> Binary,
+        status -> Nullable<Integer>,
+        response_body -> Nullable<Binary>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    security_events (id) {
+        id -> Integer,
+        user_id -> Integer,
+        event_type -> Text,
+        metadata -> Nullable<Text>,
+        ip_address -> Nullable<Text>,
+        device_name -> Nullable<Text>,
+        created_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     sessions (id) {
         id -> Integer,
@@ -12,6 +82,20 @@ diesel::table! {
         refreshed_at -> Timestamp,
         last_used_at -> Timestamp,
         last_authenticated_at -> Timestamp,
+        device_name_auto -> Nullable<Text>,
+        prev_token_hash -> Nullable<Binary>,
+    }
+}
+
+diesel::table! {
+    trusted_devices (id) {
+        id -> Integer,
+        user_id -> Integer,
+        device_id -> Text,
+        token_hash -> Binary,
+        created_at -> Timestamp,
+        last_used_at -> Timestamp,
+        expires_at -> Timestamp,
     }
 }
 
@@ -25,6 +109,33 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    user_bans (id) {
+        id -> Integer,
+        user_id -> Integer,
+        banned_by -> Nullable<Integer>,
+        reason -> Text,
+        until -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+        lifted_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    user_stats (user_id) {
+        user_id -> Integer,
+        games_played -> Integer,
+        total_kills -> Integer,
+        total_time_played -> Integer,
+        last_game_kills -> Integer,
+        last_game_time_played -> Integer,
+        last_game_at -> Timestamp,
+        wins -> Integer,
+        losses -> Integer,
+        total_deaths -> Integer,
+    }
+}
+
 diesel::table! {
     users (id) {
         id -> Integer,
@@ -35,10 +146,33 @@ diesel::table! {
         totp_confirmed_at -> Nullable<Timestamp>,
         password_hash -> Text,
         created_at -> Timestamp,
+        is_admin -> Bool,
+        last_seen -> Nullable<Timestamp>,
+        locked_at -> Nullable<Timestamp>,
+        lock_reason -> Nullable<Text>,
     }
 }
 
+diesel::joinable!(game_history -> game_sessions (game_session_id));
+diesel::joinable!(game_history -> users (user_id));
+diesel::joinable!(idempotency_keys -> users (user_id));
+diesel::joinable!(security_events -> users (user_id));
 diesel::joinable!(sessions -> users (user_id));
+diesel::joinable!(trusted_devices -> users (user_id));
 diesel::joinable!(two_fa_recovery_codes -> users (user_id));
+diesel::joinable!(user_stats -> users (user_id));
 
-diesel::allow_tables_to_appear_in_same_query!(sessions, two_fa_recovery_codes, users,);
+diesel::allow_tables_to_appear_in_same_query!(
+    feature_flags,
+    game_history,
+    game_invites,
+    game_sessions,
+    idempotency_keys,
+    security_events,
+    sessions,
+    trusted_devices,
+    two_fa_recovery_codes,
+    user_bans,
+    user_stats,
+    users,
+);
@@ -0,0 +1,53 @@
+//! Why the server closed a connection, as a code the client can act on.
+//!
+//! Before the server closes a connection it sends
+//! [`ControlServerMessage::Shutdown`](super::control::ControlServerMessage::Shutdown)
+//! with a [`CloseCode`] and a short reason on the control stream. A WebSocket
+//! connection is then closed with the same code and reason. A WebTransport
+//! session just ends: the HTTP/3 implementation can't close a session with an
+//! application code, so there the control message is the only place the code
+//! is sent.
+//!
+//! | Code | Name                | What the client should do                  |
+//! |------|---------------------|--------------------------------------------|
+//! | 4000 | `Generic`           | Reconnect                                  |
+//! | 4001 | `AuthExpired`       | Refresh or log in again, then reconnect    |
+//! | 4002 | `Replaced`          | Nothing, a newer connection took over      |
+//! | 4003 | `BindTimeout`       | Reconnect and bind the new connection      |
+//! | 4004 | `ProtocolViolation` | Fix the client, don't retry right away     |
+//! | 4005 | `ServerShutdown`    | Reconnect with backoff                     |
+//! | 4006 | `Banned`            | Don't reconnect, the account is restricted |
+//! | 4007 | `UserRequested`     | Don't reconnect until the user acts        |
+
+use serde::Serialize;
+
+/// Application close code of a connection, serialized as its number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(into = "u16")]
+#[repr(u16)]
+pub enum CloseCode {
+    /// Closed by the server for another reason, e.g. a failed stream
+    Generic = 4000,
+    /// The access of the session ended, or the session was logged out or
+    /// revoked
+    AuthExpired = 4001,
+    /// Another connection of the user replaced this one
+    Replaced = 4002,
+    /// The connection wasn't bound before the bind deadline
+    BindTimeout = 4003,
+    /// The client broke the stream protocol, e.g. in the handshake
+    ProtocolViolation = 4004,
+    /// The server is shutting down
+    ServerShutdown = 4005,
+    /// The user was banned or the account locked
+    Banned = 4006,
+    /// The user closed the connection, e.g. from the sessions screen of
+    /// another device
+    UserRequested = 4007,
+}
+
+impl From<CloseCode> for u16 {
+    fn from(code: CloseCode) -> Self {
+        code as u16
+    }
+}
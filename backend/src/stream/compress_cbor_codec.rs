@@ -18,7 +18,8 @@
 //! ```
 //!
 //! - **`total_len`** (u32, big-endian): Length of `flags + payload`
-//! - **`flags`** (u8): `0x00` = uncompressed CBOR, `0x01` = Zstd-compressed CBOR
+//! - **`flags`** (u8): `0x00` = uncompressed CBOR, `0x01` = Zstd-compressed CBOR,
+//!   `0x02` = Zstd-compressed CBOR using a shared dictionary
 //! - **`payload`**: Raw or compressed CBOR data
 //!
 //! # Usage Example
@@ -38,18 +39,47 @@
 //!
 //! Compression is applied only when the serialized CBOR payload exceeds
 //! [`COMPRESS_THRESHOLD`] bytes (default: 1 KiB). This avoids the overhead of
-//! compressing small messages where the savings would be negligible.
+//! compressing small messages where the savings would be negligible. A client
+//! that declined zstd in the handshake gets encoders with compression turned
+//! off via [`CompressedCborEncoder::set_compression`], which only produce
+//! flag `0x00`.
+//!
+//! ## Dictionary Mode
+//!
+//! Small, repetitive frames (e.g. game state updates) barely compress on their
+//! own. If both sides agree on a zstd dictionary, create the codecs with
+//! [`CompressedCborEncoder::with_dictionary`] and
+//! [`CompressedCborDecoder::with_dictionary`]. The encoder then compresses
+//! every frame with the dictionary regardless of the threshold and keeps the
+//! result only if it is smaller than the raw CBOR (flag `0x02`), otherwise it
+//! falls back to an uncompressed frame (flag `0x00`). Streams without a
+//! dictionary never produce flag `0x02`.
 //!
 //! # Security
 //!
 //! The decoder enforces a maximum frame size (`MAX_DECODE_FRAME`, default: 8 MiB)
-//! to prevent denial-of-service attacks via memory exhaustion.
+//! to prevent denial-of-service attacks via memory exhaustion. The same limit
+//! applies to the decompressed payload of compressed frames, so a small
+//! "zstd bomb" can't expand into an arbitrarily large allocation.
+//!
+//! Nesting of arrays, maps and enum variants is limited to `MAX_DEPTH`
+//! (default: 32) levels, so a small frame like `[[[[...]]]]` can't recurse
+//! deeply while deserializing. The number of elements needs no own limit:
+//! every CBOR item takes at least one byte, so it is bounded by the frame
+//! size.
+//!
+//! A frame that breaks these rules or the wire format fails with
+//! [`InvalidFrame`], which stream handlers treat as a protocol violation.
+//! Other errors of a [`Decoder`] come from reading the stream.
 
-use std::{io::Write, marker::PhantomData};
+use std::io::{self, Read, Write};
+use std::{marker::PhantomData, sync::Arc};
 
 use bytes::{Buf, BufMut, BytesMut};
 use serde::{Serialize, de::DeserializeOwned};
+use thiserror::Error;
 use tokio_util::codec::{Decoder, Encoder};
+use zstd::dict::{DecoderDictionary, EncoderDictionary};
 
 use crate::utils::adaptive_buffer::{AdaptiveBuffer, BufferParams};
 
@@ -69,6 +99,19 @@ const COMPRESS_THRESHOLD: usize = 1024;
 /// suitable for real-time network traffic.
 const COMPRESS_LEVEL: i32 = 3;
 
+/// Default maximum size of a received frame, see [`CompressedCborDecoder`].
+pub const DEFAULT_MAX_FRAME: usize = 8 * 1024 * 1024;
+
+/// Default maximum nesting of a received frame, see [`CompressedCborDecoder`].
+pub const DEFAULT_MAX_DEPTH: usize = 32;
+
+/// Frame flag: uncompressed CBOR.
+const FLAG_RAW: u8 = 0;
+/// Frame flag: Zstd-compressed CBOR.
+const FLAG_ZSTD: u8 = 1;
+/// Frame flag: Zstd-compressed CBOR using the shared dictionary.
+const FLAG_ZSTD_DICT: u8 = 2;
+
 // =============================================================================
 // Codec Buffer Parameters
 // =============================================================================
@@ -104,7 +147,8 @@ impl BufferParams for CodecBufferParams {
 /// ```
 ///
 /// - **`total_len`**: Length of everything after this field (flags + payload)
-/// - **`flags`**: `0x00` = uncompressed, `0x01` = Zstd-compressed
+/// - **`flags`**: `0x00` = uncompressed, `0x01` = Zstd-compressed,
+///   `0x02` = Zstd-compressed with dictionary
 /// - **`payload`**: The CBOR data (raw or compressed)
 ///
 /// # Memory Management
@@ -120,6 +164,11 @@ pub struct CompressedCborEncoder<T, BP: BufferParams = CodecBufferParams> {
     cbor_buf: AdaptiveBuffer<u8, BP>,
     /// Temporary buffer for Zstd-compressed output.
     compress_buf: AdaptiveBuffer<u8, BP>,
+    /// Prepared dictionary, see [`with_dictionary`](Self::with_dictionary).
+    dictionary: Option<Arc<EncoderDictionary<'static>>>,
+    /// Whether frames may be compressed, see
+    /// [`set_compression`](Self::set_compression).
+    compression: bool,
     /// Marker for the message type `T`.
     _phantom: PhantomData<T>,
 }
@@ -134,9 +183,33 @@ impl<T, BP: BufferParams> CompressedCborEncoder<T, BP> {
         Self {
             cbor_buf: AdaptiveBuffer::new(),
             compress_buf: AdaptiveBuffer::new(),
+            dictionary: None,
+            compression: true,
             _phantom: PhantomData,
         }
     }
+
+    /// Allow or forbid compressed frames, e.g. for a client that declined
+    /// zstd. Without compression every frame is sent as raw CBOR.
+    pub fn set_compression(&mut self, enabled: bool) {
+        self.compression = enabled;
+    }
+
+    /// Creates a new encoder that compresses every frame with the given
+    /// zstd dictionary, as long as that makes the frame smaller.
+    ///
+    /// The receiving side must use a [`CompressedCborDecoder`] with the
+    /// same dictionary.
+    #[must_use]
+    pub fn with_dictionary(dict: Arc<[u8]>) -> Self {
+        Self {
+            dictionary: Some(Arc::new(EncoderDictionary::copy(
+                &dict,
+                COMPRESS_LEVEL,
+            ))),
+            ..Self::new()
+        }
+    }
 }
 
 impl<T, BP: BufferParams> Default for CompressedCborEncoder<T, BP> {
@@ -159,7 +232,26 @@ impl<T: Serialize, BP: BufferParams> Encoder<T>
         ciborium::into_writer(&item, self.cbor_buf.as_mut_vec())?;
 
         // Step 2: Decide whether to compress based on payload size
-        let (payload, flags) = if self.cbor_buf.len() > COMPRESS_THRESHOLD {
+        let used_compress_buf;
+        let flags = if !self.compression {
+            used_compress_buf = false;
+            FLAG_RAW
+        } else if let Some(dictionary) = &self.dictionary {
+            // Compress with the dictionary regardless of size, but only
+            // keep the result if it actually shrinks the payload
+            let mut encoder = zstd::Encoder::with_prepared_dictionary(
+                self.compress_buf.as_mut_vec(),
+                dictionary,
+            )?;
+            encoder.write_all(&self.cbor_buf)?;
+            encoder.finish()?; // Critical: flushes remaining compressed data
+            used_compress_buf = true;
+            if self.compress_buf.len() < self.cbor_buf.len() {
+                FLAG_ZSTD_DICT
+            } else {
+                FLAG_RAW
+            }
+        } else if self.cbor_buf.len() > COMPRESS_THRESHOLD {
             // Compress with Zstd - encoder must be finished to flush all data
             let mut encoder = zstd::Encoder::new(
                 self.compress_buf.as_mut_vec(),
@@ -167,11 +259,27 @@ impl<T: Serialize, BP: BufferParams> Encoder<T>
             )?;
             encoder.write_all(&self.cbor_buf)?;
             encoder.finish()?; // Critical: flushes remaining compressed data
-            (self.compress_buf.as_vec().as_slice(), 1u8)
+            used_compress_buf = true;
+            FLAG_ZSTD
         } else {
             // Send uncompressed
-            (self.cbor_buf.as_vec().as_slice(), 0u8)
+            used_compress_buf = false;
+            FLAG_RAW
         };
+        let payload = if flags == FLAG_RAW {
+            self.cbor_buf.as_vec().as_slice()
+        } else {
+            self.compress_buf.as_vec().as_slice()
+        };
+        if self.cbor_buf.len() > COMPRESS_THRESHOLD {
+            tracing::debug!(
+                message_type = std::any::type_name::<T>(),
+                cbor_len = self.cbor_buf.len(),
+                frame_len = payload.len(),
+                flags,
+                "Large stream frame"
+            );
+        }
 
         // Step 3: Write the length-prefixed frame to the output buffer
         // Frame structure: [total_len: u32][flags: u8][payload: bytes]
@@ -183,7 +291,7 @@ impl<T: Serialize, BP: BufferParams> Encoder<T>
 
         // Step 4: Reset internal buffers for next message (may trigger shrinking)
         self.cbor_buf.finish();
-        if flags == 1u8 {
+        if used_compress_buf {
             self.compress_buf.finish();
         }
 
@@ -195,6 +303,100 @@ impl<T: Serialize, BP: BufferParams> Encoder<T>
 // CompressedCborDecoder
 // =============================================================================
 
+/// A reader that fails once the inner reader produced more than `limit` bytes.
+struct LimitedReader<R> {
+    inner: R,
+    remaining: usize,
+    exceeded: bool,
+}
+
+impl<R: Read> LimitedReader<R> {
+    fn new(inner: R, limit: usize) -> Self {
+        Self {
+            inner,
+            remaining: limit,
+            exceeded: false,
+        }
+    }
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Allow reading one byte past the limit to detect exceeding it
+        let max = buf.len().min(self.remaining.saturating_add(1));
+        let n = self.inner.read(&mut buf[..max])?;
+        if n > self.remaining {
+            self.exceeded = true;
+            return Err(io::Error::other("decompressed payload too large"));
+        }
+        self.remaining -= n;
+        Ok(n)
+    }
+}
+
+/// Parse CBOR from a decompressing reader, producing at most `limit` bytes.
+fn from_decompressing_reader<T: DeserializeOwned>(
+    reader: impl Read,
+    limit: usize,
+    max_depth: usize,
+) -> Result<T, InvalidFrame> {
+    let mut reader = LimitedReader::new(reader, limit);
+    from_reader(&mut reader, max_depth).map_err(|err| {
+        if reader.exceeded {
+            InvalidFrame::DecompressedTooLarge { max: limit }
+        } else {
+            err
+        }
+    })
+}
+
+/// Parse CBOR nested at most `max_depth` levels.
+///
+/// The payload is already in memory, so errors of the reader mean it is
+/// truncated or doesn't decompress.
+fn from_reader<T: DeserializeOwned>(
+    reader: impl Read,
+    max_depth: usize,
+) -> Result<T, InvalidFrame> {
+    use ciborium::de::Error;
+
+    ciborium::de::from_reader_with_recursion_limit(reader, max_depth).map_err(
+        |err| match err {
+            Error::RecursionLimitExceeded => InvalidFrame::TooDeep(max_depth),
+            Error::Io(err) => InvalidFrame::Malformed(err.to_string()),
+            Error::Syntax(offset) => {
+                InvalidFrame::Malformed(format!("syntax error at {offset}"))
+            }
+            Error::Semantic(_, message) => InvalidFrame::Malformed(message),
+        },
+    )
+}
+
+/// A received frame that breaks the wire format or the limits of the
+/// [`CompressedCborDecoder`].
+///
+/// Returned wrapped in the [`anyhow::Error`] of the decoder, check for it with
+/// [`anyhow::Error::is`].
+#[derive(Error, Debug)]
+pub enum InvalidFrame {
+    #[error("Frame size {size} exceeds maximum allowed size {max}")]
+    TooLarge { size: usize, max: usize },
+    #[error("Invalid frame: total_len must be at least 1 (for flags byte)")]
+    Empty,
+    #[error("Unknown frame flags: {0} (expected 0, 1 or 2)")]
+    UnknownFlags(u8),
+    #[error(
+        "Received dictionary-compressed frame, but no dictionary is configured"
+    )]
+    NoDictionary,
+    #[error("Decompressed payload exceeds maximum allowed size {max}")]
+    DecompressedTooLarge { max: usize },
+    #[error("Frame is nested deeper than {0} levels")]
+    TooDeep(usize),
+    #[error("Malformed CBOR: {0}")]
+    Malformed(String),
+}
+
 /// A decoder that deserializes types using CBOR with optional Zstd decompression.
 ///
 /// This decoder implements [`Decoder`] from `tokio_util::codec`,
@@ -207,7 +409,8 @@ impl<T: Serialize, BP: BufferParams> Encoder<T>
 /// ```
 ///
 /// - **`total_len`**: Length of everything after this field (flags + payload)
-/// - **`flags`**: `0x00` = uncompressed, `0x01` = Zstd-compressed
+/// - **`flags`**: `0x00` = uncompressed, `0x01` = Zstd-compressed,
+///   `0x02` = Zstd-compressed with dictionary
 /// - **`payload`**: The CBOR data (raw or compressed)
 ///
 /// # Type Parameters
@@ -216,36 +419,52 @@ impl<T: Serialize, BP: BufferParams> Encoder<T>
 /// # Const Generics
 /// - `MAX_DECODE_FRAME`: Maximum allowed frame size for decoding (default: 8 MiB).
 ///   Frames larger than this will cause a decoding error.
+/// - `MAX_DEPTH`: Maximum nesting of arrays, maps and enum variants
+///   (default: 32). Deeper frames will cause a decoding error.
 pub struct CompressedCborDecoder<
     T,
-    const MAX_DECODE_FRAME: usize = { 8 * 1024 * 1024 },
+    const MAX_DECODE_FRAME: usize = DEFAULT_MAX_FRAME,
+    const MAX_DEPTH: usize = DEFAULT_MAX_DEPTH,
 > {
+    /// Prepared dictionary, see [`with_dictionary`](Self::with_dictionary).
+    dictionary: Option<Arc<DecoderDictionary<'static>>>,
     /// Marker for the message type `T`.
     _phantom: PhantomData<T>,
 }
 
-impl<T, const MAX_DECODE_FRAME: usize>
-    CompressedCborDecoder<T, MAX_DECODE_FRAME>
+impl<T, const MAX_DECODE_FRAME: usize, const MAX_DEPTH: usize>
+    CompressedCborDecoder<T, MAX_DECODE_FRAME, MAX_DEPTH>
 {
     /// Creates a new decoder.
     #[must_use]
     pub fn new() -> Self {
         Self {
+            dictionary: None,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Creates a new decoder that also accepts frames compressed with the
+    /// given zstd dictionary (flag `0x02`).
+    #[must_use]
+    pub fn with_dictionary(dict: Arc<[u8]>) -> Self {
+        Self {
+            dictionary: Some(Arc::new(DecoderDictionary::copy(&dict))),
             _phantom: PhantomData,
         }
     }
 }
 
-impl<T, const MAX_DECODE_FRAME: usize> Default
-    for CompressedCborDecoder<T, MAX_DECODE_FRAME>
+impl<T, const MAX_DECODE_FRAME: usize, const MAX_DEPTH: usize> Default
+    for CompressedCborDecoder<T, MAX_DECODE_FRAME, MAX_DEPTH>
 {
     fn default() -> Self {
         Self::new()
     }
 }
 
-impl<T: DeserializeOwned, const MAX_DECODE_FRAME: usize> Decoder
-    for CompressedCborDecoder<T, MAX_DECODE_FRAME>
+impl<T: DeserializeOwned, const MAX_DECODE_FRAME: usize, const MAX_DEPTH: usize>
+    Decoder for CompressedCborDecoder<T, MAX_DECODE_FRAME, MAX_DEPTH>
 {
     type Item = T;
     type Error = anyhow::Error;
@@ -265,17 +484,14 @@ impl<T: DeserializeOwned, const MAX_DECODE_FRAME: usize> Decoder
 
         // Step 2: Validate frame size to prevent DoS attacks
         if total_len > MAX_DECODE_FRAME {
-            return Err(anyhow::anyhow!(
-                "Frame size {} exceeds maximum allowed size {}",
-                total_len,
-                MAX_DECODE_FRAME
-            ));
+            return Err(InvalidFrame::TooLarge {
+                size: total_len,
+                max: MAX_DECODE_FRAME,
+            }
+            .into());
         }
         if total_len < 1 {
-            return Err(anyhow::anyhow!(
-                "Invalid frame: total_len must be at least 1 (for flags byte), got {}",
-                total_len
-            ));
+            return Err(InvalidFrame::Empty.into());
         }
 
         // Step 3: Check if we have the complete frame
@@ -295,25 +511,286 @@ impl<T: DeserializeOwned, const MAX_DECODE_FRAME: usize> Decoder
 
         // Step 6: Deserialize based on compression flag
         let item = match flags {
-            1 => {
+            FLAG_ZSTD => {
                 // Compressed: wrap in Zstd decoder, then parse CBOR
                 let compressed_data = src.split_to(payload_len);
                 let decoder = zstd::Decoder::new(compressed_data.reader())?;
-                ciborium::from_reader(decoder)?
+                from_decompressing_reader(decoder, MAX_DECODE_FRAME, MAX_DEPTH)?
+            }
+            FLAG_ZSTD_DICT => {
+                // Compressed with dictionary: requires a matching dictionary
+                let compressed_data = src.split_to(payload_len);
+                let Some(dictionary) = &self.dictionary else {
+                    return Err(InvalidFrame::NoDictionary.into());
+                };
+                let decoder = zstd::Decoder::with_prepared_dictionary(
+                    std::io::BufReader::new(compressed_data.reader()),
+                    dictionary,
+                )?;
+                from_decompressing_reader(decoder, MAX_DECODE_FRAME, MAX_DEPTH)?
             }
-            0 => {
+            FLAG_RAW => {
                 // Uncompressed: parse CBOR directly
                 let raw_data = src.split_to(payload_len);
-                ciborium::from_reader(raw_data.reader())?
+                from_reader(raw_data.reader(), MAX_DEPTH)?
             }
             unknown => {
-                return Err(anyhow::anyhow!(
-                    "Unknown frame flags: {} (expected 0 or 1)",
-                    unknown
-                ));
+                return Err(InvalidFrame::UnknownFlags(unknown).into());
             }
         };
 
         Ok(Some(item))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+
+    use super::*;
+
+    /// A small game state update, like the frames dictionaries are for.
+    fn state(tick: u64) -> Value {
+        json!({
+            "type": "State",
+            "data": {
+                "tick": tick,
+                "ball": { "x": 0.5, "y": 0.25, "vx": -0.01, "vy": 0.02 },
+                "paddles": [{ "y": 0.5, "score": 3 }, { "y": 0.4, "score": 1 }],
+            },
+        })
+    }
+
+    /// A raw-content dictionary made of the CBOR of a few states.
+    fn dictionary() -> Arc<[u8]> {
+        let mut dict = Vec::new();
+        for tick in 0..4 {
+            ciborium::into_writer(&state(tick), &mut dict).unwrap();
+        }
+        dict.into()
+    }
+
+    fn encode(
+        encoder: &mut CompressedCborEncoder<Value>,
+        item: Value,
+    ) -> BytesMut {
+        let mut frame = BytesMut::new();
+        encoder.encode(item, &mut frame).unwrap();
+        frame
+    }
+
+    fn decode(
+        decoder: &mut CompressedCborDecoder<Value>,
+        mut frame: BytesMut,
+    ) -> anyhow::Result<Value> {
+        let item = decoder.decode(&mut frame)?.expect("the frame is complete");
+        assert!(frame.is_empty());
+        Ok(item)
+    }
+
+    fn invalid_frame(result: anyhow::Result<Value>) -> InvalidFrame {
+        result
+            .unwrap_err()
+            .downcast()
+            .expect("the error should be an InvalidFrame")
+    }
+
+    #[test]
+    fn small_frames_are_raw_and_large_ones_compressed() {
+        let mut encoder = CompressedCborEncoder::new();
+        let mut decoder = CompressedCborDecoder::new();
+
+        let small = encode(&mut encoder, state(1));
+        let mut cbor = Vec::new();
+        ciborium::into_writer(&state(1), &mut cbor).unwrap();
+        assert_eq!(small[..4], ((cbor.len() + 1) as u32).to_be_bytes());
+        assert_eq!(small[4], FLAG_RAW);
+        assert_eq!(small[5..], cbor);
+        assert_eq!(decode(&mut decoder, small).unwrap(), state(1));
+
+        let large = json!(vec![state(2); 32]);
+        let frame = encode(&mut encoder, large.clone());
+        assert_eq!(frame[4], FLAG_ZSTD);
+        assert_eq!(decode(&mut decoder, frame).unwrap(), large);
+
+        encoder.set_compression(false);
+        let frame = encode(&mut encoder, large.clone());
+        assert_eq!(frame[4], FLAG_RAW);
+        assert_eq!(decode(&mut decoder, frame).unwrap(), large);
+    }
+
+    #[test]
+    fn small_frames_round_trip_with_a_dictionary() {
+        let mut encoder = CompressedCborEncoder::with_dictionary(dictionary());
+        let mut decoder = CompressedCborDecoder::with_dictionary(dictionary());
+
+        for tick in [1, 100, 10_000] {
+            let frame = encode(&mut encoder, state(tick));
+            assert_eq!(frame[4], FLAG_ZSTD_DICT);
+            assert!(frame.len() < 40, "only {} bytes saved", frame.len());
+            assert_eq!(decode(&mut decoder, frame).unwrap(), state(tick));
+        }
+        let large = json!(vec![state(2); 32]);
+        let frame = encode(&mut encoder, large.clone());
+        assert_eq!(frame[4], FLAG_ZSTD_DICT);
+        assert_eq!(decode(&mut decoder, frame).unwrap(), large);
+    }
+
+    #[test]
+    fn frames_that_dont_shrink_fall_back_to_raw() {
+        let mut encoder = CompressedCborEncoder::with_dictionary(dictionary());
+        // Without a dictionary the decoder still reads the fallback frames
+        let mut decoder = CompressedCborDecoder::new();
+
+        for item in [json!(1), json!("x"), json!([true, null])] {
+            let frame = encode(&mut encoder, item.clone());
+            assert_eq!(frame[4], FLAG_RAW);
+            assert_eq!(decode(&mut decoder, frame).unwrap(), item);
+        }
+    }
+
+    #[test]
+    fn dictionary_frames_need_the_dictionary() {
+        let mut encoder = CompressedCborEncoder::with_dictionary(dictionary());
+        let frame = encode(&mut encoder, state(1));
+
+        let mut without = CompressedCborDecoder::new();
+        assert!(matches!(
+            invalid_frame(decode(&mut without, frame.clone())),
+            InvalidFrame::NoDictionary
+        ));
+
+        let mut other = CompressedCborDecoder::with_dictionary(
+            b"a different dictionary".as_slice().into(),
+        );
+        assert!(decode(&mut other, frame.clone()).is_err());
+    }
+
+    #[test]
+    fn corrupt_dictionary_frames_are_malformed() {
+        let mut encoder = CompressedCborEncoder::with_dictionary(dictionary());
+        let mut decoder = CompressedCborDecoder::with_dictionary(dictionary());
+        let mut frame = encode(&mut encoder, state(1));
+        let last = frame.len() - 1;
+        frame.truncate(last);
+        frame[..4].copy_from_slice(&(last as u32 - 4).to_be_bytes());
+        assert!(matches!(
+            invalid_frame(decode(&mut decoder, frame)),
+            InvalidFrame::Malformed(_)
+        ));
+
+        let frame = BytesMut::from(&[0, 0, 0, 4, FLAG_ZSTD_DICT, 1, 2, 3][..]);
+        assert!(matches!(
+            invalid_frame(decode(&mut decoder, frame)),
+            InvalidFrame::Malformed(_)
+        ));
+    }
+
+    #[test]
+    fn unknown_flags_are_rejected() {
+        let mut decoder = CompressedCborDecoder::new();
+        let frame = BytesMut::from(&[0, 0, 0, 2, 3, 0][..]);
+        assert!(matches!(
+            invalid_frame(decode(&mut decoder, frame)),
+            InvalidFrame::UnknownFlags(3)
+        ));
+    }
+
+    /// `depth` nested arrays around a number.
+    fn nested(depth: usize) -> Value {
+        (0..depth).fold(json!(0), |inner, _| json!([inner]))
+    }
+
+    #[test]
+    fn frames_nested_up_to_the_limit_decode() {
+        let mut encoder = CompressedCborEncoder::new();
+        let mut decoder = CompressedCborDecoder::new();
+        let item = nested(DEFAULT_MAX_DEPTH);
+        let frame = encode(&mut encoder, item.clone());
+        assert_eq!(decode(&mut decoder, frame).unwrap(), item);
+    }
+
+    #[test]
+    fn frames_nested_too_deep_are_rejected() {
+        let mut encoder = CompressedCborEncoder::new();
+        let mut decoder = CompressedCborDecoder::new();
+        for depth in [DEFAULT_MAX_DEPTH + 1, 40] {
+            let frame = encode(&mut encoder, nested(depth));
+            assert!(matches!(
+                invalid_frame(decode(&mut decoder, frame)),
+                InvalidFrame::TooDeep(DEFAULT_MAX_DEPTH)
+            ));
+        }
+
+        // Also when compressed, the limit applies to the decompressed CBOR
+        let mut encoder = CompressedCborEncoder::with_dictionary(dictionary());
+        let mut decoder = CompressedCborDecoder::with_dictionary(dictionary());
+        let frame = encode(&mut encoder, json!(vec![nested(40); 64]));
+        assert_ne!(frame[4], FLAG_RAW);
+        assert!(matches!(
+            invalid_frame(decode(&mut decoder, frame)),
+            InvalidFrame::TooDeep(DEFAULT_MAX_DEPTH)
+        ));
+    }
+
+    /// A zstd frame of a CBOR array of `len` zeros, much smaller than that.
+    fn bomb(len: usize) -> BytesMut {
+        let mut cbor = Vec::with_capacity(len + 5);
+        cbor.push(0x9a); // array with a u32 length
+        cbor.extend_from_slice(&(len as u32).to_be_bytes());
+        cbor.resize(len + 5, 0);
+        let payload =
+            zstd::encode_all(cbor.as_slice(), COMPRESS_LEVEL).unwrap();
+        let mut frame = BytesMut::new();
+        frame.put_u32(payload.len() as u32 + 1);
+        frame.put_u8(FLAG_ZSTD);
+        frame.extend_from_slice(&payload);
+        frame
+    }
+
+    /// Counts the bytes read from the inner reader.
+    struct Counting<R> {
+        inner: R,
+        read: usize,
+    }
+
+    impl<R: Read> Read for Counting<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.inner.read(buf)?;
+            self.read += n;
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn decompression_bombs_are_rejected() {
+        const MAX: usize = 64 * 1024;
+        let frame = bomb(64 * 1024 * 1024);
+        assert!(frame.len() < MAX / 16, "{} bytes", frame.len());
+
+        let mut decoder = CompressedCborDecoder::<Value, MAX>::new();
+        let mut src = frame.clone();
+        let err = decoder.decode(&mut src).unwrap_err();
+        assert!(err.is::<InvalidFrame>());
+        assert!(matches!(
+            err.downcast().unwrap(),
+            InvalidFrame::DecompressedTooLarge { max: MAX }
+        ));
+
+        // Decompression stops right after the limit
+        let mut counting = Counting {
+            inner: zstd::Decoder::new(&frame[5..]).unwrap(),
+            read: 0,
+        };
+        let result = from_decompressing_reader::<Value>(
+            &mut counting,
+            MAX,
+            DEFAULT_MAX_DEPTH,
+        );
+        assert!(matches!(
+            result,
+            Err(InvalidFrame::DecompressedTooLarge { max: MAX })
+        ));
+        assert!(counting.read <= MAX + 1, "read {} bytes", counting.read);
+    }
+}
@@ -0,0 +1,654 @@
+//! The lifecycle of a connection as a state machine.
+//!
+//! The connection handler only waits for the next [`Event`] and carries out
+//! the [`Action`]s [`State::on_event`] returns, every decision about the
+//! connection and every deadline is made here, without IO:
+//!
+//! ```text
+//!            bound
+//! Pending ─────────▶ Bound
+//!    │                 │
+//!    └────────┬────────┘
+//!             ▼ close
+//!          Draining
+//! ```
+//!
+//! A connection authenticated with an access token starts [`State::Bound`],
+//! any other [`State::Pending`] until it is bound or the bind deadline
+//! passes. Every way a connection ends leads to [`State::Draining`], the
+//! handler then tells the client why (see [`CloseReason::close_frame`]) and
+//! exits.
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use super::CloseCode;
+use super::control::{ControlClientMessage, ControlServerMessage};
+use super::presence::MAX_SUBSCRIPTIONS;
+use super::stream_manager::{ConnectionCommand, ConnectionSession};
+use super::transport::{RecvHalf, SendHalf};
+
+/// How long telling the client why the connection closes may take.
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub enum State {
+    /// Waiting for the client to bind the connection, see
+    /// [`StreamManager::bind`](super::StreamManager::bind)
+    Pending { bind_deadline: Instant },
+    /// Registered for the user of the session
+    Bound {
+        /// When the access of the session ends, extended on refreshes
+        auth_deadline: Instant,
+        /// Users whose presence changes the connection gets, see
+        /// [`super::presence`]
+        presence: HashSet<i32>,
+    },
+    /// Closing, the client is told why until `until`
+    Draining { reason: CloseReason, until: Instant },
+}
+
+/// Why a connection closed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The client closed the connection or the transport failed
+    Disconnected,
+    /// A pending connection sent data before it was bound
+    SentBeforeBind,
+    /// The bind deadline passed
+    BindExpired,
+    /// The bind was aborted, e.g. the pending entry was removed
+    BindAborted,
+    HeartbeatViolation,
+    HeartbeatTimedOut,
+    HeartbeatFailed,
+    /// The control stream failed and couldn't be reopened
+    ControlFailed,
+    AccessExpired,
+    /// The registry dropped the connection without saying why
+    Unregistered,
+    /// Closed through the registry, e.g. by
+    /// [`StreamManager::close_stream_with`](super::StreamManager::close_stream_with)
+    Closed {
+        code: CloseCode,
+        reason: String,
+    },
+}
+
+impl CloseReason {
+    /// What the connection handler logs.
+    pub fn log_message(&self) -> &'static str {
+        match self {
+            Self::Disconnected => "Connection closed",
+            Self::SentBeforeBind => "Data sent before bind",
+            Self::BindExpired => "Bind deadline passed",
+            Self::BindAborted => "Bind aborted",
+            Self::HeartbeatViolation => "Protocol violation on heartbeat",
+            Self::HeartbeatTimedOut => "Heartbeat timed out",
+            Self::HeartbeatFailed => "Heartbeat write failed",
+            Self::ControlFailed => "Failed to reopen control stream",
+            Self::AccessExpired => "Session access expired",
+            Self::Unregistered => "Channel closed",
+            Self::Closed { .. } => "Closed by the server",
+        }
+    }
+
+    /// Whether the client broke the protocol, logged as a warning.
+    pub fn is_violation(&self) -> bool {
+        matches!(self, Self::SentBeforeBind | Self::HeartbeatViolation)
+    }
+
+    /// The code and reason the client is told in the
+    /// [`ControlServerMessage::Shutdown`] and the connection is closed with,
+    /// `None` if the client is gone.
+    pub fn close_frame(&self) -> Option<(CloseCode, &str)> {
+        let code = match self {
+            Self::Disconnected => return None,
+            Self::Closed { code, reason } => return Some((*code, reason)),
+            Self::SentBeforeBind | Self::HeartbeatViolation => {
+                CloseCode::ProtocolViolation
+            }
+            Self::BindExpired => CloseCode::BindTimeout,
+            Self::AccessExpired => CloseCode::AuthExpired,
+            Self::BindAborted
+            | Self::HeartbeatTimedOut
+            | Self::HeartbeatFailed
+            | Self::ControlFailed
+            | Self::Unregistered => CloseCode::Generic,
+        };
+        let reason = match self {
+            Self::Unregistered => "Connection closed by the server",
+            _ => self.log_message(),
+        };
+        Some((code, reason))
+    }
+}
+
+/// Deadline of a [`State`], see [`State::deadline`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerKind {
+    Bind,
+    Auth,
+}
+
+/// Something that happened to the connection.
+pub enum Event {
+    /// The [`State::deadline`] passed
+    Timer(TimerKind),
+    /// The client bound the pending connection
+    Bound {
+        session: ConnectionSession,
+        auth_deadline: Instant,
+    },
+    /// The pending entry went away without a bind
+    BindAborted,
+    /// The session was refreshed, its access ends at the new deadline
+    DeadlineExtended(Instant),
+    /// The client wrote `n` bytes on the heartbeat stream
+    HeartbeatData(usize),
+    HeartbeatClosed,
+    /// The heartbeat frames read don't match the protocol
+    HeartbeatViolation,
+    /// Too many pings in a row weren't answered
+    HeartbeatTimedOut,
+    HeartbeatFailed,
+    ControlFrame(ControlClientMessage),
+    /// The control stream at `generation` failed
+    ControlClosed {
+        generation: u64,
+    },
+    ControlReopenFailed,
+    /// A stream opened by the client
+    StreamAccepted(SendHalf, RecvHalf),
+    /// No more streams can be accepted, the connection is gone
+    AcceptClosed,
+    Command(ConnectionCommand),
+    /// The registry dropped the command sender
+    CommandsClosed,
+}
+
+/// What the connection handler does in reaction to an [`Event`].
+pub enum Action {
+    /// Register the bound connection in the [`StreamManager`](super::StreamManager)
+    Register(ConnectionSession),
+    /// Remove the connection from the registry, it is closing
+    Unregister,
+    /// Mark the pending entry as expired, so late binds are told so
+    ExpireBind,
+    /// Remove the pending entry after its tombstone time
+    ForgetBind,
+    /// Read the heartbeat bytes into the pong buffer
+    ReadHeartbeat(usize),
+    SendControl(ControlServerMessage),
+    ReopenControl {
+        generation: u64,
+    },
+    SubscribePresence(Vec<i32>),
+    UnsubscribePresence(Vec<i32>),
+    DispatchIncoming(SendHalf, RecvHalf),
+    RunCommand(ConnectionCommand),
+}
+
+impl State {
+    /// A connection that was authenticated when it connected.
+    pub fn bound(auth_deadline: Instant) -> Self {
+        Self::Bound {
+            auth_deadline,
+            presence: HashSet::new(),
+        }
+    }
+
+    /// The deadline the handler waits for, `None` while draining.
+    pub fn deadline(&self) -> Option<(Instant, TimerKind)> {
+        match self {
+            Self::Pending { bind_deadline } => {
+                Some((*bind_deadline, TimerKind::Bind))
+            }
+            Self::Bound { auth_deadline, .. } => {
+                Some((*auth_deadline, TimerKind::Auth))
+            }
+            Self::Draining { .. } => None,
+        }
+    }
+
+    pub fn is_pending(&self) -> bool {
+        matches!(self, Self::Pending { .. })
+    }
+
+    pub fn is_bound(&self) -> bool {
+        matches!(self, Self::Bound { .. })
+    }
+
+    /// Transition on `event` at `now`.
+    ///
+    /// Events that can't happen in a state, e.g. commands of a pending
+    /// connection, are ignored. A draining connection ignores everything.
+    pub fn on_event(self, event: Event, now: Instant) -> (Self, Vec<Action>) {
+        match self {
+            Self::Pending { bind_deadline } => {
+                Self::on_pending_event(bind_deadline, event, now)
+            }
+            Self::Bound {
+                auth_deadline,
+                presence,
+            } => Self::on_bound_event(auth_deadline, presence, event, now),
+            draining @ Self::Draining { .. } => (draining, Vec::new()),
+        }
+    }
+
+    fn drain(reason: CloseReason, now: Instant) -> Self {
+        Self::Draining {
+            reason,
+            until: now + DRAIN_TIMEOUT,
+        }
+    }
+
+    fn on_pending_event(
+        bind_deadline: Instant,
+        event: Event,
+        now: Instant,
+    ) -> (Self, Vec<Action>) {
+        let reason = match event {
+            Event::Bound {
+                session,
+                auth_deadline,
+            } => {
+                return (
+                    Self::bound(auth_deadline),
+                    vec![Action::ForgetBind, Action::Register(session)],
+                );
+            }
+            Event::Timer(TimerKind::Bind) => {
+                let state = Self::drain(CloseReason::BindExpired, now);
+                return (state, vec![Action::ExpireBind, Action::ForgetBind]);
+            }
+            Event::BindAborted => CloseReason::BindAborted,
+            Event::Command(ConnectionCommand::Close { code, reason }) => {
+                CloseReason::Closed { code, reason }
+            }
+            // The client must not send anything before it is bound
+            Event::HeartbeatData(_) | Event::HeartbeatViolation => {
+                CloseReason::SentBeforeBind
+            }
+            Event::HeartbeatClosed | Event::AcceptClosed => {
+                CloseReason::Disconnected
+            }
+            _ => return (Self::Pending { bind_deadline }, Vec::new()),
+        };
+        (Self::drain(reason, now), vec![Action::ForgetBind])
+    }
+
+    fn on_bound_event(
+        mut auth_deadline: Instant,
+        mut presence: HashSet<i32>,
+        event: Event,
+        now: Instant,
+    ) -> (Self, Vec<Action>) {
+        let mut actions = Vec::new();
+        let reason = match event {
+            Event::Timer(TimerKind::Auth) => Some(CloseReason::AccessExpired),
+            Event::DeadlineExtended(deadline) => {
+                auth_deadline = deadline;
+                None
+            }
+            Event::HeartbeatData(n) => {
+                actions.push(Action::ReadHeartbeat(n));
+                None
+            }
+            Event::HeartbeatClosed | Event::AcceptClosed => {
+                Some(CloseReason::Disconnected)
+            }
+            Event::HeartbeatViolation => Some(CloseReason::HeartbeatViolation),
+            Event::HeartbeatTimedOut => Some(CloseReason::HeartbeatTimedOut),
+            Event::HeartbeatFailed => Some(CloseReason::HeartbeatFailed),
+            Event::ControlFrame(message) => {
+                on_control_frame(&mut presence, message, &mut actions);
+                None
+            }
+            Event::ControlClosed { generation } => {
+                actions.push(Action::ReopenControl { generation });
+                None
+            }
+            Event::ControlReopenFailed => Some(CloseReason::ControlFailed),
+            Event::StreamAccepted(send, recv) => {
+                actions.push(Action::DispatchIncoming(send, recv));
+                None
+            }
+            Event::Command(ConnectionCommand::Close { code, reason }) => {
+                Some(CloseReason::Closed { code, reason })
+            }
+            Event::Command(command) => {
+                actions.push(Action::RunCommand(command));
+                None
+            }
+            Event::CommandsClosed => Some(CloseReason::Unregistered),
+            // Bound already
+            Event::Timer(TimerKind::Bind)
+            | Event::Bound { .. }
+            | Event::BindAborted => None,
+        };
+        let Some(reason) = reason else {
+            let state = Self::Bound {
+                auth_deadline,
+                presence,
+            };
+            return (state, actions);
+        };
+        actions.push(Action::Unregister);
+        if !presence.is_empty() {
+            actions.push(Action::UnsubscribePresence(
+                presence.into_iter().collect(),
+            ));
+        }
+        (Self::drain(reason, now), actions)
+    }
+}
+
+fn on_control_frame(
+    presence: &mut HashSet<i32>,
+    message: ControlClientMessage,
+    actions: &mut Vec<Action>,
+) {
+    match message {
+        ControlClientMessage::Ping => {
+            actions.push(Action::SendControl(ControlServerMessage::Pong));
+        }
+        // The handshake is over, a repeated ack changes nothing
+        ControlClientMessage::HelloAck { .. } => {}
+        // TODO only allow subscribing to friends, once friendships exist,
+        // presence is public until then
+        ControlClientMessage::SubscribePresence { user_ids } => {
+            let added: HashSet<i32> = user_ids
+                .into_iter()
+                .filter(|id| !presence.contains(id))
+                .collect();
+            if presence.len() + added.len() > MAX_SUBSCRIPTIONS {
+                actions.push(Action::SendControl(
+                    ControlServerMessage::PresenceSubscriptionRejected {
+                        max_user_ids: MAX_SUBSCRIPTIONS,
+                    },
+                ));
+                return;
+            }
+            presence.extend(&added);
+            actions
+                .push(Action::SubscribePresence(added.into_iter().collect()));
+        }
+        ControlClientMessage::UnsubscribePresence { user_ids } => {
+            let removed = user_ids
+                .into_iter()
+                .filter(|id| presence.remove(id))
+                .collect();
+            actions.push(Action::UnsubscribePresence(removed));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::oneshot;
+
+    use super::*;
+
+    /// The event `name`, built fresh for every row since events hold streams
+    /// and channels.
+    fn event(name: &str, now: Instant) -> Event {
+        match name {
+            "Timer(Bind)" => Event::Timer(TimerKind::Bind),
+            "Timer(Auth)" => Event::Timer(TimerKind::Auth),
+            "Bound" => Event::Bound {
+                session: ConnectionSession::for_user(1),
+                auth_deadline: now + Duration::from_secs(60),
+            },
+            "BindAborted" => Event::BindAborted,
+            "DeadlineExtended" => {
+                Event::DeadlineExtended(now + Duration::from_secs(120))
+            }
+            "HeartbeatData" => Event::HeartbeatData(9),
+            "HeartbeatClosed" => Event::HeartbeatClosed,
+            "HeartbeatViolation" => Event::HeartbeatViolation,
+            "HeartbeatTimedOut" => Event::HeartbeatTimedOut,
+            "HeartbeatFailed" => Event::HeartbeatFailed,
+            "ControlFrame(Ping)" => {
+                Event::ControlFrame(ControlClientMessage::Ping)
+            }
+            "ControlClosed" => Event::ControlClosed { generation: 3 },
+            "ControlReopenFailed" => Event::ControlReopenFailed,
+            "StreamAccepted" => {
+                let (recv, send) = tokio::io::split(tokio::io::duplex(64).0);
+                Event::StreamAccepted(Box::new(send), Box::new(recv))
+            }
+            "AcceptClosed" => Event::AcceptClosed,
+            "Command(OpenBidiStream)" => {
+                Event::Command(ConnectionCommand::OpenBidiStream {
+                    response: oneshot::channel().0,
+                })
+            }
+            "Command(Close)" => Event::Command(ConnectionCommand::Close {
+                code: CloseCode::Banned,
+                reason: "Banned".into(),
+            }),
+            "CommandsClosed" => Event::CommandsClosed,
+            _ => unreachable!("unknown event {name}"),
+        }
+    }
+
+    fn action_name(action: &Action) -> &'static str {
+        match action {
+            Action::Register(_) => "Register",
+            Action::Unregister => "Unregister",
+            Action::ExpireBind => "ExpireBind",
+            Action::ForgetBind => "ForgetBind",
+            Action::ReadHeartbeat(_) => "ReadHeartbeat",
+            Action::SendControl(_) => "SendControl",
+            Action::ReopenControl { .. } => "ReopenControl",
+            Action::SubscribePresence(_) => "SubscribePresence",
+            Action::UnsubscribePresence(_) => "UnsubscribePresence",
+            Action::DispatchIncoming(..) => "DispatchIncoming",
+            Action::RunCommand(_) => "RunCommand",
+        }
+    }
+
+    /// The state after `event` or the reason it drains for, and the names
+    /// of the actions.
+    fn step(
+        state: State,
+        event: &str,
+        now: Instant,
+    ) -> (Result<State, CloseReason>, Vec<&'static str>) {
+        let (state, actions) = state.on_event(self::event(event, now), now);
+        let actions = actions.iter().map(action_name).collect();
+        match state {
+            State::Draining { reason, until } => {
+                assert_eq!(until, now + DRAIN_TIMEOUT);
+                (Err(reason), actions)
+            }
+            state => (Ok(state), actions),
+        }
+    }
+
+    const EVENTS: [&str; 18] = [
+        "Timer(Bind)",
+        "Timer(Auth)",
+        "Bound",
+        "BindAborted",
+        "DeadlineExtended",
+        "HeartbeatData",
+        "HeartbeatClosed",
+        "HeartbeatViolation",
+        "HeartbeatTimedOut",
+        "HeartbeatFailed",
+        "ControlFrame(Ping)",
+        "ControlClosed",
+        "ControlReopenFailed",
+        "StreamAccepted",
+        "AcceptClosed",
+        "Command(OpenBidiStream)",
+        "Command(Close)",
+        "CommandsClosed",
+    ];
+
+    #[test]
+    fn pending_connections() {
+        use CloseReason::*;
+        let now = Instant::now();
+        let bind_deadline = now + Duration::from_secs(10);
+        let closed = Closed {
+            code: CloseCode::Banned,
+            reason: "Banned".into(),
+        };
+        let drains: &[(&str, CloseReason, &[&str])] = &[
+            ("Timer(Bind)", BindExpired, &["ExpireBind", "ForgetBind"]),
+            ("BindAborted", BindAborted, &["ForgetBind"]),
+            ("HeartbeatData", SentBeforeBind, &["ForgetBind"]),
+            ("HeartbeatViolation", SentBeforeBind, &["ForgetBind"]),
+            ("HeartbeatClosed", Disconnected, &["ForgetBind"]),
+            ("AcceptClosed", Disconnected, &["ForgetBind"]),
+            ("Command(Close)", closed, &["ForgetBind"]),
+        ];
+
+        for name in EVENTS {
+            let pending = State::Pending { bind_deadline };
+            let (state, actions) = step(pending, name, now);
+            if name == "Bound" {
+                let state = state.unwrap();
+                assert!(state.is_bound());
+                assert_eq!(
+                    state.deadline(),
+                    Some((now + Duration::from_secs(60), TimerKind::Auth))
+                );
+                assert_eq!(actions, ["ForgetBind", "Register"]);
+            } else if let Some((_, reason, expected)) =
+                drains.iter().find(|(event, ..)| *event == name)
+            {
+                assert_eq!(state.unwrap_err(), *reason, "{name}");
+                assert_eq!(actions, *expected, "{name}");
+            } else {
+                let state = state.expect(name);
+                assert!(state.is_pending(), "{name}");
+                assert_eq!(
+                    state.deadline(),
+                    Some((bind_deadline, TimerKind::Bind))
+                );
+                assert!(actions.is_empty(), "{name}");
+            }
+        }
+    }
+
+    #[test]
+    fn bound_connections() {
+        use CloseReason::*;
+        let now = Instant::now();
+        let auth_deadline = now + Duration::from_secs(60);
+        let closed = Closed {
+            code: CloseCode::Banned,
+            reason: "Banned".into(),
+        };
+        let drains: &[(&str, CloseReason)] = &[
+            ("Timer(Auth)", AccessExpired),
+            ("HeartbeatClosed", Disconnected),
+            ("AcceptClosed", Disconnected),
+            ("HeartbeatViolation", HeartbeatViolation),
+            ("HeartbeatTimedOut", HeartbeatTimedOut),
+            ("HeartbeatFailed", HeartbeatFailed),
+            ("ControlReopenFailed", ControlFailed),
+            ("Command(Close)", closed),
+            ("CommandsClosed", Unregistered),
+        ];
+        let stays: &[(&str, &[&str])] = &[
+            ("Timer(Bind)", &[]),
+            ("Bound", &[]),
+            ("BindAborted", &[]),
+            ("DeadlineExtended", &[]),
+            ("HeartbeatData", &["ReadHeartbeat"]),
+            ("ControlFrame(Ping)", &["SendControl"]),
+            ("ControlClosed", &["ReopenControl"]),
+            ("StreamAccepted", &["DispatchIncoming"]),
+            ("Command(OpenBidiStream)", &["RunCommand"]),
+        ];
+        assert_eq!(drains.len() + stays.len(), EVENTS.len());
+
+        for name in EVENTS {
+            let bound = State::Bound {
+                auth_deadline,
+                presence: HashSet::from([7]),
+            };
+            let (state, actions) = step(bound, name, now);
+            if let Some((_, reason)) =
+                drains.iter().find(|(event, _)| *event == name)
+            {
+                assert_eq!(state.unwrap_err(), *reason, "{name}");
+                assert_eq!(
+                    actions,
+                    ["Unregister", "UnsubscribePresence"],
+                    "{name}"
+                );
+                continue;
+            }
+            let (_, expected) =
+                stays.iter().find(|(event, _)| *event == name).expect(name);
+            let state = state.expect(name);
+            let extended = if name == "DeadlineExtended" {
+                now + Duration::from_secs(120)
+            } else {
+                auth_deadline
+            };
+            assert_eq!(state.deadline(), Some((extended, TimerKind::Auth)));
+            assert_eq!(actions, *expected, "{name}");
+        }
+    }
+
+    #[test]
+    fn draining_connections_ignore_everything() {
+        let now = Instant::now();
+        for name in EVENTS {
+            let draining = State::drain(CloseReason::Disconnected, now);
+            let (state, actions) = draining
+                .on_event(event(name, now), now + Duration::from_secs(5));
+            assert!(
+                matches!(
+                    state,
+                    State::Draining {
+                        reason: CloseReason::Disconnected,
+                        until,
+                    } if until == now + DRAIN_TIMEOUT
+                ),
+                "{name}"
+            );
+            assert_eq!(state.deadline(), None);
+            assert!(actions.is_empty(), "{name}");
+        }
+    }
+
+    #[test]
+    fn closing_without_subscriptions_only_unregisters() {
+        let now = Instant::now();
+        let (state, actions) = step(State::bound(now), "HeartbeatClosed", now);
+        assert_eq!(state.unwrap_err(), CloseReason::Disconnected);
+        assert_eq!(actions, ["Unregister"]);
+    }
+
+    #[test]
+    fn presence_subscriptions_are_limited() {
+        let now = Instant::now();
+        let subscribe = |state: State, user_ids: Vec<i32>| {
+            let event =
+                Event::ControlFrame(ControlClientMessage::SubscribePresence {
+                    user_ids,
+                });
+            let (state, actions) = state.on_event(event, now);
+            (state, actions.iter().map(action_name).collect::<Vec<_>>())
+        };
+        let (state, actions) = subscribe(State::bound(now), vec![1, 2]);
+        assert_eq!(actions, ["SubscribePresence"]);
+        let too_many = (3..=MAX_SUBSCRIPTIONS as i32 + 1).collect();
+        let (state, actions) = subscribe(state, too_many);
+        assert_eq!(actions, ["SendControl"]);
+        let State::Bound { presence, .. } = state else {
+            panic!("should stay bound");
+        };
+        assert_eq!(presence, HashSet::from([1, 2]));
+    }
+}
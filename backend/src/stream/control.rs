@@ -0,0 +1,159 @@
+//! Messages of the per-connection control stream.
+//!
+//! Every connection has one persistent [`StreamType::Control`](super::StreamType)
+//! stream for small server events, so they don't need a stream each. It is
+//! opened by the server right after the heartbeat stream and reopened once if
+//! it fails, see [`StreamManager::send_control`](super::StreamManager::send_control).
+//!
+//! # Handshake
+//!
+//! The first message on a new connection is [`ControlServerMessage::Hello`].
+//! The client has to answer with [`ControlClientMessage::HelloAck`] within
+//! 10 seconds, naming the protocol version it speaks and the compression it
+//! accepts, or the connection is closed. A version outside of
+//! [`MIN_SUPPORTED_VERSION`]..=[`PROTOCOL_VERSION`] gets
+//! [`ControlServerMessage::UnsupportedVersion`] before the close. Only then
+//! a pending connection gets its [`BindChallenge`].
+//!
+//! If `stream.dictionary_file` is configured, the Hello also offers
+//! [`COMPRESSION_ZSTD_DICT`]. A client that has the same dictionary and
+//! accepts it gets typed streams whose frames are compressed with it (flag
+//! `0x02`, see [`super::compress_cbor_codec`]) and may send such frames
+//! itself. The control and heartbeat streams never use it.
+
+use serde::{Deserialize, Serialize};
+
+use super::{BindChallenge, CloseCode};
+
+/// Version of the stream protocol spoken by the server.
+///
+/// Increment it on incompatible changes of the stream messages.
+pub const PROTOCOL_VERSION: u16 = 2;
+/// Oldest protocol version clients may still speak.
+///
+/// Version 2 tags the messages as `type` and `data` and renames the fields
+/// to camelCase, version 1 clients can't read the Hello.
+pub const MIN_SUPPORTED_VERSION: u16 = 2;
+/// Name of zstd compression in the handshake.
+pub const COMPRESSION_ZSTD: &str = "zstd";
+/// Name of zstd compression with the dictionary of `stream.dictionary_file`
+/// in the handshake, only offered if one is configured.
+pub const COMPRESSION_ZSTD_DICT: &str = "zstd-dict";
+
+/// Messages sent from the server to the client on the control stream.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", content = "data", rename_all_fields = "camelCase")]
+pub enum ControlServerMessage {
+    /// Sent first on every connection, see the module docs
+    Hello {
+        protocol_version: u16,
+        /// Compression the server can send, e.g. `zstd` or `zstd-dict`
+        compression: Vec<String>,
+        /// Largest frame the server accepts, in bytes
+        max_frame: u32,
+    },
+    /// The version of the HelloAck isn't supported, the connection is closed
+    UnsupportedVersion {
+        min_supported: u16,
+        max_supported: u16,
+    },
+    /// Answer to [`ControlClientMessage::Ping`]
+    Pong,
+    /// Sent first on a connection that has to be bound
+    BindChallenge(BindChallenge),
+    Notification(Notification),
+    /// The user opened another connection that replaces this one, sent
+    /// right before this connection is closed
+    ConnectionReplaced {
+        new_connection_id: u64,
+    },
+    /// The server is about to close the connection, see
+    /// [`super::close_codes`]
+    Shutdown {
+        code: CloseCode,
+        reason: String,
+    },
+    /// Answer to [`ControlClientMessage::SubscribePresence`], the presence of
+    /// the newly subscribed users
+    Presence {
+        users: Vec<UserPresence>,
+    },
+    /// Answer to a [`ControlClientMessage::SubscribePresence`] that would
+    /// exceed the limit, the subscriptions are unchanged
+    PresenceSubscriptionRejected {
+        max_user_ids: usize,
+    },
+}
+
+/// Messages sent from the client to the server on the control stream.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all_fields = "camelCase")]
+pub enum ControlClientMessage {
+    /// Answer to [`ControlServerMessage::Hello`]
+    HelloAck {
+        /// The version the client speaks
+        protocol_version: u16,
+        /// The offered compression the client can decode, frames are only
+        /// compressed if it contains `zstd`, or `zstd-dict` for the
+        /// dictionary
+        accepted_compression: Vec<String>,
+    },
+    /// Check that the control stream is alive, answered with a Pong
+    Ping,
+    /// Get a [`Notification::PresenceChanged`] whenever one of the users goes
+    /// online or offline, see [`super::presence`]
+    SubscribePresence {
+        user_ids: Vec<i32>,
+    },
+    UnsubscribePresence {
+        user_ids: Vec<i32>,
+    },
+}
+
+/// Server events pushed to a user, see [`StreamManager::notify`](super::StreamManager::notify).
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", content = "data", rename_all_fields = "camelCase")]
+pub enum Notification {
+    /// Sent to the recipient of a new game invite
+    GameInvite {
+        invite_id: i32,
+        from_user_id: i32,
+        from_nickname: String,
+    },
+    /// Sent to the sender of a game invite once the recipient answered
+    GameInviteAnswered { invite_id: i32, accepted: bool },
+    /// Sent to both players once the matchmaking queue paired them or an
+    /// invite was accepted
+    MatchFound {
+        game_session_id: i32,
+        opponent_user_id: i32,
+        opponent_nickname: String,
+        /// Resumes the game on a new connection, see
+        /// `POST /api/game/{gameSessionId}/resume`
+        resume_token: String,
+    },
+    /// Sent after a recovery code was used and only a few are left
+    RecoveryCodesLow { remaining: i64 },
+    /// Sent after a Session was revoked because its previous refresh token
+    /// was presented, which suggests it was stolen
+    SessionRevoked { session_id: i32 },
+    /// Sent to the connections subscribed to a user that went online or
+    /// offline
+    PresenceChanged(UserPresence),
+    /// An operator's message to everyone connected, not stored
+    Announcement {
+        message: String,
+        created_at: chrono::NaiveDateTime,
+    },
+}
+
+/// Whether a user is online, as in the public user endpoints.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserPresence {
+    pub user_id: i32,
+    pub online: bool,
+    /// Last REST activity, `None` if the server doesn't know it without
+    /// loading it, the profile has it then
+    pub last_seen: Option<chrono::NaiveDateTime>,
+}
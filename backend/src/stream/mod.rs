@@ -1,7 +1,8 @@
 //! WebTransport Stream Management
 //!
 //! This module provides infrastructure for real-time bidirectional communication
-//! with clients over WebTransport/QUIC. It centers around the [`StreamManager`],
+//! with clients over WebTransport/QUIC, or a WebSocket where QUIC is blocked
+//! (see [`websocket`]). It centers around the [`StreamManager`],
 //! a global registry that manages WebTransport connections for authenticated users.
 //!
 //! # Architecture Overview
@@ -36,15 +37,25 @@
 //!
 //! # Key Concepts
 //!
-//! 1. **Server-Initiated Streams**: Clients don't open streams directly. Instead,
-//!    they use REST APIs to request actions (join chat, start game, etc.), and the
-//!    server opens streams as needed via [`StreamManager::request_stream`].
+//! 1. **Server-Initiated Streams**: Clients usually don't open streams directly.
+//!    Instead, they use REST APIs to request actions (join chat, start game, etc.),
+//!    and the server opens streams as needed via [`StreamManager::request_stream`].
+//!    For latency-sensitive input, components can accept client-initiated streams
+//!    of a [`StreamType`] via [`StreamManager::set_incoming_handler`].
 //!
 //! 2. **Single Connection Per User**: Each user can have only one active WebTransport
-//!    connection. New connections automatically replace old ones.
+//!    connection. New connections automatically replace old ones, unless
+//!    `stream.allow_multiple_connections` is set.
 //!
 //! 3. **Typed Message Passing**: Streams use CBOR serialization with optional Zstd
-//!    compression for efficient, type-safe communication.
+//!    compression for efficient, type-safe communication. Like the REST bodies,
+//!    fields are camelCase. Message enums are adjacently tagged, e.g.
+//!    `{"type": "Start", "data": {"gameSessionId": 1, ...}}`, enums without
+//!    data are plain strings.
+//!
+//! 4. **Control Stream**: Small server events such as [`Notification`]s go over
+//!    one persistent stream per connection via [`StreamManager::send_control`]
+//!    (or fire-and-forget [`StreamManager::notify`]), see [`control`].
 //!
 //! # Usage Example
 //!
@@ -55,6 +66,7 @@
 //!
 //! /// Messages sent FROM the server TO the client
 //! #[derive(Serialize)]
+//! #[serde(tag = "type", content = "data", rename_all_fields = "camelCase")]
 //! enum ServerMessage {
 //!     Welcome { room_id: i32 },
 //!     ChatMessage { from: String, content: String },
@@ -63,6 +75,7 @@
 //!
 //! /// Messages sent FROM the client TO the server
 //! #[derive(Deserialize)]
+//! #[serde(tag = "type", content = "data", rename_all_fields = "camelCase")]
 //! enum ClientMessage {
 //!     SendChat { content: String },
 //!     Typing,
@@ -131,6 +144,11 @@
 //!     // Closes the WebTransport session, all streams will error
 //!     StreamManager::global().close_stream(user_id);
 //! }
+//!
+//! fn ban_user(user_id: i32) {
+//!     // The client is told why, see `close_codes`
+//!     StreamManager::global().close_stream_with(user_id, CloseCode::Banned, "Banned");
+//! }
 //! ```
 //!
 //! # Stream Lifecycle
@@ -138,28 +156,51 @@
 //! - **Creation**: Each `request_stream()` call creates a NEW bidirectional stream
 //! - **Ownership**: The `Sender`/`Receiver` are owned by the caller
 //! - **Termination**: Streams end when dropped, session closes, or an error occurs
-//! - **Connection Replacement**: Old streams error when a new connection replaces them
+//! - **Connection Replacement**: Old streams error when a new connection replaces them,
+//!   after the old connection got a `ConnectionReplaced` control message
 //!
 //! # Error Handling
 //!
-//! - [`StreamManagerError::UserNotConnected`]: User has no active WebTransport session
-//! - [`StreamManagerError::ConnectionClosed`]: Connection died (auto-cleaned up)
+//! - [`StreamManagerError::UserNotConnected`](stream_manager::StreamManagerError::UserNotConnected): User has no active WebTransport session
+//! - [`StreamManagerError::ConnectionClosed`](stream_manager::StreamManagerError::ConnectionClosed): Connection died (auto-cleaned up)
 
+mod close_codes;
 mod compress_cbor_codec;
+mod connection;
+pub mod control;
+mod presence;
+mod stats;
 mod stream_manager;
+mod transport;
+mod websocket;
 
+pub use close_codes::CloseCode;
+pub use control::Notification;
 pub use futures::SinkExt;
 pub use futures::StreamExt;
+pub use stats::{ConnectionInfo, ConnectionStats};
 pub use stream_manager::{
-    Receiver, Sender, StreamManager, StreamManagerError, connect_stream,
+    BindChallenge, BindError, Receiver, Sender, StreamManager, connect_stream,
+    connect_websocket,
 };
+#[cfg(test)]
+pub use transport::testing;
+pub use websocket::WEBSOCKET_FALLBACK_FLAG;
 
-#[derive(Debug, serde::Serialize)]
+/// Purpose of a stream, sent as its first frame.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    serde::Serialize,
+    serde::Deserialize,
+)]
 pub enum StreamType {
     Chat,
+    Game,
+    /// The persistent control stream of a connection, see [`control`]
+    Control,
 }
-
-// TODO need AUTH (while the connection is open: session could expire, get deleted, logged out, user deleted, etc.)
-// maybe enforce regular access verification by requiring the client to continuously
-// use a rest endpoint where the access for a user is verified.
-// And if that doesnt happen for a while, the stream is closed.
@@ -0,0 +1,81 @@
+//! Presence subscriptions of connections.
+//!
+//! A client sends [`ControlClientMessage::SubscribePresence`] on the control
+//! stream to get a [`Notification::PresenceChanged`] whenever one of the
+//! users goes online or offline, instead of polling their profiles. The
+//! connection handler keeps the users its connection subscribed to, the
+//! [`PresenceWatchers`] of the [`StreamManager`] the reverse.
+//!
+//! [`ControlClientMessage::SubscribePresence`]: super::control::ControlClientMessage::SubscribePresence
+//! [`Notification::PresenceChanged`]: super::Notification::PresenceChanged
+//! [`StreamManager`]: super::StreamManager
+
+use std::collections::HashSet;
+
+use dashmap::DashMap;
+
+use super::control::UserPresence;
+use crate::auth::presence;
+
+/// Users a connection may be subscribed to at the same time.
+pub const MAX_SUBSCRIPTIONS: usize = 200;
+
+/// The connections subscribed to each user, by user ID and connection ID.
+#[derive(Debug, Default)]
+pub(super) struct PresenceWatchers {
+    watchers: DashMap<i32, HashSet<(i32, u64)>, ahash::RandomState>,
+}
+
+impl PresenceWatchers {
+    /// Subscribe a connection to `user_ids`, returns their presence right
+    /// now.
+    pub fn subscribe(
+        &self,
+        watcher: i32,
+        connection_id: u64,
+        user_ids: impl IntoIterator<Item = i32>,
+    ) -> Vec<UserPresence> {
+        user_ids
+            .into_iter()
+            .map(|user_id| {
+                self.watchers
+                    .entry(user_id)
+                    .or_default()
+                    .insert((watcher, connection_id));
+                current(user_id)
+            })
+            .collect()
+    }
+
+    pub fn unsubscribe(
+        &self,
+        watcher: i32,
+        connection_id: u64,
+        user_ids: impl IntoIterator<Item = i32>,
+    ) {
+        for user_id in user_ids {
+            if let Some(mut watchers) = self.watchers.get_mut(&user_id) {
+                watchers.remove(&(watcher, connection_id));
+            }
+            self.watchers
+                .remove_if(&user_id, |_, watchers| watchers.is_empty());
+        }
+    }
+
+    /// The connections subscribed to `user_id`.
+    pub fn of(&self, user_id: i32) -> Vec<(i32, u64)> {
+        self.watchers
+            .get(&user_id)
+            .map(|watchers| watchers.iter().copied().collect())
+            .unwrap_or_default()
+    }
+}
+
+/// The presence of a user right now.
+fn current(user_id: i32) -> UserPresence {
+    UserPresence {
+        user_id,
+        online: presence::is_online(user_id),
+        last_seen: presence::last_seen(user_id),
+    }
+}
@@ -0,0 +1,275 @@
+//! Traffic counters of connections, see [`StreamManager::stats`].
+//!
+//! The connection handler runs on a [`CountedTransport`], which wraps every
+//! stream it opens or accepts, so the counters also include the heartbeat and
+//! control streams.
+//!
+//! [`StreamManager::stats`]: super::StreamManager::stats
+
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::task::{Context, Poll, ready};
+use std::time::Instant;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use super::CloseCode;
+use super::transport::{RecvHalf, SendHalf, StreamTransport};
+use crate::prelude::*;
+
+/// Stored latency of a connection that hasn't answered a ping yet.
+const LATENCY_UNKNOWN: u32 = u32::MAX;
+
+/// Counters of a connection, shared by its streams.
+#[derive(Debug)]
+pub struct ConnectionCounters {
+    pub connected_at: Instant,
+    /// Wall-clock time of `connected_at`, for clients
+    pub connected_at_utc: chrono::NaiveDateTime,
+    /// Latest heartbeat round-trip time
+    latency_ms: AtomicU32,
+    streams_opened: AtomicU64,
+    streams_accepted: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+}
+
+impl ConnectionCounters {
+    pub fn new() -> Self {
+        Self {
+            connected_at: Instant::now(),
+            connected_at_utc: chrono::Utc::now().naive_utc(),
+            latency_ms: AtomicU32::new(LATENCY_UNKNOWN),
+            streams_opened: AtomicU64::new(0),
+            streams_accepted: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+        }
+    }
+
+    /// `None` if the client hasn't answered a ping yet.
+    pub fn latency_ms(&self) -> Option<u32> {
+        let latency = self.latency_ms.load(Ordering::Relaxed);
+        (latency != LATENCY_UNKNOWN).then_some(latency)
+    }
+
+    /// Store the round-trip time of the latest heartbeat, below
+    /// `u32::MAX`.
+    pub fn set_latency_ms(&self, latency_ms: u32) {
+        self.latency_ms.store(latency_ms, Ordering::Relaxed);
+    }
+
+    pub fn streams(&self) -> u64 {
+        self.streams_opened.load(Ordering::Relaxed)
+            + self.streams_accepted.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received.load(Ordering::Relaxed)
+    }
+}
+
+/// A registered connection, as returned by [`StreamManager::stats`].
+///
+/// [`StreamManager::stats`]: super::StreamManager::stats
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionStats {
+    pub user_id: i32,
+    pub connection_id: u64,
+    /// The session the connection was authenticated or bound with
+    pub session_id: i32,
+    /// Seconds since the connection was established, including the time it
+    /// waited to be bound
+    pub connected_secs: u64,
+    /// Latest heartbeat round-trip time, `None` before the first echo
+    pub latency_ms: Option<u32>,
+    /// Negotiated in the handshake
+    pub protocol_version: u16,
+    /// Streams opened by the server, including heartbeat and control streams
+    pub streams_opened: u64,
+    /// Streams opened by the client
+    pub streams_accepted: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+/// A registered connection of a user, as returned by
+/// [`StreamManager::connection_info`].
+///
+/// [`StreamManager::connection_info`]: super::StreamManager::connection_info
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionInfo {
+    /// The session the connection was authenticated or bound with
+    pub session_id: i32,
+    /// When the connection was established, including the time it waited
+    /// to be bound
+    pub connected_at: chrono::NaiveDateTime,
+}
+
+impl ConnectionInfo {
+    pub fn new(session_id: i32, counters: &ConnectionCounters) -> Self {
+        Self {
+            session_id,
+            connected_at: counters.connected_at_utc,
+        }
+    }
+}
+
+impl ConnectionStats {
+    pub fn new(
+        user_id: i32,
+        connection_id: u64,
+        session_id: i32,
+        protocol_version: u16,
+        counters: &ConnectionCounters,
+    ) -> Self {
+        Self {
+            user_id,
+            connection_id,
+            session_id,
+            connected_secs: counters.connected_at.elapsed().as_secs(),
+            latency_ms: counters.latency_ms(),
+            protocol_version,
+            streams_opened: counters.streams_opened.load(Ordering::Relaxed),
+            streams_accepted: counters.streams_accepted.load(Ordering::Relaxed),
+            bytes_sent: counters.bytes_sent(),
+            bytes_received: counters.bytes_received(),
+        }
+    }
+}
+
+/// A [`StreamTransport`] that counts the streams and bytes of another one.
+pub struct CountedTransport<'a, T> {
+    inner: &'a T,
+    counters: Arc<ConnectionCounters>,
+}
+
+impl<'a, T> CountedTransport<'a, T> {
+    pub fn new(inner: &'a T, counters: Arc<ConnectionCounters>) -> Self {
+        Self { inner, counters }
+    }
+
+    fn instrument(
+        &self,
+        (send, recv): (SendHalf, RecvHalf),
+    ) -> (SendHalf, RecvHalf) {
+        (
+            Box::new(InstrumentedSend {
+                inner: send,
+                counters: Arc::clone(&self.counters),
+            }),
+            Box::new(InstrumentedRecv {
+                inner: recv,
+                counters: Arc::clone(&self.counters),
+            }),
+        )
+    }
+}
+
+impl<T: StreamTransport> StreamTransport for CountedTransport<'_, T> {
+    async fn open_bi(&self) -> anyhow::Result<(SendHalf, RecvHalf)> {
+        let stream = self.inner.open_bi().await?;
+        self.counters.streams_opened.fetch_add(1, Ordering::Relaxed);
+        Ok(self.instrument(stream))
+    }
+
+    async fn accept_bi(&self) -> anyhow::Result<Option<(SendHalf, RecvHalf)>> {
+        let Some(stream) = self.inner.accept_bi().await? else {
+            return Ok(None);
+        };
+        self.counters
+            .streams_accepted
+            .fetch_add(1, Ordering::Relaxed);
+        Ok(Some(self.instrument(stream)))
+    }
+
+    fn close(&self, code: CloseCode, reason: &str) {
+        self.inner.close(code, reason);
+    }
+}
+
+/// Send half that adds the written bytes to [`ConnectionCounters`].
+pub struct InstrumentedSend {
+    inner: SendHalf,
+    counters: Arc<ConnectionCounters>,
+}
+
+impl InstrumentedSend {
+    fn count(
+        &self,
+        written: Poll<io::Result<usize>>,
+    ) -> Poll<io::Result<usize>> {
+        if let Poll::Ready(Ok(n)) = written {
+            self.counters
+                .bytes_sent
+                .fetch_add(n as u64, Ordering::Relaxed);
+        }
+        written
+    }
+}
+
+impl AsyncWrite for InstrumentedSend {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let written = Pin::new(&mut self.inner).poll_write(cx, buf);
+        self.count(written)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let written = Pin::new(&mut self.inner).poll_write_vectored(cx, bufs);
+        self.count(written)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Receive half that adds the read bytes to [`ConnectionCounters`].
+pub struct InstrumentedRecv {
+    inner: RecvHalf,
+    counters: Arc<ConnectionCounters>,
+}
+
+impl AsyncRead for InstrumentedRecv {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        self.counters
+            .bytes_received
+            .fetch_add((buf.filled().len() - before) as u64, Ordering::Relaxed);
+        Poll::Ready(Ok(()))
+    }
+}
@@ -39,9 +39,9 @@
 //!
 //! # Design Principles
 //!
-//! ## Server-Initiated Streams Only
+//! ## Server-Initiated Streams by Default
 //!
-//! Clients cannot initiate streams directly on their WebTransport connection.
+//! Clients usually don't initiate streams on their WebTransport connection.
 //! Instead, they use the REST API to request actions (join chat, start game, etc.),
 //! and the corresponding server-side manager opens streams as needed. This design:
 //!
@@ -50,18 +50,62 @@
 //! - Ensures proper authentication before any stream is opened
 //! - Allows the server to control resource allocation
 //!
+//! ## Client-Initiated Streams
+//!
+//! Where a REST round-trip per action is too slow (e.g. game input), a server-side
+//! component can accept client-initiated streams of a given [`StreamType`] by
+//! registering a handler with [`StreamManager::set_incoming_handler`]. The client
+//! must send a single [`StreamHeader`] frame (same framing as
+//! [`CompressedCborEncoder`]) as the first data on the stream. Streams with an
+//! invalid header or a type without a registered handler are closed immediately.
+//! After [`MAX_STREAM_VIOLATIONS`] such streams or [`InvalidFrame`]s on the
+//! control stream the connection is closed.
+//!
+//! ## Binding Connections Without Cookies
+//!
+//! Browsers don't reliably send cookies with the WebTransport CONNECT request.
+//! A connection without a valid access token is therefore kept *pending*:
+//!
+//! 1. The server sends a [`BindChallenge`] with the connection id and a
+//!    random nonce as the first message on the control stream
+//! 2. The client submits both to the authenticated `POST /api/stream/bind`
+//!    endpoint, which calls [`StreamManager::bind`]
+//! 3. The connection is registered for the session's user like a connection
+//!    that was authenticated right away
+//!
+//! A connection that isn't bound within [`BIND_DEADLINE`] is closed. Each
+//! nonce can be used once; finished bind attempts are remembered for
+//! [`BIND_TOMBSTONE`] so replays and late binds get a distinct [`BindError`].
+//!
+//! ## Session-Bound Lifetime
+//!
+//! A connection belongs to the session it was authenticated (or bound) with
+//! and must not outlive that session's access token. The handler closes it
+//! once the token expires, unless the session was refreshed in the meantime
+//! ([`StreamManager::extend_deadline`]). Logging out or deleting the session
+//! closes it right away ([`StreamManager::close_session_stream`]).
+//!
 //! ## Single Connection Per User
 //!
 //! Each user can have only one active WebTransport connection at a time. When a
 //! user connects from a new device or browser tab:
 //!
 //! 1. The new connection registers with the manager
-//! 2. The old connection's command channel sender is dropped
-//! 3. The old handler's `cmd_rx.recv()` returns `None`, causing it to exit cleanly
-//! 4. The new connection takes over
+//! 2. The old connection gets a [`ControlServerMessage::ConnectionReplaced`],
+//!    waiting at most [`REPLACED_NOTICE_TIMEOUT`] for it to be sent
+//! 3. The old connection is closed with [`CloseCode::Replaced`] and its
+//!    command channel sender is dropped
+//! 4. The old handler's `cmd_rx.recv()` returns the close command (or `None`),
+//!    causing it to exit cleanly
+//! 5. The new connection takes over
 //!
 //! This prevents resource exhaustion and simplifies state management.
 //!
+//! With `stream.allow_multiple_connections` a user keeps up to
+//! `stream.max_connections_per_user` connections instead, and only the oldest
+//! is replaced like above. Stream requests and [`StreamManager::send_control`]
+//! go to the most recent connection, [`StreamManager::notify`] to all of them.
+//!
 //! ## Connection ID for Safe Cleanup
 //!
 //! Each connection is assigned a unique, monotonically increasing `connection_id`.
@@ -78,22 +122,60 @@
 //! ```
 //!
 //! Without the ID check, connection A's cleanup would remove connection B from
-//! the registry. With the ID check, `unregister(user_id, Some(1))` sees that the
-//! current entry has `id=2` and does nothing.
+//! the registry. With the ID check, `unregister(user_id, Some(1))` finds no
+//! entry with `id=1` and does nothing.
+//!
+//! External components use [`StreamManager::close_stream_with`] to
+//! force-disconnect a user regardless of connection ID (e.g., on logout or
+//! ban), with a [`CloseCode`] that tells the client why.
+//!
+//! ## Close Codes
+//!
+//! Whenever the server closes a connection the client can still be reached
+//! on, it sends a [`ControlServerMessage::Shutdown`] with a [`CloseCode`]
+//! first, see [`super::close_codes`] for the codes and what clients should do
+//! on each.
 //!
-//! External components can use `unregister(user_id, None)` to force-disconnect
-//! a user regardless of connection ID (e.g., on logout or ban).
+//! ## Control Stream
+//!
+//! Right after the heartbeat stream the server opens a [`StreamType::Control`]
+//! stream, which stays open for the whole connection and carries
+//! [`ControlServerMessage`]s (handshake, notifications, bind challenge,
+//! shutdown notice) and [`ControlClientMessage`]s. Its first messages are a
+//! handshake that fixes the protocol version and whether frames may be
+//! compressed, see [`super::control`]. Small events use
+//! [`StreamManager::send_control`] instead of a stream each; heavyweight flows
+//! like chat rooms and games keep using [`StreamManager::request_stream`].
+//!
+//! If the control stream fails (write error, reset or EOF from the client),
+//! the handler opens a new one once. If that fails too, the connection is
+//! closed.
 //!
 //! ## Heartbeat Stream for Connection Detection
 //!
 //! The handler opens a bidirectional stream immediately upon connection that serves
-//! as a "heartbeat". Neither side sends data on it. The handler reads from it, and
-//! when the read returns (EOF or error), it knows the connection has closed.
+//! as a "heartbeat". The handler reads from it, and when the read returns (EOF or
+//! error), it knows the connection has closed.
 //!
 //! This is necessary because the command channel alone cannot detect when the
 //! underlying QUIC connection dies - we need an active read on a stream to get
 //! that notification.
 //!
+//! A silent stream cannot tell a dead network path from an idle client, so the
+//! handler also sends a ping frame on it every `heartbeat.interval_secs` (see
+//! [`crate::config::HeartbeatConfig`]), which the client echoes unchanged:
+//!
+//! ```text
+//! ┌────────┬──────────────────────────────────────────────┐
+//! │ 1 byte │ 8 bytes                                      │
+//! │ 0x01   │ send time (u64 big-endian ms since connect)  │
+//! └────────┴──────────────────────────────────────────────┘
+//! ```
+//!
+//! The round-trip time of the last echo is available through
+//! [`StreamManager::latency_ms`]. After `heartbeat.max_missed` consecutive
+//! unanswered pings the connection is closed.
+//!
 //! ## Stream Lifetime and Ownership
 //!
 //! **Important architectural note:** Streams returned by [`StreamManager::request_stream`]
@@ -102,7 +184,7 @@
 //!
 //! When the handler exits (and thus drops the WebTransport session):
 //! - All streams opened on that session will error on subsequent read/write operations
-//! - The stream handles (`WtSend`, `WtRecv`) remain valid Rust objects but are unusable
+//! - The stream handles ([`SendHalf`], [`RecvHalf`]) remain valid Rust objects but are unusable
 //! - Callers should handle stream errors gracefully and treat them as disconnection
 //!
 //! This means that components holding streams (e.g., chat rooms with member streams)
@@ -110,6 +192,14 @@
 //! explicitly know about the disconnection. This is the desired behavior - it allows
 //! clean error propagation without requiring explicit cleanup coordination.
 //!
+//! ## Transport
+//!
+//! The connection handler is [`run_connection_handler`], which only needs a
+//! [`StreamTransport`] to open and accept streams on. [`connect_stream`] runs
+//! it on the WebTransport session of the request, [`connect_websocket`] on a
+//! WebSocket for clients that can't use WebTransport, see
+//! [`super::websocket`] for how streams are multiplexed on it.
+//!
 //! # Error Handling
 //!
 //! The API uses only two error variants for simplicity:
@@ -146,24 +236,42 @@
 //! safe to use from multiple tasks simultaneously. The global singleton is
 //! initialized lazily on first access.
 
-use std::sync::LazyLock;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
 
-use bytes::Bytes;
+use base64::Engine as _;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as base64url;
+use bytes::{BufMut, BytesMut};
 use dashmap::DashMap;
-use futures::SinkExt as _;
-use salvo::proto::quic::BidiStream;
+use futures::{SinkExt as _, StreamExt as _};
+use salvo::websocket::WebSocketUpgrade;
 use serde::de::DeserializeOwned;
+use smallvec::SmallVec;
 use thiserror::Error;
-use tokio::io::AsyncReadExt;
-use tokio::sync::{mpsc, oneshot};
-use tokio_util::codec::{FramedRead, FramedWrite};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{Mutex, mpsc, oneshot, watch};
+use tokio_util::codec::{Decoder as _, FramedRead, FramedWrite};
 
-use super::StreamType;
 use super::compress_cbor_codec::{
     CodecBufferParams, CompressedCborDecoder, CompressedCborEncoder,
+    DEFAULT_MAX_DEPTH, DEFAULT_MAX_FRAME, InvalidFrame,
+};
+use super::connection::{Action, CloseReason, Event, State};
+use super::control::{
+    COMPRESSION_ZSTD, COMPRESSION_ZSTD_DICT, ControlClientMessage,
+    ControlServerMessage, MIN_SUPPORTED_VERSION, Notification,
+    PROTOCOL_VERSION, UserPresence,
 };
+use super::presence::PresenceWatchers;
+use super::stats::{
+    ConnectionCounters, ConnectionInfo, ConnectionStats, CountedTransport,
+};
+use super::transport::{RecvHalf, SendHalf, StreamTransport, WebTransport};
+use super::websocket::{MAX_MESSAGE, WebSocketTransport};
+use super::{CloseCode, StreamType};
+use crate::config::StreamConfig;
+use crate::models::Session;
 use crate::prelude::*;
 use crate::utils::adaptive_buffer::BufferParams;
 
@@ -173,13 +281,27 @@ use crate::utils::adaptive_buffer::BufferParams;
 /// the connection is considered dead and will be cleaned up.
 const STREAM_TIMEOUT: Duration = Duration::from_secs(10);
 
-/// Send half of a WebTransport bidirectional stream (raw, unframed).
-type WtSend =
-    salvo::webtransport::stream::SendStream<h3_quinn::SendStream<Bytes>, Bytes>;
+/// Opcode of a heartbeat ping frame (echoed unchanged by the client).
+const HEARTBEAT_PING: u8 = 0x01;
+/// Opcode plus u64 timestamp.
+const HEARTBEAT_FRAME_LEN: usize = 9;
+
+/// Maximum size of a client-initiated stream's [`StreamHeader`] frame.
+const MAX_HEADER_FRAME: usize = 64;
+/// Invalid client-initiated streams and control frames tolerated before
+/// closing the connection.
+const MAX_STREAM_VIOLATIONS: u32 = 5;
 
-/// Receive half of a WebTransport bidirectional stream (raw, unframed).
-type WtRecv =
-    salvo::webtransport::stream::RecvStream<h3_quinn::RecvStream, Bytes>;
+/// Time the client has to answer the handshake, see [`super::control`].
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long registering a connection waits for the replaced one to be told.
+const REPLACED_NOTICE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Time a pending connection has to be bound to a user.
+const BIND_DEADLINE: Duration = Duration::from_secs(30);
+/// How long a bound or expired pending connection is remembered.
+const BIND_TOMBSTONE: Duration = Duration::from_secs(60);
 
 /// A sink for sending typed messages to a client.
 ///
@@ -189,7 +311,7 @@ type WtRecv =
 /// sender.send(MyMessage { ... }).await?;
 /// ```
 pub type Sender<S, BP = CodecBufferParams> =
-    FramedWrite<WtSend, CompressedCborEncoder<S, BP>>;
+    FramedWrite<SendHalf, CompressedCborEncoder<S, BP>>;
 
 /// A stream for receiving typed messages from a client.
 ///
@@ -200,8 +322,14 @@ pub type Sender<S, BP = CodecBufferParams> =
 ///     handle(msg?);
 /// }
 /// ```
-pub type Receiver<R, const MAX_FRAME: usize = { 8 * 1024 * 1024 }> =
-    FramedRead<WtRecv, CompressedCborDecoder<R, MAX_FRAME>>;
+///
+/// A frame the client broke fails with an error that
+/// [is](anyhow::Error::is) an [`InvalidFrame`].
+pub type Receiver<
+    R,
+    const MAX_FRAME: usize = DEFAULT_MAX_FRAME,
+    const MAX_DEPTH: usize = DEFAULT_MAX_DEPTH,
+> = FramedRead<RecvHalf, CompressedCborDecoder<R, MAX_FRAME, MAX_DEPTH>>;
 
 /// Errors returned by [`StreamManager`] operations.
 #[derive(Error, Debug)]
@@ -227,21 +355,294 @@ pub enum StreamManagerError {
     ConnectionClosed { user_id: i32, reason: String },
 }
 
+/// Errors returned by [`StreamManager::bind`].
+#[derive(Error, Debug, Clone, Copy, strum::IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum BindError {
+    #[error("No pending connection with this id")]
+    UnknownConnection,
+    #[error("Challenge nonce does not match")]
+    NonceMismatch,
+    #[error("Connection is already bound")]
+    AlreadyBound,
+    #[error("Bind deadline has passed")]
+    Expired,
+}
+
+/// Sent to a pending connection on its control stream.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BindChallenge {
+    pub connection_id: u64,
+    /// 32 random bytes, base64url-encoded without padding
+    pub challenge_nonce: String,
+}
+
+/// What the client agreed to in the handshake, see [`super::control`].
+#[derive(Debug, Clone, Copy)]
+struct Capabilities {
+    protocol_version: u16,
+    /// Whether the client accepted zstd-compressed frames
+    compression: bool,
+    /// Whether the client accepted frames compressed with the dictionary of
+    /// the [`StreamManager`]
+    dictionary: bool,
+}
+
+/// The session a connection is authenticated with.
+pub(crate) struct ConnectionSession {
+    user_id: i32,
+    session_id: i32,
+    valid_until: chrono::NaiveDateTime,
+}
+
+#[cfg(test)]
+impl ConnectionSession {
+    /// A session of `user_id` whose access lasts an hour.
+    pub fn for_user(user_id: i32) -> Self {
+        Self {
+            user_id,
+            session_id: user_id,
+            valid_until: chrono::Utc::now().naive_utc()
+                + chrono::TimeDelta::hours(1),
+        }
+    }
+
+    pub fn user_id(&self) -> i32 {
+        self.user_id
+    }
+}
+
+impl From<&Session> for ConnectionSession {
+    fn from(session: &Session) -> Self {
+        Self {
+            user_id: session.user_id,
+            session_id: session.id,
+            valid_until: session.access_valid_until(),
+        }
+    }
+}
+
+/// What a pending connection handler is sent: the session it is bound to,
+/// or the code and reason it is closed with before that.
+type BindOutcome = std::result::Result<ConnectionSession, (CloseCode, String)>;
+
+enum PendingState {
+    /// Sends the outcome to the waiting connection handler.
+    Waiting(oneshot::Sender<BindOutcome>),
+    Bound,
+    Expired,
+}
+
+/// A connection waiting to be bound to a user.
+struct PendingConnection {
+    nonce: [u8; 32],
+    deadline: Instant,
+    state: PendingState,
+}
+
+/// First frame of a client-initiated stream, identifying its purpose.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamHeader {
+    pub stream_type: StreamType,
+}
+
+/// A client-initiated stream, passed to the handler registered with
+/// [`StreamManager::set_incoming_handler`].
+pub struct IncomingStream {
+    send: SendHalf,
+    recv: RecvHalf,
+    /// Whether the client accepted compressed frames
+    compression: bool,
+    /// The negotiated zstd dictionary
+    dictionary: Option<Arc<[u8]>>,
+}
+
+impl IncomingStream {
+    /// Wrap the stream halves for typed message passing with default
+    /// codec parameters, like [`StreamManager::request_stream`].
+    pub fn into_typed<S, R>(self) -> (Sender<S>, Receiver<R>)
+    where
+        S: Serialize,
+        R: DeserializeOwned,
+    {
+        let (encoder, decoder) =
+            typed_codecs(self.compression, self.dictionary.as_ref());
+        (
+            FramedWrite::new(self.send, encoder),
+            FramedRead::new(self.recv, decoder),
+        )
+    }
+}
+
+/// Codecs of a typed stream, compressing the way the client accepted in the
+/// handshake.
+///
+/// With a dictionary every frame that shrinks with it is compressed, see
+/// [`CompressedCborEncoder::with_dictionary`].
+fn typed_codecs<S, R, BP, const MAX_FRAME: usize, const MAX_DEPTH: usize>(
+    compression: bool,
+    dictionary: Option<&Arc<[u8]>>,
+) -> (
+    CompressedCborEncoder<S, BP>,
+    CompressedCborDecoder<R, MAX_FRAME, MAX_DEPTH>,
+)
+where
+    BP: BufferParams,
+{
+    match dictionary {
+        Some(dictionary) => (
+            CompressedCborEncoder::with_dictionary(Arc::clone(dictionary)),
+            CompressedCborDecoder::with_dictionary(Arc::clone(dictionary)),
+        ),
+        None => {
+            let mut encoder = CompressedCborEncoder::new();
+            encoder.set_compression(compression);
+            (encoder, CompressedCborDecoder::new())
+        }
+    }
+}
+
+/// Handler for client-initiated streams of one [`StreamType`].
+///
+/// Called with the user id on the connection task, so it must not block;
+/// spawn a task for the actual stream handling.
+type IncomingHandler = Arc<dyn Fn(i32, IncomingStream) + Send + Sync>;
+
 /// Result type for [`StreamManager`] operations.
 pub type Result<T> = std::result::Result<T, StreamManagerError>;
 
 /// Commands that can be sent to a user's WebTransport connection handler.
-enum ConnectionCommand {
+pub(super) enum ConnectionCommand {
     /// Request to open a new bidirectional stream.
     OpenBidiStream {
-        response: oneshot::Sender<Result<(WtSend, WtRecv)>>,
+        response: oneshot::Sender<Result<(SendHalf, RecvHalf)>>,
     },
+    /// Reopen the control stream if it is still at `generation`, responds
+    /// whether a working control stream is available.
+    ReopenControl {
+        generation: u64,
+        response: oneshot::Sender<bool>,
+    },
+    /// Close the connection with `code`, sent right before the entry is
+    /// removed from the registry.
+    Close { code: CloseCode, reason: String },
+}
+
+/// The persistent control stream of a connection, see the module docs.
+///
+/// Only the connection handler reopens it, it also owns the receive half.
+struct ControlStream {
+    sender: Mutex<Sender<ControlServerMessage>>,
+    /// Incremented on every reopen, so concurrent failures reopen only once.
+    generation: AtomicU64,
+    /// Whether the client accepted compressed frames, also applied to
+    /// reopened streams.
+    compression: AtomicBool,
+}
+
+impl ControlStream {
+    fn new(sender: Sender<ControlServerMessage>) -> Self {
+        Self {
+            sender: Mutex::new(sender),
+            generation: AtomicU64::new(0),
+            compression: AtomicBool::new(true),
+        }
+    }
+
+    async fn set_compression(&self, enabled: bool) {
+        let mut sender = self.sender.lock().await;
+        sender.encoder_mut().set_compression(enabled);
+        self.compression.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Send a message, returning the generation of the stream that failed.
+    async fn send(
+        &self,
+        message: ControlServerMessage,
+    ) -> std::result::Result<(), u64> {
+        let mut sender = self.sender.lock().await;
+        let generation = self.generation.load(Ordering::Relaxed);
+        match tokio::time::timeout(STREAM_TIMEOUT, sender.send(message)).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(_)) | Err(_) => Err(generation),
+        }
+    }
+
+    /// Replace the stream if it is still at `failed_generation`.
+    ///
+    /// Returns the new receive half, or `None` if another failure already
+    /// reopened it.
+    async fn reopen(
+        &self,
+        transport: &impl StreamTransport,
+        failed_generation: u64,
+    ) -> anyhow::Result<Option<Receiver<ControlClientMessage>>> {
+        let mut sender = self.sender.lock().await;
+        if self.generation.load(Ordering::Relaxed) != failed_generation {
+            return Ok(None);
+        }
+        let (mut new_sender, receiver) = tokio::time::timeout(
+            STREAM_TIMEOUT,
+            open_control_stream(transport),
+        )
+        .await??;
+        new_sender
+            .encoder_mut()
+            .set_compression(self.compression.load(Ordering::Relaxed));
+        *sender = new_sender;
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        Ok(Some(receiver))
+    }
 }
 
 /// Entry in the connection registry, containing the channel and a unique connection ID.
 struct ConnectionEntry {
     tx: mpsc::Sender<ConnectionCommand>,
     connection_id: u64,
+    session_id: i32,
+    /// The handler closes the connection when this passes.
+    deadline: watch::Sender<tokio::time::Instant>,
+    control: Arc<ControlStream>,
+    /// Streams, bytes and heartbeat latency of the connection, written by
+    /// the handler.
+    counters: Arc<ConnectionCounters>,
+    capabilities: Capabilities,
+}
+
+impl ConnectionEntry {
+    /// Tell the handler to close with `code` once the entry is removed.
+    ///
+    /// If its command channel is full the handler closes with
+    /// [`CloseCode::Generic`] instead.
+    fn close(&self, code: CloseCode, reason: &str) {
+        let _ = self.tx.try_send(ConnectionCommand::Close {
+            code,
+            reason: reason.to_owned(),
+        });
+    }
+
+    fn control_target(&self) -> ControlTarget {
+        ControlTarget {
+            control: Arc::clone(&self.control),
+            tx: self.tx.clone(),
+            connection_id: self.connection_id,
+        }
+    }
+}
+
+/// The connections of a user, oldest first and never empty.
+///
+/// Holds a single entry unless `stream.allow_multiple_connections` is set.
+type UserConnections = SmallVec<[ConnectionEntry; 1]>;
+
+/// What sending on the control stream of a connection needs, taken out of
+/// the registry so no lock is held while sending.
+struct ControlTarget {
+    control: Arc<ControlStream>,
+    tx: mpsc::Sender<ConnectionCommand>,
+    connection_id: u64,
 }
 
 /// Global manager for WebTransport client connections.
@@ -250,52 +651,352 @@ struct ConnectionEntry {
 /// allowing external components to request new streams.
 pub struct StreamManager {
     /// Registry mapping user IDs to their connection entries.
-    connections: DashMap<i32, ConnectionEntry, ahash::RandomState>,
+    connections: DashMap<i32, UserConnections, ahash::RandomState>,
     /// Counter for generating unique connection IDs.
     connection_id_counter: AtomicU64,
+    /// Handlers for client-initiated streams.
+    incoming_handlers: DashMap<StreamType, IncomingHandler, ahash::RandomState>,
+    /// Connections waiting to be bound, by connection ID.
+    pending: DashMap<u64, PendingConnection, ahash::RandomState>,
+    /// Connections subscribed to the presence of each user.
+    presence_watchers: PresenceWatchers,
+    /// zstd dictionary offered in the handshake, see
+    /// [`StreamConfig::dictionary_file`].
+    dictionary: Option<Arc<[u8]>>,
+    /// See [`StreamConfig::connections_per_user`].
+    connections_per_user: usize,
 }
 
 impl StreamManager {
     /// Create a new StreamManager.
-    fn new() -> Self {
+    fn new(config: &StreamConfig) -> Self {
         Self {
             connections: DashMap::default(),
             connection_id_counter: AtomicU64::new(0),
+            incoming_handlers: DashMap::default(),
+            pending: DashMap::default(),
+            presence_watchers: PresenceWatchers::default(),
+            dictionary: config.dictionary.clone(),
+            connections_per_user: config.connections_per_user(),
         }
     }
 
     /// Get the global StreamManager instance.
     pub fn global() -> &'static Self {
         static INSTANCE: LazyLock<StreamManager> =
-            LazyLock::new(StreamManager::new);
+            LazyLock::new(|| match crate::config::CONFIG.get() {
+                Some(config) => StreamManager::new(&config.stream),
+                None => StreamManager::default(),
+            });
         &INSTANCE
     }
 
+    /// The dictionary of streams of a connection with `capabilities`.
+    fn dictionary_for(&self, capabilities: Capabilities) -> Option<&Arc<[u8]>> {
+        self.dictionary.as_ref().filter(|_| capabilities.dictionary)
+    }
+
     /// Returns whether the given user is connected
     pub fn is_connected(&self, user_id: i32) -> bool {
         self.connections.contains_key(&user_id)
     }
 
-    /// Register a user's WebTransport connection command channel.
+    /// Returns the number of users with a registered connection.
+    pub fn connected_users(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// Returns the number of registered connections, pending ones are not
+    /// included.
+    pub fn connection_count(&self) -> usize {
+        self.connections.iter().map(|entries| entries.len()).sum()
+    }
+
+    /// Returns the latest heartbeat round-trip time of the given user.
+    ///
+    /// `None` if the user is not connected or hasn't answered a ping yet.
+    pub fn latency_ms(&self, user_id: i32) -> Option<u32> {
+        self.connections
+            .get(&user_id)?
+            .last()?
+            .counters
+            .latency_ms()
+    }
+
+    /// Returns the stats of all registered connections.
+    ///
+    /// Pending connections are not included.
+    pub fn stats(&self) -> Vec<ConnectionStats> {
+        self.connections
+            .iter()
+            .flat_map(|entries| {
+                let user_id = *entries.key();
+                entries
+                    .iter()
+                    .map(|entry| {
+                        ConnectionStats::new(
+                            user_id,
+                            entry.connection_id,
+                            entry.session_id,
+                            entry.capabilities.protocol_version,
+                            &entry.counters,
+                        )
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Returns the registered connections of the given user, oldest first.
+    ///
+    /// Empty if the user is not connected, pending ones are not included.
+    pub fn connection_info(&self, user_id: i32) -> Vec<ConnectionInfo> {
+        let Some(entries) = self.connections.get(&user_id) else {
+            return Vec::new();
+        };
+        entries
+            .iter()
+            .map(|entry| ConnectionInfo::new(entry.session_id, &entry.counters))
+            .collect()
+    }
+
+    /// Accept client-initiated streams of the given type with `handler`.
+    ///
+    /// Replaces a previously registered handler for the same type.
+    pub fn set_incoming_handler(
+        &self,
+        stream_type: StreamType,
+        handler: impl Fn(i32, IncomingStream) + Send + Sync + 'static,
+    ) {
+        self.incoming_handlers
+            .insert(stream_type, Arc::new(handler));
+    }
+
+    /// [Dispatch](Self::dispatch_incoming) a client-initiated stream in the
+    /// background, counting a rejected one as a violation of the connection.
+    fn spawn_dispatch(
+        &'static self,
+        user_id: i32,
+        connection_id: u64,
+        (send, recv): (SendHalf, RecvHalf),
+        capabilities: Capabilities,
+        violations: Arc<AtomicU32>,
+    ) {
+        tokio::spawn(async move {
+            let Err(reason) = self
+                .dispatch_incoming(user_id, send, recv, capabilities)
+                .await
+            else {
+                return;
+            };
+            self.count_violation(&violations, user_id, connection_id, &reason);
+        });
+    }
+
+    /// Read the header of a client-initiated stream and pass the stream to
+    /// the registered handler.
     ///
-    /// Returns a unique connection ID that must be passed to `unregister` later.
-    /// If the user already has a connection, the old sender is dropped,
-    /// causing the old handler's `rx.recv()` to return `None` and exit.
-    fn register(
+    /// Returns an error describing the violation if the stream was rejected.
+    async fn dispatch_incoming(
         &self,
         user_id: i32,
+        send: SendHalf,
+        mut recv: RecvHalf,
+        capabilities: Capabilities,
+    ) -> std::result::Result<(), String> {
+        let header =
+            tokio::time::timeout(STREAM_TIMEOUT, read_stream_header(&mut recv))
+                .await
+                .map_err(|_| "timed out waiting for stream header".to_string())?
+                .map_err(|e| format!("invalid stream header: {e}"))?;
+
+        let handler = self
+            .incoming_handlers
+            .get(&header.stream_type)
+            .map(|handler| Arc::clone(&handler))
+            .ok_or_else(|| {
+                format!("no handler for stream type {:?}", header.stream_type)
+            })?;
+        handler(
+            user_id,
+            IncomingStream {
+                send,
+                recv,
+                compression: capabilities.compression,
+                dictionary: self.dictionary_for(capabilities).cloned(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Generate a unique connection ID.
+    fn next_connection_id(&self) -> u64 {
+        self.connection_id_counter.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Bind a pending connection to `session`.
+    ///
+    /// The connection handler then registers the connection for the session's
+    /// user, replacing any existing connection.
+    pub fn bind(
+        &self,
+        connection_id: u64,
+        nonce: &[u8],
+        session: &Session,
+    ) -> std::result::Result<(), BindError> {
+        let mut pending = self
+            .pending
+            .get_mut(&connection_id)
+            .ok_or(BindError::UnknownConnection)?;
+        match pending.state {
+            PendingState::Bound => return Err(BindError::AlreadyBound),
+            PendingState::Expired => return Err(BindError::Expired),
+            PendingState::Waiting(_) if Instant::now() >= pending.deadline => {
+                return Err(BindError::Expired);
+            }
+            PendingState::Waiting(_) => {}
+        }
+        // Compare hashes to not leak the nonce through timing
+        if blake3::hash(nonce) != blake3::hash(&pending.nonce) {
+            return Err(BindError::NonceMismatch);
+        }
+
+        match std::mem::replace(&mut pending.state, PendingState::Bound) {
+            PendingState::Waiting(tx) => tx
+                .send(Ok(session.into()))
+                .map_err(|_| BindError::UnknownConnection),
+            _ => unreachable!("checked above"),
+        }
+    }
+
+    /// Make a connection pending and send it its [`BindChallenge`].
+    ///
+    /// Returns the receiver of the session it gets bound to and the bind
+    /// deadline, `None` if the challenge couldn't be sent.
+    async fn start_bind(
+        &'static self,
+        connection_id: u64,
+        control: &ControlStream,
+    ) -> Option<(oneshot::Receiver<BindOutcome>, Instant)> {
+        let nonce: [u8; 32] = rand::random();
+        let deadline = Instant::now() + BIND_DEADLINE;
+        let (bind_tx, bind_rx) = oneshot::channel();
+        self.pending.insert(
+            connection_id,
+            PendingConnection {
+                nonce,
+                deadline,
+                state: PendingState::Waiting(bind_tx),
+            },
+        );
+
+        let challenge = BindChallenge {
+            connection_id,
+            challenge_nonce: base64url.encode(nonce),
+        };
+        let challenge = ControlServerMessage::BindChallenge(challenge);
+        if control.send(challenge).await.is_err() {
+            tracing::info!(connection_id, "Failed to send bind challenge");
+            self.forget_bind(connection_id);
+            return None;
+        }
+        Some((bind_rx, deadline))
+    }
+
+    /// Reject binds of a pending connection whose deadline passed.
+    fn expire_bind(&self, connection_id: u64) {
+        if let Some(mut pending) = self.pending.get_mut(&connection_id) {
+            pending.state = PendingState::Expired;
+        }
+    }
+
+    /// Remove a connection that is no longer pending, after keeping the
+    /// tombstone to reject late or replayed binds distinctly.
+    fn forget_bind(&'static self, connection_id: u64) {
+        tokio::spawn(async move {
+            tokio::time::sleep(BIND_TOMBSTONE).await;
+            self.pending.remove(&connection_id);
+        });
+    }
+
+    /// Register a user's WebTransport connection command channel.
+    ///
+    /// `connection_id` is from [`next_connection_id`](Self::next_connection_id)
+    /// and must be passed to `unregister` later.
+    /// If the user already has as many connections as allowed, the oldest one
+    /// is told about the replacement and closed with [`CloseCode::Replaced`].
+    /// Dropping its sender makes the old handler exit even if it missed the
+    /// close command.
+    async fn register(
+        &self,
+        session: &ConnectionSession,
+        connection_id: u64,
         tx: mpsc::Sender<ConnectionCommand>,
-    ) -> u64 {
-        let connection_id =
-            self.connection_id_counter.fetch_add(1, Ordering::Relaxed);
-        self.connections
-            .insert(user_id, ConnectionEntry { tx, connection_id });
+        control: Arc<ControlStream>,
+        counters: Arc<ConnectionCounters>,
+        capabilities: Capabilities,
+    ) -> watch::Receiver<tokio::time::Instant> {
+        let user_id = session.user_id;
+        let (deadline, deadline_rx) =
+            watch::channel(instant_at(session.valid_until));
+        let entry = ConnectionEntry {
+            tx,
+            connection_id,
+            session_id: session.session_id,
+            deadline,
+            control,
+            counters,
+            capabilities,
+        };
+        let was_online = crate::auth::presence::is_online(user_id);
+        let replaced: Vec<ConnectionEntry> = {
+            let mut entries = self.connections.entry(user_id).or_default();
+            entries.push(entry);
+            let excess =
+                entries.len().saturating_sub(self.connections_per_user);
+            entries.drain(..excess).collect()
+        };
         tracing::info!(
             user_id,
             connection_id,
             "Registered WebTransport connection"
         );
-        connection_id
+        if !was_online {
+            self.publish_presence(
+                user_id,
+                true,
+                crate::auth::presence::last_seen(user_id),
+            );
+        }
+
+        // The replaced handlers keep running until their entries are dropped
+        let notices = replaced.iter().map(|old| {
+            tracing::info!(
+                user_id,
+                connection_id = old.connection_id,
+                new_connection_id = connection_id,
+                "Replacing connection"
+            );
+            let message = ControlServerMessage::ConnectionReplaced {
+                new_connection_id: connection_id,
+            };
+            self.send_control_to(user_id, old.control_target(), message)
+        });
+        let notices = futures::future::join_all(notices);
+        if tokio::time::timeout(REPLACED_NOTICE_TIMEOUT, notices)
+            .await
+            .is_err()
+        {
+            tracing::debug!(
+                user_id,
+                connection_id,
+                "Replacement notice timed out"
+            );
+        }
+        for old in replaced {
+            old.close(CloseCode::Replaced, "Connection replaced");
+        }
+        deadline_rx
     }
 
     /// Disconnect a user's WebTransport connection.
@@ -306,8 +1007,8 @@ impl StreamManager {
     /// # Parameters
     ///
     /// - `user_id`: The user to disconnect
-    /// - `connection_id`: If `Some(id)`, only disconnects if the current connection
-    ///   matches that ID. If `None`, forcefully disconnects regardless of ID.
+    /// - `connection_id`: If `Some(id)`, only disconnects the connection with that
+    ///   ID. If `None`, forcefully disconnects all connections of the user.
     ///
     /// # When to use `Some(connection_id)`
     ///
@@ -322,46 +1023,171 @@ impl StreamManager {
     fn unregister(&self, user_id: i32, connection_id: Option<u64>) {
         match connection_id {
             Some(id) => {
-                self.connections.remove_if(&user_id, |_, entry| {
-                    let matches = entry.connection_id == id;
-                    if matches {
-                        tracing::info!(
-                            user_id,
-                            connection_id = id,
-                            "Unregistered connection"
-                        );
-                    }
-                    matches
-                });
+                let removed = self
+                    .remove_where(user_id, |entry| entry.connection_id == id);
+                if removed {
+                    tracing::info!(
+                        user_id,
+                        connection_id = id,
+                        "Unregistered connection"
+                    );
+                }
             }
             None => {
                 if self.connections.remove(&user_id).is_some() {
                     tracing::info!(user_id, "Force-disconnected user");
+                    self.on_disconnected(user_id);
                 }
             }
         }
     }
 
-    /// Request a new raw bidirectional stream for a connected user.
+    /// Count an invalid stream or frame of a connection, and close the
+    /// connection after [`MAX_STREAM_VIOLATIONS`].
+    fn count_violation(
+        &self,
+        violations: &AtomicU32,
+        user_id: i32,
+        connection_id: u64,
+        reason: &str,
+    ) {
+        let count = violations.fetch_add(1, Ordering::Relaxed) + 1;
+        tracing::warn!(
+            user_id,
+            connection_id,
+            count,
+            reason,
+            "Client protocol violation"
+        );
+        if count < MAX_STREAM_VIOLATIONS {
+            return;
+        }
+        tracing::warn!(
+            user_id,
+            connection_id,
+            "Too many protocol violations, closing connection"
+        );
+        let this_connection =
+            |entry: &ConnectionEntry| entry.connection_id == connection_id;
+        self.send_close(
+            user_id,
+            this_connection,
+            CloseCode::ProtocolViolation,
+            "Too many protocol violations",
+        );
+        // Dropping the command sender ends the main loop
+        self.unregister(user_id, Some(connection_id));
+    }
+
+    /// Remove the connections of a user matching `filter`, and the user once
+    /// no connection is left. Returns whether any was removed.
+    fn remove_where(
+        &self,
+        user_id: i32,
+        filter: impl Fn(&ConnectionEntry) -> bool,
+    ) -> bool {
+        let removed = match self.connections.get_mut(&user_id) {
+            Some(mut entries) => {
+                let before = entries.len();
+                entries.retain(|entry| !filter(entry));
+                entries.len() < before
+            }
+            None => false,
+        };
+        let disconnected = self
+            .connections
+            .remove_if(&user_id, |_, entries| entries.is_empty())
+            .is_some();
+        if removed && disconnected {
+            self.on_disconnected(user_id);
+        }
+        removed
+    }
+
+    /// Publish that a user went offline, unless still recently active.
+    fn on_disconnected(&self, user_id: i32) {
+        if !crate::auth::presence::is_online(user_id) {
+            self.publish_presence(
+                user_id,
+                false,
+                crate::auth::presence::last_seen(user_id),
+            );
+        }
+    }
+
+    /// Send a [`Notification::PresenceChanged`] to the connections subscribed
+    /// to a user, in the background.
+    ///
+    /// Called when the user connects, disconnects, becomes active over REST
+    /// again or goes offline by inactivity over REST.
+    pub fn publish_presence(
+        &self,
+        user_id: i32,
+        online: bool,
+        last_seen: Option<chrono::NaiveDateTime>,
+    ) {
+        let targets: Vec<(i32, ControlTarget)> = self
+            .presence_watchers
+            .of(user_id)
+            .into_iter()
+            .filter_map(|(watcher, connection_id)| {
+                let entries = self.connections.get(&watcher)?;
+                let entry = entries
+                    .iter()
+                    .find(|entry| entry.connection_id == connection_id)?;
+                Some((watcher, entry.control_target()))
+            })
+            .collect();
+        let message = ControlServerMessage::Notification(
+            Notification::PresenceChanged(UserPresence {
+                user_id,
+                online,
+                last_seen,
+            }),
+        );
+        // Connections are only registered in the global manager
+        let manager = Self::global();
+        for (watcher, target) in targets {
+            let message = message.clone();
+            tokio::spawn(async move {
+                if let Err(e) =
+                    manager.send_control_to(watcher, target, message).await
+                {
+                    tracing::debug!(user_id = watcher, error = %e, "Presence change dropped");
+                }
+            });
+        }
+    }
+
+    /// The most recent connection of a user, which stream requests go to.
+    fn latest_connection(
+        &self,
+        user_id: i32,
+    ) -> Result<(mpsc::Sender<ConnectionCommand>, u64, Capabilities)> {
+        let entries = self
+            .connections
+            .get(&user_id)
+            .ok_or(StreamManagerError::UserNotConnected { user_id })?;
+        let entry = entries
+            .last()
+            .ok_or(StreamManagerError::UserNotConnected { user_id })?;
+        Ok((entry.tx.clone(), entry.connection_id, entry.capabilities))
+    }
+
+    /// Request a new raw bidirectional stream on a connection of a user.
     ///
-    /// Returns unframed WebTransport stream halves. This is a low-level API;
+    /// Returns unframed stream halves. This is a low-level API;
     /// prefer [`request_stream`](Self::request_stream) for typed message passing.
     ///
     /// # Errors
     ///
-    /// - [`StreamManagerError::UserNotConnected`]: No active session for this user
     /// - [`StreamManagerError::ConnectionClosed`]: Connection died (auto-cleaned up)
     async fn request_unframed_stream(
         &self,
         user_id: i32,
-    ) -> Result<(WtSend, WtRecv)> {
-        let tx = self
-            .connections
-            .get(&user_id)
-            .ok_or(StreamManagerError::UserNotConnected { user_id })?
-            .tx
-            .clone();
-
+        tx: mpsc::Sender<ConnectionCommand>,
+        connection_id: u64,
+    ) -> Result<(SendHalf, RecvHalf)> {
         let (response_tx, response_rx) = oneshot::channel();
 
         // Send command to handler
@@ -372,7 +1198,7 @@ impl StreamManager {
             .await
             .is_err()
         {
-            self.unregister(user_id, None);
+            self.unregister(user_id, Some(connection_id));
             return Err(StreamManagerError::ConnectionClosed {
                 user_id,
                 reason: "handler exited".into(),
@@ -383,7 +1209,7 @@ impl StreamManager {
         match tokio::time::timeout(STREAM_TIMEOUT, response_rx).await {
             Ok(Ok(result)) => result,
             Ok(Err(_)) | Err(_) => {
-                self.unregister(user_id, None);
+                self.unregister(user_id, Some(connection_id));
                 Err(StreamManagerError::ConnectionClosed {
                     user_id,
                     reason: "handler unresponsive or crashed".into(),
@@ -394,6 +1220,7 @@ impl StreamManager {
 
     /// Request a new bidirectional stream for typed message passing.
     ///
+    /// The stream is opened on the most recent connection of the user.
     /// This is the primary API for server-side components to communicate with clients.
     /// The returned stream halves use CBOR serialization with optional Zstd compression,
     /// using default codec parameters.
@@ -434,17 +1261,20 @@ impl StreamManager {
         S: Serialize,
         R: DeserializeOwned,
     {
-        self.request_custom_stream::<S, R, CodecBufferParams, { 8 * 1024 * 1024 }>(
-            user_id,
-            r#type,
-        )
+        self.request_custom_stream::<
+            S,
+            R,
+            CodecBufferParams,
+            DEFAULT_MAX_FRAME,
+            DEFAULT_MAX_DEPTH,
+        >(user_id, r#type)
         .await
     }
 
     /// Request a new bidirectional stream with custom codec parameters.
     ///
     /// This is an advanced API for cases where you need to customize the codec
-    /// buffer behavior or maximum frame size. For most use cases, prefer
+    /// buffer behavior, maximum frame size or nesting. For most use cases, prefer
     /// [`request_stream`](Self::request_stream) which uses sensible defaults.
     ///
     /// # Type Parameters
@@ -453,148 +1283,1661 @@ impl StreamManager {
     /// - `R`: The type to receive (must implement [`DeserializeOwned`])
     /// - `BP`: Buffer parameters for the encoder (implements [`BufferParams`])
     /// - `MAX_FRAME`: Maximum allowed receive frame size in bytes
+    /// - `MAX_DEPTH`: Maximum nesting of a received frame
     ///
     /// # Errors
     ///
     /// - [`StreamManagerError::UserNotConnected`]: No active session for this user
     /// - [`StreamManagerError::ConnectionClosed`]: Connection died (auto-cleaned up)
-    pub async fn request_custom_stream<S, R, BP, const MAX_FRAME: usize>(
+    pub async fn request_custom_stream<
+        S,
+        R,
+        BP,
+        const MAX_FRAME: usize,
+        const MAX_DEPTH: usize,
+    >(
         &self,
         user_id: i32,
         r#type: StreamType,
-    ) -> Result<(Sender<S, BP>, Receiver<R, MAX_FRAME>)>
+    ) -> Result<(Sender<S, BP>, Receiver<R, MAX_FRAME, MAX_DEPTH>)>
     where
         S: Serialize,
         R: DeserializeOwned,
         BP: BufferParams,
     {
-        let (send, recv) = self.request_unframed_stream(user_id).await?;
+        let (tx, connection_id, capabilities) =
+            self.latest_connection(user_id)?;
+        let (send, recv) = self
+            .request_unframed_stream(user_id, tx, connection_id)
+            .await?;
 
         let mut sender =
             FramedWrite::new(send, CompressedCborEncoder::<_, BP>::new());
         sender.send(r#type).await.map_err(|e| {
-            self.unregister(user_id, None);
+            self.unregister(user_id, Some(connection_id));
             StreamManagerError::ConnectionClosed {
                 user_id,
                 reason: format!("failed to send stream type: {e}"),
             }
         })?;
         sender.flush().await.map_err(|e| {
-            self.unregister(user_id, None);
+            self.unregister(user_id, Some(connection_id));
             StreamManagerError::ConnectionClosed {
                 user_id,
                 reason: format!("failed to flush stream type: {e}"),
             }
         })?;
-        let sender = sender.map_encoder(|_| CompressedCborEncoder::new());
-        let receiver = FramedRead::new(recv, CompressedCborDecoder::new());
-
-        Ok((sender, receiver))
+        let (encoder, decoder) = typed_codecs(
+            capabilities.compression,
+            self.dictionary_for(capabilities),
+        );
+        Ok((
+            sender.map_encoder(|_| encoder),
+            FramedRead::new(recv, decoder),
+        ))
     }
 
-    /// Force-disconnect a user's WebTransport connection.
+    /// Send a message on the control stream of the most recent connection of
+    /// a user.
     ///
-    /// This is useful for logout, ban, or other administrative actions that
-    /// require immediately terminating a user's session.
+    /// If the control stream failed, the connection handler reopens it once
+    /// and the message is sent again on the new stream.
     ///
-    /// Note: This is a no-op if the user has no active connection.
-    pub fn close_stream(&self, user_id: i32) {
-        self.unregister(user_id, None);
+    /// # Errors
+    ///
+    /// - [`StreamManagerError::UserNotConnected`]: No active session for this user
+    /// - [`StreamManagerError::ConnectionClosed`]: The control stream could not
+    ///   be reopened (connection cleaned up)
+    pub async fn send_control(
+        &self,
+        user_id: i32,
+        message: ControlServerMessage,
+    ) -> Result<()> {
+        let target = self
+            .connections
+            .get(&user_id)
+            .and_then(|entries| Some(entries.last()?.control_target()))
+            .ok_or(StreamManagerError::UserNotConnected { user_id })?;
+        self.send_control_to(user_id, target, message).await
     }
-}
 
-/// WebTransport connection endpoint.
-///
-/// Establishes a WebTransport/QUIC session for real-time bidirectional communication.
-/// This endpoint upgrades the HTTP/3 connection to a WebTransport session and maintains
-/// it until the client disconnects or is force-disconnected.
-///
-/// # Protocol
-///
-/// 1. Client initiates WebTransport connection via HTTP/3 CONNECT
-/// 2. Server opens a heartbeat stream for connection liveness detection
-/// 3. Server registers the connection in [`StreamManager`]
-/// 4. Server-side components can request streams via [`StreamManager::request_stream`]
-/// 5. Connection ends when client disconnects or heartbeat fails
-///
-/// # Single Connection Policy
-///
+    /// Send a message on the control stream of one connection, reopening the
+    /// stream once if it failed.
+    async fn send_control_to(
+        &self,
+        user_id: i32,
+        target: ControlTarget,
+        message: ControlServerMessage,
+    ) -> Result<()> {
+        let ControlTarget {
+            control,
+            tx,
+            connection_id,
+        } = target;
+        let Err(generation) = control.send(message.clone()).await else {
+            return Ok(());
+        };
+
+        let (response, response_rx) = oneshot::channel();
+        let reopened = tx
+            .send(ConnectionCommand::ReopenControl {
+                generation,
+                response,
+            })
+            .await
+            .is_ok()
+            && matches!(
+                tokio::time::timeout(STREAM_TIMEOUT, response_rx).await,
+                Ok(Ok(true))
+            );
+        if reopened && control.send(message).await.is_ok() {
+            return Ok(());
+        }
+
+        self.unregister(user_id, Some(connection_id));
+        Err(StreamManagerError::ConnectionClosed {
+            user_id,
+            reason: "control stream failed".into(),
+        })
+    }
+
+    /// Send a [`Notification`] on the control streams of all connections of a
+    /// user, in the background.
+    ///
+    /// Notifications are best-effort: if the user is not connected, the event
+    /// is dropped. Clients should reload the state over REST when they connect.
+    pub fn notify(&'static self, user_id: i32, notification: Notification) {
+        let targets: Vec<ControlTarget> = match self.connections.get(&user_id) {
+            Some(entries) => entries
+                .iter()
+                .map(ConnectionEntry::control_target)
+                .collect(),
+            None => {
+                tracing::debug!(user_id, "Notification dropped, not connected");
+                return;
+            }
+        };
+        let message = ControlServerMessage::Notification(notification);
+        for target in targets {
+            let message = message.clone();
+            tokio::spawn(async move {
+                if let Err(e) =
+                    self.send_control_to(user_id, target, message).await
+                {
+                    tracing::debug!(user_id, error = %e, "Notification dropped");
+                }
+            });
+        }
+    }
+
+    /// Send a [`Notification`] to every connected user, like
+    /// [`notify`](Self::notify).
+    ///
+    /// Returns how many users it was sent to. Users connecting later don't
+    /// get it.
+    pub fn notify_all(&'static self, notification: Notification) -> usize {
+        let user_ids: Vec<i32> = self
+            .connections
+            .iter()
+            .map(|entries| *entries.key())
+            .collect();
+        for &user_id in &user_ids {
+            self.notify(user_id, notification.clone());
+        }
+        user_ids.len()
+    }
+
+    /// Force-disconnect all WebTransport connections of a user with
+    /// [`CloseCode::Generic`].
+    ///
+    /// Note: This is a no-op if the user has no active connection.
+    pub fn close_stream(&self, user_id: i32) {
+        self.close_stream_with(
+            user_id,
+            CloseCode::Generic,
+            "Connection closed by the server",
+        );
+    }
+
+    /// Force-disconnect all WebTransport connections of a user, telling the
+    /// client why with `code` and a short `reason`.
+    ///
+    /// This is useful for logout, ban, or other administrative actions that
+    /// require immediately terminating a user's session.
+    pub fn close_stream_with(
+        &self,
+        user_id: i32,
+        code: CloseCode,
+        reason: &str,
+    ) {
+        self.send_close(user_id, |_| true, code, reason);
+        self.unregister(user_id, None);
+    }
+
+    /// Force-disconnect the WebTransport connections of a user that belong to
+    /// the given session (e.g. on logout of that session), with
+    /// [`CloseCode::AuthExpired`].
+    pub fn close_session_stream(&self, user_id: i32, session_id: i32) {
+        self.close_session_stream_with(
+            user_id,
+            session_id,
+            CloseCode::AuthExpired,
+            "Session ended",
+        );
+    }
+
+    /// Force-disconnect the WebTransport connections of a user that belong to
+    /// the given session, telling the client why with `code` and a short
+    /// `reason`.
+    pub fn close_session_stream_with(
+        &self,
+        user_id: i32,
+        session_id: i32,
+        code: CloseCode,
+        reason: &str,
+    ) {
+        let in_session =
+            |entry: &ConnectionEntry| entry.session_id == session_id;
+        self.send_close(user_id, in_session, code, reason);
+        if self.remove_where(user_id, in_session) {
+            tracing::info!(user_id, session_id, "Closed connection of session");
+        }
+    }
+
+    /// Close every connection, e.g. when the server shuts down. Pending
+    /// connections are closed with the same code, late binds are rejected
+    /// as expired.
+    pub fn close_all(&self, code: CloseCode, reason: &str) {
+        let user_ids: Vec<i32> = self
+            .connections
+            .iter()
+            .map(|entries| *entries.key())
+            .collect();
+        for user_id in user_ids {
+            self.close_stream_with(user_id, code, reason);
+        }
+        for mut pending in self.pending.iter_mut() {
+            match std::mem::replace(&mut pending.state, PendingState::Expired) {
+                PendingState::Waiting(tx) => {
+                    // The handler may have exited already
+                    let _ = tx.send(Err((code, reason.to_owned())));
+                }
+                state => pending.state = state,
+            }
+        }
+    }
+
+    /// Tell the handlers of the connections of a user matching `filter` to
+    /// close with `code`, before their entries are removed.
+    fn send_close(
+        &self,
+        user_id: i32,
+        filter: impl Fn(&ConnectionEntry) -> bool,
+        code: CloseCode,
+        reason: &str,
+    ) {
+        let Some(entries) = self.connections.get(&user_id) else {
+            return;
+        };
+        for entry in entries.iter().filter(|entry| filter(entry)) {
+            entry.close(code, reason);
+        }
+    }
+
+    /// Let the connections of the given session live until `valid_until`.
+    ///
+    /// Called when the session is refreshed. A no-op for connections of other
+    /// sessions.
+    pub fn extend_deadline(
+        &self,
+        user_id: i32,
+        session_id: i32,
+        valid_until: chrono::NaiveDateTime,
+    ) {
+        let Some(entries) = self.connections.get(&user_id) else {
+            return;
+        };
+        for entry in entries
+            .iter()
+            .filter(|entry| entry.session_id == session_id)
+        {
+            entry.deadline.send_replace(instant_at(valid_until));
+        }
+    }
+}
+
+/// Convert a UTC timestamp to a tokio Instant (now if it already passed).
+fn instant_at(at: chrono::NaiveDateTime) -> tokio::time::Instant {
+    let remaining = (at - chrono::Utc::now().naive_utc())
+        .to_std()
+        .unwrap_or_default();
+    tokio::time::Instant::now() + remaining
+}
+
+/// Open a [`StreamType::Control`] stream.
+async fn open_control_stream(
+    transport: &impl StreamTransport,
+) -> anyhow::Result<(
+    Sender<ControlServerMessage>,
+    Receiver<ControlClientMessage>,
+)> {
+    let (send, recv) = transport.open_bi().await?;
+    let mut sender = FramedWrite::new(
+        send,
+        CompressedCborEncoder::<_, CodecBufferParams>::new(),
+    );
+    sender.send(StreamType::Control).await?;
+    let sender = sender.map_encoder(|_| CompressedCborEncoder::new());
+    Ok((sender, FramedRead::new(recv, CompressedCborDecoder::new())))
+}
+
+/// Offer the protocol version and compression and wait for the client's
+/// answer, see [`super::control`].
+///
+/// Returns `None` if the client didn't answer in time, sent something else
+/// or speaks an unsupported version, which it is told first.
+async fn handshake(
+    control: &ControlStream,
+    control_recv: &mut Receiver<ControlClientMessage>,
+    connection_id: u64,
+    offer_dictionary: bool,
+) -> Option<Capabilities> {
+    let mut compression = vec![COMPRESSION_ZSTD.to_owned()];
+    if offer_dictionary {
+        compression.push(COMPRESSION_ZSTD_DICT.to_owned());
+    }
+    let hello = ControlServerMessage::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        compression,
+        max_frame: DEFAULT_MAX_FRAME as u32,
+    };
+    if control.send(hello).await.is_err() {
+        tracing::info!(connection_id, "Failed to send hello");
+        return None;
+    }
+
+    let (protocol_version, accepted_compression) =
+        match tokio::time::timeout(HELLO_TIMEOUT, control_recv.next()).await {
+            Ok(Some(Ok(ControlClientMessage::HelloAck {
+                protocol_version,
+                accepted_compression,
+            }))) => (protocol_version, accepted_compression),
+            Ok(Some(Ok(_))) => {
+                tracing::warn!(connection_id, "Expected HelloAck");
+                return None;
+            }
+            Ok(Some(Err(_)) | None) => {
+                tracing::info!(
+                    connection_id,
+                    "Control stream closed in handshake"
+                );
+                return None;
+            }
+            Err(_) => {
+                tracing::info!(connection_id, "Handshake timed out");
+                return None;
+            }
+        };
+    if !(MIN_SUPPORTED_VERSION..=PROTOCOL_VERSION).contains(&protocol_version) {
+        tracing::info!(
+            connection_id,
+            protocol_version,
+            "Unsupported protocol version"
+        );
+        let unsupported = ControlServerMessage::UnsupportedVersion {
+            min_supported: MIN_SUPPORTED_VERSION,
+            max_supported: PROTOCOL_VERSION,
+        };
+        let _ = control.send(unsupported).await;
+        return None;
+    }
+    Some(Capabilities {
+        protocol_version,
+        compression: accepted_compression
+            .iter()
+            .any(|name| name == COMPRESSION_ZSTD),
+        dictionary: offer_dictionary
+            && accepted_compression
+                .iter()
+                .any(|name| name == COMPRESSION_ZSTD_DICT),
+    })
+}
+
+/// Open a stream the server requested, see
+/// [`StreamManager::request_stream`].
+async fn open_stream(
+    transport: &impl StreamTransport,
+    user_id: i32,
+    connection_id: u64,
+) -> Result<(SendHalf, RecvHalf)> {
+    transport.open_bi().await.map_err(|e| {
+        tracing::warn!(user_id, connection_id, error = %e, "Stream open failed");
+        StreamManagerError::ConnectionClosed {
+            user_id,
+            reason: format!("stream open failed: {}", e),
+        }
+    })
+}
+
+/// Log why a connection closed, violations of the client as warnings.
+fn log_close(reason: &CloseReason, user_id: Option<i32>, connection_id: u64) {
+    let close_code = reason.close_frame().map(|(code, _)| u16::from(code));
+    if reason.is_violation() {
+        tracing::warn!(
+            user_id,
+            connection_id,
+            close_code,
+            "{}",
+            reason.log_message()
+        );
+    } else {
+        tracing::info!(
+            user_id,
+            connection_id,
+            close_code,
+            "{}",
+            reason.log_message()
+        );
+    }
+}
+
+/// Log the totals of a registered connection once it ended.
+fn log_session_ended(
+    user_id: i32,
+    connection_id: u64,
+    counters: &ConnectionCounters,
+) {
+    tracing::info!(
+        user_id,
+        connection_id,
+        duration_secs = counters.connected_at.elapsed().as_secs(),
+        streams = counters.streams(),
+        bytes_sent = counters.bytes_sent(),
+        bytes_received = counters.bytes_received(),
+        "WebTransport session ended"
+    );
+}
+
+/// Tell the client why the connection is closed, without waiting long.
+async fn send_shutdown(
+    control: &ControlStream,
+    code: CloseCode,
+    reason: &str,
+    until: tokio::time::Instant,
+) {
+    let message = ControlServerMessage::Shutdown {
+        code,
+        reason: reason.to_owned(),
+    };
+    let _ = tokio::time::timeout_at(until, control.send(message)).await;
+}
+
+/// Read exactly one length-prefixed [`StreamHeader`] frame from the stream,
+/// without consuming any data that follows it.
+async fn read_stream_header(
+    recv: &mut (impl AsyncRead + Unpin),
+) -> anyhow::Result<StreamHeader> {
+    let total_len = recv.read_u32().await? as usize;
+    if total_len > MAX_HEADER_FRAME {
+        return Err(anyhow::anyhow!(
+            "Header size {} exceeds maximum allowed size {}",
+            total_len,
+            MAX_HEADER_FRAME
+        ));
+    }
+
+    let mut frame = BytesMut::with_capacity(4 + total_len);
+    frame.put_u32(total_len as u32);
+    frame.resize(4 + total_len, 0);
+    recv.read_exact(&mut frame[4..]).await?;
+
+    CompressedCborDecoder::<StreamHeader, MAX_HEADER_FRAME>::new()
+        .decode(&mut frame)?
+        .ok_or_else(|| anyhow::anyhow!("Incomplete header frame"))
+}
+
+/// Ping state of a connection's heartbeat stream.
+struct Heartbeat {
+    started: tokio::time::Instant,
+    max_missed: u32,
+    missed: u32,
+    awaiting_pong: bool,
+    buf: [u8; HEARTBEAT_FRAME_LEN],
+    filled: usize,
+}
+
+impl Heartbeat {
+    fn new(max_missed: u32) -> Self {
+        Self {
+            started: tokio::time::Instant::now(),
+            max_missed: max_missed.max(1),
+            missed: 0,
+            awaiting_pong: false,
+            buf: [0; HEARTBEAT_FRAME_LEN],
+            filled: 0,
+        }
+    }
+
+    fn now_ms(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+
+    /// Called on every ping interval tick.
+    ///
+    /// Returns the ping frame to send, or `None` if too many consecutive
+    /// pings went unanswered and the connection should be closed.
+    fn next_ping(&mut self) -> Option<[u8; HEARTBEAT_FRAME_LEN]> {
+        if self.awaiting_pong {
+            self.missed += 1;
+            if self.missed >= self.max_missed {
+                return None;
+            }
+        }
+        self.awaiting_pong = true;
+
+        let mut frame = [0; HEARTBEAT_FRAME_LEN];
+        frame[0] = HEARTBEAT_PING;
+        frame[1..].copy_from_slice(&self.now_ms().to_be_bytes());
+        Some(frame)
+    }
+
+    /// Buffer space for the next read from the heartbeat stream.
+    fn unfilled(&mut self) -> &mut [u8] {
+        &mut self.buf[self.filled..]
+    }
+
+    /// Account for `n` bytes read into [`unfilled`](Self::unfilled).
+    ///
+    /// Returns the round-trip time once a complete echo has been received,
+    /// or an error if the client sent something that isn't an echoed ping.
+    fn on_read(&mut self, n: usize) -> std::result::Result<Option<u32>, ()> {
+        self.filled += n;
+        if self.filled < HEARTBEAT_FRAME_LEN {
+            return Ok(None);
+        }
+        self.filled = 0;
+
+        let [kind, sent_at @ ..] = self.buf;
+        let sent_at = u64::from_be_bytes(sent_at);
+        let now = self.now_ms();
+        if kind != HEARTBEAT_PING || sent_at > now {
+            return Err(());
+        }
+
+        self.awaiting_pong = false;
+        self.missed = 0;
+        Ok(Some((now - sent_at).min(u32::MAX as u64 - 1) as u32))
+    }
+}
+
+/// WebTransport connection endpoint.
+///
+/// Establishes a WebTransport/QUIC session for real-time bidirectional communication.
+/// This endpoint upgrades the HTTP/3 connection to a WebTransport session and maintains
+/// it until the client disconnects or is force-disconnected.
+///
+/// # Protocol
+///
+/// 1. Client initiates WebTransport connection via HTTP/3 CONNECT
+/// 2. Server opens a heartbeat stream for connection liveness detection and
+///    latency measurement; the client must echo every ping frame on it
+/// 3. Server opens the control stream (see the module docs)
+/// 4. Server and client agree on the protocol version and compression in a
+///    handshake on the control stream, see [`super::control`]
+/// 5. Without a valid access token, the server sends a [`BindChallenge`] and
+///    waits for the client to bind the connection (see the module docs)
+/// 6. Server registers the connection in [`StreamManager`]
+/// 7. Server-side components can send control messages via
+///    [`StreamManager::send_control`] and request streams via
+///    [`StreamManager::request_stream`], and the client may open streams of
+///    types with a registered incoming handler
+/// 8. Connection ends when client disconnects or heartbeat fails
+///
+/// # Single Connection Policy
+///
 /// Each user can have only one active WebTransport connection. Connecting from a new
-/// device or tab will automatically disconnect the previous connection.
-#[endpoint]
+/// device or tab will automatically disconnect the previous connection, which
+/// gets a `ConnectionReplaced` control message first. With
+/// `stream.allow_multiple_connections` only the oldest connection beyond
+/// `stream.max_connections_per_user` is replaced.
+///
+/// # Close Codes
+///
+/// Before the server closes a connection it sends a `Shutdown` control
+/// message with a `code` and a short `reason`; a WebSocket connection is
+/// closed with the same code:
+///
+/// - 4000 `Generic`: closed for another reason, reconnect
+/// - 4001 `AuthExpired`: the session's access ended or the session was
+///   logged out, refresh or log in again before reconnecting
+/// - 4002 `Replaced`: a newer connection of the user took over
+/// - 4003 `BindTimeout`: the connection wasn't bound in time, reconnect and
+///   bind
+/// - 4004 `ProtocolViolation`: the client broke the protocol, e.g. in the
+///   handshake
+/// - 4005 `ServerShutdown`: the server is shutting down, reconnect with
+///   backoff
+/// - 4006 `Banned`: the user was banned or the account locked
+#[endpoint(
+    security((), ("jwt" = [])),
+    responses(
+        (status_code = 200, description = "The WebTransport session ended"),
+        (status_code = 400, description = "Not a WebTransport session request"),
+    )
+)]
 pub async fn connect_stream(
     req: &mut Request,
     depot: &mut Depot,
-) -> AppResult<()> {
-    let user_id: i32 = depot.user_id();
+) -> std::result::Result<(), StatusError> {
+    let version = req.version();
+    let session = match req.web_transport_mut().await {
+        Ok(session) => &*session,
+        Err(e) => {
+            // E.g. a proxy forwarding CONNECT over HTTP/2
+            tracing::info!(?version, error = %e, "Not a WebTransport request");
+            return Err(StatusError::bad_request()
+                .brief("Expected a WebTransport session request"));
+        }
+    };
+    let authenticated = depot
+        .try_user_id()
+        .map(|_| ConnectionSession::from(depot.session()));
+    let counters = Arc::new(ConnectionCounters::new());
+    let session = WebTransport::new(session);
+    let transport = CountedTransport::new(&session, Arc::clone(&counters));
+    run_connection_handler(
+        StreamManager::global(),
+        &transport,
+        authenticated,
+        counters,
+    )
+    .await;
+    Ok(())
+}
+
+/// WebSocket connection endpoint, the fallback for [`connect_stream`].
+///
+/// Upgrades the request to a WebSocket that carries the streams of the
+/// connection as described in [`super::websocket`]. From then on the
+/// protocol is the same as on WebTransport: the connection is authenticated
+/// with the access token cookie or bound later, registered in
+/// [`StreamManager`] and replaced by newer connections alike.
+///
+/// Answers 404 while the `websocket_fallback` feature flag is off.
+#[endpoint(
+    security((), ("jwt" = [])),
+    responses(
+        (status_code = 101, description = "Switched to the WebSocket protocol"),
+        (status_code = 404, description = "The websocket_fallback feature flag is off"),
+    )
+)]
+pub async fn connect_websocket(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> std::result::Result<(), StatusError> {
+    let authenticated = depot
+        .try_user_id()
+        .map(|_| ConnectionSession::from(depot.session()));
+    WebSocketUpgrade::new()
+        .max_message_size(MAX_MESSAGE)
+        .max_frame_size(MAX_MESSAGE)
+        .upgrade(req, res, move |socket| async move {
+            let counters = Arc::new(ConnectionCounters::new());
+            let socket = WebSocketTransport::new(socket);
+            let transport =
+                CountedTransport::new(&socket, Arc::clone(&counters));
+            run_connection_handler(
+                StreamManager::global(),
+                &transport,
+                authenticated,
+                counters,
+            )
+            .await;
+        })
+        .await
+}
 
-    let session = req.web_transport_mut().await.unwrap();
-    let session_id = session.session_id();
+/// The streams of a connection that completed the handshake.
+struct OpenedConnection {
+    connection_id: u64,
+    capabilities: Capabilities,
+    heartbeat: (SendHalf, RecvHalf),
+    control: Arc<ControlStream>,
+    control_recv: Receiver<ControlClientMessage>,
+}
 
+/// Open the heartbeat and control streams of a new connection and do the
+/// [`handshake`].
+///
+/// `None` if the client is gone or failed the handshake.
+async fn open_connection(
+    manager: &StreamManager,
+    transport: &impl StreamTransport,
+) -> Option<OpenedConnection> {
     // Open a heartbeat stream - reading from it detects connection closure
-    let mut heartbeat_recv: WtRecv =
-        BidiStream::split(session.open_bi(session_id).await?).1;
-
-    // Register this connection (replaces any existing connection for this user)
-    let manager = StreamManager::global();
-    let (cmd_tx, mut cmd_rx) = mpsc::channel::<ConnectionCommand>(16);
-    let connection_id = manager.register(user_id, cmd_tx);
-
-    tracing::info!(user_id, connection_id, "WebTransport session started");
-
-    // Main event loop
-    let mut heartbeat_buf = [0u8; 1];
-    loop {
-        tokio::select! {
-            // Detect connection closure via heartbeat stream
-            result = heartbeat_recv.read(&mut heartbeat_buf) => {
-                match result {
-                    Ok(0) | Err(_) => {
-                        tracing::info!(user_id, connection_id, "Connection closed");
-                        break;
+    let heartbeat = match transport.open_bi().await {
+        Ok(heartbeat) => heartbeat,
+        Err(e) => {
+            tracing::info!(error = %e, "Failed to open heartbeat stream");
+            return None;
+        }
+    };
+    let (control_send, mut control_recv) =
+        match open_control_stream(transport).await {
+            Ok(control) => control,
+            Err(e) => {
+                tracing::info!(error = %e, "Failed to open control stream");
+                return None;
+            }
+        };
+    let control = Arc::new(ControlStream::new(control_send));
+
+    let connection_id = manager.next_connection_id();
+    let Some(capabilities) = handshake(
+        &control,
+        &mut control_recv,
+        connection_id,
+        manager.dictionary.is_some(),
+    )
+    .await
+    else {
+        transport.close(CloseCode::ProtocolViolation, "Handshake failed");
+        return None;
+    };
+    control.set_compression(capabilities.compression).await;
+    Some(OpenedConnection {
+        connection_id,
+        capabilities,
+        heartbeat,
+        control,
+        control_recv,
+    })
+}
+
+/// Serve a new connection until it closes, see [`connect_stream`] for the
+/// protocol.
+///
+/// `authenticated` is the session the connection was authenticated with, a
+/// connection without one stays pending until it is bound. `counters` are
+/// only read, to be counted the transport has to be a [`CountedTransport`].
+pub(super) async fn run_connection_handler<T: StreamTransport>(
+    manager: &'static StreamManager,
+    transport: &T,
+    authenticated: Option<ConnectionSession>,
+    counters: Arc<ConnectionCounters>,
+) {
+    let Some(OpenedConnection {
+        connection_id,
+        capabilities,
+        heartbeat: (heartbeat_send, heartbeat_recv),
+        control,
+        control_recv,
+    }) = open_connection(manager, transport).await
+    else {
+        return;
+    };
+    let heartbeat_config = &crate::config::get().heartbeat;
+    let mut handler = ConnectionHandler {
+        manager,
+        transport,
+        connection_id,
+        capabilities,
+        control,
+        control_recv,
+        heartbeat_send,
+        heartbeat_recv,
+        heartbeat: Heartbeat::new(heartbeat_config.max_missed),
+        counters,
+        violations: Arc::new(AtomicU32::new(0)),
+        user_id: None,
+        cmd_rx: None,
+        deadline_rx: None,
+        bind_rx: None,
+    };
+    let state = match authenticated {
+        Some(conn_session) => {
+            let auth_deadline = instant_at(conn_session.valid_until);
+            handler.register(conn_session).await;
+            State::bound(auth_deadline)
+        }
+        None => {
+            let Some((bind_rx, bind_deadline)) =
+                manager.start_bind(connection_id, &handler.control).await
+            else {
+                return;
+            };
+            handler.bind_rx = Some(bind_rx);
+            State::Pending {
+                bind_deadline: bind_deadline.into(),
+            }
+        }
+    };
+    handler.run(state).await;
+}
+
+/// What the connection handler holds, the decisions are made by the
+/// [`State`] it drives.
+///
+/// Opening the connection, dispatching streams, presence and logging live
+/// outside of it, what is left is IO the state can't do itself. That is still
+/// more than the ~150 lines the state machine aimed for: `run` needs one
+/// `select!` arm per event source, over fields it borrows mutably, and keeps
+/// the `accept_bi` future across iterations since it isn't cancel-safe, so
+/// splitting it further would only move the arms around.
+struct ConnectionHandler<'a, T> {
+    manager: &'static StreamManager,
+    transport: &'a T,
+    connection_id: u64,
+    capabilities: Capabilities,
+    control: Arc<ControlStream>,
+    control_recv: Receiver<ControlClientMessage>,
+    heartbeat_send: SendHalf,
+    heartbeat_recv: RecvHalf,
+    heartbeat: Heartbeat,
+    counters: Arc<ConnectionCounters>,
+    /// Invalid client-initiated streams
+    violations: Arc<AtomicU32>,
+    /// Set once the connection is registered
+    user_id: Option<i32>,
+    cmd_rx: Option<mpsc::Receiver<ConnectionCommand>>,
+    deadline_rx: Option<watch::Receiver<tokio::time::Instant>>,
+    /// Set while the connection waits to be bound
+    bind_rx: Option<oneshot::Receiver<BindOutcome>>,
+}
+
+impl<T: StreamTransport> ConnectionHandler<'_, T> {
+    /// Feed events to `state` and carry out its actions until it drains.
+    async fn run(mut self, mut state: State) {
+        let heartbeat_config = &crate::config::get().heartbeat;
+        let mut ping_interval = tokio::time::interval(Duration::from_secs(
+            heartbeat_config.interval_secs.max(1),
+        ));
+        ping_interval
+            .set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        // accept_bi is not cancel-safe, so keep the same future across
+        // iterations
+        let transport = self.transport;
+        let mut accept_bi = Box::pin(transport.accept_bi());
+        let connection_id = self.connection_id;
+
+        // The control stream ends with a decode error, so the frame is
+        // counted after the event
+        let mut invalid_control_frame = None;
+        while let Some((deadline, timer)) = state.deadline() {
+            let event = tokio::select! {
+                _ = tokio::time::sleep_until(deadline) => Event::Timer(timer),
+                bound = recv_once(&mut self.bind_rx), if state.is_pending() => match bound {
+                    Ok(Ok(session)) => Event::Bound {
+                        auth_deadline: instant_at(session.valid_until),
+                        session,
+                    },
+                    Ok(Err((code, reason))) => {
+                        Event::Command(ConnectionCommand::Close { code, reason })
+                    }
+                    Err(_) => Event::BindAborted,
+                },
+                msg = self.control_recv.next(), if state.is_bound() => match msg {
+                    Some(Ok(message)) => Event::ControlFrame(message),
+                    Some(Err(err)) if err.is::<InvalidFrame>() => {
+                        invalid_control_frame = Some(err);
+                        Event::ControlClosed {
+                            generation: self.control.generation.load(Ordering::Relaxed),
+                        }
                     }
-                    Ok(_) => {
-                        tracing::warn!(user_id, connection_id, "Protocol violation on heartbeat");
-                        break;
+                    Some(Err(_)) | None => Event::ControlClosed {
+                        generation: self.control.generation.load(Ordering::Relaxed),
+                    },
+                },
+                Some(deadline) = deadline_changed(&mut self.deadline_rx) => Event::DeadlineExtended(deadline),
+                result = self.heartbeat_recv.read(self.heartbeat.unfilled()) => match result {
+                    Ok(0) | Err(_) => Event::HeartbeatClosed,
+                    Ok(n) => Event::HeartbeatData(n),
+                },
+                _ = ping_interval.tick(), if state.is_bound() => match self.heartbeat.next_ping() {
+                    None => Event::HeartbeatTimedOut,
+                    Some(frame) => match self.heartbeat_send.write_all(&frame).await {
+                        Ok(()) => continue,
+                        Err(e) => {
+                            tracing::debug!(user_id = self.user_id, connection_id, error = %e, "Heartbeat write error");
+                            Event::HeartbeatFailed
+                        }
+                    },
+                },
+                accepted = &mut accept_bi, if state.is_bound() => {
+                    accept_bi = Box::pin(transport.accept_bi());
+                    match accepted {
+                        Ok(Some((send, recv))) => Event::StreamAccepted(send, recv),
+                        Ok(None) | Err(_) => Event::AcceptClosed,
                     }
+                },
+                cmd = recv_or_pending(&mut self.cmd_rx) => match cmd {
+                    Some(command) => Event::Command(command),
+                    None => Event::CommandsClosed,
+                },
+            };
+
+            if let Some(err) = invalid_control_frame.take()
+                && let Some(user_id) = self.user_id
+            {
+                self.manager.count_violation(
+                    &self.violations,
+                    user_id,
+                    connection_id,
+                    &format!("invalid control frame: {err}"),
+                );
+            }
+
+            // Actions may fail with follow-up events
+            let mut events = vec![event];
+            while let Some(event) = events.pop() {
+                let actions;
+                (state, actions) =
+                    state.on_event(event, tokio::time::Instant::now());
+                for action in actions {
+                    self.execute(action, &mut events).await;
                 }
             }
+        }
+        let State::Draining { reason, until } = state else {
+            return;
+        };
+        log_close(&reason, self.user_id, connection_id);
+        if let Some((code, message)) = reason.close_frame() {
+            send_shutdown(&self.control, code, message, until).await;
+            self.transport.close(code, message);
+        }
+        if let Some(user_id) = self.user_id {
+            log_session_ended(user_id, connection_id, &self.counters);
+        }
+    }
 
-            // Handle stream requests
-            cmd = cmd_rx.recv() => {
-                match cmd {
-                    Some(ConnectionCommand::OpenBidiStream { response }) => {
-                        let result = match session.open_bi(session_id).await {
-                            Ok(stream) => {
-                                let (send, recv): (WtSend, WtRecv) = BidiStream::split(stream);
-                                Ok((send, recv))
-                            }
-                            Err(e) => {
-                                tracing::warn!(user_id, connection_id, error = %e, "Stream open failed");
-                                Err(StreamManagerError::ConnectionClosed {
-                                    user_id,
-                                    reason: format!("stream open failed: {}", e),
-                                })
-                            }
-                        };
-                        let _ = response.send(result);
-                    }
-                    None => {
-                        tracing::info!(user_id, connection_id, "Channel closed");
-                        break;
-                    }
+    async fn execute(&mut self, action: Action, events: &mut Vec<Event>) {
+        let (manager, connection_id) = (self.manager, self.connection_id);
+        match action {
+            Action::Register(conn_session) => self.register(conn_session).await,
+            Action::Unregister => {
+                if let Some(user_id) = self.user_id {
+                    manager.unregister(user_id, Some(connection_id));
+                }
+            }
+            Action::ExpireBind => manager.expire_bind(connection_id),
+            Action::ForgetBind => manager.forget_bind(connection_id),
+            Action::ReadHeartbeat(n) => match self.heartbeat.on_read(n) {
+                Ok(Some(rtt)) => self.counters.set_latency_ms(rtt),
+                Ok(None) => {}
+                Err(()) => events.push(Event::HeartbeatViolation),
+            },
+            Action::SendControl(message) => self.send_control(message),
+            Action::ReopenControl { generation } => {
+                if !self.reopen_control(generation).await {
+                    events.push(Event::ControlReopenFailed);
+                }
+            }
+            Action::RunCommand(ConnectionCommand::OpenBidiStream {
+                response,
+            }) => {
+                let user_id = self.user_id.unwrap_or_default();
+                let opened =
+                    open_stream(self.transport, user_id, connection_id).await;
+                let _ = response.send(opened);
+            }
+            Action::RunCommand(ConnectionCommand::ReopenControl {
+                generation,
+                response,
+            }) => {
+                let reopened = self.reopen_control(generation).await;
+                let _ = response.send(reopened);
+                if !reopened {
+                    events.push(Event::ControlReopenFailed);
+                }
+            }
+            // The state closes the connection
+            Action::RunCommand(ConnectionCommand::Close { .. }) => {}
+            Action::SubscribePresence(user_ids) => {
+                let Some(user_id) = self.user_id else { return };
+                let users = manager.presence_watchers.subscribe(
+                    user_id,
+                    connection_id,
+                    user_ids,
+                );
+                self.send_control(ControlServerMessage::Presence { users });
+            }
+            Action::UnsubscribePresence(user_ids) => {
+                if let Some(user_id) = self.user_id {
+                    manager.presence_watchers.unsubscribe(
+                        user_id,
+                        connection_id,
+                        user_ids,
+                    );
                 }
             }
+            Action::DispatchIncoming(send, recv) => {
+                let Some(user_id) = self.user_id else { return };
+                manager.spawn_dispatch(
+                    user_id,
+                    connection_id,
+                    (send, recv),
+                    self.capabilities,
+                    Arc::clone(&self.violations),
+                );
+            }
         }
     }
 
-    manager.unregister(user_id, Some(connection_id));
-    tracing::info!(user_id, connection_id, "WebTransport session ended");
-    Ok(())
+    /// Register the bound connection, replacing any existing connection of
+    /// the user beyond the allowed ones.
+    async fn register(&mut self, conn_session: ConnectionSession) {
+        let (cmd_tx, cmd_rx) = mpsc::channel::<ConnectionCommand>(16);
+        let deadline_rx = self
+            .manager
+            .register(
+                &conn_session,
+                self.connection_id,
+                cmd_tx,
+                Arc::clone(&self.control),
+                Arc::clone(&self.counters),
+                self.capabilities,
+            )
+            .await;
+        self.user_id = Some(conn_session.user_id);
+        self.cmd_rx = Some(cmd_rx);
+        self.deadline_rx = Some(deadline_rx);
+        tracing::info!(
+            user_id = conn_session.user_id,
+            connection_id = self.connection_id,
+            "WebTransport session started"
+        );
+    }
+
+    /// Send on the control stream in the background.
+    fn send_control(&self, message: ControlServerMessage) {
+        let control = Arc::clone(&self.control);
+        tokio::spawn(async move {
+            let _ = control.send(message).await;
+        });
+    }
+
+    /// Reopen the control stream if it is still at `generation`, returns
+    /// whether a working control stream is available.
+    async fn reopen_control(&mut self, generation: u64) -> bool {
+        let (user_id, connection_id) = (self.user_id, self.connection_id);
+        match self.control.reopen(self.transport, generation).await {
+            Ok(Some(recv)) => {
+                tracing::info!(
+                    user_id,
+                    connection_id,
+                    "Reopened control stream"
+                );
+                self.control_recv = recv;
+                true
+            }
+            Ok(None) => true,
+            Err(e) => {
+                tracing::debug!(user_id, connection_id, error = %e, "Control stream reopen error");
+                false
+            }
+        }
+    }
+}
+
+/// The value of `rx`, which is taken once it completed.
+async fn recv_once<T>(
+    rx: &mut Option<oneshot::Receiver<T>>,
+) -> std::result::Result<T, oneshot::error::RecvError> {
+    let Some(receiver) = rx else {
+        return std::future::pending().await;
+    };
+    let result = receiver.await;
+    *rx = None;
+    result
+}
+
+/// The next message of `rx`, never completes without a receiver.
+async fn recv_or_pending<T>(rx: &mut Option<mpsc::Receiver<T>>) -> Option<T> {
+    match rx {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The new deadline once it changed, `None` once its sender is gone.
+async fn deadline_changed(
+    rx: &mut Option<watch::Receiver<tokio::time::Instant>>,
+) -> Option<tokio::time::Instant> {
+    let rx = rx.as_mut()?;
+    rx.changed().await.ok()?;
+    Some(*rx.borrow_and_update())
 }
 
 impl Default for StreamManager {
     fn default() -> Self {
-        Self::new()
+        Self::new(&StreamConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+    use super::super::transport::testing::{FakeClient, wait_until};
+    use super::*;
+
+    /// A manager of its own, so tests don't see each other's connections.
+    fn test_manager() -> &'static StreamManager {
+        Box::leak(Box::default())
+    }
+
+    /// A session of `user_id` that was just refreshed.
+    fn session_of(user_id: i32) -> Session {
+        let now = chrono::Utc::now().naive_utc();
+        Session {
+            id: user_id,
+            user_id,
+            token_hash: crate::auth::session_token::SessionToken::generate()
+                .to_hash(),
+            device_id: "device".into(),
+            device_name: None,
+            ip_address: None,
+            created_at: now,
+            refreshed_at: now,
+            last_used_at: now,
+            last_authenticated_at: now,
+            device_name_auto: None,
+            prev_token_hash: None,
+        }
+    }
+
+    /// Read the bind challenge of a pending connection, returns the
+    /// connection id and the nonce.
+    async fn challenge(client: &mut FakeClient) -> (u64, Vec<u8>) {
+        let challenge = client.recv_control().await;
+        assert_eq!(challenge["type"], "BindChallenge", "{challenge}");
+        let nonce = challenge["data"]["challengeNonce"].as_str().unwrap();
+        (
+            challenge["data"]["connectionId"].as_u64().unwrap(),
+            base64url.decode(nonce).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn typed_streams_round_trip_over_a_duplex_transport() {
+        let manager = test_manager();
+        let session = ConnectionSession::for_user(1);
+        let mut client = FakeClient::connect(manager, Some(session)).await;
+
+        let (server, (stream_type, mut client_send, mut client_recv)) = tokio::join!(
+            manager.request_stream::<Value, Value>(1, StreamType::Game),
+            client.accept_stream(),
+        );
+        let (mut send, mut recv) = server.unwrap();
+        assert_eq!(stream_type, "Game");
+        send.send(json!({ "tick": 1 })).await.unwrap();
+        assert_eq!(
+            client_recv.next().await.unwrap().unwrap(),
+            json!({ "tick": 1 })
+        );
+        client_send.send(json!([1, "up"])).await.unwrap();
+        assert_eq!(recv.next().await.unwrap().unwrap(), json!([1, "up"]));
+
+        manager.close_stream_with(1, CloseCode::Banned, "Banned");
+        assert_eq!(client.recv_control().await["data"]["code"], 4006);
+        assert_eq!(client.closed().await, Some(CloseCode::Banned));
+        assert!(!manager.is_connected(1));
+    }
+
+    #[tokio::test]
+    async fn streams_use_the_dictionary_the_client_accepted() {
+        let state = |tick| json!({ "tick": tick, "ball": [0.5, 0.5], "paddles": [0.5, 0.5] });
+        let mut dictionary = Vec::new();
+        ciborium::into_writer(&state(1), &mut dictionary).unwrap();
+        let dictionary: Arc<[u8]> = dictionary.into();
+        let manager: &'static StreamManager =
+            Box::leak(Box::new(StreamManager::new(&StreamConfig {
+                dictionary: Some(Arc::clone(&dictionary)),
+                ..StreamConfig::default()
+            })));
+
+        let mut client = FakeClient::connect_accepting(
+            manager,
+            Some(ConnectionSession::for_user(5)),
+            &["zstd", COMPRESSION_ZSTD_DICT],
+        )
+        .await;
+        let (server, (_, client_send, client_recv)) = tokio::join!(
+            manager.request_stream::<Value, Value>(5, StreamType::Game),
+            client.accept_stream(),
+        );
+        let (mut send, mut recv) = server.unwrap();
+        let mut client_recv = client_recv.map_decoder(|_| {
+            CompressedCborDecoder::<Value>::with_dictionary(Arc::clone(
+                &dictionary,
+            ))
+        });
+        let mut client_send = client_send.map_encoder(|_| {
+            CompressedCborEncoder::<Value>::with_dictionary(Arc::clone(
+                &dictionary,
+            ))
+        });
+        send.send(state(2)).await.unwrap();
+        assert_eq!(client_recv.next().await.unwrap().unwrap(), state(2));
+        client_send.send(state(2)).await.unwrap();
+        assert_eq!(recv.next().await.unwrap().unwrap(), state(2));
+
+        // Without accepting it, frames are readable without the dictionary
+        let mut client =
+            FakeClient::connect(manager, Some(ConnectionSession::for_user(6)))
+                .await;
+        let (server, (_, _, mut client_recv)) = tokio::join!(
+            manager.request_stream::<Value, Value>(6, StreamType::Game),
+            client.accept_stream(),
+        );
+        let (mut send, _) = server.unwrap();
+        send.send(state(2)).await.unwrap();
+        assert_eq!(client_recv.next().await.unwrap().unwrap(), state(2));
+    }
+
+    #[tokio::test]
+    async fn closing_the_heartbeat_disconnects() {
+        let manager = test_manager();
+        let session = ConnectionSession::for_user(2);
+        let mut client = FakeClient::connect(manager, Some(session)).await;
+
+        client.heartbeat.shutdown().await.unwrap();
+        assert_eq!(client.closed().await, None);
+        assert!(!manager.is_connected(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn heartbeat_measures_the_round_trip_time() {
+        let mut heartbeat = Heartbeat::new(2);
+        tokio::time::advance(Duration::from_millis(1000)).await;
+        let ping = heartbeat.next_ping().unwrap();
+        assert_eq!(ping[0], HEARTBEAT_PING);
+        assert_eq!(ping[1..], 1000u64.to_be_bytes());
+
+        // The echo may arrive in pieces
+        tokio::time::advance(Duration::from_millis(42)).await;
+        heartbeat.unfilled()[..4].copy_from_slice(&ping[..4]);
+        assert_eq!(heartbeat.on_read(4), Ok(None));
+        heartbeat.unfilled().copy_from_slice(&ping[4..]);
+        assert_eq!(heartbeat.on_read(5), Ok(Some(42)));
+
+        // An answered ping doesn't count as missed
+        assert!(heartbeat.next_ping().is_some());
+        assert!(heartbeat.next_ping().is_some());
+        assert!(heartbeat.next_ping().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn heartbeat_rejects_what_isnt_an_echo() {
+        let mut heartbeat = Heartbeat::new(2);
+        let ping = heartbeat.next_ping().unwrap();
+
+        let mut pong = ping;
+        pong[0] = 0x02;
+        heartbeat.unfilled().copy_from_slice(&pong);
+        assert_eq!(heartbeat.on_read(HEARTBEAT_FRAME_LEN), Err(()));
+
+        // A timestamp from the future
+        let mut future = ping;
+        future[1..].copy_from_slice(&1000u64.to_be_bytes());
+        heartbeat.unfilled().copy_from_slice(&future);
+        assert_eq!(heartbeat.on_read(HEARTBEAT_FRAME_LEN), Err(()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn two_missed_pings_close_the_connection() {
+        let manager = test_manager();
+        let session = ConnectionSession::for_user(7);
+        let mut client = FakeClient::connect(manager, Some(session)).await;
+        assert_eq!(manager.latency_ms(7), None);
+
+        // The first ping comes right away, echo it late
+        let mut ping = [0; HEARTBEAT_FRAME_LEN];
+        client.heartbeat.read_exact(&mut ping).await.unwrap();
+        tokio::time::advance(Duration::from_millis(30)).await;
+        client.heartbeat.write_all(&ping).await.unwrap();
+        wait_until(|| manager.latency_ms(7).is_some()).await;
+        // Plus the steps of the paused clock until the handler read it
+        let latency = manager.latency_ms(7).unwrap();
+        assert!((30..35).contains(&latency), "latency {latency}");
+
+        // Miss the next two, the third tick closes the connection
+        for interval in [20, 40] {
+            client.heartbeat.read_exact(&mut ping).await.unwrap();
+            assert!(manager.is_connected(7));
+            assert!(
+                u64::from_be_bytes(ping[1..].try_into().unwrap())
+                    >= interval * 1000
+            );
+        }
+        tokio::time::advance(Duration::from_secs(20)).await;
+        wait_until(|| !manager.is_connected(7)).await;
+        let shutdown = loop {
+            let message = client.recv_control().await;
+            if message["type"] == "Shutdown" {
+                break message;
+            }
+        };
+        assert_eq!(shutdown["data"]["reason"], "Heartbeat timed out");
+        assert_eq!(client.closed().await, Some(CloseCode::Generic));
+    }
+
+    #[tokio::test]
+    async fn control_messages_reach_the_latest_connection() {
+        let manager = test_manager();
+        assert!(matches!(
+            manager.send_control(8, ControlServerMessage::Pong).await,
+            Err(StreamManagerError::UserNotConnected { user_id: 8 })
+        ));
+
+        let session = ConnectionSession::for_user(8);
+        let mut client = FakeClient::connect(manager, Some(session)).await;
+        manager
+            .send_control(8, ControlServerMessage::Pong)
+            .await
+            .unwrap();
+        assert_eq!(client.recv_control().await, json!({ "type": "Pong" }));
+
+        // A failed control stream is replaced
+        client.reopen_control().await;
+        let message = ControlServerMessage::Notification(
+            Notification::RecoveryCodesLow { remaining: 2 },
+        );
+        manager.send_control(8, message).await.unwrap();
+        let message = client.recv_control().await;
+        assert_eq!(message["data"]["type"], "RecoveryCodesLow");
+        assert_eq!(message["data"]["data"]["remaining"], 2);
+        assert!(manager.is_connected(8));
+    }
+
+    #[tokio::test]
+    async fn client_streams_go_to_their_handler() {
+        let manager = test_manager();
+        let (streams, mut accepted) = tokio::sync::mpsc::unbounded_channel();
+        manager.set_incoming_handler(
+            StreamType::Game,
+            move |user_id, stream| {
+                let _ = streams.send((user_id, stream));
+            },
+        );
+        let session = ConnectionSession::for_user(3);
+        let mut client = FakeClient::connect(manager, Some(session)).await;
+
+        let (mut client_send, mut client_recv) =
+            client.open_stream(json!({ "streamType": "Game" })).await;
+        let (user_id, stream) = accepted.recv().await.unwrap();
+        assert_eq!(user_id, 3);
+        let (mut send, mut recv) = stream.into_typed::<Value, Value>();
+        client_send.send(json!("input")).await.unwrap();
+        assert_eq!(recv.next().await.unwrap().unwrap(), json!("input"));
+        send.send(json!("state")).await.unwrap();
+        assert_eq!(client_recv.next().await.unwrap().unwrap(), json!("state"));
+    }
+
+    #[tokio::test]
+    async fn invalid_client_streams_are_closed_and_counted() {
+        let manager = test_manager();
+        let session = ConnectionSession::for_user(4);
+        let mut client = FakeClient::connect(manager, Some(session)).await;
+
+        // No handler for chat streams, a header that isn't one, and garbage
+        let (_, mut unhandled) =
+            client.open_stream(json!({ "streamType": "Chat" })).await;
+        assert!(unhandled.next().await.is_none());
+        let (_, mut invalid) =
+            client.open_stream(json!({ "type": "Game" })).await;
+        assert!(invalid.next().await.is_none());
+        let mut garbage = client.open_raw_stream();
+        garbage.write_all(&[0, 0, 0, 2, 0xff, 0xff]).await.unwrap();
+        let mut buf = Vec::new();
+        garbage.read_to_end(&mut buf).await.unwrap();
+        assert!(buf.is_empty());
+        assert!(manager.is_connected(4));
+
+        for _ in 3..MAX_STREAM_VIOLATIONS {
+            client.open_stream(json!({ "streamType": "Chat" })).await;
+        }
+        wait_until(|| !manager.is_connected(4)).await;
+        let shutdown = loop {
+            let message = client.recv_control().await;
+            if message["type"] == "Shutdown" {
+                break message;
+            }
+        };
+        assert_eq!(
+            shutdown["data"]["code"],
+            CloseCode::ProtocolViolation as u16
+        );
+        assert_eq!(client.closed().await, Some(CloseCode::ProtocolViolation));
+    }
+
+    #[tokio::test]
+    async fn pending_connections_are_bound_once_with_their_nonce() {
+        let manager = test_manager();
+        let mut client = FakeClient::connect(manager, None).await;
+        let (connection_id, nonce) = challenge(&mut client).await;
+        assert!(!manager.is_connected(11));
+
+        let mut wrong = nonce.clone();
+        wrong[0] ^= 1;
+        assert!(matches!(
+            manager.bind(connection_id, &wrong, &session_of(11)),
+            Err(BindError::NonceMismatch)
+        ));
+        assert!(matches!(
+            manager.bind(connection_id + 1000, &nonce, &session_of(11)),
+            Err(BindError::UnknownConnection)
+        ));
+
+        manager
+            .bind(connection_id, &nonce, &session_of(11))
+            .unwrap();
+        wait_until(|| manager.is_connected(11)).await;
+        manager
+            .send_control(11, ControlServerMessage::Pong)
+            .await
+            .unwrap();
+        assert_eq!(client.recv_control().await, json!({ "type": "Pong" }));
+
+        // Replays are rejected, also for another user
+        assert!(matches!(
+            manager.bind(connection_id, &nonce, &session_of(11)),
+            Err(BindError::AlreadyBound)
+        ));
+        assert!(matches!(
+            manager.bind(connection_id, &nonce, &session_of(12)),
+            Err(BindError::AlreadyBound)
+        ));
+        assert!(!manager.is_connected(12));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connections_not_bound_in_time_are_closed() {
+        let manager = test_manager();
+        let mut client = FakeClient::connect(manager, None).await;
+        let (connection_id, nonce) = challenge(&mut client).await;
+
+        tokio::time::advance(BIND_DEADLINE).await;
+        assert_eq!(client.closed().await, Some(CloseCode::BindTimeout));
+        assert!(matches!(
+            manager.bind(connection_id, &nonce, &session_of(13)),
+            Err(BindError::Expired)
+        ));
+        assert!(!manager.is_connected(13));
+    }
+
+    #[tokio::test]
+    async fn closed_connections_get_a_generic_code_by_default() {
+        let manager = test_manager();
+        let session = ConnectionSession::for_user(30);
+        let mut client = FakeClient::connect(manager, Some(session)).await;
+        wait_until(|| manager.is_connected(30)).await;
+
+        manager.close_stream(30);
+        let shutdown = client.recv_control().await;
+        assert_eq!(shutdown["data"]["code"], CloseCode::Generic as u16);
+        assert_eq!(
+            shutdown["data"]["reason"],
+            "Connection closed by the server"
+        );
+        assert_eq!(client.closed().await, Some(CloseCode::Generic));
+    }
+
+    #[tokio::test]
+    async fn shutting_down_closes_bound_and_pending_connections() {
+        let manager = test_manager();
+        let session = ConnectionSession::for_user(31);
+        let mut bound = FakeClient::connect(manager, Some(session)).await;
+        wait_until(|| manager.is_connected(31)).await;
+        let mut pending = FakeClient::connect(manager, None).await;
+        let (connection_id, nonce) = challenge(&mut pending).await;
+
+        manager.close_all(CloseCode::ServerShutdown, "Server shutting down");
+        let shutdown = bound.recv_control().await;
+        assert_eq!(shutdown["data"]["code"], CloseCode::ServerShutdown as u16);
+        assert_eq!(bound.closed().await, Some(CloseCode::ServerShutdown));
+        let shutdown = pending.recv_control().await;
+        assert_eq!(shutdown["data"]["reason"], "Server shutting down");
+        assert_eq!(pending.closed().await, Some(CloseCode::ServerShutdown));
+        assert!(matches!(
+            manager.bind(connection_id, &nonce, &session_of(32)),
+            Err(BindError::Expired)
+        ));
+        assert!(!manager.is_connected(31));
+    }
+
+    #[tokio::test]
+    async fn data_sent_before_binding_is_a_violation() {
+        let manager = test_manager();
+        let mut client = FakeClient::connect(manager, None).await;
+        challenge(&mut client).await;
+
+        client
+            .heartbeat
+            .write_all(&[HEARTBEAT_PING; 9])
+            .await
+            .unwrap();
+        let shutdown = client.recv_control().await;
+        assert_eq!(shutdown["data"]["reason"], "Data sent before bind");
+        assert_eq!(client.closed().await, Some(CloseCode::ProtocolViolation));
+    }
+
+    /// A session of `user_id` whose access ends in `seconds`.
+    fn expiring_in(user_id: i32, seconds: i64) -> ConnectionSession {
+        ConnectionSession {
+            user_id,
+            session_id: user_id,
+            valid_until: chrono::Utc::now().naive_utc()
+                + chrono::TimeDelta::seconds(seconds),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connections_close_when_the_access_expires() {
+        let manager = test_manager();
+        let mut client =
+            FakeClient::connect(manager, Some(expiring_in(14, 10))).await;
+
+        tokio::time::advance(Duration::from_secs(11)).await;
+        wait_until(|| !manager.is_connected(14)).await;
+        let shutdown = loop {
+            let message = client.recv_control().await;
+            if message["type"] == "Shutdown" {
+                break message;
+            }
+        };
+        assert_eq!(shutdown["data"]["code"], CloseCode::AuthExpired as u16);
+        assert_eq!(client.closed().await, Some(CloseCode::AuthExpired));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refreshed_sessions_keep_their_connections() {
+        let manager = test_manager();
+        let mut client =
+            FakeClient::connect(manager, Some(expiring_in(15, 10))).await;
+
+        let now = chrono::Utc::now().naive_utc();
+        manager.extend_deadline(15, 15, now + chrono::TimeDelta::seconds(20));
+        // Refreshing another session of the user doesn't count
+        manager.extend_deadline(15, 16, now + chrono::TimeDelta::hours(1));
+        tokio::time::advance(Duration::from_secs(15)).await;
+        manager
+            .send_control(15, ControlServerMessage::Pong)
+            .await
+            .unwrap();
+        assert_eq!(client.recv_control().await, json!({ "type": "Pong" }));
+        assert!(manager.is_connected(15));
+
+        // Until the extended deadline passes too
+        tokio::time::advance(Duration::from_secs(6)).await;
+        wait_until(|| !manager.is_connected(15)).await;
+        assert_eq!(client.closed().await, Some(CloseCode::AuthExpired));
+    }
+
+    #[tokio::test]
+    async fn replaced_connections_are_told_before_they_close() {
+        let manager = test_manager();
+        let session = || Some(ConnectionSession::for_user(21));
+        let mut old = FakeClient::connect(manager, session()).await;
+        let mut new = FakeClient::connect(manager, session()).await;
+
+        let notice = old.recv_control().await;
+        assert_eq!(notice["type"], "ConnectionReplaced", "{notice}");
+        let replaced_by = notice["data"]["newConnectionId"].as_u64().unwrap();
+        assert_eq!(manager.connection_info(21).len(), 1);
+        assert_eq!(old.closed().await, Some(CloseCode::Replaced));
+
+        manager
+            .send_control(21, ControlServerMessage::Pong)
+            .await
+            .unwrap();
+        assert_eq!(new.recv_control().await, json!({ "type": "Pong" }));
+        assert_eq!(
+            manager.connections.get(&21).unwrap()[0].connection_id,
+            replaced_by
+        );
+    }
+
+    #[tokio::test]
+    async fn several_connections_get_notifications_and_the_latest_streams() {
+        let manager: &'static StreamManager =
+            Box::leak(Box::new(StreamManager::new(&StreamConfig {
+                allow_multiple_connections: true,
+                max_connections_per_user: 2,
+                ..StreamConfig::default()
+            })));
+        let session = || Some(ConnectionSession::for_user(22));
+        let mut oldest = FakeClient::connect(manager, session()).await;
+        let mut older = FakeClient::connect(manager, session()).await;
+        wait_until(|| manager.connection_info(22).len() == 2).await;
+
+        // Beyond the limit only the oldest one is replaced
+        let mut latest = FakeClient::connect(manager, session()).await;
+        assert_eq!(oldest.recv_control().await["type"], "ConnectionReplaced");
+        assert_eq!(oldest.closed().await, Some(CloseCode::Replaced));
+        assert_eq!(manager.connection_info(22).len(), 2);
+
+        manager.notify(22, Notification::RecoveryCodesLow { remaining: 1 });
+        for client in [&mut older, &mut latest] {
+            let message = client.recv_control().await;
+            assert_eq!(message["data"]["type"], "RecoveryCodesLow");
+        }
+
+        let (server, (stream_type, _, _)) = tokio::join!(
+            manager.request_stream::<Value, Value>(22, StreamType::Game),
+            latest.accept_stream(),
+        );
+        server.unwrap();
+        assert_eq!(stream_type, "Game");
+    }
+
+    #[tokio::test]
+    async fn notifications_to_all_reach_only_connected_users() {
+        let manager = test_manager();
+        let session = |user_id| Some(ConnectionSession::for_user(user_id));
+        let mut ann = FakeClient::connect(manager, session(40)).await;
+        let mut bob = FakeClient::connect(manager, session(41)).await;
+        wait_until(|| manager.connected_users() == 2).await;
+
+        let announcement = Notification::Announcement {
+            message: "Maintenance at noon".into(),
+            created_at: chrono::Utc::now().naive_utc(),
+        };
+        assert_eq!(manager.notify_all(announcement), 2);
+        for client in [&mut ann, &mut bob] {
+            let message = client.recv_control().await;
+            assert_eq!(message["data"]["type"], "Announcement");
+            assert_eq!(
+                message["data"]["data"]["message"],
+                "Maintenance at noon"
+            );
+        }
+
+        // Nothing was queued for users that were offline
+        let mut cid = FakeClient::connect(manager, session(42)).await;
+        wait_until(|| manager.is_connected(42)).await;
+        manager
+            .send_control(42, ControlServerMessage::Pong)
+            .await
+            .unwrap();
+        assert_eq!(cid.recv_control().await, json!({ "type": "Pong" }));
+    }
+
+    #[tokio::test]
+    async fn requests_that_are_not_webtransport_are_rejected() {
+        use salvo::test::{ResponseExt as _, TestClient};
+
+        // The route only lets CONNECT through, skip that filter
+        let service =
+            Service::new(Router::with_path("api/wt").get(connect_stream));
+        let mut res = TestClient::get("http://localhost/api/wt")
+            .send(&service)
+            .await;
+        assert_eq!(res.status_code, Some(StatusCode::BAD_REQUEST));
+        let body = res.take_string().await.unwrap();
+        assert!(body.contains("Expected a WebTransport session"), "{body}");
     }
 }
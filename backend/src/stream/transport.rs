@@ -0,0 +1,393 @@
+//! The connection a connection handler runs on.
+//!
+//! The handler only opens and accepts bidirectional streams, so it is generic
+//! over [`StreamTransport`] instead of talking to the WebTransport session
+//! directly. Stream halves are boxed, so [`Sender`](super::Sender) and
+//! [`Receiver`](super::Receiver) are the same types for every transport and
+//! a transport without QUIC (e.g. on [`tokio::io::duplex`]) can stand in for
+//! a client.
+
+use bytes::Bytes;
+use salvo::proto::quic::BidiStream;
+use salvo::webtransport::server::AcceptedBi;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::CloseCode;
+
+/// Send half of a raw, unframed bidirectional stream.
+pub type SendHalf = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Receive half of a raw, unframed bidirectional stream.
+pub type RecvHalf = Box<dyn AsyncRead + Send + Unpin>;
+
+/// A connection to a client that bidirectional streams can be opened on.
+///
+/// The connection is closed once its connection handler exits, streams
+/// opened on it error from then on.
+pub trait StreamTransport: Sync {
+    /// Open a new bidirectional stream.
+    fn open_bi(
+        &self,
+    ) -> impl Future<Output = anyhow::Result<(SendHalf, RecvHalf)>> + Send;
+
+    /// Wait for the next bidirectional stream opened by the client.
+    ///
+    /// Returns `None` once the connection is closed. Not cancel-safe, keep
+    /// the same future until it completes.
+    fn accept_bi(
+        &self,
+    ) -> impl Future<Output = anyhow::Result<Option<(SendHalf, RecvHalf)>>> + Send;
+
+    /// Close the connection with `code` and `reason` once the handler exits,
+    /// if the transport can tell the client, see [`super::close_codes`].
+    fn close(&self, code: CloseCode, reason: &str);
+}
+
+/// The WebTransport session of a connection.
+type WtSession = salvo::proto::WebTransportSession<h3_quinn::Connection, Bytes>;
+
+/// Send half of a WebTransport bidirectional stream.
+type WtSend =
+    salvo::webtransport::stream::SendStream<h3_quinn::SendStream<Bytes>, Bytes>;
+
+/// Receive half of a WebTransport bidirectional stream.
+type WtRecv =
+    salvo::webtransport::stream::RecvStream<h3_quinn::RecvStream, Bytes>;
+
+/// [`StreamTransport`] of a WebTransport session.
+pub struct WebTransport<'a> {
+    session: &'a WtSession,
+}
+
+impl<'a> WebTransport<'a> {
+    pub fn new(session: &'a WtSession) -> Self {
+        Self { session }
+    }
+}
+
+impl StreamTransport for WebTransport<'_> {
+    async fn open_bi(&self) -> anyhow::Result<(SendHalf, RecvHalf)> {
+        let stream = self.session.open_bi(self.session.session_id()).await?;
+        let (send, recv): (WtSend, WtRecv) = BidiStream::split(stream);
+        Ok((Box::new(send), Box::new(recv)))
+    }
+
+    async fn accept_bi(&self) -> anyhow::Result<Option<(SendHalf, RecvHalf)>> {
+        loop {
+            match self.session.accept_bi().await? {
+                Some(AcceptedBi::BidiStream(_, stream)) => {
+                    let (send, recv): (WtSend, WtRecv) =
+                        BidiStream::split(stream);
+                    return Ok(Some((Box::new(send), Box::new(recv))));
+                }
+                Some(AcceptedBi::Request(..)) => {
+                    tracing::warn!(
+                        session_id = ?self.session.session_id(),
+                        "Unexpected request on WebTransport session"
+                    );
+                }
+                None => return Ok(None),
+            }
+        }
+    }
+
+    fn close(&self, _code: CloseCode, _reason: &str) {
+        // The session can't be closed with an application code, the client
+        // got it in the shutdown message
+    }
+}
+
+/// An in-memory [`StreamTransport`] for tests, and the client on the other
+/// end of it.
+///
+/// Streams are [`tokio::io::duplex`] pipes, so a connection handler can run
+/// against a [`FakeClient`] without any network.
+#[cfg(test)]
+pub mod testing {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use futures::{SinkExt as _, StreamExt as _};
+    use serde::de::DeserializeOwned;
+    use serde_json::{Value, json};
+    use tokio::io::{DuplexStream, ReadHalf, WriteHalf};
+    use tokio::sync::mpsc;
+    use tokio::task::JoinHandle;
+    use tokio_util::codec::{FramedRead, FramedWrite};
+
+    use super::*;
+    use crate::stream::compress_cbor_codec::{
+        CompressedCborDecoder, CompressedCborEncoder,
+    };
+    pub use crate::stream::compress_cbor_codec::{
+        DEFAULT_MAX_FRAME, InvalidFrame,
+    };
+    use crate::stream::stats::{ConnectionCounters, CountedTransport};
+    pub(crate) use crate::stream::stream_manager::ConnectionSession;
+    use crate::stream::stream_manager::run_connection_handler;
+    use crate::stream::{Receiver, StreamManager};
+
+    /// Buffer of each direction of a stream
+    const PIPE_CAPACITY: usize = 64 * 1024;
+    /// How long the client waits for the server
+    pub const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Send half of a stream on the client side, for any message.
+    pub type ClientSender =
+        FramedWrite<WriteHalf<DuplexStream>, CompressedCborEncoder<Value>>;
+    /// Receive half of a stream on the client side, for any message.
+    pub type ClientReceiver =
+        FramedRead<ReadHalf<DuplexStream>, CompressedCborDecoder<Value>>;
+
+    /// The server side of a fake connection.
+    pub struct DuplexTransport {
+        /// Streams opened by the server, to the client
+        opened: mpsc::UnboundedSender<DuplexStream>,
+        /// Streams opened by the client
+        accepted: tokio::sync::Mutex<mpsc::UnboundedReceiver<DuplexStream>>,
+        closed: Arc<Mutex<Option<CloseCode>>>,
+    }
+
+    fn split(stream: DuplexStream) -> (SendHalf, RecvHalf) {
+        let (recv, send) = tokio::io::split(stream);
+        (Box::new(send), Box::new(recv))
+    }
+
+    impl StreamTransport for DuplexTransport {
+        async fn open_bi(&self) -> anyhow::Result<(SendHalf, RecvHalf)> {
+            let (server, client) = tokio::io::duplex(PIPE_CAPACITY);
+            self.opened
+                .send(client)
+                .map_err(|_| anyhow::anyhow!("the client is gone"))?;
+            Ok(split(server))
+        }
+
+        async fn accept_bi(
+            &self,
+        ) -> anyhow::Result<Option<(SendHalf, RecvHalf)>> {
+            Ok(self.accepted.lock().await.recv().await.map(split))
+        }
+
+        fn close(&self, code: CloseCode, _reason: &str) {
+            *self.closed.lock().unwrap() = Some(code);
+        }
+    }
+
+    /// A client connected to a connection handler over a [`DuplexTransport`].
+    pub struct FakeClient {
+        opened: mpsc::UnboundedReceiver<DuplexStream>,
+        /// Streams opened by the client, the connection closes without it
+        open: mpsc::UnboundedSender<DuplexStream>,
+        closed: Arc<Mutex<Option<CloseCode>>>,
+        handler: JoinHandle<()>,
+        /// The heartbeat stream, the server writes pings the client echoes
+        pub heartbeat: DuplexStream,
+        pub control_send: ClientSender,
+        pub control_recv: ClientReceiver,
+    }
+
+    impl FakeClient {
+        /// Run a connection handler of `manager` on a new connection and
+        /// accept its heartbeat and control streams.
+        ///
+        /// Without `session` the connection has to be bound.
+        pub async fn start(
+            manager: &'static StreamManager,
+            session: Option<ConnectionSession>,
+        ) -> Self {
+            crate::config::init_for_tests();
+            let (opened, opened_rx) = mpsc::unbounded_channel();
+            let (open, accepted) = mpsc::unbounded_channel();
+            let closed = Arc::default();
+            let transport = DuplexTransport {
+                opened,
+                accepted: tokio::sync::Mutex::new(accepted),
+                closed: Arc::clone(&closed),
+            };
+            let handler = tokio::spawn(async move {
+                let counters = Arc::new(ConnectionCounters::new());
+                let transport =
+                    CountedTransport::new(&transport, Arc::clone(&counters));
+                run_connection_handler(manager, &transport, session, counters)
+                    .await;
+            });
+            let mut opened = opened_rx;
+            let heartbeat = recv_stream(&mut opened).await;
+            let (control_recv, control_send) =
+                tokio::io::split(recv_stream(&mut opened).await);
+            let mut client = Self {
+                opened,
+                open,
+                closed,
+                handler,
+                heartbeat,
+                control_send: FramedWrite::new(
+                    control_send,
+                    CompressedCborEncoder::new(),
+                ),
+                control_recv: FramedRead::new(
+                    control_recv,
+                    CompressedCborDecoder::new(),
+                ),
+            };
+            assert_eq!(client.recv_control().await, "Control");
+            client
+        }
+
+        /// [`start`](Self::start) a connection and answer the handshake,
+        /// accepting zstd.
+        pub async fn connect(
+            manager: &'static StreamManager,
+            session: Option<ConnectionSession>,
+        ) -> Self {
+            Self::connect_accepting(manager, session, &["zstd"]).await
+        }
+
+        /// [`start`](Self::start) a connection and answer the handshake,
+        /// accepting `compression` of the offered one.
+        pub async fn connect_accepting(
+            manager: &'static StreamManager,
+            session: Option<ConnectionSession>,
+            compression: &[&str],
+        ) -> Self {
+            let user_id = session.as_ref().map(ConnectionSession::user_id);
+            let mut client = Self::start(manager, session).await;
+            let hello = client.recv_control().await;
+            assert_eq!(hello["type"], "Hello");
+            let offered = hello["data"]["compression"].as_array().unwrap();
+            let accepted: Vec<_> = compression
+                .iter()
+                .filter(|name| offered.iter().any(|offer| offer == *name))
+                .collect();
+            client
+                .send_control(json!({
+                    "type": "HelloAck",
+                    "data": {
+                        "protocolVersion": 2,
+                        "acceptedCompression": accepted,
+                    },
+                }))
+                .await;
+            if let Some(user_id) = user_id {
+                wait_until(|| manager.is_connected(user_id)).await;
+            }
+            client
+        }
+
+        /// The next message on the control stream.
+        pub async fn recv_control(&mut self) -> Value {
+            tokio::time::timeout(CLIENT_TIMEOUT, self.control_recv.next())
+                .await
+                .expect("the server should send on the control stream")
+                .expect("the control stream should be open")
+                .expect("the control frame should decode")
+        }
+
+        pub async fn send_control(&mut self, message: Value) {
+            self.control_send
+                .send(message)
+                .await
+                .expect("the control stream should be open");
+        }
+
+        /// Close the control stream and switch to the one the server opens
+        /// instead.
+        pub async fn reopen_control(&mut self) {
+            // Replacing the halves drops the old stream
+            let (closed, _) = tokio::io::duplex(1);
+            let (recv, send) = tokio::io::split(closed);
+            self.control_send =
+                FramedWrite::new(send, CompressedCborEncoder::new());
+            self.control_recv =
+                FramedRead::new(recv, CompressedCborDecoder::new());
+
+            let (recv, send) =
+                tokio::io::split(recv_stream(&mut self.opened).await);
+            self.control_send =
+                FramedWrite::new(send, CompressedCborEncoder::new());
+            self.control_recv =
+                FramedRead::new(recv, CompressedCborDecoder::new());
+            assert_eq!(self.recv_control().await, "Control");
+        }
+
+        /// Wait for the next stream the server opens, returns its type and
+        /// halves.
+        pub async fn accept_stream(
+            &mut self,
+        ) -> (Value, ClientSender, ClientReceiver) {
+            let (recv, send) =
+                tokio::io::split(recv_stream(&mut self.opened).await);
+            let mut recv = FramedRead::new(recv, CompressedCborDecoder::new());
+            let stream_type = tokio::time::timeout(CLIENT_TIMEOUT, recv.next())
+                .await
+                .expect("the server should send the stream type")
+                .expect("the stream should be open")
+                .expect("the stream type should decode");
+            let send = FramedWrite::new(send, CompressedCborEncoder::new());
+            (stream_type, send, recv)
+        }
+
+        /// Open a stream to the server and send `header` as its first
+        /// frame, the `StreamHeader` of client streams.
+        pub async fn open_stream(
+            &mut self,
+            header: Value,
+        ) -> (ClientSender, ClientReceiver) {
+            let (recv, send) = tokio::io::split(self.open_raw_stream());
+            let mut send = FramedWrite::new(send, CompressedCborEncoder::new());
+            send.send(header).await.expect("the header should be sent");
+            (send, FramedRead::new(recv, CompressedCborDecoder::new()))
+        }
+
+        /// Open a stream to the server without sending anything.
+        pub fn open_raw_stream(&mut self) -> DuplexStream {
+            let (server, client) = tokio::io::duplex(PIPE_CAPACITY);
+            self.open.send(server).expect("the handler should accept");
+            client
+        }
+
+        /// Wait for the handler to exit, returns the close code it closed
+        /// the transport with.
+        pub async fn closed(self) -> Option<CloseCode> {
+            tokio::time::timeout(CLIENT_TIMEOUT, self.handler)
+                .await
+                .expect("the handler should exit")
+                .expect("the handler should not panic");
+            *self.closed.lock().unwrap()
+        }
+    }
+
+    /// A stream the client only sends on, for a task reading typed
+    /// messages without a connection handler.
+    pub fn one_way_stream<R: DeserializeOwned>() -> (ClientSender, Receiver<R>)
+    {
+        let (server, client) = tokio::io::duplex(PIPE_CAPACITY);
+        let (_, send) = tokio::io::split(client);
+        let (_, recv) = split(server);
+        (
+            FramedWrite::new(send, CompressedCborEncoder::new()),
+            FramedRead::new(recv, CompressedCborDecoder::new()),
+        )
+    }
+
+    async fn recv_stream(
+        opened: &mut mpsc::UnboundedReceiver<DuplexStream>,
+    ) -> DuplexStream {
+        tokio::time::timeout(CLIENT_TIMEOUT, opened.recv())
+            .await
+            .expect("the server should open a stream")
+            .expect("the handler should run")
+    }
+
+    /// Wait until `condition` holds, for state that changes in the
+    /// background.
+    pub async fn wait_until(condition: impl Fn() -> bool) {
+        tokio::time::timeout(CLIENT_TIMEOUT, async {
+            while !condition() {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .expect("the condition should hold in time");
+    }
+}
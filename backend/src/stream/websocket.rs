@@ -0,0 +1,535 @@
+//! WebSocket fallback for clients that can't use WebTransport.
+//!
+//! Some networks and browsers block QUIC, so `GET /api/ws` speaks the same
+//! protocol over a WebSocket. [`WebSocketTransport`] multiplexes the
+//! bidirectional streams of [`StreamTransport`] on it, every binary message
+//! is one envelope:
+//!
+//! ```text
+//! ┌──────────────────────┬────────┬─────────────────────────┐
+//! │ 4 bytes              │ 1 byte │ rest                    │
+//! │ channel (u32 BE)     │ kind   │ payload                 │
+//! └──────────────────────┴────────┴─────────────────────────┘
+//! ```
+//!
+//! - [`OPEN`]: a new stream on the channel, empty payload
+//! - [`DATA`]: bytes of the stream, at most [`MAX_CHUNK`]
+//! - [`FIN`]: the sender closed its half of the stream
+//!
+//! The server opens even channels, the client odd ones. A channel carries
+//! the same bytes as a WebTransport stream, including the length-prefixed
+//! frames of the codec, so the connection handler and everything built on
+//! [`StreamManager`](super::StreamManager) work the same on both transports.
+//!
+//! There is no flow control per stream: once a stream has
+//! [`CHANNEL_BUFFER`] unread messages, reading the socket waits for it. The
+//! handler and the components owning streams read them continuously.
+//!
+//! The endpoint answers 404 until the [`WEBSOCKET_FALLBACK_FLAG`] feature
+//! flag is turned on, see [`crate::utils::flags`].
+
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+
+use bytes::{Buf, Bytes};
+use dashmap::DashMap;
+use futures::stream::SplitStream;
+use futures::{SinkExt as _, StreamExt as _};
+use salvo::websocket::{Message, WebSocket};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{Mutex, mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio_util::sync::PollSender;
+
+use super::CloseCode;
+use super::transport::{RecvHalf, SendHalf, StreamTransport};
+
+/// Feature flag of `GET /api/ws`.
+pub const WEBSOCKET_FALLBACK_FLAG: &str = "websocket_fallback";
+
+/// Kind of an envelope that opens a stream.
+pub const OPEN: u8 = 0x01;
+/// Kind of an envelope with stream data.
+pub const DATA: u8 = 0x02;
+/// Kind of an envelope that ends one direction of a stream.
+pub const FIN: u8 = 0x03;
+
+/// Channel id and kind.
+const ENVELOPE_HEADER_LEN: usize = 5;
+/// Largest payload of a [`DATA`] envelope, longer writes are split.
+pub const MAX_CHUNK: usize = 16 * 1024;
+/// Largest message accepted from the client.
+pub const MAX_MESSAGE: usize = ENVELOPE_HEADER_LEN + MAX_CHUNK;
+
+/// Messages buffered per stream and for the socket before senders wait.
+const CHANNEL_BUFFER: usize = 32;
+/// Client-opened streams the handler hasn't accepted yet.
+const ACCEPT_BUFFER: usize = 8;
+/// Streams open at the same time, the client opening more is a violation.
+const MAX_CHANNELS: usize = 256;
+/// How long queued messages may take to be sent once the connection ends.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+type Channels = DashMap<u32, mpsc::Sender<Bytes>, ahash::RandomState>;
+
+/// [`StreamTransport`] on an upgraded WebSocket connection.
+///
+/// The socket is closed when this is dropped, after the messages queued up
+/// to then are sent, with the code of [`close`](StreamTransport::close) if
+/// it was called.
+pub struct WebSocketTransport {
+    outbound: mpsc::Sender<Message>,
+    channels: Arc<Channels>,
+    next_channel: AtomicU32,
+    accepted: Mutex<mpsc::Receiver<(SendHalf, RecvHalf)>>,
+    reader: JoinHandle<()>,
+    close: Option<oneshot::Sender<()>>,
+}
+
+impl WebSocketTransport {
+    pub fn new(socket: WebSocket) -> Self {
+        let (sink, stream) = socket.split();
+        let (outbound, mut outbound_rx) = mpsc::channel(CHANNEL_BUFFER);
+        let (close, mut close_rx) = oneshot::channel();
+        tokio::spawn(async move {
+            let mut sink = sink;
+            loop {
+                tokio::select! {
+                    biased;
+                    message = outbound_rx.recv() => match message {
+                        Some(message) => {
+                            if sink.send(message).await.is_err() {
+                                return;
+                            }
+                        }
+                        None => break,
+                    },
+                    _ = &mut close_rx => break,
+                }
+            }
+            let drain = async {
+                while let Ok(message) = outbound_rx.try_recv() {
+                    sink.feed(message).await?;
+                }
+                sink.close().await
+            };
+            let _ = tokio::time::timeout(CLOSE_TIMEOUT, drain).await;
+        });
+
+        let channels = Arc::new(Channels::default());
+        let (accepted_tx, accepted) = mpsc::channel(ACCEPT_BUFFER);
+        let reader = tokio::spawn(read_messages(
+            stream,
+            Arc::clone(&channels),
+            accepted_tx,
+            outbound.clone(),
+        ));
+        Self {
+            outbound,
+            channels,
+            next_channel: AtomicU32::new(0),
+            accepted: Mutex::new(accepted),
+            reader,
+            close: Some(close),
+        }
+    }
+}
+
+impl Drop for WebSocketTransport {
+    fn drop(&mut self) {
+        self.reader.abort();
+        // Streams still held elsewhere error from now on
+        self.channels.clear();
+        if let Some(close) = self.close.take() {
+            let _ = close.send(());
+        }
+    }
+}
+
+impl StreamTransport for WebSocketTransport {
+    async fn open_bi(&self) -> anyhow::Result<(SendHalf, RecvHalf)> {
+        let channel = self.next_channel.fetch_add(2, Ordering::Relaxed);
+        self.outbound
+            .send(envelope(channel, OPEN, &[]))
+            .await
+            .map_err(|_| anyhow::anyhow!("WebSocket closed"))?;
+        Ok(open_channel(channel, &self.channels, &self.outbound))
+    }
+
+    async fn accept_bi(&self) -> anyhow::Result<Option<(SendHalf, RecvHalf)>> {
+        Ok(self.accepted.lock().await.recv().await)
+    }
+
+    fn close(&self, code: CloseCode, reason: &str) {
+        // Sent after the queued messages, the socket closes once dropped
+        let message = Message::close_with(code, reason.to_owned());
+        let _ = self.outbound.try_send(message);
+    }
+}
+
+fn envelope(channel: u32, kind: u8, payload: &[u8]) -> Message {
+    let mut message = Vec::with_capacity(ENVELOPE_HEADER_LEN + payload.len());
+    message.extend_from_slice(&channel.to_be_bytes());
+    message.push(kind);
+    message.extend_from_slice(payload);
+    Message::binary(message)
+}
+
+fn open_channel(
+    channel: u32,
+    channels: &Channels,
+    outbound: &mpsc::Sender<Message>,
+) -> (SendHalf, RecvHalf) {
+    let (tx, rx) = mpsc::channel(CHANNEL_BUFFER);
+    channels.insert(channel, tx);
+    let send = ChannelSend {
+        channel,
+        outbound: PollSender::new(outbound.clone()),
+        finished: false,
+    };
+    let recv = ChannelRecv {
+        rx,
+        buffered: Bytes::new(),
+    };
+    (Box::new(send), Box::new(recv))
+}
+
+/// Pass the envelopes from the client to their streams until the socket
+/// closes or the client violates the protocol.
+async fn read_messages(
+    mut stream: SplitStream<WebSocket>,
+    channels: Arc<Channels>,
+    accepted: mpsc::Sender<(SendHalf, RecvHalf)>,
+    outbound: mpsc::Sender<Message>,
+) {
+    while let Some(Ok(message)) = stream.next().await {
+        if message.is_close() {
+            break;
+        }
+        // Pings are answered by the socket, text has no meaning here
+        if !message.is_binary() {
+            continue;
+        }
+        let Some((header, payload)) = message
+            .as_bytes()
+            .split_first_chunk::<ENVELOPE_HEADER_LEN>()
+        else {
+            tracing::warn!("WebSocket message without envelope");
+            break;
+        };
+        let channel =
+            u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        match header[4] {
+            OPEN if channel % 2 == 1
+                && !channels.contains_key(&channel)
+                && channels.len() < MAX_CHANNELS =>
+            {
+                let halves = open_channel(channel, &channels, &outbound);
+                if accepted.send(halves).await.is_err() {
+                    break;
+                }
+            }
+            DATA => {
+                let Some(tx) = channels.get(&channel).map(|tx| tx.clone())
+                else {
+                    // Data sent before the FIN of a closed stream arrived
+                    continue;
+                };
+                if tx.send(Bytes::copy_from_slice(payload)).await.is_err() {
+                    // The receive half was dropped
+                    channels.remove(&channel);
+                }
+            }
+            FIN => {
+                channels.remove(&channel);
+            }
+            kind => {
+                tracing::warn!(channel, kind, "Invalid WebSocket envelope");
+                break;
+            }
+        }
+    }
+    // Streams see the end of the connection as EOF
+    channels.clear();
+}
+
+/// Send half of a multiplexed stream.
+struct ChannelSend {
+    channel: u32,
+    outbound: PollSender<Message>,
+    finished: bool,
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "WebSocket closed")
+}
+
+impl AsyncWrite for ChannelSend {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.finished {
+            return Poll::Ready(Err(closed()));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        ready!(self.outbound.poll_reserve(cx)).map_err(|_| closed())?;
+        let n = buf.len().min(MAX_CHUNK);
+        let message = envelope(self.channel, DATA, &buf[..n]);
+        self.outbound.send_item(message).map_err(|_| closed())?;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        // Queued messages are sent in order without being asked to
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.finished {
+            return Poll::Ready(Ok(()));
+        }
+        ready!(self.outbound.poll_reserve(cx)).map_err(|_| closed())?;
+        let message = envelope(self.channel, FIN, &[]);
+        self.outbound.send_item(message).map_err(|_| closed())?;
+        self.finished = true;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for ChannelSend {
+    fn drop(&mut self) {
+        if !self.finished
+            && let Some(outbound) = self.outbound.get_ref()
+        {
+            let _ = outbound.try_send(envelope(self.channel, FIN, &[]));
+        }
+    }
+}
+
+/// Receive half of a multiplexed stream.
+struct ChannelRecv {
+    rx: mpsc::Receiver<Bytes>,
+    buffered: Bytes,
+}
+
+impl AsyncRead for ChannelRecv {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.buffered.is_empty() {
+            match ready!(self.rx.poll_recv(cx)) {
+                Some(bytes) => self.buffered = bytes,
+                // FIN or the end of the connection
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+        let n = self.buffered.len().min(buf.remaining());
+        buf.put_slice(&self.buffered[..n]);
+        self.buffered.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+
+    use bytes::BytesMut;
+    use salvo::conn::{Acceptor as _, TcpListener};
+    use salvo::http::header::{COOKIE, HeaderValue};
+    use salvo::prelude::*;
+    use salvo::test::TestClient;
+    use serde_json::{Value, json};
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest as _;
+    use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+    use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, tungstenite};
+    use tokio_util::codec::{Decoder as _, Encoder as _};
+
+    use super::*;
+    use crate::auth::JWT_COOKIE_NAME;
+    use crate::prelude::db;
+    use crate::stream::compress_cbor_codec::{
+        CompressedCborDecoder, CompressedCborEncoder,
+    };
+    use crate::stream::testing::{CLIENT_TIMEOUT, wait_until};
+    use crate::stream::{StreamManager, StreamType};
+    use crate::test_db::{self, Client};
+    use crate::utils::flags;
+
+    /// The heartbeat and control streams come first
+    const FIRST_REQUESTED: u32 = 4;
+    const CONTROL: u32 = 2;
+
+    /// A client of `GET /api/ws`, with the bytes received on each channel.
+    struct WsClient {
+        socket: WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>,
+        received: HashMap<u32, BytesMut>,
+    }
+
+    impl WsClient {
+        /// Connect to `addr` with the access token of `client` and answer
+        /// the handshake.
+        async fn connect(addr: SocketAddr, client: &Client) -> Self {
+            let mut request =
+                format!("ws://{addr}/api/ws").into_client_request().unwrap();
+            let token = client.cookie(JWT_COOKIE_NAME).unwrap();
+            request.headers_mut().insert(
+                COOKIE,
+                HeaderValue::from_str(&format!("{JWT_COOKIE_NAME}={token}"))
+                    .unwrap(),
+            );
+            let (socket, _) =
+                tokio_tungstenite::connect_async(request).await.unwrap();
+            let mut ws = Self {
+                socket,
+                received: HashMap::new(),
+            };
+            assert_eq!(ws.recv(CONTROL).await, "Control");
+            assert_eq!(ws.recv(CONTROL).await["type"], "Hello");
+            ws.send(
+                CONTROL,
+                json!({
+                    "type": "HelloAck",
+                    "data": {
+                        "protocolVersion": 2,
+                        "acceptedCompression": [],
+                    },
+                }),
+            )
+            .await;
+            ws
+        }
+
+        /// The next message the server sent on `channel`.
+        async fn recv(&mut self, channel: u32) -> Value {
+            loop {
+                let mut decoder: CompressedCborDecoder<Value> =
+                    CompressedCborDecoder::new();
+                if let Some(received) = self.received.get_mut(&channel)
+                    && let Some(message) = decoder.decode(received).unwrap()
+                {
+                    return message;
+                }
+                let message = self.next().await.expect("the socket is open");
+                let tungstenite::Message::Binary(message) = message else {
+                    continue;
+                };
+                let (header, payload) = message.split_at(ENVELOPE_HEADER_LEN);
+                let of_message =
+                    u32::from_be_bytes(header[..4].try_into().unwrap());
+                match header[4] {
+                    OPEN => assert!(
+                        self.received
+                            .insert(of_message, BytesMut::new())
+                            .is_none()
+                    ),
+                    DATA => self
+                        .received
+                        .get_mut(&of_message)
+                        .expect("the channel should be open")
+                        .extend_from_slice(payload),
+                    _ => {}
+                }
+            }
+        }
+
+        async fn send(&mut self, channel: u32, message: Value) {
+            let mut frame = BytesMut::new();
+            let mut encoder: CompressedCborEncoder<Value> =
+                CompressedCborEncoder::new();
+            encoder.encode(message, &mut frame).unwrap();
+            self.socket
+                .send(tungstenite::Message::binary(
+                    envelope(channel, DATA, &frame).as_bytes().to_vec(),
+                ))
+                .await
+                .unwrap();
+        }
+
+        /// The next message on the socket, `None` once it is closed.
+        async fn next(&mut self) -> Option<tungstenite::Message> {
+            tokio::time::timeout(CLIENT_TIMEOUT, self.socket.next())
+                .await
+                .expect("the server should send")
+                .and_then(Result::ok)
+        }
+    }
+
+    #[tokio::test]
+    async fn streams_work_over_the_websocket_fallback() {
+        test_db::with_service(|service| async move {
+            let res = TestClient::get("http://localhost/api/ws")
+                .send(&service)
+                .await;
+            assert_eq!(res.status_code, Some(StatusCode::NOT_FOUND));
+
+            let (client, body) =
+                Client::register(&service, "wes@example.com", "wes").await;
+            let user_id = body["user"]["id"].as_i64().unwrap() as i32;
+            flags::store(
+                &mut db::get().unwrap(),
+                WEBSOCKET_FALLBACK_FLAG.to_owned(),
+                true,
+                None,
+            )
+            .unwrap();
+            let acceptor = TcpListener::new("127.0.0.1:0").bind().await;
+            let addr = acceptor.holdings()[0]
+                .local_addr
+                .clone()
+                .into_std()
+                .unwrap();
+            tokio::spawn(Server::new(acceptor).serve(service));
+
+            let mut ws = WsClient::connect(addr, &client).await;
+            let manager = StreamManager::global();
+            wait_until(|| manager.is_connected(user_id)).await;
+
+            let (mut send, mut recv) = manager
+                .request_stream::<Value, Value>(user_id, StreamType::Game)
+                .await
+                .unwrap();
+            assert_eq!(ws.recv(FIRST_REQUESTED).await, "Game");
+            send.send(json!({ "ping": 1 })).await.unwrap();
+            assert_eq!(ws.recv(FIRST_REQUESTED).await, json!({ "ping": 1 }));
+            ws.send(FIRST_REQUESTED, json!({ "pong": 1 })).await;
+            assert_eq!(
+                recv.next().await.unwrap().unwrap(),
+                json!({ "pong": 1 })
+            );
+
+            manager.close_stream(user_id);
+            let close = loop {
+                match ws.next().await {
+                    Some(tungstenite::Message::Close(frame)) => break frame,
+                    Some(_) => {}
+                    None => panic!("the server should send a close frame"),
+                }
+            };
+            let Some(CloseFrame { code, .. }) = close else {
+                panic!("the close frame should have a code");
+            };
+            assert_eq!(u16::from(code), u16::from(CloseCode::Generic));
+            assert!(ws.next().await.is_none_or(|message| message.is_close()));
+            assert!(!manager.is_connected(user_id));
+        })
+        .await;
+    }
+}
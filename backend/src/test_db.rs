@@ -0,0 +1,286 @@
+//! Isolated databases for tests.
+//!
+//! Every test gets its own SQLite database in the temp dir with the
+//! migrations applied, so tests can run in parallel without seeing each
+//! other's rows. Unlike a shared-cache in-memory database, a file waits for
+//! locks like the one of the server, so concurrent writes work the same.
+//! The database is installed for the test's thread, where [db::get] and
+//! [db::with_conn] pick it up. `#[tokio::test]` runs spawned tasks on that
+//! thread too, so use the default current-thread runtime.
+//!
+//! Caches, presence and rate limits are global to the process, so the ids of
+//! each database start at an offset of their own and the requests of each
+//! [with_service] come from an address of their own.
+
+use std::cell::RefCell;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+
+use diesel::connection::SimpleConnection;
+use diesel::r2d2::{ConnectionManager, Pool};
+use salvo::catcher::Catcher;
+use salvo::http::header::{COOKIE, USER_AGENT};
+use salvo::test::{RequestBuilder, ResponseExt, TestClient};
+use tracing::subscriber::DefaultGuard;
+use ulid::Ulid;
+
+use crate::db::{self, DbPool};
+use crate::prelude::*;
+
+/// Autoincrement ids of the n-th test database start after `n * ID_STRIDE`
+const ID_STRIDE: u32 = 100_000;
+
+static NEXT_DB: AtomicU32 = AtomicU32::new(1);
+
+thread_local! {
+    static CURRENT: RefCell<Option<DbPool>> = const { RefCell::new(None) };
+}
+
+/// The database of the test running on this thread, if any.
+pub fn current_pool() -> Option<DbPool> {
+    CURRENT.with_borrow(Clone::clone)
+}
+
+/// Uninstalls and deletes the database when the test ends, by returning or
+/// panicking.
+struct Installed(PathBuf);
+
+impl Drop for Installed {
+    fn drop(&mut self) {
+        CURRENT.set(None);
+        for suffix in ["", "-wal", "-shm"] {
+            let mut path = self.0.clone().into_os_string();
+            path.push(suffix);
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+fn install() -> (u32, Installed) {
+    crate::config::init_for_tests();
+    let n = NEXT_DB.fetch_add(1, Ordering::Relaxed);
+    let path = std::env::temp_dir()
+        .join(format!("transcendence_test_{}.sqlite", Ulid::new()));
+    let pool = db::build_pool(path.to_str().expect("temp dir is UTF-8"));
+    {
+        let conn = &mut pool.get().expect("test database should open");
+        db::migrate(conn);
+        conn.batch_execute(&format!(
+            "INSERT INTO sqlite_sequence (name, seq)
+             SELECT name, {} FROM sqlite_master
+             WHERE type = 'table' AND sql LIKE '%AUTOINCREMENT%'",
+            n * ID_STRIDE
+        ))
+        .expect("id offsets should be set");
+    }
+    CURRENT.set(Some(pool));
+    (n, Installed(path))
+}
+
+/// Run `f` with a fresh database.
+pub async fn with_db<F, Fut, T>(f: F) -> T
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = T>,
+{
+    let _installed = install();
+    f().await
+}
+
+/// Run `f` with the SQLite database at `path` as it is, without migrations.
+///
+/// Connections are only opened when taken, so the database may not exist or
+/// fail to open. It is deleted afterwards like the ones of [with_db].
+pub async fn with_unmigrated_db<F, Fut, T>(path: PathBuf, f: F) -> T
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = T>,
+{
+    crate::config::init_for_tests();
+    let manager = ConnectionManager::<SqliteConnection>::new(
+        path.to_str().expect("temp dir is UTF-8"),
+    );
+    CURRENT.set(Some(
+        Pool::builder().min_idle(Some(0)).build_unchecked(manager),
+    ));
+    let _installed = Installed(path);
+    f().await
+}
+
+/// Run `f` with a fresh database and the full router of the app on it.
+pub async fn with_service<F, Fut, T>(f: F) -> T
+where
+    F: FnOnce(Service) -> Fut,
+    Fut: Future<Output = T>,
+{
+    let (n, _installed) = install();
+    f(service_from(Ipv4Addr::from(0x0a00_0000 + n))).await
+}
+
+/// The service of [with_service] for requests from `peer`, e.g. a client on
+/// another network. It uses the database of the test running on this thread.
+pub fn service_from(peer: Ipv4Addr) -> Service {
+    Service::new(ROUTER.clone())
+        .catcher(Catcher::new(crate::error::error_catcher))
+        .hoop(PeerAddr(SocketAddr::from((peer, 40000))))
+}
+
+/// The router of [with_service], built once since building the OpenAPI
+/// documents from several threads at once deadlocks.
+static ROUTER: LazyLock<Arc<Router>> = LazyLock::new(|| {
+    Arc::new(crate::routers::root().hoop(crate::auth::device_id_inserter_hoop))
+});
+
+/// Sets the peer address of every request, [with_service] gives each test
+/// one of its own.
+struct PeerAddr(SocketAddr);
+
+#[async_trait]
+impl Handler for PeerAddr {
+    async fn handle(
+        &self,
+        req: &mut Request,
+        _depot: &mut Depot,
+        _res: &mut Response,
+        _ctrl: &mut FlowCtrl,
+    ) {
+        *req.remote_addr_mut() = self.0.into();
+    }
+}
+
+/// The cookies of a client, for requests as a logged in user.
+#[derive(Debug, Clone, Default)]
+pub struct Client {
+    cookies: Vec<(String, String)>,
+}
+
+impl Client {
+    /// Keep the cookies `res` sets.
+    pub fn store(&mut self, res: &Response) {
+        for cookie in res.cookies().iter() {
+            self.set_cookie(cookie.name(), cookie.value());
+        }
+    }
+
+    /// Value of the stored cookie `name`.
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.cookies
+            .iter()
+            .find(|(stored, _)| stored == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Replace the stored cookie `name`.
+    pub fn set_cookie(&mut self, name: &str, value: impl Into<String>) {
+        self.cookies.retain(|(stored, _)| stored != name);
+        self.cookies.push((name.to_owned(), value.into()));
+    }
+
+    /// `req` with the stored cookies.
+    pub fn with_cookies(&self, req: RequestBuilder) -> RequestBuilder {
+        let header = self
+            .cookies
+            .iter()
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect::<Vec<_>>()
+            .join("; ");
+        let req = req.add_header(COOKIE, header, true);
+        req.add_header(USER_AGENT, "test-client", true)
+    }
+
+    /// Send `req` with the stored cookies and store the new ones.
+    pub async fn send(
+        &mut self,
+        req: RequestBuilder,
+        service: &Service,
+    ) -> Response {
+        let res = self.with_cookies(req).send(service).await;
+        self.store(&res);
+        res
+    }
+
+    /// Register `email` with `nickname` and a strong password, see
+    /// [PASSWORD].
+    pub async fn register(
+        service: &Service,
+        email: &str,
+        nickname: &str,
+    ) -> (Self, serde_json::Value) {
+        let mut client = Self::default();
+        let mut res = client
+            .send(
+                TestClient::post("http://localhost/api/auth/register").json(
+                    &serde_json::json!({
+                        "email": email,
+                        "password": PASSWORD,
+                        "nickname": nickname,
+                    }),
+                ),
+                service,
+            )
+            .await;
+        assert_eq!(res.status_code, Some(StatusCode::OK), "register {email}");
+        let body = res.take_json().await.expect("register returns json");
+        (client, body)
+    }
+}
+
+/// Password of the users of [Client::register]
+pub const PASSWORD: &str = "correct horse battery staple 42";
+
+/// Insert a user named `nickname` without a password, for tests that don't
+/// go through the API.
+pub fn create_user(conn: &mut DbConn, nickname: &str) -> crate::models::User {
+    diesel::insert_into(crate::schema::users::table)
+        .values(crate::models::NewUser {
+            email: format!("{nickname}@example.com"),
+            nickname: nickname.to_owned(),
+            totp_enabled: false,
+            totp_secret_enc: None,
+            totp_confirmed_at: None,
+            password_hash: String::new(),
+            created_at: chrono::Utc::now().naive_utc(),
+            is_admin: false,
+            last_seen: None,
+            locked_at: None,
+            lock_reason: None,
+        })
+        .get_result(conn)
+        .expect("user should be inserted")
+}
+
+/// Collects what a `tracing` subscriber writes, to check what was logged.
+#[derive(Clone, Default)]
+pub struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl io::Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Captured {
+    /// Capture what is logged at INFO and above on this thread, until the
+    /// guard is dropped.
+    pub fn install() -> (Self, DefaultGuard) {
+        let captured = Self::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        (captured, tracing::subscriber::set_default(subscriber))
+    }
+
+    pub fn contains(&self, text: &str) -> bool {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).contains(text)
+    }
+}
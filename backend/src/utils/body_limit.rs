@@ -0,0 +1,123 @@
+//! Size limit of request bodies.
+//!
+//! Salvo only applies [`Request::secure_max_size`] once a handler parses the
+//! body, and reports a body over it like malformed input. [`BodyLimit`]
+//! rejects such requests with 413 before any handler runs.
+
+use http_body_util::LengthLimitError;
+use salvo::http::ParseError;
+use salvo::http::header::CONTENT_LENGTH;
+use salvo::prelude::*;
+
+/// Rejects requests with a body over the given number of bytes.
+///
+/// Bodies with a `Content-Length` are checked by the header. Others are read
+/// up to the limit right away, so handlers parse the buffered body.
+#[derive(Debug, Clone, Copy)]
+pub struct BodyLimit(pub usize);
+
+#[async_trait]
+impl Handler for BodyLimit {
+    async fn handle(
+        &self,
+        req: &mut Request,
+        _depot: &mut Depot,
+        res: &mut Response,
+        ctrl: &mut FlowCtrl,
+    ) {
+        let too_large = match req.header::<u64>(CONTENT_LENGTH) {
+            Some(len) => len > self.0 as u64,
+            None => matches!(
+                req.payload_with_max_size(self.0).await,
+                Err(ParseError::Other(err)) if err.is::<LengthLimitError>()
+            ),
+        };
+        if too_large {
+            res.status_code(StatusCode::PAYLOAD_TOO_LARGE);
+            ctrl.skip_rest();
+            return;
+        }
+        req.set_secure_max_size(self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use salvo::test::{ResponseExt, TestClient};
+    use serde_json::{Value, json};
+
+    use super::*;
+    use crate::test_db;
+
+    /// Counts the requests that got through, with the size of their body.
+    #[derive(Clone, Default)]
+    struct Reached(Arc<AtomicUsize>);
+
+    #[async_trait]
+    impl Handler for Reached {
+        async fn handle(
+            &self,
+            req: &mut Request,
+            _depot: &mut Depot,
+            res: &mut Response,
+            _ctrl: &mut FlowCtrl,
+        ) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            match req.payload().await {
+                Ok(body) => res.render(body.len().to_string()),
+                Err(_) => {
+                    res.status_code(StatusCode::BAD_REQUEST);
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_bodies_never_reach_the_handler() {
+        let reached = Reached::default();
+        let service = Service::new(
+            Router::new().hoop(BodyLimit(1024)).post(reached.clone()),
+        );
+        let post = |len: usize| {
+            TestClient::post("http://localhost/").bytes(vec![b'x'; len])
+        };
+
+        // Without a Content-Length, like chunked bodies
+        let mut res = post(1024).send(&service).await;
+        assert_eq!(res.status_code, Some(StatusCode::OK));
+        assert_eq!(res.take_string().await.unwrap(), "1024");
+        let res = post(1025).send(&service).await;
+        assert_eq!(res.status_code, Some(StatusCode::PAYLOAD_TOO_LARGE));
+
+        // By the header, before reading anything
+        let res = post(3 * 1024 * 1024)
+            .add_header(CONTENT_LENGTH, 3 * 1024 * 1024, true)
+            .send(&service)
+            .await;
+        assert_eq!(res.status_code, Some(StatusCode::PAYLOAD_TOO_LARGE));
+        assert_eq!(reached.0.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn oversized_logins_get_413_in_the_error_envelope() {
+        test_db::with_service(|service| async move {
+            let login = |password: String| {
+                TestClient::post("http://localhost/api/auth/login").json(
+                    &json!({ "email": "big@example.com", "password": password }),
+                )
+            };
+            let mut res = login("x".repeat(65 * 1024)).send(&service).await;
+            assert_eq!(res.status_code, Some(StatusCode::PAYLOAD_TOO_LARGE));
+            let body: Value = res.take_json().await.unwrap();
+            assert_eq!(body["code"], "payload_too_large", "{body}");
+            assert!(body["requestId"].is_string(), "{body}");
+
+            let res = login("wrong password".into()).send(&service).await;
+            assert_eq!(res.status_code, Some(StatusCode::UNAUTHORIZED));
+        })
+        .await;
+    }
+}
@@ -0,0 +1,164 @@
+//! The address of the client a request comes from.
+//!
+//! Behind a reverse proxy the peer of every request is the proxy. When the
+//! peer is one of the configured `trusted_proxies`, the client is the
+//! rightmost address in `X-Forwarded-For` (or `Forwarded`) that is not a
+//! trusted proxy itself. The headers of other peers are ignored, so clients
+//! can't pick their own address.
+
+use std::net::{IpAddr, SocketAddr};
+
+use ipnet::IpNet;
+use salvo::http::header::{FORWARDED, HeaderName};
+use salvo::prelude::*;
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+
+/// Prefix lengths kept by [`truncate`].
+const IPV4_PREFIX: u8 = 24;
+const IPV6_PREFIX: u8 = 48;
+
+/// The address of the client of `req`, `None` if the peer has no IP address.
+pub fn client_ip(req: &Request) -> Option<IpAddr> {
+    let peer = req.remote_addr().to_owned().into_std()?.ip().to_canonical();
+    let trusted = &crate::config::get().trusted_proxies;
+    if !is_trusted(trusted, peer) {
+        return Some(peer);
+    }
+
+    let mut client = peer;
+    for hop in forwarded_chain(req).iter().rev() {
+        // Obfuscated or malformed entries hide everything before them, the
+        // proxy that added them is the best guess
+        let Some(ip) = parse_hop(hop) else {
+            break;
+        };
+        client = ip;
+        if !is_trusted(trusted, ip) {
+            break;
+        }
+    }
+    Some(client)
+}
+
+/// `ip` without its host bits, as stored with sessions.
+pub fn truncate(ip: IpAddr) -> IpAddr {
+    let prefix = match ip {
+        IpAddr::V4(_) => IPV4_PREFIX,
+        IpAddr::V6(_) => IPV6_PREFIX,
+    };
+    IpNet::new(ip, prefix)
+        .expect("prefix fits the address family")
+        .trunc()
+        .addr()
+}
+
+fn is_trusted(trusted: &[IpNet], ip: IpAddr) -> bool {
+    trusted.iter().any(|net| net.contains(&ip))
+}
+
+/// The addresses the request was forwarded for, closest proxy last.
+///
+/// `X-Forwarded-For` takes precedence, `Forwarded` is only read without it.
+fn forwarded_chain(req: &Request) -> Vec<&str> {
+    let headers = req.headers();
+    let x_forwarded_for: Vec<&str> = headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+    if !x_forwarded_for.is_empty() {
+        return x_forwarded_for;
+    }
+    headers
+        .get_all(FORWARDED)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (name, value) = pair.trim().split_once('=')?;
+                name.eq_ignore_ascii_case("for").then_some(value.trim())
+            })
+        })
+        .collect()
+}
+
+/// Parse an address with an optional port, IPv6 addresses with a port in
+/// brackets, as in `Forwarded: for="[2001:db8::1]:4711"`.
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    let hop = hop.trim_matches('"');
+    let ip = hop
+        .parse::<IpAddr>()
+        .or_else(|_| hop.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .or_else(|_| {
+            hop.strip_prefix('[')
+                .and_then(|hop| hop.strip_suffix(']'))
+                .unwrap_or(hop)
+                .parse::<IpAddr>()
+        })
+        .ok()?;
+    Some(ip.to_canonical())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A request from `peer` through proxies that added `forwarded_for`,
+    /// see the `trusted_proxies` of [crate::config::TEST_CONFIG].
+    fn request(peer: &str, forwarded_for: Option<&str>) -> Request {
+        crate::config::init_for_tests();
+        let mut req = Request::new();
+        *req.remote_addr_mut() =
+            SocketAddr::new(peer.parse().unwrap(), 40000).into();
+        if let Some(forwarded_for) = forwarded_for {
+            req.headers_mut()
+                .insert(X_FORWARDED_FOR, forwarded_for.parse().unwrap());
+        }
+        req
+    }
+
+    fn ip(addr: &str) -> Option<IpAddr> {
+        Some(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn untrusted_peers_cant_spoof_their_address() {
+        let req = request("198.51.100.7", Some("203.0.113.1"));
+        assert_eq!(client_ip(&req), ip("198.51.100.7"));
+    }
+
+    #[test]
+    fn rightmost_untrusted_hop_is_the_client() {
+        let req =
+            request("192.0.2.1", Some("203.0.113.1, 198.51.100.7, 192.0.2.2"));
+        assert_eq!(client_ip(&req), ip("198.51.100.7"));
+    }
+
+    #[test]
+    fn forwarded_is_read_without_x_forwarded_for() {
+        let mut req = request("192.0.2.1", None);
+        req.headers_mut().insert(
+            FORWARDED,
+            r#"for="[2001:db8::1]:4711", for=192.0.2.2"#.parse().unwrap(),
+        );
+        assert_eq!(client_ip(&req), ip("2001:db8::1"));
+    }
+
+    #[test]
+    fn malformed_hops_stop_at_the_proxy_that_added_them() {
+        let req = request("192.0.2.1", Some("203.0.113.1, unknown"));
+        assert_eq!(client_ip(&req), ip("192.0.2.1"));
+    }
+
+    #[test]
+    fn truncate_keeps_the_network() {
+        let v4: IpAddr = "198.51.100.7".parse().unwrap();
+        let v6: IpAddr = "2001:db8:1:2:3::4".parse().unwrap();
+        assert_eq!(truncate(v4).to_string(), "198.51.100.0");
+        assert_eq!(truncate(v6).to_string(), "2001:db8:1::");
+    }
+}
@@ -0,0 +1,190 @@
+//! CORS for API clients on other origins, e.g. the mobile companion app.
+//!
+//! Only requests from the origins of `cors.allowed_origins` get CORS
+//! headers, others are served as without CORS and blocked by the browser.
+//! [`CorsHoop`] runs on the service, because a preflight `OPTIONS` request
+//! matches no route: it is answered with 204 right away, before the auth
+//! hoops could reject it.
+
+use salvo::cors::{AllowOrigin, Cors, CorsHandler};
+use salvo::http::header::{self, HeaderName, RETRY_AFTER};
+use salvo::http::{HeaderValue, Method};
+use salvo::prelude::*;
+
+use super::idempotency::IDEMPOTENCY_KEY;
+use super::limiter::{X_RATELIMIT_LIMIT, X_RATELIMIT_REMAINING};
+use super::request_id::X_REQUEST_ID;
+use crate::config::CorsConfig;
+
+/// Paths CORS applies to.
+const API_PREFIX: &str = "/api/";
+
+pub struct CorsHoop {
+    origins: Vec<HeaderValue>,
+    cors: CorsHandler,
+}
+
+impl CorsHoop {
+    pub fn new(config: &CorsConfig) -> Self {
+        let origins = config
+            .allowed_origins
+            .iter()
+            .map(|origin| {
+                HeaderValue::from_str(origin).expect("checked in config::init")
+            })
+            .collect::<Vec<_>>();
+        let cors = Cors::new()
+            .allow_origin(AllowOrigin::list(origins.clone()))
+            .allow_credentials(config.allow_credentials)
+            .allow_methods(vec![
+                Method::GET,
+                Method::POST,
+                Method::PUT,
+                Method::PATCH,
+                Method::DELETE,
+            ])
+            .allow_headers(vec![
+                header::CONTENT_TYPE,
+                HeaderName::from_static(IDEMPOTENCY_KEY),
+            ])
+            .expose_headers(vec![
+                X_REQUEST_ID,
+                X_RATELIMIT_LIMIT,
+                X_RATELIMIT_REMAINING,
+                RETRY_AFTER,
+            ])
+            .max_age(config.max_age_secs)
+            .into_handler();
+        Self { origins, cors }
+    }
+}
+
+#[async_trait]
+impl Handler for CorsHoop {
+    async fn handle(
+        &self,
+        req: &mut Request,
+        depot: &mut Depot,
+        res: &mut Response,
+        ctrl: &mut FlowCtrl,
+    ) {
+        let allowed = req
+            .headers()
+            .get(header::ORIGIN)
+            .is_some_and(|origin| self.origins.contains(origin));
+        if !allowed || !req.uri().path().starts_with(API_PREFIX) {
+            return;
+        }
+        let preflight = req.method() == Method::OPTIONS
+            && req
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+        if preflight {
+            // The CORS handler answers it without the routes
+            ctrl.skip_rest();
+        }
+        self.cors.handle(req, depot, res, ctrl).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use salvo::http::header::{
+        ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+        ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS,
+        ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS,
+        ACCESS_CONTROL_REQUEST_METHOD, ORIGIN,
+    };
+    use salvo::test::TestClient;
+
+    use super::*;
+    use crate::test_db;
+
+    const APP: &str = "https://app.example.com";
+    const ME: &str = "http://localhost/api/user/me";
+
+    fn cors(allow_credentials: bool) -> CorsHoop {
+        CorsHoop::new(&CorsConfig {
+            allowed_origins: vec![APP.to_owned()],
+            allow_credentials,
+            max_age_secs: 600,
+        })
+    }
+
+    fn preflight(origin: &str) -> salvo::test::RequestBuilder {
+        TestClient::options(ME)
+            .add_header(ORIGIN, origin, true)
+            .add_header(ACCESS_CONTROL_REQUEST_METHOD, "GET", true)
+            .add_header(ACCESS_CONTROL_REQUEST_HEADERS, IDEMPOTENCY_KEY, true)
+    }
+
+    #[tokio::test]
+    async fn preflights_of_allowed_origins_skip_the_routes() {
+        test_db::with_service(|service| async move {
+            let service = service.hoop(cors(true));
+            let res = preflight(APP).send(&service).await;
+            assert_eq!(res.status_code, Some(StatusCode::NO_CONTENT));
+            let headers = res.headers();
+            assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], APP);
+            assert_eq!(headers[ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+            assert_eq!(headers[ACCESS_CONTROL_MAX_AGE], "600");
+            let allowed =
+                headers[ACCESS_CONTROL_ALLOW_HEADERS].to_str().unwrap();
+            assert!(allowed.contains(IDEMPOTENCY_KEY), "{allowed}");
+
+            // The actual request reaches the auth hoops
+            let res = TestClient::get(ME)
+                .add_header(ORIGIN, APP, true)
+                .send(&service)
+                .await;
+            assert_eq!(res.status_code, Some(StatusCode::UNAUTHORIZED));
+            assert_eq!(res.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], APP);
+            let exposed = res.headers()[ACCESS_CONTROL_EXPOSE_HEADERS]
+                .to_str()
+                .unwrap();
+            assert!(exposed.contains(X_REQUEST_ID.as_str()), "{exposed}");
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn other_origins_get_no_cors_headers() {
+        test_db::with_service(|service| async move {
+            let service = service.hoop(cors(true));
+            let evil = "https://evil.example.com";
+            let res = preflight(evil).send(&service).await;
+            assert_ne!(res.status_code, Some(StatusCode::NO_CONTENT));
+            assert!(!res.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+
+            let res = TestClient::get(ME)
+                .add_header(ORIGIN, evil, true)
+                .send(&service)
+                .await;
+            assert_eq!(res.status_code, Some(StatusCode::UNAUTHORIZED));
+            assert!(!res.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+
+            // Only the API is shared
+            let res = TestClient::get("http://localhost/healthz")
+                .add_header(ORIGIN, APP, true)
+                .send(&service)
+                .await;
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+            assert!(!res.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn credentials_are_only_allowed_when_configured() {
+        test_db::with_service(|service| async move {
+            let service = service.hoop(cors(false));
+            let res = preflight(APP).send(&service).await;
+            assert_eq!(res.status_code, Some(StatusCode::NO_CONTENT));
+            assert_eq!(res.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], APP);
+            assert!(
+                !res.headers().contains_key(ACCESS_CONTROL_ALLOW_CREDENTIALS)
+            );
+        })
+        .await;
+    }
+}
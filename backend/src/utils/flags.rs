@@ -0,0 +1,198 @@
+//! Features that are turned on and off at runtime.
+//!
+//! Flags are rows of `feature_flags`, changed through the admin endpoints.
+//! A flag without a row is off, so unfinished features can ship dark.
+//! [`is_enabled`] keeps every flag for [`TTL`]: a change is seen right away
+//! by the process that made it and within [`TTL`] by the others.
+
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+
+use crate::models::FeatureFlag;
+use crate::prelude::*;
+use crate::schema::feature_flags;
+
+const TTL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy)]
+struct CachedFlag {
+    enabled: bool,
+    cached_at: Instant,
+}
+
+static FLAGS: LazyLock<DashMap<String, CachedFlag, ahash::RandomState>> =
+    LazyLock::new(DashMap::default);
+
+/// Whether the feature `key` is on, off if the flag can't be loaded.
+pub async fn is_enabled(key: &str) -> bool {
+    if let Some(cached) = FLAGS.get(key)
+        && cached.cached_at.elapsed() < TTL
+    {
+        return cached.enabled;
+    }
+    let owned_key = key.to_owned();
+    let enabled = match db::with_conn(move |conn| load(conn, &owned_key)).await
+    {
+        Ok(flag) => flag.is_some_and(|flag| flag.enabled),
+        Err(err) => {
+            tracing::error!(%err, key, "Failed to load feature flag");
+            // Don't cache, so the next request tries again
+            return false;
+        }
+    };
+    cache(key, enabled);
+    enabled
+}
+
+fn cache(key: &str, enabled: bool) {
+    FLAGS.insert(
+        key.to_owned(),
+        CachedFlag {
+            enabled,
+            cached_at: Instant::now(),
+        },
+    );
+}
+
+pub fn load(conn: &mut DbConn, key: &str) -> AppResult<Option<FeatureFlag>> {
+    Ok(feature_flags::table
+        .find(key)
+        .first::<FeatureFlag>(conn)
+        .optional()?)
+}
+
+/// Create or replace the flag `key`.
+pub fn store(
+    conn: &mut DbConn,
+    key: String,
+    enabled: bool,
+    payload: Option<String>,
+) -> AppResult<FeatureFlag> {
+    let flag = FeatureFlag {
+        key,
+        enabled,
+        payload,
+        updated_at: chrono::Utc::now().naive_utc(),
+    };
+    let stored: FeatureFlag = diesel::insert_into(feature_flags::table)
+        .values(&flag)
+        .on_conflict(feature_flags::key)
+        .do_update()
+        .set(&flag)
+        .get_result(conn)?;
+    cache(&stored.key, stored.enabled);
+    Ok(stored)
+}
+
+/// Hoop answering 404 while the feature `key` is off, see [`feature_gate`].
+#[derive(Debug, Clone, Copy)]
+pub struct FeatureGate(&'static str);
+
+/// Hide the routes behind it while the feature `key` is off.
+pub fn feature_gate(key: &'static str) -> FeatureGate {
+    FeatureGate(key)
+}
+
+#[async_trait]
+impl Handler for FeatureGate {
+    async fn handle(
+        &self,
+        _req: &mut Request,
+        _depot: &mut Depot,
+        res: &mut Response,
+        ctrl: &mut FlowCtrl,
+    ) {
+        if !is_enabled(self.0).await {
+            res.render(StatusError::not_found());
+            ctrl.skip_rest();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use salvo::test::{ResponseExt, TestClient};
+    use serde_json::{Value, json};
+
+    use super::*;
+    use crate::routers::admin::ADMIN_TOKEN_HEADER;
+    use crate::test_db;
+
+    #[handler]
+    fn reached() -> &'static str {
+        "reached"
+    }
+
+    /// A route behind the flag `key`. The flags are cached for the whole
+    /// process, so every test uses a key of its own.
+    fn gated_service(key: &'static str) -> Service {
+        Service::new(
+            Router::with_path("gated")
+                .hoop(feature_gate(key))
+                .get(reached),
+        )
+    }
+
+    async fn gated_status(service: &Service) -> Option<StatusCode> {
+        TestClient::get("http://localhost/gated")
+            .send(service)
+            .await
+            .status_code
+    }
+
+    #[tokio::test]
+    async fn missing_flags_are_off() {
+        test_db::with_db(|| async {
+            assert!(!is_enabled("test_never_set").await);
+            let service = gated_service("test_never_set");
+            assert_eq!(
+                gated_status(&service).await,
+                Some(StatusCode::NOT_FOUND)
+            );
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn turning_a_flag_on_takes_effect_right_away() {
+        test_db::with_service(|service| async move {
+            let gated = gated_service("test_toggled");
+            assert_eq!(gated_status(&gated).await, Some(StatusCode::NOT_FOUND));
+
+            let set = async |enabled| {
+                TestClient::put("http://localhost/api/admin/flags/test_toggled")
+                    .add_header(ADMIN_TOKEN_HEADER, "test-admin-token", true)
+                    .json(&json!({ "enabled": enabled }))
+                    .send(&service)
+                    .await
+            };
+            let mut res = set(true).await;
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+            let flag: Value = res.take_json().await.unwrap();
+            assert_eq!(flag["enabled"], true);
+            // Cached as off before, the PUT replaced the cached value
+            let mut res =
+                TestClient::get("http://localhost/gated").send(&gated).await;
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+            assert_eq!(res.take_string().await.unwrap(), "reached");
+
+            assert_eq!(set(false).await.status_code, Some(StatusCode::OK));
+            assert_eq!(gated_status(&gated).await, Some(StatusCode::NOT_FOUND));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn stored_flags_are_loaded_once_the_cache_expires() {
+        test_db::with_db(|| async {
+            store(&mut db::get().unwrap(), "test_stored".into(), true, None)
+                .unwrap();
+            // As on a server that didn't make the change
+            FLAGS.remove("test_stored");
+            assert!(is_enabled("test_stored").await);
+        })
+        .await;
+    }
+}
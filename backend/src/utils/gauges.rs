@@ -0,0 +1,92 @@
+//! Resource gauges for the metrics.
+//!
+//! The request counters of [`super::metrics`] don't show saturation, so a
+//! task started with [`periodic_sampling`] samples the database pool, the
+//! tokio runtime, the [`StreamManager`] registry and the matchmaking queue
+//! every [`SAMPLE_INTERVAL`] into the same registry. They are served with
+//! the sample time, a scraper can tell stale gauges by it.
+
+use std::time::Duration;
+
+use super::metrics::{self, GaugeSnapshot};
+use crate::game::matchmaking;
+use crate::stream::StreamManager;
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Start the task that samples the gauges.
+pub fn periodic_sampling() {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+        loop {
+            interval.tick().await;
+            metrics::record_gauges(sample(StreamManager::global()));
+        }
+    });
+}
+
+fn sample(streams: &StreamManager) -> GaugeSnapshot {
+    let pool = crate::db::pool_state();
+    let runtime = tokio::runtime::Handle::current().metrics();
+    GaugeSnapshot {
+        sampled_at: chrono::Utc::now().naive_utc(),
+        db_connections: pool.connections,
+        db_idle_connections: pool.idle_connections,
+        tokio_workers: runtime.num_workers(),
+        tokio_alive_tasks: runtime.num_alive_tasks(),
+        stream_users: streams.connected_users(),
+        stream_connections: streams.connection_count(),
+        matchmaking_players: matchmaking::players_waiting(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::testing::{ConnectionSession, FakeClient, wait_until};
+    use crate::test_db;
+
+    fn in_use(gauges: &GaugeSnapshot) -> u32 {
+        gauges.db_connections - gauges.db_idle_connections
+    }
+
+    #[tokio::test]
+    async fn pool_gauges_count_connections_in_use() {
+        test_db::with_db(|| async {
+            let streams = StreamManager::default();
+            assert_eq!(in_use(&sample(&streams)), 0);
+
+            let held: Vec<_> =
+                (0..3).map(|_| crate::db::get().unwrap()).collect();
+            metrics::record_gauges(sample(&streams));
+            let gauges = metrics::snapshot().gauges.unwrap();
+            assert_eq!(in_use(&gauges), 3);
+            assert!(gauges.db_connections >= 3);
+            let text = metrics::render_prometheus(&metrics::snapshot());
+            assert!(text.contains("gauges_sampled_timestamp_seconds"));
+
+            drop(held);
+            assert_eq!(in_use(&sample(&streams)), 0);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn registry_gauges_track_connections() {
+        let streams: &'static StreamManager = Box::leak(Box::default());
+        let gauges = sample(streams);
+        assert_eq!((gauges.stream_users, gauges.stream_connections), (0, 0));
+
+        let session = |user_id| Some(ConnectionSession::for_user(user_id));
+        let first = FakeClient::connect(streams, session(1)).await;
+        let _second = FakeClient::connect(streams, session(2)).await;
+        wait_until(|| streams.connection_count() == 2).await;
+        let gauges = sample(streams);
+        assert_eq!((gauges.stream_users, gauges.stream_connections), (2, 2));
+
+        streams.close_stream(1);
+        first.closed().await;
+        let gauges = sample(streams);
+        assert_eq!((gauges.stream_users, gauges.stream_connections), (1, 1));
+    }
+}
@@ -0,0 +1,392 @@
+//! Safe retries of requests that create something.
+//!
+//! Routes with the [`idempotent`] hoop accept an optional `Idempotency-Key`
+//! header with a ULID or UUID picked by the client. The first request with a
+//! key is handled normally and its successful response is stored for
+//! [`KEY_TTL`]. Retries with the same key and body get the stored response
+//! with an `Idempotent-Replayed: true` header instead of being handled again,
+//! so a retry after a lost response can't e.g. record a game twice.
+//!
+//! Keys are per user. Reusing a key for another request is rejected with
+//! [`IdempotencyError::KeyReused`], and so are retries while the first request
+//! is still in progress. Failed requests don't store anything, so they can be
+//! retried with the same key, and neither do requests that never finished
+//! e.g. after a panic, their key is free again after [`STALE_AFTER`]. Expired
+//! keys are deleted by [`periodic_cleanup`].
+
+use std::time::Duration;
+
+use salvo::http::header::{CONTENT_TYPE, HeaderValue};
+use salvo::http::{Method, ResBody};
+use thiserror::Error;
+
+use crate::models::{IdempotencyKey, NewIdempotencyKey};
+use crate::prelude::*;
+use crate::schema::idempotency_keys;
+
+pub const IDEMPOTENCY_KEY: &str = "idempotency-key";
+const REPLAYED: &str = "idempotent-replayed";
+
+/// How long a response is replayed for its key.
+const KEY_TTL: chrono::TimeDelta = chrono::TimeDelta::hours(24);
+/// A claim without a response after this is from a request that died.
+const STALE_AFTER: chrono::TimeDelta = chrono::TimeDelta::minutes(5);
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Error, Debug, Clone, Copy, strum::IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum IdempotencyError {
+    #[error("The Idempotency-Key header has to be a ULID or UUID")]
+    InvalidKey,
+    #[error("The Idempotency-Key was already used for a different request")]
+    KeyReused,
+    #[error("A request with this Idempotency-Key is still in progress")]
+    InProgress,
+}
+
+enum Claim {
+    /// The key is new, handle the request
+    New,
+    Replay {
+        status: i32,
+        body: Vec<u8>,
+    },
+}
+
+fn is_valid_key(key: &str) -> bool {
+    let is_uuid = key.len() == 36
+        && key.char_indices().all(|(i, c)| match i {
+            8 | 13 | 18 | 23 => c == '-',
+            _ => c.is_ascii_hexdigit(),
+        });
+    is_uuid || ulid::Ulid::from_string(key).is_ok()
+}
+
+fn hash_request(method: &Method, path: &str, body: &[u8]) -> Vec<u8> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(method.as_str().as_bytes());
+    hasher.update(b" ");
+    hasher.update(path.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(body);
+    hasher.finalize().as_bytes().to_vec()
+}
+
+/// Handle requests with an `Idempotency-Key` at most once per key.
+///
+/// Needs a logged in user, requests without the header and safe methods
+/// pass through unchanged.
+#[handler]
+pub async fn idempotent(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+    ctrl: &mut FlowCtrl,
+) {
+    if req.method().is_safe() {
+        return;
+    }
+    let Some(key) = req.header::<String>(IDEMPOTENCY_KEY) else {
+        return;
+    };
+    if !is_valid_key(&key) {
+        res.render(ApiError::from(IdempotencyError::InvalidKey));
+        ctrl.skip_rest();
+        return;
+    }
+    let method = req.method().clone();
+    let path = req.uri().path().to_owned();
+    // Bodies that can't be read are rejected by the handler
+    let Ok(body) = req.payload().await else {
+        return;
+    };
+    let request_hash = hash_request(&method, &path, body);
+    let me = depot.user_id();
+
+    let claim_key = key.clone();
+    let claimed = db::with_transaction(move |conn| {
+        claim(conn, me, claim_key, request_hash)
+    })
+    .await;
+    match claimed {
+        Ok(Claim::New) => {}
+        Ok(Claim::Replay { status, body }) => {
+            tracing::debug!(user_id = me, key, "Replaying idempotent response");
+            let status = u16::try_from(status)
+                .ok()
+                .and_then(|status| StatusCode::from_u16(status).ok())
+                .unwrap_or(StatusCode::OK);
+            res.status_code(status);
+            res.headers_mut().insert(
+                CONTENT_TYPE,
+                HeaderValue::from_static("application/json; charset=utf-8"),
+            );
+            res.headers_mut()
+                .insert(REPLAYED, HeaderValue::from_static("true"));
+            res.body(ResBody::Once(body.into()));
+            ctrl.skip_rest();
+            return;
+        }
+        Err(err) => {
+            res.render(err);
+            ctrl.skip_rest();
+            return;
+        }
+    }
+
+    ctrl.call_next(req, depot, res).await;
+
+    let status = res.status_code.unwrap_or(StatusCode::OK);
+    let response = match &res.body {
+        ResBody::Once(body) if status.is_success() => {
+            Some((i32::from(status.as_u16()), body.to_vec()))
+        }
+        _ => None,
+    };
+    let stored_key = key.clone();
+    let stored = db::with_conn(move |conn| match response {
+        Some((status, body)) => complete(conn, me, &stored_key, status, body),
+        None => release(conn, me, &stored_key),
+    })
+    .await;
+    if let Err(err) = stored {
+        tracing::error!(%err, user_id = me, key, "Failed to store idempotent response");
+    }
+}
+
+/// Reserve `key` for a request, or find the stored response of a retry.
+fn claim(
+    conn: &mut DbConn,
+    target_user: i32,
+    key: String,
+    request_hash: Vec<u8>,
+) -> AppResult<Claim> {
+    let now = chrono::Utc::now().naive_utc();
+    let own_key = idempotency_keys::user_id
+        .eq(target_user)
+        .and(idempotency_keys::key.eq(key.clone()));
+
+    // An expired key is free again, even if the cleanup didn't run yet
+    diesel::delete(
+        idempotency_keys::table.filter(own_key.clone()).filter(
+            idempotency_keys::created_at.le(now - KEY_TTL).or(
+                idempotency_keys::status
+                    .is_null()
+                    .and(idempotency_keys::created_at.le(now - STALE_AFTER)),
+            ),
+        ),
+    )
+    .execute(conn)?;
+    let inserted = diesel::insert_into(idempotency_keys::table)
+        .values(&NewIdempotencyKey {
+            user_id: target_user,
+            key,
+            request_hash: request_hash.clone(),
+            status: None,
+            response_body: None,
+            created_at: now,
+        })
+        .on_conflict_do_nothing()
+        .execute(conn)?;
+    if inserted == 1 {
+        return Ok(Claim::New);
+    }
+
+    let stored: IdempotencyKey = idempotency_keys::table
+        .filter(own_key)
+        .select(IdempotencyKey::as_select())
+        .first(conn)?;
+    if stored.request_hash != request_hash {
+        return Err(IdempotencyError::KeyReused.into());
+    }
+    match (stored.status, stored.response_body) {
+        (Some(status), Some(body)) => Ok(Claim::Replay { status, body }),
+        _ => Err(IdempotencyError::InProgress.into()),
+    }
+}
+
+/// Store the successful response of a claimed key.
+fn complete(
+    conn: &mut DbConn,
+    target_user: i32,
+    key: &str,
+    status: i32,
+    body: Vec<u8>,
+) -> AppResult<()> {
+    diesel::update(
+        idempotency_keys::table
+            .filter(idempotency_keys::user_id.eq(target_user))
+            .filter(idempotency_keys::key.eq(key)),
+    )
+    .set((
+        idempotency_keys::status.eq(status),
+        idempotency_keys::response_body.eq(body),
+    ))
+    .execute(conn)?;
+    Ok(())
+}
+
+/// Free a claimed key after a failed request, so it can be retried.
+fn release(conn: &mut DbConn, target_user: i32, key: &str) -> AppResult<()> {
+    diesel::delete(
+        idempotency_keys::table
+            .filter(idempotency_keys::user_id.eq(target_user))
+            .filter(idempotency_keys::key.eq(key))
+            .filter(idempotency_keys::status.is_null()),
+    )
+    .execute(conn)?;
+    Ok(())
+}
+
+fn delete_expired() -> AppResult<usize> {
+    let cutoff = chrono::Utc::now().naive_utc() - KEY_TTL;
+    let conn = &mut db::get()?;
+    Ok(diesel::delete(
+        idempotency_keys::table.filter(idempotency_keys::created_at.le(cutoff)),
+    )
+    .execute(conn)?)
+}
+
+/// Start the task that deletes expired keys.
+pub fn periodic_cleanup() {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+        loop {
+            interval.tick().await;
+            match tokio::task::spawn_blocking(delete_expired).await {
+                Ok(Ok(0)) => {}
+                Ok(Ok(deleted)) => {
+                    tracing::debug!(
+                        deleted,
+                        "Deleted expired idempotency keys"
+                    );
+                }
+                Ok(Err(err)) => {
+                    tracing::error!(error = ?err, "Failed to delete expired idempotency keys");
+                }
+                Err(err) => {
+                    tracing::error!(error = ?err, "Idempotency key cleanup panicked");
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use salvo::test::{RequestBuilder, ResponseExt, TestClient};
+    use serde_json::{Value, json};
+
+    use super::*;
+    use crate::test_db::{self, Client};
+
+    const KEY: &str = "01JAAAAAAAAAAAAAAAAAAAAAAA";
+
+    fn submit(kills: i32) -> RequestBuilder {
+        TestClient::post("http://localhost/api/game/result")
+            .add_header(IDEMPOTENCY_KEY, KEY, true)
+            .json(&json!({"practice": true, "kills": kills, "timePlayed": 60}))
+    }
+
+    async fn history_len(client: &mut Client, service: &Service) -> usize {
+        let mut res = client
+            .send(
+                TestClient::get("http://localhost/api/game/history"),
+                service,
+            )
+            .await;
+        let body: Value = res.take_json().await.unwrap();
+        body.as_array().unwrap().len()
+    }
+
+    /// Move the stored key of the only user back by `age`.
+    fn age_keys(age: chrono::TimeDelta) {
+        diesel::update(idempotency_keys::table)
+            .set(
+                idempotency_keys::created_at
+                    .eq(chrono::Utc::now().naive_utc() - age),
+            )
+            .execute(&mut db::get().unwrap())
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn retries_replay_the_first_response() {
+        test_db::with_service(|service| async move {
+            let (mut client, _) =
+                Client::register(&service, "ida@example.com", "ida").await;
+            let mut first = client.send(submit(3), &service).await;
+            assert_eq!(first.status_code, Some(StatusCode::OK));
+            assert!(first.headers().get(REPLAYED).is_none());
+            let first_body = first.take_string().await.unwrap();
+            // Practice games don't count for the stats
+            let first_json: Value = serde_json::from_str(&first_body).unwrap();
+            assert!(first_json["stats"].is_null());
+
+            let mut retry = client.send(submit(3), &service).await;
+            assert_eq!(retry.status_code, Some(StatusCode::OK));
+            assert_eq!(retry.headers()[REPLAYED], "true");
+            assert_eq!(retry.take_string().await.unwrap(), first_body);
+            assert_eq!(history_len(&mut client, &service).await, 1);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn reusing_a_key_for_another_body_conflicts() {
+        test_db::with_service(|service| async move {
+            let (mut client, _) =
+                Client::register(&service, "jon@example.com", "jon").await;
+            client.send(submit(3), &service).await;
+            let mut res = client.send(submit(4), &service).await;
+            assert_eq!(res.status_code, Some(StatusCode::CONFLICT));
+            let body: Value = res.take_json().await.unwrap();
+            assert_eq!(body["code"], "key_reused");
+            assert_eq!(history_len(&mut client, &service).await, 1);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn expired_keys_can_be_reused() {
+        test_db::with_service(|service| async move {
+            let (mut client, _) =
+                Client::register(&service, "kai@example.com", "kai").await;
+            client.send(submit(3), &service).await;
+            age_keys(KEY_TTL);
+            let res = client.send(submit(4), &service).await;
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+            assert!(res.headers().get(REPLAYED).is_none());
+            assert_eq!(history_len(&mut client, &service).await, 2);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn stale_claims_are_released() {
+        test_db::with_service(|service| async move {
+            let (mut client, _) =
+                Client::register(&service, "lou@example.com", "lou").await;
+            // As if the request panicked after claiming the key
+            client.send(submit(3), &service).await;
+            diesel::update(idempotency_keys::table)
+                .set((
+                    idempotency_keys::status.eq(None::<i32>),
+                    idempotency_keys::response_body.eq(None::<Vec<u8>>),
+                ))
+                .execute(&mut db::get().unwrap())
+                .unwrap();
+
+            let mut res = client.send(submit(3), &service).await;
+            assert_eq!(res.status_code, Some(StatusCode::CONFLICT));
+            let body: Value = res.take_json().await.unwrap();
+            assert_eq!(body["code"], "in_progress");
+
+            age_keys(STALE_AFTER);
+            let res = client.send(submit(3), &service).await;
+            assert_eq!(res.status_code, Some(StatusCode::OK));
+            assert!(res.headers().get(REPLAYED).is_none());
+        })
+        .await;
+    }
+}
@@ -0,0 +1,233 @@
+//! Sweep of rows whose user no longer exists.
+//!
+//! Foreign keys cascade deletes of users, but only while `foreign_keys` is
+//! on for the connection that deletes, so databases older than that or
+//! edited by hand can have orphaned child rows. The sweep counts them per
+//! table and deletes them in batches of [`BATCH_SIZE`], pausing between
+//! batches so it can run while serving traffic.
+//!
+//! It runs on startup with `maintenance.integrity_sweep`, and on demand
+//! through the admin API.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use diesel::sql_types::BigInt;
+
+use crate::prelude::*;
+
+const BATCH_SIZE: usize = 500;
+/// Pause between batches, to let requests get the database.
+const BATCH_PAUSE: Duration = Duration::from_millis(50);
+
+/// The tables and columns that reference `users.id`.
+///
+/// `user_bans.banned_by` and the players of `game_sessions` are set to null
+/// on delete, a missing admin or opponent is not an orphan.
+const USER_REFERENCES: &[(&str, &str)] = &[
+    ("sessions", "user_id"),
+    ("user_stats", "user_id"),
+    ("game_history", "user_id"),
+    ("two_fa_recovery_codes", "user_id"),
+    ("trusted_devices", "user_id"),
+    ("security_events", "user_id"),
+    ("user_bans", "user_id"),
+    ("idempotency_keys", "user_id"),
+    ("game_invites", "from_user_id"),
+    ("game_invites", "to_user_id"),
+];
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SweepReport {
+    /// Nothing was deleted, the counts are what would be
+    pub dry_run: bool,
+    /// Orphaned rows per table, tables without any are left out
+    pub orphaned_rows: BTreeMap<&'static str, usize>,
+}
+
+#[derive(QueryableByName)]
+struct Count {
+    #[diesel(sql_type = BigInt)]
+    count: i64,
+}
+
+/// Run the sweep in the background if `maintenance.integrity_sweep` is set.
+pub fn sweep_on_startup() {
+    let config = &crate::config::get().maintenance;
+    if !config.integrity_sweep {
+        return;
+    }
+    let dry_run = config.dry_run;
+    tokio::spawn(async move {
+        if let Err(err) = sweep(dry_run).await {
+            tracing::error!(%err, "Integrity sweep failed");
+        }
+    });
+}
+
+/// Count the orphaned rows and, unless `dry_run`, delete them.
+pub async fn sweep(dry_run: bool) -> AppResult<SweepReport> {
+    let mut orphaned_rows = BTreeMap::new();
+    for &(table, column) in USER_REFERENCES {
+        let found = if dry_run {
+            db::with_conn(move |conn| count_orphans(conn, table, column))
+                .await?
+        } else {
+            delete_orphans(table, column).await?
+        };
+        if found > 0 {
+            *orphaned_rows.entry(table).or_default() += found;
+        }
+    }
+
+    let report = SweepReport {
+        dry_run,
+        orphaned_rows,
+    };
+    tracing::info!(
+        report = %serde_json::to_string(&report).unwrap_or_default(),
+        "Integrity sweep finished"
+    );
+    Ok(report)
+}
+
+fn count_orphans(
+    conn: &mut DbConn,
+    table: &str,
+    column: &str,
+) -> AppResult<usize> {
+    let count: Count = diesel::sql_query(format!(
+        "SELECT COUNT(*) AS count FROM {table} \
+         WHERE {column} NOT IN (SELECT id FROM users)"
+    ))
+    .get_result(conn)?;
+    Ok(count.count as usize)
+}
+
+async fn delete_orphans(
+    table: &'static str,
+    column: &'static str,
+) -> AppResult<usize> {
+    let mut deleted = 0;
+    loop {
+        let batch = db::with_conn(move |conn| {
+            Ok(diesel::sql_query(format!(
+                "DELETE FROM {table} WHERE rowid IN (\
+                 SELECT rowid FROM {table} \
+                 WHERE {column} NOT IN (SELECT id FROM users) \
+                 LIMIT {BATCH_SIZE})"
+            ))
+            .execute(conn)?)
+        })
+        .await?;
+        deleted += batch;
+        if batch < BATCH_SIZE {
+            return Ok(deleted);
+        }
+        tokio::time::sleep(BATCH_PAUSE).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use diesel::connection::SimpleConnection as _;
+
+    use super::*;
+    use crate::auth::session_token::SessionToken;
+    use crate::models::NewSession;
+    use crate::routers::game::{PlayerResult, record_game};
+    use crate::test_db;
+
+    /// A session, stats, a game and `events` security events of `user_id`.
+    fn seed_rows(conn: &mut DbConn, user_id: i32, events: usize) {
+        diesel::insert_into(crate::schema::sessions::table)
+            .values(&NewSession::new(
+                user_id,
+                SessionToken::generate().to_hash(),
+                format!("device-{user_id}"),
+                None,
+                None,
+            ))
+            .execute(conn)
+            .unwrap();
+        let result = PlayerResult {
+            kills: 1,
+            deaths: 0,
+            time_played: 30,
+            won: Some(true),
+        };
+        record_game(conn, user_id, result, None).unwrap();
+        conn.batch_execute(&format!(
+            "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n \
+             WHERE i < {events}) \
+             INSERT INTO security_events (user_id, event_type, created_at) \
+             SELECT {user_id}, 'LoginSucceeded', CURRENT_TIMESTAMP FROM n; \
+             INSERT INTO user_stats (user_id, last_game_at) \
+             VALUES ({user_id}, CURRENT_TIMESTAMP)"
+        ))
+        .unwrap();
+    }
+
+    fn rows_of(conn: &mut DbConn, user_id: i32) -> BTreeMap<&'static str, i64> {
+        USER_REFERENCES
+            .iter()
+            .map(|&(table, column)| {
+                let count: Count = diesel::sql_query(format!(
+                    "SELECT COUNT(*) AS count FROM {table} \
+                     WHERE {column} = {user_id}"
+                ))
+                .get_result(conn)
+                .unwrap();
+                (table, count.count)
+            })
+            .filter(|&(_, count)| count > 0)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn orphaned_rows_are_reported_then_deleted() {
+        test_db::with_db(|| async {
+            let conn = &mut db::get().unwrap();
+            let alive = test_db::create_user(conn, "alive").id;
+            let gone = test_db::create_user(conn, "gone").id;
+            seed_rows(conn, alive, 3);
+            seed_rows(conn, gone, BATCH_SIZE + 20);
+            let kept = rows_of(conn, alive);
+            // Without foreign keys the delete doesn't cascade
+            conn.batch_execute(&format!(
+                "PRAGMA foreign_keys = OFF; \
+                 DELETE FROM users WHERE id = {gone}; \
+                 PRAGMA foreign_keys = ON;"
+            ))
+            .unwrap();
+            let orphaned: BTreeMap<_, _> = rows_of(conn, gone)
+                .into_iter()
+                .map(|(table, count)| (table, count as usize))
+                .collect();
+            assert_eq!(
+                orphaned,
+                BTreeMap::from([
+                    ("game_history", 1),
+                    ("security_events", BATCH_SIZE + 20),
+                    ("sessions", 1),
+                    ("user_stats", 1),
+                ])
+            );
+
+            let report = sweep(true).await.unwrap();
+            assert!(report.dry_run);
+            assert_eq!(report.orphaned_rows, orphaned);
+            assert_eq!(rows_of(conn, gone).len(), orphaned.len());
+
+            let report = sweep(false).await.unwrap();
+            assert!(!report.dry_run);
+            assert_eq!(report.orphaned_rows, orphaned);
+            assert!(rows_of(conn, gone).is_empty());
+            assert_eq!(rows_of(conn, alive), kept);
+
+            assert!(sweep(false).await.unwrap().orphaned_rows.is_empty());
+        })
+        .await;
+    }
+}
@@ -0,0 +1,29 @@
+use base64::Engine;
+use base64::engine::general_purpose::{
+    STANDARD as base64std, URL_SAFE_NO_PAD as base64url,
+};
+
+/// Parse a 32-byte key given as hex, base64url (no padding) or standard
+/// base64.
+pub fn parse_32_byte_key(s: &str) -> Option<[u8; 32]> {
+    let trimmed = s.trim();
+
+    // Hex (64 chars)
+    if trimmed.len() == 64
+        && let Ok(bytes) = hex::decode(trimmed)
+        && bytes.len() == 32
+    {
+        return bytes.try_into().ok();
+    }
+
+    // Base64url no pad or standard base64
+    for engine in [base64url, base64std] {
+        if let Ok(bytes) = engine.decode(trimmed.as_bytes())
+            && bytes.len() == 32
+        {
+            return bytes.try_into().ok();
+        }
+    }
+
+    None
+}
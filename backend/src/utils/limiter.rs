@@ -1,9 +1,11 @@
+use std::net::IpAddr;
 use std::sync::Arc;
 use std::sync::atomic::AtomicUsize;
 use std::time::Duration;
 
 use pingora_limits::rate::Rate;
 use salvo::http::StatusCode;
+use salvo::http::header::{HeaderName, HeaderValue, RETRY_AFTER};
 use salvo::{Depot, FlowCtrl, Handler, Request, Response, Router, async_trait};
 
 use crate::auth::DepotAuthExt;
@@ -11,6 +13,11 @@ use crate::auth::DepotAuthExt;
 const RATE_HASHES: usize = 3;
 const RATE_SLOTS: usize = 512;
 
+pub const X_RATELIMIT_LIMIT: HeaderName =
+    HeaderName::from_static("x-ratelimit-limit");
+pub const X_RATELIMIT_REMAINING: HeaderName =
+    HeaderName::from_static("x-ratelimit-remaining");
+
 static RATE_LIMITED_COUNTERS: [AtomicUsize; 8] = [
     AtomicUsize::new(0),
     AtomicUsize::new(0),
@@ -56,6 +63,9 @@ pub trait RouterRateLimitExt {
 pub struct RateLimit {
     rate: Arc<Rate>,
     limit: u32,
+    interval: Duration,
+    /// Mixed into every key, so limits sharing an estimator don't collide.
+    label: &'static str,
 }
 
 impl RateLimit {
@@ -71,9 +81,18 @@ impl RateLimit {
                 RATE_SLOTS,
             )),
             limit,
+            interval,
+            label: "",
         }
     }
 
+    /// Namespace the keys of this limit with a route label.
+    #[must_use]
+    pub fn with_label(mut self, label: &'static str) -> Self {
+        self.label = label;
+        self
+    }
+
     #[must_use]
     pub fn per_minute(limit: u32) -> Self {
         Self::new(limit, Duration::from_secs(60))
@@ -89,27 +108,68 @@ impl RateLimit {
         Self::new(limit, Duration::from_secs(900))
     }
 
+    #[must_use]
+    pub fn per_hour(limit: u32) -> Self {
+        Self::new(limit, Duration::from_secs(3600))
+    }
+
     #[must_use]
     pub fn per_day(limit: u32) -> Self {
         Self::new(limit, Duration::from_secs(86400))
     }
 
+    /// The IP limit of a user limit, 5x as high.
+    ///
+    /// It gets its own estimator, so user ids and IPs don't compete for the
+    /// same sketch slots.
+    fn ip_side(&self) -> Self {
+        Self::new(self.limit * 5, self.interval).with_label(self.label)
+    }
+
     async fn rate_limit<T: std::hash::Hash>(
         &self,
         key: &T,
         res: &mut Response,
         ctrl: &mut FlowCtrl,
     ) {
-        let observed = self.rate.observe(key, 1);
+        let observed = self.rate.observe(&(self.label, key), 1);
+        let limited = observed <= 0 || observed > self.limit as isize;
+        let remaining = if limited {
+            0
+        } else {
+            self.limit - observed as u32
+        };
+        self.set_quota_headers(remaining, res);
 
-        if observed <= 0 || observed > self.limit as isize {
+        if limited {
             RATE_LIMITED_COUNTERS
                 [observed as usize % RATE_LIMITED_COUNTERS.len()]
             .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            // The window boundary is unknown, so be conservative
+            // and report the full interval.
+            res.headers_mut().insert(
+                RETRY_AFTER,
+                HeaderValue::from(self.interval.as_secs()),
+            );
             res.status_code(StatusCode::TOO_MANY_REQUESTS);
             ctrl.cease();
         }
     }
+
+    /// Report the quota of this limit, unless a stricter limit
+    /// on the same route already did.
+    fn set_quota_headers(&self, remaining: u32, res: &mut Response) {
+        let headers = res.headers_mut();
+        let stricter_reported = headers
+            .get(&X_RATELIMIT_REMAINING)
+            .and_then(|v| v.to_str().ok()?.parse::<u32>().ok())
+            .is_some_and(|reported| reported <= remaining);
+        if stricter_reported {
+            return;
+        }
+        headers.insert(X_RATELIMIT_LIMIT, HeaderValue::from(self.limit));
+        headers.insert(X_RATELIMIT_REMAINING, HeaderValue::from(remaining));
+    }
 }
 
 #[derive(Clone)]
@@ -124,10 +184,10 @@ impl Handler for IpRateLimitHoop {
         res: &mut Response,
         ctrl: &mut FlowCtrl,
     ) {
-        let ip = match req.remote_addr() {
-            salvo::conn::SocketAddr::IPv4(a) => a.ip().to_ipv6_mapped(),
-            salvo::conn::SocketAddr::IPv6(a) => *a.ip(),
-            _ => return,
+        let ip = match super::client_ip::client_ip(req) {
+            Some(IpAddr::V4(ip)) => ip.to_ipv6_mapped(),
+            Some(IpAddr::V6(ip)) => ip,
+            None => return,
         };
         self.0.rate_limit(&ip, res, ctrl).await;
     }
@@ -157,9 +217,110 @@ impl RouterRateLimitExt for Router {
 
     fn user_rate_limit(self, quota: &RateLimit) -> Self {
         self.hoop(UserRateLimitHoop(quota.clone()))
-            .hoop(IpRateLimitHoop(RateLimit {
-                rate: Arc::clone(&quota.rate),
-                limit: quota.limit * 5,
-            }))
+            .hoop(IpRateLimitHoop(quota.ip_side()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use salvo::test::{ResponseExt, TestClient};
+    use serde_json::{Value, json};
+
+    use super::*;
+    use crate::test_db;
+
+    /// Count a request of `key` against `quota`, returns whether it passed.
+    async fn passes(quota: &RateLimit, key: u32) -> bool {
+        let mut res = Response::new();
+        let mut ctrl = FlowCtrl::new(Vec::new());
+        quota.rate_limit(&key, &mut res, &mut ctrl).await;
+        !ctrl.is_ceased()
+    }
+
+    fn header(res: &Response, name: impl AsRef<str>) -> Option<&str> {
+        res.headers().get(name.as_ref())?.to_str().ok()
+    }
+
+    #[tokio::test]
+    async fn responses_report_the_quota_and_rejections_retry_after() {
+        test_db::with_service(|service| async move {
+            let login = |n| {
+                TestClient::post("http://localhost/api/auth/login").json(
+                    &json!({
+                        "email": format!("quota-{n}@example.com"),
+                        "password": "wrong password",
+                    }),
+                )
+            };
+            // The login route allows 10 per minute and IP
+            for n in 0..10 {
+                let res = login(n).send(&service).await;
+                assert_eq!(res.status_code, Some(StatusCode::UNAUTHORIZED));
+                assert_eq!(header(&res, X_RATELIMIT_LIMIT), Some("10"));
+                assert_eq!(
+                    header(&res, X_RATELIMIT_REMAINING),
+                    Some((9 - n).to_string().as_str())
+                );
+                assert_eq!(header(&res, RETRY_AFTER), None);
+            }
+
+            let mut res = login(10).send(&service).await;
+            assert_eq!(res.status_code, Some(StatusCode::TOO_MANY_REQUESTS));
+            assert_eq!(header(&res, X_RATELIMIT_LIMIT), Some("10"));
+            assert_eq!(header(&res, X_RATELIMIT_REMAINING), Some("0"));
+            assert_eq!(header(&res, RETRY_AFTER), Some("60"));
+            let body: Value = res.take_json().await.unwrap();
+            assert_eq!(body["code"], "too_many_requests", "{body}");
+            assert_eq!(body["message"], "Too Many Requests");
+            assert!(body["requestId"].is_string(), "{body}");
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn stricter_limits_are_reported() {
+        let strict = RateLimit::per_minute(2);
+        let loose = RateLimit::per_minute(5);
+        let mut res = Response::new();
+        let mut ctrl = FlowCtrl::new(Vec::new());
+        loose.rate_limit(&1, &mut res, &mut ctrl).await;
+        strict.rate_limit(&1, &mut res, &mut ctrl).await;
+        assert_eq!(header(&res, X_RATELIMIT_LIMIT), Some("2"));
+        assert_eq!(header(&res, X_RATELIMIT_REMAINING), Some("1"));
+
+        // Whatever the order
+        let mut res = Response::new();
+        strict.rate_limit(&2, &mut res, &mut ctrl).await;
+        loose.rate_limit(&2, &mut res, &mut ctrl).await;
+        assert_eq!(header(&res, X_RATELIMIT_LIMIT), Some("2"));
+        assert_eq!(header(&res, X_RATELIMIT_REMAINING), Some("1"));
+    }
+
+    #[tokio::test]
+    async fn labels_keep_separate_buckets() {
+        let shared = RateLimit::per_minute(2);
+        let a = shared.clone().with_label("test_a");
+        let b = shared.with_label("test_b");
+        assert!(passes(&a, 1).await);
+        assert!(passes(&a, 1).await);
+        assert!(!passes(&a, 1).await);
+        // Same key and estimator, but another label
+        assert!(passes(&b, 1).await);
+        assert!(passes(&b, 1).await);
+        assert!(!passes(&b, 1).await);
+    }
+
+    #[tokio::test]
+    async fn the_ip_side_of_user_limits_has_its_own_estimator() {
+        let user = RateLimit::per_minute(1);
+        let ip = user.ip_side();
+        assert!(!Arc::ptr_eq(&user.rate, &ip.rate));
+        assert!(passes(&user, 7).await);
+        assert!(!passes(&user, 7).await);
+        // A user id equal to the hashed IP doesn't share its count
+        for _ in 0..5 {
+            assert!(passes(&ip, 7).await);
+        }
+        assert!(!passes(&ip, 7).await);
     }
 }
@@ -5,11 +5,31 @@ use tracing::{Instrument, Level};
 use salvo::http::{Request, ResBody, Response, StatusCode};
 use salvo::{Depot, FlowCtrl, Handler, async_trait};
 
+use super::request_id::DepotRequestIdExt;
+
 /// ----------
 /// Copied from salvo crate with minor modification to check for ctrl-flow deceased state
 /// ----------
 /// A simple logger middleware.
-pub struct Logger;
+///
+/// Requests to the excluded paths (e.g. probes) are neither logged nor
+/// counted in the metrics.
+#[derive(Debug, Default)]
+pub struct Logger {
+    excluded_paths: &'static [&'static str],
+}
+
+impl Logger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pass requests to `paths` through untouched, matched exactly.
+    pub fn excluding(mut self, paths: &'static [&'static str]) -> Self {
+        self.excluded_paths = paths;
+        self
+    }
+}
 
 #[async_trait]
 impl Handler for Logger {
@@ -20,22 +40,23 @@ impl Handler for Logger {
         res: &mut Response,
         ctrl: &mut FlowCtrl,
     ) {
+        if self.excluded_paths.contains(&req.uri().path()) {
+            return;
+        }
         let span = tracing::span!(
             Level::INFO,
             "Request",
             remote_addr = %req.remote_addr().to_string(),
+            client_ip = super::client_ip::client_ip(req).map(tracing::field::display),
             version = ?req.version(),
             method = %req.method(),
             path = %req.uri(),
+            request_id = depot.request_id().unwrap_or_default(),
         );
 
         async move {
             let now = Instant::now();
             ctrl.call_next(req, depot, res).await;
-            // added this check to not log certain requests (like rate-limited requests)
-            if ctrl.is_ceased() {
-                return;
-            }
             let duration = now.elapsed();
 
             let status = res.status_code.unwrap_or(match &res.body {
@@ -43,6 +64,12 @@ impl Handler for Logger {
                 ResBody::Error(e) => e.code,
                 _ => StatusCode::OK,
             });
+            super::metrics::record(req.uri().path(), status, duration);
+
+            // added this check to not log certain requests (like rate-limited requests)
+            if ctrl.is_ceased() {
+                return;
+            }
             if let ResBody::Error(error) = &res.body {
                 tracing::info!(
                     %status,
@@ -62,3 +89,41 @@ impl Handler for Logger {
         .await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use salvo::prelude::*;
+    use salvo::test::TestClient;
+
+    use super::*;
+    use crate::test_db::Captured;
+
+    #[handler]
+    fn ok() -> &'static str {
+        "ok"
+    }
+
+    #[tokio::test]
+    async fn excluded_paths_are_not_logged() {
+        crate::config::init_for_tests();
+        let (captured, _default) = Captured::install();
+        let service = Service::new(
+            Router::new()
+                .hoop(Logger::new().excluding(&["/probe"]))
+                .push(Router::with_path("probe").get(ok))
+                .push(Router::with_path("page").get(ok)),
+        );
+
+        let res = TestClient::get("http://localhost/probe")
+            .send(&service)
+            .await;
+        assert_eq!(res.status_code, Some(StatusCode::OK));
+        assert!(!captured.contains("probe"));
+
+        TestClient::get("http://localhost/page")
+            .send(&service)
+            .await;
+        assert!(captured.contains("path=http://localhost/page"));
+        assert!(!captured.contains("probe"));
+    }
+}
@@ -0,0 +1,447 @@
+//! Lock-free request metrics.
+//!
+//! Requests are counted per route prefix (the first path segment after
+//! `/api/`), per status class and in a fixed-bucket latency histogram.
+//! Recording only touches atomics, so it is cheap enough for every request.
+//! The counters are updated by the [`Logger`](super::logger::Logger) hoop.
+//! The resource gauges are sampled into the same registry by
+//! [`super::gauges`], so `/metrics` is a single scrape target.
+
+use std::fmt::Write as _;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use chrono::NaiveDateTime;
+use salvo::http::StatusCode;
+use salvo::oapi::ToSchema;
+use serde::Serialize;
+
+/// Known route prefixes, everything else is counted as `other`.
+const ROUTES: [&str; 8] = [
+    "auth", "user", "users", "game", "admin", "stream", "wt", "other",
+];
+
+/// Upper bounds (inclusive) of the latency histogram buckets in milliseconds.
+///
+/// Slower requests land in an additional overflow bucket.
+const LATENCY_BUCKETS_MS: [u64; 12] =
+    [1, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+const STATUS_CLASSES: [&str; 5] = ["1xx", "2xx", "3xx", "4xx", "5xx"];
+
+struct RouteMetrics {
+    requests: AtomicU64,
+    status_classes: [AtomicU64; STATUS_CLASSES.len()],
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS_MS.len() + 1],
+    latency_sum_us: AtomicU64,
+}
+
+impl RouteMetrics {
+    const fn new() -> Self {
+        Self {
+            requests: AtomicU64::new(0),
+            status_classes: [const { AtomicU64::new(0) }; STATUS_CLASSES.len()],
+            latency_buckets: [const { AtomicU64::new(0) };
+                LATENCY_BUCKETS_MS.len() + 1],
+            latency_sum_us: AtomicU64::new(0),
+        }
+    }
+}
+
+static METRICS: [RouteMetrics; ROUTES.len()] =
+    [const { RouteMetrics::new() }; ROUTES.len()];
+
+static SESSION_CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+static SESSION_CACHE_MISSES: AtomicU64 = AtomicU64::new(0);
+static SQLITE_BUSY_ERRORS: AtomicU64 = AtomicU64::new(0);
+
+/// The last sample of [`super::gauges`], `None` until the first one.
+static GAUGES: RwLock<Option<GaugeSnapshot>> = RwLock::new(None);
+
+fn route_index(path: &str) -> usize {
+    let segment = path
+        .strip_prefix("/api/")
+        .and_then(|rest| rest.split('/').next())
+        .unwrap_or_default();
+    ROUTES[..ROUTES.len() - 1]
+        .iter()
+        .position(|route| *route == segment)
+        .unwrap_or(ROUTES.len() - 1)
+}
+
+/// Record a finished request.
+pub fn record(path: &str, status: StatusCode, duration: Duration) {
+    let metrics = &METRICS[route_index(path)];
+    metrics.requests.fetch_add(1, Ordering::Relaxed);
+
+    let class = (status.as_u16() / 100).clamp(1, 5) as usize - 1;
+    metrics.status_classes[class].fetch_add(1, Ordering::Relaxed);
+
+    let ms = duration.as_millis() as u64;
+    let bucket = LATENCY_BUCKETS_MS
+        .iter()
+        .position(|bound| ms <= *bound)
+        .unwrap_or(LATENCY_BUCKETS_MS.len());
+    metrics.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    metrics
+        .latency_sum_us
+        .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+}
+
+/// Record a lookup in the [session cache](crate::auth::session_cache).
+pub fn record_session_cache(hit: bool) {
+    let counter = if hit {
+        &SESSION_CACHE_HITS
+    } else {
+        &SESSION_CACHE_MISSES
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Record a query that failed because the database stayed locked for the
+/// whole busy timeout.
+pub fn record_sqlite_busy() {
+    SQLITE_BUSY_ERRORS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Replace the gauges with a new sample.
+pub fn record_gauges(gauges: GaugeSnapshot) {
+    *GAUGES.write().unwrap_or_else(|err| err.into_inner()) = Some(gauges);
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct LatencyBucket {
+    /// Upper bound in milliseconds, `None` for the overflow bucket
+    pub le_ms: Option<u64>,
+    /// Requests in this bucket (not cumulative)
+    pub count: u64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct StatusClassCount {
+    pub class: &'static str,
+    pub count: u64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RouteSnapshot {
+    pub route: &'static str,
+    pub requests: u64,
+    pub status_classes: Vec<StatusClassCount>,
+    pub latency_buckets: Vec<LatencyBucket>,
+    pub latency_sum_ms: f64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheSnapshot {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Resource usage, sampled every 15 seconds by [`super::gauges`].
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct GaugeSnapshot {
+    /// When the gauges were sampled (UTC)
+    pub sampled_at: NaiveDateTime,
+    /// Open database connections, idle or in use
+    pub db_connections: u32,
+    pub db_idle_connections: u32,
+    pub tokio_workers: usize,
+    /// Spawned tasks that haven't finished
+    pub tokio_alive_tasks: usize,
+    /// Users with a registered WebTransport or WebSocket connection
+    pub stream_users: usize,
+    /// Registered connections, pending ones are not included
+    pub stream_connections: usize,
+    pub matchmaking_players: usize,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct MetricsSnapshot {
+    pub routes: Vec<RouteSnapshot>,
+    /// Lookups of authenticated requests in the session cache
+    pub session_cache: CacheSnapshot,
+    /// Queries that failed because the database stayed locked
+    pub sqlite_busy_errors: u64,
+    /// `None` until the gauges were sampled once
+    pub gauges: Option<GaugeSnapshot>,
+}
+
+/// Read all counters.
+///
+/// The counters are read one by one, so the totals of a route can be off by a
+/// few requests that finished while taking the snapshot.
+pub fn snapshot() -> MetricsSnapshot {
+    let routes = ROUTES
+        .iter()
+        .zip(&METRICS)
+        .map(|(route, metrics)| RouteSnapshot {
+            route,
+            requests: metrics.requests.load(Ordering::Relaxed),
+            status_classes: STATUS_CLASSES
+                .iter()
+                .zip(&metrics.status_classes)
+                .map(|(class, count)| StatusClassCount {
+                    class,
+                    count: count.load(Ordering::Relaxed),
+                })
+                .collect(),
+            latency_buckets: metrics
+                .latency_buckets
+                .iter()
+                .enumerate()
+                .map(|(i, count)| LatencyBucket {
+                    le_ms: LATENCY_BUCKETS_MS.get(i).copied(),
+                    count: count.load(Ordering::Relaxed),
+                })
+                .collect(),
+            latency_sum_ms: metrics.latency_sum_us.load(Ordering::Relaxed)
+                as f64
+                / 1000.0,
+        })
+        .collect();
+
+    MetricsSnapshot {
+        routes,
+        session_cache: CacheSnapshot {
+            hits: SESSION_CACHE_HITS.load(Ordering::Relaxed),
+            misses: SESSION_CACHE_MISSES.load(Ordering::Relaxed),
+        },
+        sqlite_busy_errors: SQLITE_BUSY_ERRORS.load(Ordering::Relaxed),
+        gauges: GAUGES.read().unwrap_or_else(|err| err.into_inner()).clone(),
+    }
+}
+
+/// Render a snapshot in the Prometheus text exposition format.
+pub fn render_prometheus(snapshot: &MetricsSnapshot) -> String {
+    let mut out = String::new();
+
+    out.push_str("# TYPE http_requests_total counter\n");
+    for route in &snapshot.routes {
+        for status in &route.status_classes {
+            let _ = writeln!(
+                out,
+                "http_requests_total{{route=\"{}\",status=\"{}\"}} {}",
+                route.route, status.class, status.count
+            );
+        }
+    }
+
+    out.push_str("# TYPE http_request_duration_seconds histogram\n");
+    for route in &snapshot.routes {
+        let mut cumulative = 0;
+        for bucket in &route.latency_buckets {
+            cumulative += bucket.count;
+            let le = match bucket.le_ms {
+                Some(ms) => (ms as f64 / 1000.0).to_string(),
+                None => "+Inf".into(),
+            };
+            let _ = writeln!(
+                out,
+                "http_request_duration_seconds_bucket{{route=\"{}\",le=\"{}\"}} {}",
+                route.route, le, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "http_request_duration_seconds_sum{{route=\"{}\"}} {}",
+            route.route,
+            route.latency_sum_ms / 1000.0
+        );
+        let _ = writeln!(
+            out,
+            "http_request_duration_seconds_count{{route=\"{}\"}} {}",
+            route.route, route.requests
+        );
+    }
+
+    out.push_str("# TYPE session_cache_lookups_total counter\n");
+    let cache = &snapshot.session_cache;
+    for (result, count) in [("hit", cache.hits), ("miss", cache.misses)] {
+        let _ = writeln!(
+            out,
+            "session_cache_lookups_total{{result=\"{result}\"}} {count}"
+        );
+    }
+
+    out.push_str("# TYPE sqlite_busy_errors_total counter\n");
+    let _ = writeln!(
+        out,
+        "sqlite_busy_errors_total {}",
+        snapshot.sqlite_busy_errors
+    );
+
+    let Some(gauges) = &snapshot.gauges else {
+        return out;
+    };
+    let values = [
+        ("db_pool_connections", gauges.db_connections as usize),
+        (
+            "db_pool_idle_connections",
+            gauges.db_idle_connections as usize,
+        ),
+        ("tokio_workers", gauges.tokio_workers),
+        ("tokio_alive_tasks", gauges.tokio_alive_tasks),
+        ("stream_connected_users", gauges.stream_users),
+        ("stream_connections", gauges.stream_connections),
+        ("matchmaking_queue_players", gauges.matchmaking_players),
+    ];
+    for (name, value) in values {
+        let _ = writeln!(out, "# TYPE {name} gauge\n{name} {value}");
+    }
+    // Scrapers compare it with the scrape time to detect stale gauges
+    out.push_str("# TYPE gauges_sampled_timestamp_seconds gauge\n");
+    let sampled_at = gauges.sampled_at.and_utc().timestamp_millis();
+    let _ = writeln!(
+        out,
+        "gauges_sampled_timestamp_seconds {}",
+        sampled_at as f64 / 1000.0
+    );
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use salvo::test::TestClient;
+    use serde_json::Value;
+
+    use super::*;
+    use crate::test_db::{self, Client};
+
+    /// Requests of `route` in `snapshot`, in total and with status `class`.
+    fn counts(snapshot: &Value, route: &str, class: &str) -> (u64, u64) {
+        let route = snapshot["routes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|snapshot| snapshot["route"] == route)
+            .unwrap();
+        let class = route["statusClasses"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|status| status["class"] == class)
+            .unwrap();
+        let buckets: u64 = route["latencyBuckets"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|bucket| bucket["count"].as_u64().unwrap())
+            .sum();
+        let requests = route["requests"].as_u64().unwrap();
+        assert_eq!(buckets, requests, "every request has a latency");
+        (requests, class["count"].as_u64().unwrap())
+    }
+
+    #[test]
+    fn paths_are_counted_by_their_first_segment() {
+        assert_eq!(ROUTES[route_index("/api/users/7/profile")], "users");
+        assert_eq!(ROUTES[route_index("/api/user/me")], "user");
+        assert_eq!(ROUTES[route_index("/api/unknown")], "other");
+        assert_eq!(ROUTES[route_index("/health")], "other");
+    }
+
+    #[tokio::test]
+    async fn responses_are_counted_per_route_and_status() {
+        test_db::with_service(|service| async move {
+            let before = serde_json::to_value(snapshot()).unwrap();
+
+            let (mut client, _) =
+                Client::register(&service, "metrics@example.com", "metrics")
+                    .await;
+            for _ in 0..3 {
+                let res = client
+                    .send(
+                        TestClient::get(
+                            "http://localhost/api/users/999999999/profile",
+                        ),
+                        &service,
+                    )
+                    .await;
+                assert_eq!(res.status_code, Some(StatusCode::NOT_FOUND));
+            }
+            // The operator routes allow 30 requests per minute and IP, so
+            // some are limited even if a new window starts in between
+            let mut limited = 0;
+            for _ in 0..61 {
+                let res = TestClient::get("http://localhost/api/admin/streams")
+                    .send(&service)
+                    .await;
+                if res.status_code == Some(StatusCode::TOO_MANY_REQUESTS) {
+                    limited += 1;
+                }
+            }
+            assert!(limited >= 1);
+
+            // Other tests count requests at the same time
+            let after = serde_json::to_value(snapshot()).unwrap();
+            let (users_before, users_4xx_before) =
+                counts(&before, "users", "4xx");
+            let (users, users_4xx) = counts(&after, "users", "4xx");
+            assert!(users >= users_before + 3);
+            assert!(users_4xx >= users_4xx_before + 3);
+            let (admin_before, admin_4xx_before) =
+                counts(&before, "admin", "4xx");
+            let (admin, admin_4xx) = counts(&after, "admin", "4xx");
+            assert!(admin >= admin_before + 61, "{admin} {admin_before}");
+            assert!(admin_4xx >= admin_4xx_before + 61);
+            let (_, auth_2xx_before) = counts(&before, "auth", "2xx");
+            let (_, auth_2xx) = counts(&after, "auth", "2xx");
+            assert!(auth_2xx > auth_2xx_before, "the registration");
+        })
+        .await;
+    }
+
+    #[test]
+    fn prometheus_buckets_are_cumulative() {
+        let route = |requests| RouteSnapshot {
+            route: "game",
+            requests,
+            status_classes: vec![StatusClassCount {
+                class: "2xx",
+                count: requests,
+            }],
+            latency_buckets: vec![
+                LatencyBucket {
+                    le_ms: Some(1),
+                    count: 2,
+                },
+                LatencyBucket {
+                    le_ms: Some(5),
+                    count: 1,
+                },
+                LatencyBucket {
+                    le_ms: None,
+                    count: 1,
+                },
+            ],
+            latency_sum_ms: 1500.0,
+        };
+        let text = render_prometheus(&MetricsSnapshot {
+            routes: vec![route(4)],
+            session_cache: CacheSnapshot { hits: 3, misses: 1 },
+            sqlite_busy_errors: 0,
+            gauges: None,
+        });
+        for line in [
+            r#"http_requests_total{route="game",status="2xx"} 4"#,
+            r#"http_request_duration_seconds_bucket{route="game",le="0.001"} 2"#,
+            r#"http_request_duration_seconds_bucket{route="game",le="0.005"} 3"#,
+            r#"http_request_duration_seconds_bucket{route="game",le="+Inf"} 4"#,
+            r#"http_request_duration_seconds_sum{route="game"} 1.5"#,
+            r#"http_request_duration_seconds_count{route="game"} 4"#,
+            r#"session_cache_lookups_total{result="hit"} 3"#,
+        ] {
+            assert!(text.lines().any(|l| l == line), "{line} in\n{text}");
+        }
+        assert!(!text.contains("tokio_workers"), "no gauges yet");
+    }
+}
@@ -1,3 +1,15 @@
 pub mod adaptive_buffer;
+pub mod body_limit;
+pub mod client_ip;
+pub mod cors;
+pub mod flags;
+pub mod gauges;
+pub mod idempotency;
+pub mod integrity;
+pub mod keys;
 pub mod limiter;
 pub mod logger;
+pub mod metrics;
+pub mod request_id;
+pub mod stream_limiter;
+pub mod timeout;
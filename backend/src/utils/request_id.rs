@@ -0,0 +1,90 @@
+//! Per-request ids to correlate error responses with server logs.
+//!
+//! The id is a ULID, stored in the Depot and sent back in the
+//! `X-Request-Id` response header.
+
+use salvo::http::header::{HeaderName, HeaderValue};
+use salvo::{Depot, Response, handler};
+use ulid::Ulid;
+
+pub const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+const DEPOT_KEY: &str = "request_id";
+
+pub trait DepotRequestIdExt {
+    /// `None` if no route matched, so [request_id_hoop] didn't run.
+    fn request_id(&self) -> Option<&str>;
+}
+
+impl DepotRequestIdExt for Depot {
+    fn request_id(&self) -> Option<&str> {
+        self.get::<String>(DEPOT_KEY).map(|s| s.as_str()).ok()
+    }
+}
+
+/// Get the id of the current request, assigning one if it has none yet.
+pub fn ensure_request_id(depot: &mut Depot, res: &mut Response) -> String {
+    if let Some(id) = depot.request_id() {
+        return id.to_owned();
+    }
+    let id = Ulid::new().to_string();
+    res.headers_mut().insert(
+        X_REQUEST_ID,
+        HeaderValue::from_str(&id).expect("ULIDs are valid header values"),
+    );
+    depot.insert(DEPOT_KEY, id.clone());
+    id
+}
+
+#[handler]
+pub fn request_id_hoop(depot: &mut Depot, res: &mut Response) {
+    ensure_request_id(depot, res);
+}
+
+#[cfg(test)]
+mod tests {
+    use salvo::http::StatusCode;
+    use salvo::test::{ResponseExt, TestClient};
+    use serde_json::Value;
+
+    use super::*;
+    use crate::test_db;
+
+    fn header(res: &Response) -> String {
+        res.headers()[X_REQUEST_ID].to_str().unwrap().to_owned()
+    }
+
+    #[tokio::test]
+    async fn errors_carry_the_id_of_the_header() {
+        test_db::with_service(|service| async move {
+            let mut res = TestClient::get("http://localhost/api/user/me")
+                .send(&service)
+                .await;
+            assert_eq!(res.status_code, Some(StatusCode::UNAUTHORIZED));
+            let id = header(&res);
+            assert!(id.parse::<Ulid>().is_ok(), "{id}");
+            let body: Value = res.take_json().await.unwrap();
+            assert_eq!(body["requestId"], id.as_str(), "{body}");
+            assert!(body["code"].is_string(), "{body}");
+            assert!(body["message"].is_string(), "{body}");
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests_get_distinct_ids() {
+        test_db::with_service(|service| async move {
+            let request = || {
+                TestClient::get("http://localhost/api/user/me").send(&service)
+            };
+            let (mut first, mut second) = tokio::join!(request(), request());
+            let ids = [header(&first), header(&second)];
+            assert_ne!(ids[0], ids[1]);
+            for (res, id) in [(&mut first, &ids[0]), (&mut second, &ids[1])] {
+                let body: Value = res.take_json().await.unwrap();
+                assert_eq!(body["requestId"], id.as_str(), "{body}");
+            }
+        })
+        .await;
+    }
+}
@@ -0,0 +1,143 @@
+//! Rate limits of the messages a client sends on an open stream.
+//!
+//! REST requests are limited by [`super::limiter`] before a handler runs,
+//! messages on a stream by a [`StreamLimiter`] owned by the task reading
+//! them. It is a token bucket: every message takes a token and tokens refill
+//! at a steady rate up to the capacity, so short bursts pass. A client that
+//! keeps sending over the limit is [`Admission::Abusive`] and should lose the
+//! stream.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Limited messages within [`ABUSE_WINDOW`] that make a client abusive.
+const ABUSE_HITS: usize = 3;
+const ABUSE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Burst size and sustained rate of a [`StreamLimiter`].
+#[derive(Debug, Clone, Copy)]
+pub struct StreamRate {
+    capacity: u32,
+    per_second: u32,
+}
+
+impl StreamRate {
+    /// Bursts of up to `capacity` messages, `per_second` on average.
+    pub const fn new(capacity: u32, per_second: u32) -> Self {
+        assert!(capacity > 0 && per_second > 0);
+        Self {
+            capacity,
+            per_second,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    Allowed,
+    /// Drop the message, the next one is allowed after `retry_after`
+    Limited {
+        retry_after: Duration,
+    },
+    /// The client was limited repeatedly, close the stream
+    Abusive,
+}
+
+#[derive(Debug)]
+pub struct StreamLimiter {
+    rate: StreamRate,
+    tokens: f64,
+    refilled_at: Instant,
+    /// When recent messages were limited, oldest first
+    hits: VecDeque<Instant>,
+}
+
+impl StreamLimiter {
+    pub fn new(rate: StreamRate) -> Self {
+        Self {
+            rate,
+            tokens: f64::from(rate.capacity),
+            refilled_at: Instant::now(),
+            hits: VecDeque::with_capacity(ABUSE_HITS),
+        }
+    }
+
+    /// Take a token for a received message.
+    pub fn check(&mut self) -> Admission {
+        let now = Instant::now();
+        let per_second = f64::from(self.rate.per_second);
+        let refilled =
+            now.duration_since(self.refilled_at).as_secs_f64() * per_second;
+        self.tokens =
+            (self.tokens + refilled).min(f64::from(self.rate.capacity));
+        self.refilled_at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Admission::Allowed;
+        }
+
+        while self
+            .hits
+            .front()
+            .is_some_and(|&hit| now.duration_since(hit) >= ABUSE_WINDOW)
+        {
+            self.hits.pop_front();
+        }
+        self.hits.push_back(now);
+        if self.hits.len() >= ABUSE_HITS {
+            return Admission::Abusive;
+        }
+        Admission::Limited {
+            retry_after: Duration::from_secs_f64(
+                (1.0 - self.tokens) / per_second,
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: StreamRate = StreamRate::new(3, 10);
+
+    #[tokio::test(start_paused = true)]
+    async fn bursts_over_the_capacity_wait_for_the_refill() {
+        let mut limiter = StreamLimiter::new(RATE);
+        for _ in 0..3 {
+            assert_eq!(limiter.check(), Admission::Allowed);
+        }
+        let limited = Admission::Limited {
+            retry_after: Duration::from_millis(100),
+        };
+        assert_eq!(limiter.check(), limited);
+
+        tokio::time::advance(Duration::from_millis(100)).await;
+        assert_eq!(limiter.check(), Admission::Allowed);
+        assert_eq!(limiter.check(), limited);
+
+        // Idle time refills up to the capacity only
+        tokio::time::advance(Duration::from_secs(60)).await;
+        for _ in 0..3 {
+            assert_eq!(limiter.check(), Admission::Allowed);
+        }
+        assert!(matches!(limiter.check(), Admission::Limited { .. }));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn repeated_limits_within_a_minute_are_abusive() {
+        let mut limiter = StreamLimiter::new(StreamRate::new(1, 1));
+        assert_eq!(limiter.check(), Admission::Allowed);
+        assert!(matches!(limiter.check(), Admission::Limited { .. }));
+        assert!(matches!(limiter.check(), Admission::Limited { .. }));
+
+        // The first two hits are forgotten after the window
+        tokio::time::advance(ABUSE_WINDOW).await;
+        assert_eq!(limiter.check(), Admission::Allowed);
+        assert!(matches!(limiter.check(), Admission::Limited { .. }));
+        assert!(matches!(limiter.check(), Admission::Limited { .. }));
+        assert_eq!(limiter.check(), Admission::Abusive);
+    }
+}
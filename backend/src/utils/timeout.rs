@@ -0,0 +1,187 @@
+//! Time limit of requests.
+//!
+//! [`RequestTimeout`] sits on the outermost router of a group of routes and
+//! ends requests that take longer than its default with 503. Routes further
+//! down change their own limit with [`RouterTimeoutExt`]. Their hoops run
+//! inside of [`RequestTimeout`], so they can't wrap the handler in a longer
+//! timeout of their own and move the shared deadline instead.
+
+use std::time::Duration;
+
+use salvo::http::headers::{Connection, HeaderMapExt};
+use salvo::prelude::*;
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+/// The deadline of the current request, `None` if it has none.
+struct Deadline {
+    started: Instant,
+    deadline: watch::Sender<Option<Instant>>,
+}
+
+/// Ends requests that aren't handled after the given time.
+///
+/// Only covers the handlers, a body streamed after they returned isn't
+/// limited.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestTimeout(pub Duration);
+
+#[async_trait]
+impl Handler for RequestTimeout {
+    async fn handle(
+        &self,
+        req: &mut Request,
+        depot: &mut Depot,
+        res: &mut Response,
+        ctrl: &mut FlowCtrl,
+    ) {
+        let started = Instant::now();
+        let (deadline, mut deadline_rx) =
+            watch::channel(Some(started + self.0));
+        depot.inject(Deadline { started, deadline });
+
+        let timed_out = {
+            let handled = ctrl.call_next(req, depot, res);
+            tokio::pin!(handled);
+            loop {
+                let deadline = *deadline_rx.borrow_and_update();
+                let expired = async {
+                    match deadline {
+                        Some(deadline) => {
+                            tokio::time::sleep_until(deadline).await
+                        }
+                        None => std::future::pending().await,
+                    }
+                };
+                tokio::select! {
+                    _ = &mut handled => break false,
+                    // The sender lives in the depot until the request is done
+                    _ = deadline_rx.changed() => {}
+                    () = expired => break true,
+                }
+            }
+        };
+        if timed_out {
+            tracing::warn!("Request timed out");
+            // Like salvo's Timeout: 503 instead of 408, which browsers retry
+            res.headers_mut().typed_insert(Connection::close());
+            res.render(
+                StatusError::service_unavailable()
+                    .brief("The request took too long"),
+            );
+            ctrl.skip_rest();
+        }
+    }
+}
+
+/// Replaces the limit of the [`RequestTimeout`] further up, counted from the
+/// start of the request.
+#[derive(Debug, Clone, Copy)]
+struct TimeoutOverride(Option<Duration>);
+
+#[async_trait]
+impl Handler for TimeoutOverride {
+    async fn handle(
+        &self,
+        _req: &mut Request,
+        depot: &mut Depot,
+        _res: &mut Response,
+        _ctrl: &mut FlowCtrl,
+    ) {
+        if let Ok(Deadline { started, deadline }) = depot.obtain::<Deadline>() {
+            deadline.send_replace(self.0.map(|limit| *started + limit));
+        }
+    }
+}
+
+pub trait RouterTimeoutExt {
+    /// Allow requests to take `limit` instead of the default.
+    fn with_timeout(self, limit: Duration) -> Self;
+    /// Allow requests to take as long as they need, e.g. for connections
+    /// that stay open.
+    fn no_timeout(self) -> Self;
+}
+
+impl RouterTimeoutExt for Router {
+    fn with_timeout(self, limit: Duration) -> Self {
+        self.hoop(TimeoutOverride(Some(limit)))
+    }
+
+    fn no_timeout(self) -> Self {
+        self.hoop(TimeoutOverride(None))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use salvo::http::header::CONNECTION;
+    use salvo::test::TestClient;
+
+    use super::*;
+
+    #[handler]
+    async fn sleep_2s() -> &'static str {
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        "done"
+    }
+
+    async fn get(service: &Service, path: &str) -> Response {
+        TestClient::get(format!("http://localhost/{path}"))
+            .send(service)
+            .await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn routes_can_change_the_default_limit() {
+        let service = Service::new(
+            Router::new()
+                .hoop(RequestTimeout(Duration::from_secs(1)))
+                .push(Router::with_path("default").get(sleep_2s))
+                .push(
+                    Router::with_path("longer")
+                        .with_timeout(Duration::from_secs(3))
+                        .get(sleep_2s),
+                )
+                .push(
+                    Router::with_path("shorter")
+                        .with_timeout(Duration::from_millis(1500))
+                        .push(
+                            Router::with_path("nested")
+                                .no_timeout()
+                                .get(sleep_2s),
+                        )
+                        .get(sleep_2s),
+                )
+                .push(Router::with_path("exempt").no_timeout().get(sleep_2s)),
+        );
+
+        let res = get(&service, "default").await;
+        assert_eq!(res.status_code, Some(StatusCode::SERVICE_UNAVAILABLE));
+        assert_eq!(res.headers()[CONNECTION], "close");
+        let res = get(&service, "shorter").await;
+        assert_eq!(res.status_code, Some(StatusCode::SERVICE_UNAVAILABLE));
+        for path in ["longer", "exempt", "shorter/nested"] {
+            let res = get(&service, path).await;
+            assert_eq!(res.status_code, Some(StatusCode::OK), "{path}");
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timeouts_count_from_the_start_of_the_request() {
+        #[handler]
+        async fn wait_1s() {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+
+        // The hoop before the override already used up most of the time
+        let service = Service::new(
+            Router::new()
+                .hoop(RequestTimeout(Duration::from_secs(10)))
+                .hoop(wait_1s)
+                .with_timeout(Duration::from_millis(2500))
+                .get(sleep_2s),
+        );
+        let res = get(&service, "").await;
+        assert_eq!(res.status_code, Some(StatusCode::SERVICE_UNAVAILABLE));
+    }
+}
@@ -1,6 +1,21 @@
 use std::borrow::Cow;
+use std::collections::HashSet;
+use std::sync::LazyLock;
 
-use validator::ValidationError;
+use validator::{ValidationError, ValidationErrors};
+
+/// Passwords shorter than this need at least two character classes.
+const PASSPHRASE_LEN: usize = 16;
+
+/// Lowercase list of the 10 000 most common passwords and their usual
+/// variations, one per line. Only entries that pass the length check are
+/// included.
+static COMMON_PASSWORDS: LazyLock<HashSet<&'static str>> =
+    LazyLock::new(|| {
+        include_str!("validate/common_passwords.txt")
+            .lines()
+            .collect()
+    });
 
 pub fn nickname(nickname: &str) -> Result<(), ValidationError> {
     let len = nickname.len();
@@ -9,7 +24,7 @@ pub fn nickname(nickname: &str) -> Result<(), ValidationError> {
         ValidationError::new("trim").with_message(Cow::Borrowed(
             "Must not have leading or trailing whitespace.",
         ))
-    } else if len < 3 || len > 16 {
+    } else if !(3..=16).contains(&len) {
         ValidationError::new("length").with_message(Cow::Borrowed(
             "Must be between 3 and 16 characters long.",
         ))
@@ -29,15 +44,177 @@ pub fn nickname(nickname: &str) -> Result<(), ValidationError> {
     Err(err)
 }
 
+/// A name for a Session picked by the user, after trimming it.
+pub fn device_name(name: &str) -> Result<(), ValidationError> {
+    let err = if !(1..=48).contains(&name.chars().count()) {
+        ValidationError::new("length").with_message(Cow::Borrowed(
+            "Must be between 1 and 48 characters long.",
+        ))
+    } else if name.chars().any(char::is_control) {
+        ValidationError::new("invalid_chars")
+            .with_message(Cow::Borrowed("Must not contain control characters."))
+    } else {
+        return Ok(());
+    };
+    Err(err)
+}
+
 pub fn password(password: &str) -> Result<(), ValidationError> {
     let len = password.len();
 
-    if len < 8 || len > 128 {
-        let err = ValidationError::new("length").with_message(Cow::Borrowed(
+    let err = if !(8..=128).contains(&len) {
+        ValidationError::new("length").with_message(Cow::Borrowed(
             "Must be between 8 and 128 characters long.",
+        ))
+    } else if COMMON_PASSWORDS.contains(password.to_lowercase().as_str()) {
+        ValidationError::new("common").with_message(Cow::Borrowed(
+            "Must not be a commonly used password.",
+        ))
+    } else if password.chars().count() < PASSPHRASE_LEN
+        && character_classes(password) < 2
+    {
+        ValidationError::new("character_classes").with_message(Cow::Borrowed(
+            "Must contain at least two of lowercase letters, uppercase letters, \
+             digits and symbols, or be at least 16 characters long.",
+        ))
+    } else {
+        return Ok(());
+    };
+    Err(err)
+}
+
+/// Same as [password], but also rejects passwords equal to one of `context`
+/// (ignoring case), e.g. the nickname or the local-part of the email.
+pub fn password_with_context(
+    password: &str,
+    context: &[&str],
+) -> Result<(), ValidationError> {
+    self::password(password)?;
+
+    if context
+        .iter()
+        .any(|value| !value.is_empty() && value.eq_ignore_ascii_case(password))
+    {
+        return Err(ValidationError::new("context").with_message(
+            Cow::Borrowed("Must not be your nickname or email address."),
         ));
-        return Err(err);
     }
-
     Ok(())
 }
+
+/// Trim an email address and lowercase its domain.
+///
+/// The local-part keeps its case, `users.email` is compared
+/// case-insensitively anyway.
+pub fn normalize_email(email: &str) -> String {
+    let email = email.trim();
+    match email.rsplit_once('@') {
+        Some((local, domain)) => format!("{local}@{}", domain.to_lowercase()),
+        None => email.to_owned(),
+    }
+}
+
+/// Rejects addresses of a domain in `auth.blocked_email_domains_file` or a
+/// subdomain of one. Expects a [normalized](normalize_email) address.
+pub fn email_domain(email: &str) -> Result<(), ValidationError> {
+    let blocked = &crate::config::get().auth.blocked_email_domains;
+    let Some((_, mut domain)) = email.rsplit_once('@') else {
+        return Ok(());
+    };
+    loop {
+        if blocked.contains(domain) {
+            return Err(ValidationError::new("blocked_domain").with_message(
+                Cow::Borrowed("Disposable email addresses are not allowed."),
+            ));
+        }
+        match domain.split_once('.') {
+            Some((_, parent)) => domain = parent,
+            None => return Ok(()),
+        }
+    }
+}
+
+/// The local-part of an email address, for [password_with_context].
+pub fn email_local_part(email: &str) -> &str {
+    email.rsplit_once('@').map_or(email, |(local, _)| local)
+}
+
+/// Add the result of a check that needs context the derived
+/// `Validate::validate` does not have, so all errors are reported together.
+pub fn with_field_check(
+    result: Result<(), ValidationErrors>,
+    field: &'static str,
+    check: Result<(), ValidationError>,
+) -> Result<(), ValidationErrors> {
+    let Err(err) = check else {
+        return result;
+    };
+    let mut errs = result.err().unwrap_or_default();
+    errs.add(field, err);
+    Err(errs)
+}
+
+/// Number of classes among lowercase, uppercase, digits and symbols.
+fn character_classes(password: &str) -> usize {
+    let mut classes = [false; 4];
+    for c in password.chars() {
+        let class = if c.is_lowercase() {
+            0
+        } else if c.is_uppercase() {
+            1
+        } else if c.is_numeric() {
+            2
+        } else {
+            3
+        };
+        classes[class] = true;
+    }
+    classes.into_iter().filter(|&class| class).count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn code(result: Result<(), ValidationError>) -> Option<String> {
+        result.err().map(|err| err.code.into_owned())
+    }
+
+    #[test]
+    fn common_passwords_are_rejected_ignoring_case() {
+        assert_eq!(COMMON_PASSWORDS.len(), 10_000);
+        for common in ["password123", "Password123", "QWERTYUIOP", "p@ssw0rd"] {
+            assert_eq!(code(password(common)).as_deref(), Some("common"));
+        }
+        assert_eq!(code(password("correct horse battery staple")), None);
+    }
+
+    #[test]
+    fn short_passwords_need_two_character_classes() {
+        assert_eq!(
+            code(password("kxqzvbwm")).as_deref(),
+            Some("character_classes")
+        );
+        assert_eq!(code(password("kxqzvbw7")), None);
+        assert_eq!(code(password("KxqzvbwM")), None);
+        // Long passphrases may use a single class
+        assert_eq!(code(password("kxqzvbwmkxqzvbwm")), None);
+    }
+
+    #[test]
+    fn passwords_must_not_match_the_context() {
+        let email = "Zorro.Fan@example.com";
+        let context = ["zorrotheking", email_local_part(email)];
+        assert_eq!(
+            code(password_with_context("ZorroTheKing", &context)).as_deref(),
+            Some("context")
+        );
+        assert_eq!(
+            code(password_with_context("zorro.fan", &context)).as_deref(),
+            Some("context")
+        );
+        assert_eq!(code(password_with_context("zorro.fan!", &context)), None);
+        // Empty context values don't match anything
+        assert_eq!(code(password_with_context("zorro.fan!", &[""])), None);
+    }
+}
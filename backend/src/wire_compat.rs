@@ -0,0 +1,571 @@
+//! Golden tests of the wire format.
+//!
+//! Response bodies and stream messages are serialized and compared with the
+//! fixtures in `fixtures/wire`, so renaming a field or changing how an enum is
+//! tagged fails a test instead of silently breaking the client. JSON fixtures
+//! are compared by value, the CBOR fixtures of stream messages byte for byte.
+//! Messages the server only receives are decoded from their fixture instead.
+//!
+//! After an intended change of the wire format, run the tests with
+//! `UPDATE_GOLDEN=1` to rewrite the fixtures of serialized types and review
+//! their diff. Types private to a module get their [`golden!`] tests there.
+
+use std::path::PathBuf;
+
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::models::User;
+
+/// Whether the fixtures are rewritten instead of compared.
+fn update() -> bool {
+    std::env::var_os("UPDATE_GOLDEN").is_some()
+}
+
+fn fixture(name: &str, extension: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("fixtures/wire")
+        .join(name)
+        .with_extension(extension)
+}
+
+fn read_fixture(name: &str, extension: &str) -> Vec<u8> {
+    let path = fixture(name, extension);
+    std::fs::read(&path).unwrap_or_else(|err| {
+        panic!(
+            "can't read {}: {err}, run with UPDATE_GOLDEN=1 to create it",
+            path.display()
+        )
+    })
+}
+
+fn write_fixture(name: &str, extension: &str, content: &[u8]) {
+    let path = fixture(name, extension);
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(&path, content).unwrap();
+}
+
+/// Check that `item` serializes to the JSON of the fixture `name`.
+pub fn assert_json(name: &str, item: &impl Serialize) {
+    let actual = serde_json::to_value(item).expect("item should serialize");
+    if update() {
+        let mut json = serde_json::to_string_pretty(&actual).unwrap();
+        json.push('\n');
+        write_fixture(name, "json", json.as_bytes());
+        return;
+    }
+    let golden: Value = serde_json::from_slice(&read_fixture(name, "json"))
+        .expect("the fixture should be JSON");
+    assert_eq!(actual, golden, "{name} doesn't match its fixture");
+}
+
+/// Check that `item` serializes to exactly the CBOR of the fixture `name`.
+pub fn assert_cbor(name: &str, item: &impl Serialize) {
+    let mut actual = Vec::new();
+    ciborium::into_writer(item, &mut actual).expect("item should serialize");
+    if update() {
+        write_fixture(name, "cbor", &actual);
+        return;
+    }
+    let golden = read_fixture(name, "cbor");
+    assert!(
+        actual == golden,
+        "{name} doesn't match its fixture:\n  actual {}\n  golden {}",
+        hex::encode(&actual),
+        hex::encode(&golden)
+    );
+}
+
+/// Decode the JSON fixture `name`, and its CBOR encoding, as a `T`.
+pub fn decode<T: DeserializeOwned>(name: &str) -> T {
+    let json: Value = serde_json::from_slice(&read_fixture(name, "json"))
+        .expect("the fixture should be JSON");
+    let mut cbor = Vec::new();
+    ciborium::into_writer(&json, &mut cbor).unwrap();
+    ciborium::from_reader::<T, _>(cbor.as_slice())
+        .unwrap_or_else(|err| panic!("{name} doesn't decode as CBOR: {err}"));
+    serde_json::from_value(json)
+        .unwrap_or_else(|err| panic!("{name} doesn't decode: {err}"))
+}
+
+/// Golden tests named after their fixture.
+///
+/// - `name => value` compares the JSON of `value`, e.g. of a response body
+/// - `cbor name => value` also compares its CBOR, for stream messages
+/// - `decode name => Type, pattern` decodes the fixture as a `Type` that has
+///   to match `pattern`, for messages from the client
+macro_rules! golden {
+    () => {};
+    ($name:ident => $value:expr $(, $($rest:tt)*)?) => {
+        #[test]
+        fn $name() {
+            $crate::wire_compat::assert_json(stringify!($name), &$value);
+        }
+        $crate::wire_compat::golden!($($($rest)*)?);
+    };
+    (cbor $name:ident => $value:expr $(, $($rest:tt)*)?) => {
+        #[test]
+        fn $name() {
+            let value = $value;
+            $crate::wire_compat::assert_json(stringify!($name), &value);
+            $crate::wire_compat::assert_cbor(stringify!($name), &value);
+        }
+        $crate::wire_compat::golden!($($($rest)*)?);
+    };
+    (decode $name:ident => $type:ty, $pattern:pat $(, $($rest:tt)*)?) => {
+        #[test]
+        fn $name() {
+            let decoded: $type = $crate::wire_compat::decode(stringify!($name));
+            assert!(matches!(decoded, $pattern), "{decoded:?}");
+        }
+        $crate::wire_compat::golden!($($($rest)*)?);
+    };
+}
+pub(crate) use golden;
+
+/// The time of every timestamp in the fixtures.
+pub fn timestamp() -> NaiveDateTime {
+    chrono::NaiveDate::from_ymd_opt(2024, 5, 17)
+        .unwrap()
+        .and_hms_opt(12, 30, 0)
+        .unwrap()
+}
+
+/// A user, for the types that contain one.
+pub fn sample_user() -> User {
+    User {
+        id: 7,
+        email: "ann@example.com".into(),
+        nickname: "ann".into(),
+        totp_enabled: true,
+        totp_secret_enc: Some("never sent".into()),
+        totp_confirmed_at: Some(timestamp()),
+        password_hash: "never sent".into(),
+        created_at: timestamp(),
+        is_admin: false,
+        last_seen: Some(timestamp()),
+        locked_at: None,
+        lock_reason: None,
+    }
+}
+
+/// Property names of the schemas in the OpenAPI document that aren't
+/// camelCase.
+fn snake_case_properties(schema: &Value, path: &str, found: &mut Vec<String>) {
+    match schema {
+        Value::Object(object) => {
+            if let Some(Value::Object(properties)) = object.get("properties") {
+                found.extend(
+                    properties
+                        .keys()
+                        .filter(|name| name.contains('_'))
+                        .map(|name| format!("{path}.{name}")),
+                );
+            }
+            for (key, value) in object {
+                snake_case_properties(value, &format!("{path}.{key}"), found);
+            }
+        }
+        Value::Array(items) => {
+            for (index, item) in items.iter().enumerate() {
+                snake_case_properties(item, &format!("{path}[{index}]"), found);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[tokio::test]
+async fn openapi_schemas_are_camel_case() {
+    use salvo::test::{ResponseExt, TestClient};
+
+    crate::test_db::with_service(|service| async move {
+        let mut res = TestClient::get("http://localhost/api-doc/openapi.json")
+            .send(&service)
+            .await;
+        let doc: Value = res.take_json().await.unwrap();
+        let schemas = &doc["components"]["schemas"];
+        let session = &schemas["transcendence_backend.auth.user.SessionInfo"];
+        assert!(session["properties"]["sessionId"].is_object());
+
+        let mut found = Vec::new();
+        snake_case_properties(schemas, "schemas", &mut found);
+        assert!(found.is_empty(), "not camelCase: {found:#?}");
+    })
+    .await;
+}
+
+/// Collect the targets of the `$ref`s in `value`.
+fn references<'a>(value: &'a Value, found: &mut Vec<&'a str>) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                match value {
+                    Value::String(target) if key == "$ref" => {
+                        found.push(target)
+                    }
+                    _ => references(value, found),
+                }
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                references(item, found);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[tokio::test]
+async fn openapi_references_resolve() {
+    use salvo::test::{ResponseExt, TestClient};
+
+    crate::test_db::with_service(|service| async move {
+        let mut res = TestClient::get("http://localhost/api-doc/openapi.json")
+            .send(&service)
+            .await;
+        let doc: Value = res.take_json().await.unwrap();
+        let schemas = doc["components"]["schemas"].as_object().unwrap();
+
+        let mut found = Vec::new();
+        references(&doc, &mut found);
+        assert!(!found.is_empty());
+        let missing: Vec<_> = found
+            .into_iter()
+            .filter(|target| {
+                target
+                    .strip_prefix("#/components/schemas/")
+                    .is_none_or(|name| !schemas.contains_key(name))
+            })
+            .collect();
+        assert!(missing.is_empty(), "unresolved: {missing:#?}");
+
+        for name in [
+            "transcendence_backend.error.ErrorResponse",
+            "transcendence_backend.routers.stream.BindInput",
+            "transcendence_backend.auth.notification_counts.NotificationCounts",
+            "transcendence_backend.routers.users.PublicUser",
+        ] {
+            assert!(schemas.contains_key(name), "{name}");
+        }
+
+        // The WebTransport endpoint documents its responses and security
+        let wt = &doc["paths"]["/api/wt"];
+        let connect = wt.as_object().unwrap().values().next().unwrap();
+        assert!(connect["responses"]["200"].is_object(), "{wt}");
+        assert!(connect["responses"]["400"].is_object(), "{wt}");
+        assert!(connect["security"].is_array(), "{wt}");
+    })
+    .await;
+}
+
+mod rest {
+    use std::collections::BTreeMap;
+
+    use serde_json::json;
+
+    use super::{sample_user, timestamp};
+    use crate::auth::audit::SecurityEventInfo;
+    use crate::auth::notification_counts::NotificationCounts;
+    use crate::error::ErrorResponse;
+    use crate::models::*;
+    use crate::routers::game::{GameResultOutput, LeaderboardEntry};
+    use crate::routers::users::{ProfileStats, PublicProfile, PublicUser};
+    use crate::stream::ConnectionStats;
+    use crate::utils::integrity::SweepReport;
+    use crate::utils::metrics::*;
+
+    fn sample_game_history() -> GameHistory {
+        GameHistory {
+            id: 3,
+            user_id: 7,
+            kills: 5,
+            time_played: 120,
+            played_at: timestamp(),
+            game_session_id: Some(11),
+            verified: true,
+            reported_kills: None,
+            reported_time_played: None,
+            deaths: 2,
+            won: true,
+        }
+    }
+
+    fn sample_user_stats() -> UserStats {
+        UserStats {
+            user_id: 7,
+            games_played: 4,
+            total_kills: 20,
+            total_time_played: 480,
+            last_game_kills: 5,
+            last_game_time_played: 120,
+            last_game_at: timestamp(),
+            wins: 3,
+            losses: 1,
+            total_deaths: 6,
+        }
+    }
+
+    super::golden! {
+        user => sample_user(),
+        game_history => sample_game_history(),
+        user_stats => sample_user_stats(),
+        user_ban => UserBan {
+            id: 2,
+            user_id: 7,
+            banned_by: Some(1),
+            reason: "spam".into(),
+            until: Some(timestamp()),
+            created_at: timestamp(),
+            lifted_at: None,
+        },
+        game_invite => GameInvite {
+            id: 9,
+            from_user_id: 7,
+            to_user_id: 8,
+            status: InviteStatus::Pending,
+            created_at: timestamp(),
+            expires_at: timestamp(),
+        },
+        invite_status => [
+            InviteStatus::Pending,
+            InviteStatus::Accepted,
+            InviteStatus::Declined,
+            InviteStatus::Expired,
+        ],
+        game_session => GameSession {
+            id: 11,
+            player_a: Some(7),
+            player_b: Some(8),
+            state: GameSessionState::Running,
+            created_at: timestamp(),
+        },
+        game_session_state => [
+            GameSessionState::Created,
+            GameSessionState::Running,
+            GameSessionState::Finished,
+        ],
+        error_response => ErrorResponse {
+            code: "not_found".into(),
+            message: "Not found".into(),
+            request_id: "01HXYZ".into(),
+            fields: None,
+        },
+        validation_error_response => ErrorResponse {
+            code: "validation".into(),
+            message: "Invalid input".into(),
+            request_id: "01HXYZ".into(),
+            fields: Some(BTreeMap::from([(
+                "items[0].displayName".into(),
+                vec!["too long".into()],
+            )])),
+        },
+        security_event_info => SecurityEventInfo {
+            id: 4,
+            event_type: "LoginSucceeded".into(),
+            metadata: Some(json!({ "trusted_device": false })),
+            ip_address: Some("192.0.2.1".into()),
+            device_name: Some("Firefox on Linux".into()),
+            created_at: timestamp(),
+        },
+        notification_counts => NotificationCounts {
+            pending_game_invites: 2,
+        },
+        game_result_output => GameResultOutput {
+            game: sample_game_history(),
+            stats: Some(sample_user_stats()),
+        },
+        leaderboard_entry => LeaderboardEntry {
+            rank: 1,
+            user_id: 7,
+            nickname: "ann".into(),
+            games_played: 4,
+            wins: 3,
+            losses: 1,
+            win_rate: 0.75,
+            total_kills: 20,
+            total_deaths: 6,
+            total_time_played: 480,
+        },
+        public_user => PublicUser {
+            id: 7,
+            nickname: "ann".into(),
+            created_at: timestamp(),
+            online: true,
+            last_seen: None,
+        },
+        public_profile => PublicProfile {
+            id: 7,
+            nickname: "ann".into(),
+            created_at: timestamp(),
+            online: false,
+            last_seen: Some(timestamp()),
+            stats: ProfileStats {
+                games_played: 4,
+                wins: 3,
+                losses: 1,
+                total_kills: 20,
+                total_deaths: 6,
+                total_time_played: 480,
+            },
+            recent_games: vec![sample_game_history()],
+        },
+        connection_stats => ConnectionStats {
+            user_id: 7,
+            connection_id: 42,
+            session_id: 5,
+            connected_secs: 90,
+            latency_ms: Some(12),
+            protocol_version: 2,
+            streams_opened: 3,
+            streams_accepted: 1,
+            bytes_sent: 4096,
+            bytes_received: 512,
+        },
+        sweep_report => SweepReport {
+            dry_run: true,
+            orphaned_rows: BTreeMap::from([("game_history", 2)]),
+        },
+        metrics_snapshot => MetricsSnapshot {
+            routes: vec![RouteSnapshot {
+                route: "/api/game/leaderboard",
+                requests: 10,
+                status_classes: vec![StatusClassCount {
+                    class: "2xx",
+                    count: 10,
+                }],
+                latency_buckets: vec![
+                    LatencyBucket { le_ms: Some(5), count: 9 },
+                    LatencyBucket { le_ms: None, count: 10 },
+                ],
+                latency_sum_ms: 31.5,
+            }],
+            session_cache: CacheSnapshot { hits: 8, misses: 2 },
+            sqlite_busy_errors: 0,
+            gauges: Some(GaugeSnapshot {
+                sampled_at: timestamp(),
+                db_connections: 4,
+                db_idle_connections: 3,
+                tokio_workers: 8,
+                tokio_alive_tasks: 21,
+                stream_users: 2,
+                stream_connections: 3,
+                matchmaking_players: 1,
+            }),
+        },
+    }
+}
+
+mod stream {
+    use super::timestamp;
+    use crate::game::pong::{PaddleDirection, Side, Snapshot};
+    use crate::game::session::{GameClientMsg, GameServerMsg};
+    use crate::stream::control::{
+        ControlClientMessage, ControlServerMessage, UserPresence,
+    };
+    use crate::stream::{BindChallenge, CloseCode, Notification, StreamType};
+
+    fn sample_presence() -> UserPresence {
+        UserPresence {
+            user_id: 8,
+            online: true,
+            last_seen: None,
+        }
+    }
+
+    super::golden! {
+        cbor stream_types => [
+            StreamType::Chat,
+            StreamType::Game,
+            StreamType::Control,
+        ],
+        cbor hello => ControlServerMessage::Hello {
+            protocol_version: 2,
+            compression: vec!["zstd".into(), "zstd-dict".into()],
+            max_frame: 8 * 1024 * 1024,
+        },
+        cbor unsupported_version => ControlServerMessage::UnsupportedVersion {
+            min_supported: 2,
+            max_supported: 2,
+        },
+        cbor pong => ControlServerMessage::Pong,
+        cbor bind_challenge => ControlServerMessage::BindChallenge(
+            BindChallenge {
+                connection_id: 42,
+                challenge_nonce: "bm9uY2U".into(),
+            },
+        ),
+        cbor connection_replaced => ControlServerMessage::ConnectionReplaced {
+            new_connection_id: 43,
+        },
+        cbor shutdown => ControlServerMessage::Shutdown {
+            code: CloseCode::Banned,
+            reason: "Banned".into(),
+        },
+        cbor presence => ControlServerMessage::Presence {
+            users: vec![sample_presence()],
+        },
+        cbor notifications => [
+            Notification::GameInvite {
+                invite_id: 9,
+                from_user_id: 7,
+                from_nickname: "ann".into(),
+            },
+            Notification::GameInviteAnswered {
+                invite_id: 9,
+                accepted: true,
+            },
+            Notification::MatchFound {
+                game_session_id: 11,
+                opponent_user_id: 8,
+                opponent_nickname: "ben".into(),
+                resume_token: "dG9rZW4".into(),
+            },
+            Notification::RecoveryCodesLow { remaining: 2 },
+            Notification::SessionRevoked { session_id: 5 },
+            Notification::PresenceChanged(sample_presence()),
+            Notification::Announcement {
+                message: "Maintenance at noon".into(),
+                created_at: timestamp(),
+            },
+        ],
+        cbor notification => ControlServerMessage::Notification(
+            Notification::RecoveryCodesLow { remaining: 2 },
+        ),
+        cbor game_server_messages => [
+            GameServerMsg::Start {
+                game_session_id: 11,
+                side: Side::A,
+                opponent_user_id: 8,
+            },
+            GameServerMsg::State {
+                tick: 30,
+                snapshot: Snapshot {
+                    ball: [400.0, 300.0],
+                    ball_velocity: [-6.0, 2.0],
+                    paddles: [300.0, 250.0],
+                    scores: [1, 0],
+                },
+            },
+            GameServerMsg::Paused { timeout_secs: 30 },
+            GameServerMsg::Resumed,
+            GameServerMsg::Finished {
+                winner: Some(Side::B),
+                forfeit: true,
+                scores: [1, 3],
+            },
+        ],
+        decode hello_ack => ControlClientMessage,
+            ControlClientMessage::HelloAck { protocol_version: 2, .. },
+        decode ping => ControlClientMessage, ControlClientMessage::Ping,
+        decode subscribe_presence => ControlClientMessage,
+            ControlClientMessage::SubscribePresence { .. },
+        decode unsubscribe_presence => ControlClientMessage,
+            ControlClientMessage::UnsubscribePresence { .. },
+        decode game_input => GameClientMsg,
+            GameClientMsg::Input { seq: 3, payload: PaddleDirection::Up },
+    }
+}